@@ -0,0 +1,142 @@
+//! Per-topic traffic accounting, from the record batches of produce requests and fetch responses.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use tracing::info;
+
+use crate::records::{split_batches, BatchHeader};
+
+/// Direction of records crossing the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Direction {
+    /// Records produced by remote clients to the local cluster.
+    Produced,
+
+    /// Records fetched by remote clients from the local cluster.
+    Fetched,
+}
+
+impl Direction {
+    /// Returns the name of the direction, as used in metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Produced => "produced",
+            Self::Fetched => "fetched",
+        }
+    }
+}
+
+/// Traffic of a client on a topic partition, in one direction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TrafficKey {
+    /// Whether the records were produced or fetched.
+    pub direction: Direction,
+
+    /// The client id sent in the request headers, empty if none.
+    pub client_id: String,
+
+    /// Name of the topic, or its id when the name is unknown.
+    pub topic: String,
+
+    /// Index of the partition.
+    pub partition: i32,
+}
+
+/// Bytes and records counted for a [`TrafficKey`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounts {
+    /// Bytes of record batches, as sent through the tunnel.
+    pub bytes: u64,
+
+    /// Records of the batches, read from their headers.
+    pub records: u64,
+}
+
+/// Totals of the records produced and fetched through the tunnels, by client and partition.
+///
+/// Records are counted from the batch headers, without decompressing them. Legacy message sets
+/// have no such header: their bytes are counted, but not their records.
+#[derive(Default)]
+pub struct TopicAccounting {
+    traffic: DashMap<TrafficKey, TrafficCounts>,
+}
+
+impl TopicAccounting {
+    /// Create empty totals.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the records of a partition.
+    pub fn record(
+        &self,
+        direction: Direction,
+        client_id: Option<&str>,
+        topic: &str,
+        partition: i32,
+        records: &Bytes,
+    ) {
+        if records.is_empty() {
+            return;
+        }
+        let key = TrafficKey {
+            direction,
+            client_id: client_id.unwrap_or_default().to_string(),
+            topic: topic.to_string(),
+            partition,
+        };
+        let mut counts = self.traffic.entry(key).or_default();
+        counts.bytes += records.len() as u64;
+        counts.records += count_records(records);
+    }
+
+    /// Returns the totals, sorted by direction, client, topic and partition.
+    pub fn snapshot(&self) -> Vec<(TrafficKey, TrafficCounts)> {
+        let mut traffic: Vec<_> = self
+            .traffic
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        traffic.sort_by(|(a, _), (b, _)| a.cmp(b));
+        traffic
+    }
+
+    /// Print the totals of each topic and client, largest first.
+    pub fn log_summary(&self, cluster: &str) {
+        let mut topics: BTreeMap<(Direction, String, String), TrafficCounts> = BTreeMap::new();
+        for (key, counts) in self.snapshot() {
+            let total = topics
+                .entry((key.direction, key.topic, key.client_id))
+                .or_default();
+            total.bytes += counts.bytes;
+            total.records += counts.records;
+        }
+        let mut topics: Vec<_> = topics.into_iter().collect();
+        topics.sort_by_key(|(_, counts)| Reverse(counts.bytes));
+
+        info!(cluster, topics = topics.len(), "topic traffic summary");
+        for ((direction, topic, client_id), counts) in topics {
+            info!(
+                cluster,
+                direction = direction.as_str(),
+                %topic,
+                %client_id,
+                bytes = counts.bytes,
+                records = counts.records,
+                "topic traffic"
+            );
+        }
+    }
+}
+
+/// Count the records of the batches of a partition, from their headers.
+fn count_records(records: &Bytes) -> u64 {
+    split_batches(records)
+        .iter()
+        .filter_map(|batch| BatchHeader::peek(batch).ok())
+        .map(|header| header.records_count.max(0) as u64)
+        .sum()
+}
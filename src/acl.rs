@@ -0,0 +1,243 @@
+//! Authorization of the requests of remote clients by principal, much like Kafka ACLs.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// A kind of resource protected by the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceType {
+    /// A topic, by name.
+    Topic,
+
+    /// A consumer group, by id.
+    Group,
+
+    /// The transactions of a producer, by transactional id.
+    TransactionalId,
+
+    /// The cluster itself, for the requests not bound to a topic or group.
+    Cluster,
+}
+
+/// An operation on a resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Consume from a topic, or join and commit offsets as a group member.
+    Read,
+
+    /// Produce to a topic, or to a transaction as its producer.
+    Write,
+
+    /// Create a topic.
+    Create,
+
+    /// Delete a topic or group.
+    Delete,
+
+    /// Add partitions to a topic, or change the cluster.
+    Alter,
+
+    /// Look up a resource, implied by every other operation allowed on it.
+    Describe,
+
+    /// Every operation.
+    All,
+}
+
+/// Whether a rule allows or denies its operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Allow the operations, unless denied by another rule.
+    Allow,
+
+    /// Deny the operations, whatever the other rules.
+    Deny,
+}
+
+/// A rule as written in the rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    principal: String,
+    permission: Permission,
+    resource: ResourceType,
+    name: Option<String>,
+    operations: Vec<Operation>,
+}
+
+/// The rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclConfig {
+    #[serde(default)]
+    users: HashMap<String, String>,
+    tunnel_principal: Option<String>,
+    rules: Vec<RuleConfig>,
+}
+
+/// A rule, with its name pattern compiled.
+#[derive(Debug)]
+struct Rule {
+    principal: String,
+    permission: Permission,
+    resource: ResourceType,
+    name: Option<Regex>,
+    operations: Vec<Operation>,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        principal: &str,
+        resource: ResourceType,
+        name: &str,
+        operation: Operation,
+    ) -> bool {
+        (self.principal == "*" || self.principal == principal)
+            && self.resource == resource
+            && self.name.as_ref().is_none_or(|regex| regex.is_match(name))
+            && self.operations.iter().any(|&granted| {
+                granted == Operation::All
+                    || granted == operation
+                    // like Kafka, allowing any operation allows describing the resource
+                    || (self.permission == Permission::Allow
+                        && operation == Operation::Describe
+                        && matches!(
+                            granted,
+                            Operation::Read | Operation::Write | Operation::Delete | Operation::Alter
+                        ))
+            })
+    }
+}
+
+/// Rules allowing or denying operations on topics, groups and the cluster to each principal.
+///
+/// Remote clients are identified by the username they authenticate with using SASL/PLAIN, which
+/// the proxy terminates. Connections that do not authenticate are given the principal of the
+/// tunnel, if any: as each cluster is exposed with its own secret, this identifies whoever was
+/// given that secret.
+///
+/// ```json
+/// {
+///   "users": { "alice": "alice-secret", "bob": "bob-secret" },
+///   "tunnel_principal": "staging",
+///   "rules": [
+///     { "principal": "alice", "permission": "allow", "resource": "topic", "name": "orders.*", "operations": ["read", "write"] },
+///     { "principal": "*", "permission": "allow", "resource": "group", "operations": ["read"] },
+///     { "principal": "bob", "permission": "deny", "resource": "topic", "name": "payments", "operations": ["all"] }
+///   ]
+/// }
+/// ```
+///
+/// Names are patterns matching the whole resource name, a rule without one matching every
+/// resource of its type. A principal of `*` matches every principal. An operation is allowed if
+/// an allow rule matches it and no deny rule does, so everything is denied by default.
+#[derive(Debug)]
+pub struct Authorizer {
+    /// SHA-256 of the password, by username.
+    users: HashMap<String, Vec<u8>>,
+
+    /// Principal of the connections that do not authenticate.
+    tunnel_principal: Option<String>,
+
+    rules: Vec<Rule>,
+}
+
+impl Authorizer {
+    /// Load the rules from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading authorization rules from {}", path.display()))?;
+        Self::from_json(&json)
+            .with_context(|| format!("invalid authorization rules {}", path.display()))
+    }
+
+    /// Parse the rules from a JSON object.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let config: AclConfig = serde_json::from_str(json)?;
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| {
+                ensure!(
+                    !rule.operations.is_empty(),
+                    "rule for {} has no operations",
+                    rule.principal
+                );
+                let name = match rule.name {
+                    Some(pattern) => Some(
+                        Regex::new(&format!("^(?:{pattern})$"))
+                            .with_context(|| format!("invalid name {pattern:?}"))?,
+                    ),
+                    None => None,
+                };
+                Ok(Rule {
+                    principal: rule.principal,
+                    permission: rule.permission,
+                    resource: rule.resource,
+                    name,
+                    operations: rule.operations,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            users: config
+                .users
+                .into_iter()
+                .map(|(username, password)| (username, Sha256::digest(password).to_vec()))
+                .collect(),
+            tunnel_principal: config.tunnel_principal,
+            rules,
+        })
+    }
+
+    /// Returns the principal of the connections that do not authenticate, if any.
+    pub fn tunnel_principal(&self) -> Option<&str> {
+        self.tunnel_principal.as_deref()
+    }
+
+    /// Check a SASL/PLAIN message, returning the authenticated principal.
+    ///
+    /// Authorization identities other than the username are refused.
+    pub fn authenticate(&self, auth_bytes: &[u8]) -> Option<String> {
+        let message = std::str::from_utf8(auth_bytes).ok()?;
+        let mut parts = message.split('\0');
+        let (authzid, username, password) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() || !(authzid.is_empty() || authzid == username) {
+            return None;
+        }
+        let expected = self.users.get(username)?;
+        // comparing digests does not leak the password through timing
+        (Sha256::digest(password).as_slice() == expected.as_slice()).then(|| username.to_string())
+    }
+
+    /// Returns whether a principal may perform an operation on a resource.
+    ///
+    /// Connections without a principal are denied everything.
+    pub fn allows(
+        &self,
+        principal: Option<&str>,
+        resource: ResourceType,
+        name: &str,
+        operation: Operation,
+    ) -> bool {
+        let Some(principal) = principal else {
+            return false;
+        };
+        let matching = |permission| {
+            self.rules.iter().any(|rule| {
+                rule.permission == permission && rule.matches(principal, resource, name, operation)
+            })
+        };
+        !matching(Permission::Deny) && matching(Permission::Allow)
+    }
+}
@@ -0,0 +1,451 @@
+//! Local HTTP/JSON admin API of the kafka proxy.
+
+use std::cmp::Reverse;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Result};
+use futures_util::future::try_join_all;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::inventory::ClientInfo;
+use crate::kafka::{ConnectionInfo, KafkaProxy, TunnelInfo};
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Maximum byte length of an admin request head.
+const MAX_REQUEST_LENGTH: usize = 16 * 1024;
+
+/// A response of the admin API.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string_pretty(body).expect("admin responses are serializable"),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, &json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Admin API listing the tunnels and connections of proxies, and controlling them.
+///
+/// | Method | Path                        | Description                                   |
+/// |--------|-----------------------------|-----------------------------------------------|
+/// | GET    | `/`                         | Overview of the proxied clusters              |
+/// | GET    | `/tunnels`                  | Known brokers and the state of their tunnels  |
+/// | GET    | `/connections`              | Active proxied connections                    |
+/// | GET    | `/clients`                  | Remote clients seen since startup             |
+/// | GET    | `/metrics`                  | Traffic metrics, in Prometheus text format    |
+/// | POST   | `/refresh`                  | Fetch the topology of every cluster again     |
+/// | POST   | `/tunnels/<port>/close`     | Close the tunnel on a remote port             |
+///
+/// Tunnels and connections carry the name of their cluster, as do metrics in a `cluster` label.
+/// Requests must be sent to the address of the API, or to `localhost` on its port, and not from
+/// a web page of another origin, so that pages opened by the operator cannot control the proxy.
+pub struct AdminApi {
+    /// The proxies being administered, one per cluster.
+    proxies: Vec<Arc<KafkaProxy>>,
+}
+
+impl AdminApi {
+    /// Create a new admin API for a proxy.
+    pub fn new(proxy: Arc<KafkaProxy>) -> Self {
+        Self {
+            proxies: vec![proxy],
+        }
+    }
+
+    /// Administer the proxy of another cluster as well.
+    pub fn with_proxy(mut self, proxy: Arc<KafkaProxy>) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Bind to a local address and serve the admin API.
+    pub async fn listen(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve the admin API on an already bound listener.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        info!(addr = ?listener.local_addr()?, "admin api listening");
+        let this = Arc::new(self);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let this = Arc::clone(&this);
+            tokio::spawn(
+                async move {
+                    if let Err(err) = this.handle_connection(stream).await {
+                        warn!(%err, "admin request failed");
+                    }
+                }
+                .instrument(info_span!("admin", ?addr)),
+            );
+        }
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let request = timeout(NETWORK_TIMEOUT, read_request(&mut stream)).await??;
+        let response = if request.is_local(stream.local_addr()?) {
+            self.route(&request.method, &request.path).await
+        } else {
+            warn!(
+                host = request.host.as_deref().unwrap_or(""),
+                origin = request.origin.as_deref().unwrap_or(""),
+                "refusing admin request from another origin"
+            );
+            Response::error(403, "forbidden")
+        };
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.reason(),
+            response.content_type,
+            response.body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(response.body.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn route(&self, method: &str, path: &str) -> Response {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", [""]) => Response::json(200, &self.overview()),
+            ("GET", ["tunnels"]) => Response::json(200, &self.tunnels()),
+            ("GET", ["connections"]) => Response::json(200, &self.connections()),
+            ("GET", ["clients"]) => Response::json(200, &self.clients()),
+            ("GET", ["metrics"]) => Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: self.metrics(),
+            },
+            ("POST", ["refresh"]) => {
+                match try_join_all(self.proxies.iter().map(|proxy| proxy.refresh())).await {
+                    Ok(_) => Response::json(200, &self.tunnels()),
+                    Err(err) => Response::error(500, &format!("{err:#}")),
+                }
+            }
+            ("POST", ["tunnels", port, "close"]) => match port.parse() {
+                Ok(port) if self.proxies.iter().any(|proxy| proxy.close_tunnel(port)) => {
+                    Response::json(200, &json!({ "closed": port }))
+                }
+                Ok(_) => Response::error(404, "no tunnel on this port"),
+                Err(_) => Response::error(400, "invalid port"),
+            },
+            (_, [""] | ["tunnels"] | ["connections"] | ["clients"] | ["metrics"] | ["refresh"]) => {
+                Response::error(405, "method not allowed")
+            }
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    /// Tunnels of every cluster, sorted by remote port.
+    fn tunnels(&self) -> Vec<TunnelInfo> {
+        let mut tunnels: Vec<_> = self.proxies.iter().flat_map(|p| p.tunnels()).collect();
+        tunnels.sort_by_key(|tunnel| tunnel.remote_port);
+        tunnels
+    }
+
+    /// Connections of every cluster, oldest first.
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self
+            .proxies
+            .iter()
+            .flat_map(|p| p.active_connections())
+            .collect();
+        connections.sort_by_key(|connection| Reverse(connection.age_secs));
+        connections
+    }
+
+    /// Remote clients of every cluster, most recently seen first.
+    fn clients(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<_> = self.proxies.iter().flat_map(|p| p.clients()).collect();
+        clients.sort_by_key(|client| Reverse(client.last_seen));
+        clients
+    }
+
+    fn overview(&self) -> serde_json::Value {
+        let clusters: Vec<_> = self
+            .proxies
+            .iter()
+            .map(|proxy| {
+                json!({
+                    "name": proxy.name(),
+                    "last_metadata_refresh": last_metadata_refresh(proxy),
+                    "tunnels": proxy.tunnels().len(),
+                    "connections": proxy.active_connections().len(),
+                })
+            })
+            .collect();
+        json!({
+            "server": self.proxies[0].to,
+            "tunnels": self.tunnels().len(),
+            "connections": self.connections().len(),
+            "clusters": clusters,
+        })
+    }
+
+    fn metrics(&self) -> String {
+        let mut tunnels = vec![];
+        let mut connections = vec![];
+        let mut connections_total = vec![];
+        let mut received = vec![];
+        let mut sent = vec![];
+        let mut refreshes = vec![];
+        let mut topic_bytes = vec![];
+        let mut topic_records = vec![];
+        let mut lags = vec![];
+        for proxy in &self.proxies {
+            let cluster = proxy.name();
+            let active = proxy.active_connections();
+            let totals = proxy.totals();
+            let open = proxy.tunnels().iter().filter(|t| t.state == "open").count();
+            tunnels.push((cluster, open as u64));
+            connections.push((cluster, active.len() as u64));
+            connections_total.push((cluster, totals.connections.load(Ordering::Relaxed)));
+            received.push((
+                cluster,
+                active.iter().map(|c| c.bytes_received).sum::<u64>()
+                    + totals.bytes_received.load(Ordering::Relaxed),
+            ));
+            sent.push((
+                cluster,
+                active.iter().map(|c| c.bytes_sent).sum::<u64>()
+                    + totals.bytes_sent.load(Ordering::Relaxed),
+            ));
+            if let Some(refresh) = last_metadata_refresh(proxy) {
+                refreshes.push((cluster, refresh));
+            }
+            for (key, counts) in proxy.accounting().map(|a| a.snapshot()).unwrap_or_default() {
+                let labels = format!(
+                    "cluster=\"{}\",direction=\"{}\",client_id=\"{}\",topic=\"{}\",partition=\"{}\"",
+                    label_value(cluster),
+                    key.direction.as_str(),
+                    label_value(&key.client_id),
+                    label_value(&key.topic),
+                    key.partition
+                );
+                topic_bytes.push((labels.clone(), counts.bytes));
+                topic_records.push((labels, counts.records));
+            }
+            for lag in proxy.lag().map(|lag| lag.snapshot()).unwrap_or_default() {
+                let labels = format!(
+                    "cluster=\"{}\",client_id=\"{}\",group_id=\"{}\",topic=\"{}\",partition=\"{}\"",
+                    label_value(cluster),
+                    label_value(&lag.client_id),
+                    label_value(lag.group_id.as_deref().unwrap_or_default()),
+                    label_value(&lag.topic),
+                    lag.partition
+                );
+                lags.push((labels, lag.lag as u64));
+            }
+        }
+
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "kafka_proxy_tunnels",
+            "gauge",
+            "Tunnels currently open.",
+            &tunnels,
+        );
+        write_metric(
+            &mut out,
+            "kafka_proxy_connections",
+            "gauge",
+            "Connections currently proxied.",
+            &connections,
+        );
+        write_metric(
+            &mut out,
+            "kafka_proxy_connections_total",
+            "counter",
+            "Connections proxied since startup.",
+            &connections_total,
+        );
+        write_metric(
+            &mut out,
+            "kafka_proxy_received_bytes_total",
+            "counter",
+            "Bytes received from remote clients.",
+            &received,
+        );
+        write_metric(
+            &mut out,
+            "kafka_proxy_sent_bytes_total",
+            "counter",
+            "Bytes sent back to remote clients.",
+            &sent,
+        );
+        if !refreshes.is_empty() {
+            write_metric(
+                &mut out,
+                "kafka_proxy_last_metadata_refresh_seconds",
+                "gauge",
+                "Time of the last metadata refresh, in seconds since the epoch.",
+                &refreshes,
+            );
+        }
+        if !topic_bytes.is_empty() {
+            write_labeled_metric(
+                &mut out,
+                "kafka_proxy_topic_bytes_total",
+                "counter",
+                "Bytes of record batches produced or fetched, by topic partition.",
+                &topic_bytes,
+            );
+            write_labeled_metric(
+                &mut out,
+                "kafka_proxy_topic_records_total",
+                "counter",
+                "Records produced or fetched, by topic partition.",
+                &topic_records,
+            );
+        }
+        if !lags.is_empty() {
+            write_labeled_metric(
+                &mut out,
+                "kafka_proxy_consumer_lag",
+                "gauge",
+                "Records between the last fetch offset of a consumer and the high watermark.",
+                &lags,
+            );
+        }
+        out
+    }
+}
+
+/// Seconds since the epoch of the last metadata refresh of a proxy.
+fn last_metadata_refresh(proxy: &KafkaProxy) -> Option<u64> {
+    proxy
+        .last_metadata_refresh()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs())
+}
+
+/// Append a metric in the Prometheus text format, with a value per cluster.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, values: &[(&str, u64)]) {
+    let values: Vec<_> = values
+        .iter()
+        .map(|(cluster, value)| (format!("cluster=\"{}\"", label_value(cluster)), *value))
+        .collect();
+    write_labeled_metric(out, name, kind, help, &values);
+}
+
+/// Append a metric in the Prometheus text format, with a value per set of labels.
+fn write_labeled_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: &[(String, u64)],
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in values {
+        let _ = writeln!(out, "{name}{{{labels}}} {value}");
+    }
+}
+
+/// Escape a label value of the Prometheus text format.
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The head of an admin request.
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    origin: Option<String>,
+}
+
+impl Request {
+    /// Whether the request was sent to the admin address itself, by name of `localhost` or not,
+    /// and not from a web page of another origin.
+    fn is_local(&self, local: SocketAddr) -> bool {
+        let allowed = [local.to_string(), format!("localhost:{}", local.port())];
+        let host = self
+            .host
+            .as_deref()
+            .is_some_and(|host| allowed.iter().any(|a| a.eq_ignore_ascii_case(host)));
+        let origin = self.origin.as_deref().is_none_or(|origin| {
+            allowed
+                .iter()
+                .any(|a| origin.eq_ignore_ascii_case(&format!("http://{a}")))
+        });
+        host && origin
+    }
+}
+
+/// Read the head of an HTTP request, returning its method, path, host and origin.
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before the end of the request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        if request.parse(&buf)?.is_complete() {
+            let method = request.method.unwrap_or_default().to_string();
+            let path = request.path.unwrap_or_default();
+            let path = path.split('?').next().unwrap_or_default().to_string();
+            let header = |name: &str| {
+                request
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case(name))
+                    .and_then(|header| std::str::from_utf8(header.value).ok())
+                    .map(str::to_string)
+            };
+            return Ok(Request {
+                method,
+                path,
+                host: header("Host"),
+                origin: header("Origin"),
+            });
+        }
+        if buf.len() > MAX_REQUEST_LENGTH {
+            bail!("request head too long");
+        }
+    }
+}
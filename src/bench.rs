@@ -0,0 +1,393 @@
+//! Load generation through a bootstrap address, to measure the throughput and latency of a path
+//! to a cluster, such as a tunnel.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::debug;
+
+use crate::console::{fetch, list_offset, KafkaConsole, LATEST_TIMESTAMP};
+use crate::kafka_client::KafkaClient;
+use crate::records::{split_batches, BatchHeader, RecordBatch};
+
+/// Size of the send time written at the start of every message, in microseconds since the
+/// benchmark started.
+pub const SEND_TIME_BYTES: usize = 8;
+
+/// How long the consumer keeps fetching once the producer is done, for messages still in flight.
+const CONSUME_GRACE: Duration = Duration::from_secs(5);
+
+/// Pause after a failed request, before reconnecting to the leader.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What a benchmark produces, and how fast.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchConfig {
+    /// Topic the messages are produced to, which should not have other producers.
+    pub topic: String,
+
+    /// Partition the messages are produced to.
+    pub partition: i32,
+
+    /// Size of the value of every message, at least [`SEND_TIME_BYTES`].
+    pub message_bytes: usize,
+
+    /// Messages sent in every produce request.
+    pub batch_records: usize,
+
+    /// Messages produced per second, as fast as possible if not set.
+    pub rate: Option<u64>,
+
+    /// How long messages are produced.
+    pub duration: Duration,
+
+    /// Whether the messages are consumed back, to measure their end-to-end latency.
+    pub consume: bool,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            topic: "bench".to_string(),
+            partition: 0,
+            message_bytes: 1024,
+            batch_records: 100,
+            rate: None,
+            duration: Duration::from_secs(10),
+            consume: true,
+        }
+    }
+}
+
+/// Percentiles of latency samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latencies {
+    /// Number of samples.
+    pub count: usize,
+
+    /// Median latency.
+    pub p50: Duration,
+
+    /// 99th percentile.
+    pub p99: Duration,
+
+    /// 99.9th percentile.
+    pub p999: Duration,
+
+    /// Largest latency.
+    pub max: Duration,
+}
+
+impl Latencies {
+    /// Compute the percentiles of some samples, with the nearest-rank method.
+    pub fn from_samples(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let percentile = |q: f64| {
+            let rank = (q * samples.len() as f64).ceil() as usize;
+            samples
+                .get(rank.saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+        Self {
+            count: samples.len(),
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:.1?}, p99 {:.1?}, p99.9 {:.1?}, max {:.1?}",
+            self.p50, self.p99, self.p999, self.max
+        )
+    }
+}
+
+/// Outcome of the consuming side of a benchmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerReport {
+    /// Time between the start of the benchmark and the last message consumed.
+    pub elapsed: Duration,
+
+    /// Messages of the benchmark consumed back.
+    pub messages: u64,
+
+    /// Fetch requests that failed.
+    pub errors: u64,
+
+    /// Time from the creation of each message to its consumption.
+    pub latency: Latencies,
+}
+
+/// Outcome of a benchmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchReport {
+    /// Size of the value of every message.
+    pub message_bytes: usize,
+
+    /// Messages sent in every produce request.
+    pub batch_records: usize,
+
+    /// Time spent producing.
+    pub elapsed: Duration,
+
+    /// Messages acknowledged by the leader.
+    pub produced: u64,
+
+    /// Produce requests that failed.
+    pub errors: u64,
+
+    /// Time from sending each produce request to its acknowledgement.
+    pub latency: Latencies,
+
+    /// The consuming side, unless disabled.
+    pub consumer: Option<ConsumerReport>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let throughput = |messages: u64, elapsed: Duration| {
+            let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+            let mib = (messages * self.message_bytes as u64) as f64 / (1024.0 * 1024.0);
+            format!(
+                "{:.0} msg/s, {:.2} MiB/s",
+                messages as f64 / secs,
+                mib / secs
+            )
+        };
+        writeln!(
+            f,
+            "Produced {} messages of {} bytes in {:.2?}: {}, {} error(s)",
+            self.produced,
+            self.message_bytes,
+            self.elapsed,
+            throughput(self.produced, self.elapsed),
+            self.errors
+        )?;
+        write!(
+            f,
+            "Produce latency ({} requests of {} messages): {}",
+            self.latency.count, self.batch_records, self.latency
+        )?;
+        if let Some(consumer) = &self.consumer {
+            write!(
+                f,
+                "\nConsumed {} messages in {:.2?}: {}, {} error(s)",
+                consumer.messages,
+                consumer.elapsed,
+                throughput(consumer.messages, consumer.elapsed),
+                consumer.errors
+            )?;
+            if consumer.messages < self.produced {
+                write!(f, ", {} missing", self.produced - consumer.messages)?;
+            }
+            write!(f, "\nEnd-to-end latency: {}", consumer.latency)?;
+        }
+        Ok(())
+    }
+}
+
+/// Produces messages to a partition, and consumes them back, through a bootstrap address.
+///
+/// The producer sends one request at a time and waits for the leader to acknowledge it, so the
+/// produce latency is that of a single request. Each message starts with the time it was
+/// created, which gives its end-to-end latency once consumed. Comparing the reports of a
+/// direct bootstrap server and of the public address of its tunnel gives the cost of the tunnel.
+pub struct Bench {
+    /// Client of the cluster, reaching the brokers at their advertised addresses.
+    console: KafkaConsole,
+
+    /// What to produce.
+    config: BenchConfig,
+}
+
+impl Bench {
+    /// Create a benchmark of the cluster reached by a console.
+    pub fn new(console: KafkaConsole, config: BenchConfig) -> Self {
+        Self { console, config }
+    }
+
+    /// Run the benchmark, failing only if the partition cannot be reached at all.
+    pub async fn run(&self) -> Result<BenchReport> {
+        let config = &self.config;
+        ensure!(
+            config.message_bytes >= SEND_TIME_BYTES,
+            "messages must be at least {SEND_TIME_BYTES} bytes"
+        );
+        ensure!(config.batch_records > 0, "batches must have a record");
+        ensure!(config.rate != Some(0), "the rate must be positive");
+
+        let mut producer = self.leader().await?;
+        let start_offset = list_offset(
+            &mut producer,
+            &config.topic,
+            config.partition,
+            LATEST_TIMESTAMP,
+        )
+        .await?;
+        let started = Instant::now();
+        let (done, done_receiver) = watch::channel(None);
+        let consumer = if config.consume {
+            let leader = self.leader().await?;
+            let consumer = Consumer {
+                console: self.console.clone(),
+                topic: config.topic.clone(),
+                partition: config.partition,
+                leader,
+                offset: start_offset,
+                started,
+            };
+            Some(tokio::spawn(consumer.run(done_receiver)))
+        } else {
+            None
+        };
+
+        let padding = vec![b'x'; config.message_bytes - SEND_TIME_BYTES];
+        let (mut sent, mut produced, mut errors) = (0, 0, 0);
+        let mut latencies = vec![];
+        while started.elapsed() < config.duration {
+            if let Some(rate) = config.rate {
+                sleep_until(started + Duration::from_secs_f64(sent as f64 / rate as f64)).await;
+                if started.elapsed() >= config.duration {
+                    break;
+                }
+            }
+            let records = message_batch(started, config.batch_records, &padding)?;
+            let sent_at = Instant::now();
+            sent += config.batch_records as u64;
+            match producer
+                .produce(&config.topic, config.partition, records)
+                .await
+            {
+                Ok(_) => {
+                    latencies.push(sent_at.elapsed());
+                    produced += config.batch_records as u64;
+                }
+                Err(err) => {
+                    debug!("benchmark produce failed: {err:#}");
+                    errors += 1;
+                    sleep(RETRY_BACKOFF).await;
+                    if let Ok(leader) = self.leader().await {
+                        producer = leader;
+                    }
+                }
+            }
+        }
+        let elapsed = started.elapsed();
+        let _ = done.send(Some(produced));
+
+        let consumer = match consumer {
+            Some(consumer) => Some(consumer.await??),
+            None => None,
+        };
+        Ok(BenchReport {
+            message_bytes: config.message_bytes,
+            batch_records: config.batch_records,
+            elapsed,
+            produced,
+            errors,
+            latency: Latencies::from_samples(latencies),
+            consumer,
+        })
+    }
+
+    /// Connect to the leader of the partition.
+    async fn leader(&self) -> Result<KafkaClient> {
+        self.console
+            .leader(&self.config.topic, self.config.partition)
+            .await
+    }
+}
+
+/// Fetches the messages of a benchmark, from the offset it started at.
+struct Consumer {
+    console: KafkaConsole,
+    topic: String,
+    partition: i32,
+    leader: KafkaClient,
+    offset: i64,
+    started: Instant,
+}
+
+impl Consumer {
+    /// Consume until every produced message is seen, or for a grace period once the producer
+    /// is done, which sends the number of messages it produced.
+    async fn run(mut self, mut done: watch::Receiver<Option<u64>>) -> Result<ConsumerReport> {
+        let (mut messages, mut errors) = (0, 0);
+        let mut latencies = vec![];
+        let mut last_consumed = self.started;
+        let mut done_at = None;
+        loop {
+            if let Some(produced) = *done.borrow_and_update() {
+                let done_at = *done_at.get_or_insert_with(Instant::now);
+                if messages >= produced || done_at.elapsed() > CONSUME_GRACE {
+                    break;
+                }
+            }
+            let records =
+                match fetch(&mut self.leader, &self.topic, self.partition, self.offset).await {
+                    Ok((_, records)) => records,
+                    Err(err) => {
+                        debug!("benchmark fetch failed: {err:#}");
+                        errors += 1;
+                        sleep(RETRY_BACKOFF).await;
+                        if let Ok(leader) = self.console.leader(&self.topic, self.partition).await {
+                            self.leader = leader;
+                        }
+                        continue;
+                    }
+                };
+            for batch in split_batches(&records) {
+                let header = BatchHeader::peek(&batch)?;
+                let next = header.base_offset + header.last_offset_delta as i64 + 1;
+                if !header.is_control() {
+                    for record in RecordBatch::decode(&batch)?.records {
+                        let offset = header.base_offset + record.offset_delta as i64;
+                        let value = record.value.unwrap_or_default();
+                        // compressed batches may start before the fetched offset
+                        if offset < self.offset || value.len() < SEND_TIME_BYTES {
+                            continue;
+                        }
+                        let send_time =
+                            u64::from_be_bytes(value[..SEND_TIME_BYTES].try_into().unwrap());
+                        let received = self.started.elapsed();
+                        latencies.push(received.saturating_sub(Duration::from_micros(send_time)));
+                        last_consumed = Instant::now();
+                        messages += 1;
+                    }
+                }
+                self.offset = self.offset.max(next);
+            }
+        }
+        Ok(ConsumerReport {
+            elapsed: last_consumed - self.started,
+            messages,
+            errors,
+            latency: Latencies::from_samples(latencies),
+        })
+    }
+}
+
+/// Encode a batch of messages, each made of the current time since `started` and `padding`.
+fn message_batch(started: Instant, count: usize, padding: &[u8]) -> Result<Bytes> {
+    let mut value = BytesMut::with_capacity(SEND_TIME_BYTES + padding.len());
+    value.put_u64(started.elapsed().as_micros() as u64);
+    value.put_slice(padding);
+    let value = value.freeze();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let records = (0..count).map(|_| (None, Some(value.clone()))).collect();
+    RecordBatch::new(timestamp, records).encode()
+}
@@ -0,0 +1,195 @@
+//! Fault injection in the responses sent to remote clients, to test how they handle broker
+//! problems.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use kafka_protocol::messages::ApiKey;
+use kafka_protocol::ResponseError;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::kafka::parse_api_key;
+
+/// A fault as written in the rules file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum FaultConfig {
+    LatencyMs(u64),
+    Error(String),
+    Disconnect,
+    ThrottleMs(u64),
+}
+
+/// A rule as written in the rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    api: Option<String>,
+    topic: Option<String>,
+    probability: Option<f64>,
+    fault: FaultConfig,
+}
+
+/// A fault injected by the proxy in place of the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Delay the request before forwarding it.
+    Latency(Duration),
+
+    /// Answer every partition of the request with an error.
+    Error(ResponseError),
+
+    /// Close the connection instead of forwarding the request.
+    Disconnect,
+
+    /// Delay the request, and ask the client to back off for as long in the response.
+    Throttle(Duration),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Latency(delay) => write!(f, "latency of {}ms", delay.as_millis()),
+            Self::Error(error) => write!(f, "error {}", error_name(*error)),
+            Self::Disconnect => write!(f, "disconnection"),
+            Self::Throttle(delay) => write!(f, "throttling of {}ms", delay.as_millis()),
+        }
+    }
+}
+
+/// A fault injected in the requests matching an API, topic and probability.
+#[derive(Debug)]
+struct ChaosRule {
+    api: Option<ApiKey>,
+    topic: Option<Regex>,
+    probability: f64,
+    fault: Fault,
+}
+
+impl ChaosRule {
+    fn new(config: RuleConfig) -> Result<Self> {
+        let api = config.api.as_deref().map(parse_api_key).transpose()?;
+        let topic = match &config.topic {
+            Some(topic) => Some(Regex::new(&format!("^(?:{topic})$")).context("invalid topic")?),
+            None => None,
+        };
+        let probability = config.probability.unwrap_or(1.0);
+        ensure!(
+            (0.0..=1.0).contains(&probability),
+            "probability must be between 0 and 1"
+        );
+        let fault = match config.fault {
+            FaultConfig::LatencyMs(ms) => Fault::Latency(Duration::from_millis(ms)),
+            FaultConfig::Error(name) => Fault::Error(parse_error(&name)?),
+            FaultConfig::Disconnect => Fault::Disconnect,
+            FaultConfig::ThrottleMs(ms) => {
+                ensure!(ms <= i32::MAX as u64, "throttle_ms too large");
+                Fault::Throttle(Duration::from_millis(ms))
+            }
+        };
+        let partitioned = matches!(api, Some(ApiKey::ProduceKey | ApiKey::FetchKey));
+        if matches!(fault, Fault::Error(_) | Fault::Throttle(_)) && !partitioned {
+            bail!("error and throttle_ms faults need the Produce or Fetch api");
+        }
+        if topic.is_some() && api.is_some() && !partitioned {
+            bail!("topics can only be matched on Produce and Fetch requests");
+        }
+        Ok(Self {
+            api,
+            topic,
+            probability,
+            fault,
+        })
+    }
+
+    fn matches(&self, api_key: ApiKey, topics: &[String]) -> bool {
+        if self.api.is_some_and(|api| api != api_key) {
+            return false;
+        }
+        match &self.topic {
+            Some(topic) => topics.iter().any(|name| topic.is_match(name)),
+            None => true,
+        }
+    }
+}
+
+/// Faults injected by the proxy in the requests of remote clients, without any broker change.
+///
+/// Rules are loaded from a JSON array, for instance:
+///
+/// ```json
+/// [
+///   { "api": "Produce", "topic": "orders.*", "probability": 0.1, "fault": { "error": "NOT_LEADER_OR_FOLLOWER" } },
+///   { "api": "Fetch", "probability": 0.05, "fault": { "throttle_ms": 2000 } },
+///   { "api": "Metadata", "fault": { "latency_ms": 500 } },
+///   { "probability": 0.001, "fault": "disconnect" }
+/// ]
+/// ```
+///
+/// The first matching rule whose draw succeeds applies. Rules without `api` match every
+/// request, and rules with a topic regex only the produce and fetch requests of a matching
+/// topic. Errors and throttling are only injected in produce and fetch responses.
+#[derive(Debug, Default)]
+pub struct Chaos {
+    rules: Vec<ChaosRule>,
+}
+
+impl Chaos {
+    /// Load the rules from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading chaos rules from {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("invalid chaos rules {}", path.display()))
+    }
+
+    /// Parse the rules from a JSON array.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let configs: Vec<RuleConfig> = serde_json::from_str(json)?;
+        let rules = configs
+            .into_iter()
+            .enumerate()
+            .map(|(i, config)| ChaosRule::new(config).with_context(|| format!("rule {i}")))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Returns whether a rule needs the topics of the requests of an API to match them.
+    pub fn matches_topics(&self, api_key: ApiKey) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.topic.is_some() && rule.api.is_none_or(|api| api == api_key))
+    }
+
+    /// Draw the fault to inject in a request, if any.
+    pub fn pick(&self, api_key: ApiKey, topics: &[String]) -> Option<Fault> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(api_key, topics))
+            .find(|rule| rule.probability >= 1.0 || rand::random::<f64>() < rule.probability)
+            .map(|rule| rule.fault)
+    }
+}
+
+/// Parse the name of an error, such as `NOT_LEADER_OR_FOLLOWER`.
+fn parse_error(name: &str) -> Result<ResponseError> {
+    (-1..1000)
+        .filter_map(ResponseError::try_from_code)
+        .find(|error| error_name(*error) == name)
+        .with_context(|| format!("unknown error {name:?}"))
+}
+
+/// Returns the name of an error as in the Kafka documentation, such as `REQUEST_TIMED_OUT`.
+fn error_name(error: ResponseError) -> String {
+    let mut name = String::new();
+    for c in format!("{error:?}").chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
@@ -1,64 +1,82 @@
 //! Client implementation for the `bore` service.
 
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
 use futures::future::{BoxFuture, FutureExt};
-use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::{net::TcpStream, time::timeout};
-use tracing::{error, info, info_span, warn, Instrument};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
-use crate::kafka::KafkaProxy;
+use crate::auth::Authenticator;
 use crate::shared::{ClientMessage, Delimited, ServerMessage, CONTROL_PORT, NETWORK_TIMEOUT};
 
+/// A local service whose connections are forwarded through a tunnel.
+pub trait Service: Send + Sync + 'static {
+    /// Proxy a connection accepted by the server to the local service.
+    fn proxy(self: Arc<Self>, connection: ForwardedConnection) -> BoxFuture<'static, Result<()>>;
+}
+
+/// A connection accepted on the public port.
+pub struct ForwardedConnection {
+    /// Identifier of the connection, assigned by the server.
+    pub id: Uuid,
+
+    /// Address of the remote client, when the server sent it.
+    pub peer: Option<SocketAddr>,
+
+    /// Bytes of the remote client already read from the server.
+    pub buffered: Bytes,
+
+    /// Connection to the server, carrying the traffic of the remote client.
+    pub remote: TcpStream,
+}
+
 /// State structure for the client.
 pub struct Client {
     /// Control connection to the server.
     conn: Option<Delimited<TcpStream>>,
 
-    // Local host that is forwarded.
-    local_host: String,
+    /// Address of the server.
+    to: String,
 
-    /// Local port that is forwarded.
-    local_port: u16,
+    /// Optional authenticator of the client.
+    auth: Option<Authenticator>,
 
     /// Port that is publicly available on the remote.
     remote_port: u16,
 
-    /// Ref to the kafka proxy managing this connection
-    proxy: Arc<KafkaProxy>,
+    /// Service the connections are handed to.
+    service: Arc<dyn Service>,
+
+    /// Cancelled to close the tunnel, and cancelled by the client once it stops listening.
+    shutdown: CancellationToken,
 }
 
 impl Client {
-    /// Create a new client.
-    pub async fn new(local_host: &str, local_port: u16, proxy: Arc<KafkaProxy>) -> Result<Self> {
-        let to = &proxy.to;
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
-        if let Some(auth) = &proxy.auth {
-            auth.client_handshake(&mut stream).await?;
-        }
-
-        //port = 0 => to force random port
-        stream.send(ClientMessage::Hello(0)).await?;
-        let remote_port = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(remote_port)) => remote_port,
-            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
-            Some(ServerMessage::Challenge(_)) => {
-                bail!("server requires authentication, but no client secret was provided");
-            }
-            Some(_) => bail!("unexpected initial non-hello message"),
-            None => bail!("unexpected EOF"),
-        };
+    /// Create a new client, handing the connections of its public port to a service.
+    pub async fn new(
+        to: &str,
+        auth: Option<Authenticator>,
+        service: Arc<dyn Service>,
+    ) -> Result<Self> {
+        let (stream, remote_port) = handshake(to, auth.as_ref()).await?;
         info!(remote_port, "connected to server");
         info!("listening at {to}:{remote_port}");
 
         Ok(Client {
             conn: Some(stream),
-            local_host: local_host.to_string(),
-            local_port,
+            to: to.to_string(),
+            auth,
             remote_port,
-            proxy,
+            service,
+            shutdown: CancellationToken::new(),
         })
     }
 
@@ -67,6 +85,11 @@ impl Client {
         self.remote_port
     }
 
+    /// Returns a token closing this tunnel and its connections when cancelled.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
     /// Handle a new connection.
     pub fn listen_boxed(self) -> BoxFuture<'static, Result<()>> {
         self.listen().boxed()
@@ -75,24 +98,23 @@ impl Client {
     /// Start the client, listening for new connections.
     pub async fn listen(mut self) -> Result<()> {
         let mut conn = self.conn.take().unwrap();
+        let _guard = self.shutdown.clone().drop_guard();
         let this = Arc::new(self);
         loop {
-            match conn.recv().await? {
+            let message = tokio::select! {
+                message = conn.recv() => message?,
+                _ = this.shutdown.cancelled() => return Ok(()),
+            };
+            match message {
                 Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
                 Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
                 Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
-                    let this = Arc::clone(&this);
-                    tokio::spawn(
-                        async move {
-                            info!("new connection");
-                            match this.handle_connection(id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
-                        }
-                        .instrument(info_span!("proxy", %id)),
-                    );
+                Some(ServerMessage::Connection(id)) => this.spawn_connection(id, None),
+                Some(ServerMessage::ConnectionFrom(id, peer)) => {
+                    this.spawn_connection(id, Some(peer))
+                }
+                Some(ServerMessage::ConnectionTo(id, peer, _)) => {
+                    this.spawn_connection(id, Some(peer))
                 }
                 Some(ServerMessage::Error(err)) => error!(%err, "server error"),
                 None => return Ok(()),
@@ -100,23 +122,316 @@ impl Client {
         }
     }
 
-    async fn handle_connection(&self, id: Uuid) -> Result<()> {
-        let mut remote_conn =
-            Delimited::new(connect_with_timeout(&self.proxy.to, CONTROL_PORT).await?);
-        if let Some(auth) = &self.proxy.auth {
+    /// Accept a forwarded connection in the background, until the tunnel is closed.
+    fn spawn_connection(self: &Arc<Self>, id: Uuid, peer: Option<SocketAddr>) {
+        let this = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                info!(?peer, "new connection");
+                let result = tokio::select! {
+                    result = this.handle_connection(id, peer) => result,
+                    _ = this.shutdown.cancelled() => Ok(()),
+                };
+                match result {
+                    Ok(_) => info!("connection exited"),
+                    Err(err) => warn!(%err, "connection exited with error"),
+                }
+            }
+            .instrument(info_span!("proxy", %id)),
+        );
+    }
+
+    async fn handle_connection(&self, id: Uuid, peer: Option<SocketAddr>) -> Result<()> {
+        let mut remote_conn = Delimited::new(connect_with_timeout(&self.to, CONTROL_PORT).await?);
+        if let Some(auth) = &self.auth {
             auth.client_handshake(&mut remote_conn).await?;
         }
         remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
         let parts = remote_conn.into_parts();
         debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-        local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        self.proxy.kafka_proxy(local_conn, parts.io).await?;
-        Ok(())
+        let connection = ForwardedConnection {
+            id,
+            peer,
+            buffered: parts.read_buf.freeze(), // mostly of the cases, this will be empty
+            remote: parts.io,
+        };
+        Arc::clone(&self.service).proxy(connection).await
+    }
+}
+
+/// A tunnel opened through a [`Control`].
+pub struct OpenedTunnel {
+    /// Port that is publicly available on the remote.
+    pub remote_port: u16,
+
+    /// Cancelled to close the tunnel, and cancelled once it stops listening.
+    pub shutdown: CancellationToken,
+}
+
+/// A control connection to a server, shared by the tunnels opened through it.
+///
+/// The connection is opened with the first tunnel, and again with the next tunnel once it is
+/// lost, closing the tunnels it forwarded. Servers that predate shared control connections close
+/// them on hello, and every tunnel then gets a [`Client`] of its own.
+pub struct Control {
+    /// Address of the server.
+    to: String,
+
+    /// Optional authenticator of the client.
+    auth: Option<Authenticator>,
+
+    /// Requests to the task owning the current shared connection, if any.
+    requests: Mutex<Option<mpsc::UnboundedSender<ControlRequest>>>,
+
+    /// Set once the server refused to share a control connection.
+    unshared: AtomicBool,
+}
+
+/// Where the tunnel asked for, or the reason it could not be opened, is sent.
+type OpenReply = oneshot::Sender<Result<OpenedTunnel>>;
+
+/// A request to the task owning a shared control connection.
+enum ControlRequest {
+    /// Forward a new public port to a service.
+    Open(Arc<dyn Service>, OpenReply),
+
+    /// Stop forwarding a public port.
+    Close(u16),
+}
+
+impl Control {
+    /// Create a control connection to a server, opened with the first tunnel.
+    pub fn new(to: &str, auth: Option<Authenticator>) -> Self {
+        Control {
+            to: to.to_string(),
+            auth,
+            requests: Mutex::new(None),
+            unshared: AtomicBool::new(false),
+        }
+    }
+
+    /// Open a tunnel, handing the connections of its public port to a service until it is
+    /// closed.
+    pub async fn open(&self, service: Arc<dyn Service>) -> Result<OpenedTunnel> {
+        if !self.unshared.load(Ordering::Relaxed) {
+            let mut requests = self.requests.lock().await;
+            if let Some(sender) = requests.as_ref().filter(|sender| !sender.is_closed()) {
+                let (reply, opened) = oneshot::channel();
+                if sender
+                    .send(ControlRequest::Open(Arc::clone(&service), reply))
+                    .is_ok()
+                {
+                    // tunnels are opened concurrently, the server answers them in order
+                    drop(requests);
+                    return opened.await.context("control connection closed")?;
+                }
+            }
+            match hello(&self.to, self.auth.as_ref(), ClientMessage::HelloShared(0)).await? {
+                Some((conn, remote_port)) => {
+                    info!(remote_port, "connected to server");
+                    info!("listening at {}:{remote_port}", self.to);
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    let mut shared = SharedConnection {
+                        to: self.to.clone(),
+                        auth: self.auth.clone(),
+                        tunnels: HashMap::new(),
+                        pending: VecDeque::new(),
+                        requests: sender.clone(),
+                    };
+                    let tunnel = shared.add(remote_port, service);
+                    tokio::spawn(shared.listen(conn, receiver));
+                    *requests = Some(sender);
+                    return Ok(tunnel);
+                }
+                None => {
+                    debug!("server does not share control connections, opening one per tunnel");
+                    self.unshared.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        let client = Client::new(&self.to, self.auth.clone(), service).await?;
+        let tunnel = OpenedTunnel {
+            remote_port: client.remote_port(),
+            shutdown: client.shutdown_token(),
+        };
+        tokio::spawn(client.listen());
+        Ok(tunnel)
+    }
+}
+
+/// The tunnels forwarded by a shared control connection, closed once it is dropped.
+struct SharedConnection {
+    /// Address of the server.
+    to: String,
+
+    /// Optional authenticator of the client.
+    auth: Option<Authenticator>,
+
+    /// Tunnels by public port.
+    tunnels: HashMap<u16, Arc<Client>>,
+
+    /// Tunnels asked for and not yet acknowledged, in the order they were asked for.
+    pending: VecDeque<(Arc<dyn Service>, OpenReply)>,
+
+    /// Sender of the requests to this connection, for closing its tunnels.
+    requests: mpsc::UnboundedSender<ControlRequest>,
+}
+
+impl SharedConnection {
+    /// Forward a public port to a service, closing it on the server once the tunnel is closed.
+    fn add(&mut self, remote_port: u16, service: Arc<dyn Service>) -> OpenedTunnel {
+        let shutdown = CancellationToken::new();
+        let client = Client {
+            conn: None,
+            to: self.to.clone(),
+            auth: self.auth.clone(),
+            remote_port,
+            service,
+            shutdown: shutdown.clone(),
+        };
+        self.tunnels.insert(remote_port, Arc::new(client));
+
+        let closed = shutdown.clone();
+        let requests = self.requests.clone();
+        tokio::spawn(async move {
+            closed.cancelled().await;
+            let _ = requests.send(ControlRequest::Close(remote_port));
+        });
+        OpenedTunnel {
+            remote_port,
+            shutdown,
+        }
+    }
+
+    /// Handle the messages of the server and the requests of the tunnels, until the connection
+    /// is lost.
+    async fn listen(
+        mut self,
+        mut conn: Delimited<TcpStream>,
+        mut requests: mpsc::UnboundedReceiver<ControlRequest>,
+    ) {
+        loop {
+            let result = tokio::select! {
+                message = conn.recv() => match message {
+                    Ok(Some(message)) => {
+                        self.handle(message);
+                        Ok(())
+                    }
+                    Ok(None) => return,
+                    Err(err) => Err(err),
+                },
+                Some(request) = requests.recv() => match request {
+                    ControlRequest::Open(service, reply) => {
+                        self.pending.push_back((service, reply));
+                        conn.send(ClientMessage::Open(0)).await
+                    }
+                    ControlRequest::Close(remote_port) => {
+                        if self.tunnels.remove(&remote_port).is_some() {
+                            conn.send(ClientMessage::Close(remote_port)).await
+                        } else {
+                            Ok(())
+                        }
+                    }
+                },
+            };
+            if let Err(err) = result {
+                warn!(%err, "shared control connection exited with error");
+                return;
+            }
+        }
+    }
+
+    /// Handle a message of the server.
+    fn handle(&mut self, message: ServerMessage) {
+        match message {
+            ServerMessage::Hello(remote_port) => match self.pending.pop_front() {
+                Some((service, reply)) => {
+                    info!("listening at {}:{remote_port}", self.to);
+                    let tunnel = self.add(remote_port, service);
+                    if let Err(Ok(tunnel)) = reply.send(Ok(tunnel)) {
+                        tunnel.shutdown.cancel();
+                    }
+                }
+                None => warn!("unexpected hello"),
+            },
+            ServerMessage::Challenge(_) => warn!("unexpected challenge"),
+            ServerMessage::Heartbeat => (),
+            ServerMessage::Connection(_) | ServerMessage::ConnectionFrom(..) => {
+                warn!("unexpected connection without a port")
+            }
+            ServerMessage::ConnectionTo(id, peer, remote_port) => {
+                match self.tunnels.get(&remote_port) {
+                    Some(client) => client.spawn_connection(id, Some(peer)),
+                    None => warn!(remote_port, "connection to a closed tunnel"),
+                }
+            }
+            // answers the oldest open, the other tunnels keep running
+            ServerMessage::Error(err) => match self.pending.pop_front() {
+                Some((_, reply)) => {
+                    warn!(%err, "could not open tunnel");
+                    let _ = reply.send(Err(anyhow!("server error: {err}")));
+                }
+                None => error!(%err, "server error"),
+            },
+        }
+    }
+}
+
+impl Drop for SharedConnection {
+    fn drop(&mut self) {
+        for client in self.tunnels.values() {
+            client.shutdown.cancel();
+        }
+    }
+}
+
+/// Open a control connection to the server, returning it with the public port it assigned.
+///
+/// The client first asks for the peer address of every connection. Servers that predate it
+/// close the connection on this unknown message, so the client then says hello again the way
+/// they expect, and its connections come without peer addresses.
+pub(crate) async fn handshake(
+    to: &str,
+    auth: Option<&Authenticator>,
+) -> Result<(Delimited<TcpStream>, u16)> {
+    //port = 0 => to force random port
+    match hello(to, auth, ClientMessage::HelloWithPeers(0)).await? {
+        Some(handshake) => Ok(handshake),
+        None => {
+            debug!("server does not send peer addresses, saying hello again");
+            hello(to, auth, ClientMessage::Hello(0))
+                .await?
+                .context("unexpected EOF")
+        }
     }
 }
 
-async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
+/// Say hello on a new control connection, returning `None` if the server closes it instead.
+async fn hello(
+    to: &str,
+    auth: Option<&Authenticator>,
+    message: ClientMessage,
+) -> Result<Option<(Delimited<TcpStream>, u16)>> {
+    let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
+    if let Some(auth) = auth {
+        auth.client_handshake(&mut stream).await?;
+    }
+
+    stream.send(message).await?;
+    let remote_port = match stream.recv_timeout().await? {
+        Some(ServerMessage::Hello(remote_port)) => remote_port,
+        Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+        Some(ServerMessage::Challenge(_)) => {
+            bail!("server requires authentication, but no client secret was provided");
+        }
+        Some(_) => bail!("unexpected initial non-hello message"),
+        None => return Ok(None),
+    };
+    Ok(Some((stream, remote_port)))
+}
+
+/// Connect to a host, failing after the network timeout.
+pub(crate) async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
     match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
         Ok(res) => res,
         Err(err) => Err(err.into()),
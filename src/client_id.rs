@@ -0,0 +1,64 @@
+//! Client ids rewritten in the requests forwarded to the brokers.
+
+use anyhow::{Context, Result};
+
+use crate::headers::{check_placeholders, InjectionContext};
+
+/// Placeholders that can appear in a client id template.
+const PLACEHOLDERS: [&str; 5] = ["original", "cluster", "tunnel_port", "peer_ip", "principal"];
+
+/// A template replacing the client id in the header of every request of remote clients, so
+/// that broker quotas and metrics attribute tunneled traffic.
+///
+/// The template may contain the placeholders `{original}`, the client id sent by the client,
+/// `{cluster}`, `{tunnel_port}`, `{peer_ip}` and `{principal}`, the latter set when requests
+/// are authorized by principal. Missing values are replaced with nothing, and a template
+/// without placeholders pins every client id to a fixed value. The proxy itself, from its logs
+/// to its metrics, still knows clients by their original id.
+#[derive(Debug, Clone)]
+pub struct ClientIdRewrite {
+    template: String,
+}
+
+impl ClientIdRewrite {
+    /// Parse a client id template, such as `tunnel-{cluster}-{original}`.
+    pub fn parse(template: &str) -> Result<Self> {
+        check_placeholders(template, &PLACEHOLDERS)
+            .with_context(|| format!("invalid client id template {template:?}"))?;
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    /// Render the client id of a request.
+    pub fn render(
+        &self,
+        original: Option<&str>,
+        cluster: &str,
+        principal: Option<&str>,
+        context: &InjectionContext,
+    ) -> String {
+        let tunnel_port = context
+            .tunnel_port
+            .map(|port| port.to_string())
+            .unwrap_or_default();
+        let peer_ip = context.peer_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        // in a single pass, so that braces in the original id are kept as is
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').expect("checked when parsed");
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(match &rest[start + 1..end] {
+                "original" => original.unwrap_or_default(),
+                "cluster" => cluster,
+                "tunnel_port" => &tunnel_port,
+                "peer_ip" => &peer_ip,
+                _ => principal.unwrap_or_default(),
+            });
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
@@ -0,0 +1,255 @@
+//! Configuration of the Kafka clusters exposed by a single proxy process.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+
+use crate::acl::Authorizer;
+use crate::chaos::Chaos;
+use crate::client_id::ClientIdRewrite;
+use crate::encryption::Encryption;
+use crate::headers::HeaderInjection;
+use crate::kafka::{KafkaProxy, DEFAULT_CLUSTER};
+use crate::limits::ProduceLimits;
+use crate::masking::Masking;
+use crate::schemas::SchemaValidation;
+use crate::shadow::ShadowWriter;
+use crate::stats::DEFAULT_STATS_INTERVAL_SECS;
+use crate::topic_policy::TopicPolicy;
+use crate::versions::VersionPolicy;
+
+/// A named Kafka cluster to expose, along with the options of its proxy.
+///
+/// Clusters are listed in a JSON file, with paths relative to that file:
+///
+/// ```json
+/// [
+///   { "name": "staging", "bootstrap_server": "localhost:9092", "expect_cluster_id": "lkc-8w3v" },
+///   { "name": "tests", "bootstrap_server": "localhost:29092", "secret": "other secret" }
+/// ]
+/// ```
+///
+/// Names must be unique, and are made of letters, digits, `-`, `_` and `.`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Name of the cluster, shown in logs, the admin API and metrics.
+    pub name: String,
+
+    /// The local bootstrap server, discovered among common local ports if not given.
+    #[serde(default)]
+    pub bootstrap_server: Option<String>,
+
+    /// Id the cluster must have, checked before any tunnel is opened and on every metadata.
+    #[serde(default)]
+    pub expect_cluster_id: Option<String>,
+
+    /// Secret for authentication, if different from the one of the process.
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// Rules masking fields of the fetched records.
+    #[serde(default)]
+    pub masking_rules: Option<PathBuf>,
+
+    /// Headers added to every produced record, as `KEY=VALUE`.
+    #[serde(default)]
+    pub inject_headers: Vec<String>,
+
+    /// Schemas the produced records must conform to.
+    #[serde(default)]
+    pub schemas: Option<PathBuf>,
+
+    /// Keys encrypting fetched records and decrypting produced ones.
+    #[serde(default)]
+    pub encryption_keys: Option<PathBuf>,
+
+    /// Bootstrap server of a secondary cluster receiving a copy of the produced records.
+    #[serde(default)]
+    pub shadow_bootstrap_server: Option<String>,
+
+    /// Rules injecting faults in the requests of remote clients.
+    #[serde(default)]
+    pub chaos_rules: Option<PathBuf>,
+
+    /// Minimum versions of the requests of remote clients, as `API=VERSION`.
+    #[serde(default)]
+    pub min_versions: Vec<String>,
+
+    /// Policy on the topics created by remote clients.
+    #[serde(default)]
+    pub topic_policy: Option<PathBuf>,
+
+    /// Authorization rules of the requests of remote clients, by principal.
+    #[serde(default)]
+    pub acl_rules: Option<PathBuf>,
+
+    /// Maximum size of the produce requests of remote clients, in bytes.
+    #[serde(default)]
+    pub max_produce_request_bytes: Option<usize>,
+
+    /// Maximum size of the record batches produced by remote clients, in bytes.
+    #[serde(default)]
+    pub max_batch_bytes: Option<usize>,
+
+    /// Maximum number of records in the batches produced by remote clients.
+    #[serde(default)]
+    pub max_batch_records: Option<usize>,
+
+    /// Template of the client id forwarded to the brokers instead of the one of remote clients.
+    #[serde(default)]
+    pub client_id: Option<String>,
+
+    /// Topic of the cluster the traffic statistics of the proxy are published to.
+    #[serde(default)]
+    pub stats_topic: Option<String>,
+
+    /// Seconds between two statistics records, 60 if not given.
+    #[serde(default)]
+    pub stats_interval_secs: Option<u64>,
+}
+
+impl ClusterConfig {
+    /// Create the configuration of a cluster without any option.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            bootstrap_server: None,
+            expect_cluster_id: None,
+            secret: None,
+            masking_rules: None,
+            inject_headers: vec![],
+            schemas: None,
+            encryption_keys: None,
+            shadow_bootstrap_server: None,
+            chaos_rules: None,
+            min_versions: vec![],
+            topic_policy: None,
+            acl_rules: None,
+            max_produce_request_bytes: None,
+            max_batch_bytes: None,
+            max_batch_records: None,
+            client_id: None,
+            stats_topic: None,
+            stats_interval_secs: None,
+        }
+    }
+
+    /// Load the clusters listed in a JSON file.
+    pub fn from_file(path: &Path) -> Result<Vec<Self>> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading clusters from {}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_json(&json, base).with_context(|| format!("invalid clusters {}", path.display()))
+    }
+
+    /// Parse a list of clusters, resolving their paths against a base directory.
+    pub fn from_json(json: &str, base: &Path) -> Result<Vec<Self>> {
+        let mut clusters: Vec<Self> = serde_json::from_str(json)?;
+        ensure!(!clusters.is_empty(), "no cluster configured");
+        let mut names = HashSet::new();
+        for cluster in &mut clusters {
+            let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+            if cluster.name.is_empty() || !cluster.name.chars().all(valid) {
+                bail!("invalid cluster name {:?}", cluster.name);
+            }
+            if !names.insert(cluster.name.clone()) {
+                bail!("duplicate cluster name {:?}", cluster.name);
+            }
+            ensure!(
+                cluster.stats_interval_secs != Some(0),
+                "invalid statistics interval for cluster {:?}",
+                cluster.name
+            );
+            for path in [
+                &mut cluster.masking_rules,
+                &mut cluster.schemas,
+                &mut cluster.encryption_keys,
+                &mut cluster.chaos_rules,
+                &mut cluster.topic_policy,
+                &mut cluster.acl_rules,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                *path = base.join(&*path);
+            }
+        }
+        Ok(clusters)
+    }
+
+    /// Build the proxy of the cluster, forwarding to a server.
+    ///
+    /// Without a secret of its own, the cluster uses `default_secret`. Encryption keys are
+    /// watched for changes and shadow writes sent in the background, so this must be called
+    /// within a Tokio runtime.
+    pub fn build(&self, to: &str, default_secret: Option<&str>) -> Result<KafkaProxy> {
+        let secret = self.secret.as_deref().or(default_secret);
+        let mut proxy = KafkaProxy::new(to, secret).with_name(&self.name);
+        if let Some(cluster_id) = &self.expect_cluster_id {
+            proxy = proxy.with_expected_cluster_id(cluster_id);
+        }
+        if let Some(path) = &self.masking_rules {
+            proxy = proxy.with_masking(Masking::from_file(path)?);
+        }
+        if !self.inject_headers.is_empty() {
+            proxy = proxy.with_headers(HeaderInjection::parse(&self.inject_headers)?);
+        }
+        if let Some(path) = &self.schemas {
+            proxy = proxy.with_schemas(SchemaValidation::from_file(path)?);
+        }
+        if let Some(path) = &self.encryption_keys {
+            let encryption = Arc::new(Encryption::from_file(path)?);
+            tokio::spawn(Arc::clone(&encryption).watch());
+            proxy = proxy.with_encryption(encryption);
+        }
+        if let Some(bootstrap) = &self.shadow_bootstrap_server {
+            let shadow = Arc::new(ShadowWriter::new(bootstrap));
+            tokio::spawn(Arc::clone(&shadow).run());
+            proxy = proxy.with_shadow(shadow);
+        }
+        if let Some(path) = &self.chaos_rules {
+            proxy = proxy.with_chaos(Chaos::from_file(path)?);
+        }
+        if !self.min_versions.is_empty() {
+            proxy = proxy.with_version_policy(VersionPolicy::parse(&self.min_versions)?);
+        }
+        if let Some(path) = &self.topic_policy {
+            proxy = proxy.with_topic_policy(TopicPolicy::from_file(path)?);
+        }
+        if let Some(path) = &self.acl_rules {
+            proxy = proxy.with_acl(Authorizer::from_file(path)?);
+        }
+        let limits = ProduceLimits {
+            max_request_bytes: self.max_produce_request_bytes,
+            max_batch_bytes: self.max_batch_bytes,
+            max_batch_records: self.max_batch_records,
+        };
+        if !limits.is_empty() {
+            proxy = proxy.with_produce_limits(limits);
+        }
+        if let Some(template) = &self.client_id {
+            proxy = proxy.with_client_id(ClientIdRewrite::parse(template)?);
+        }
+        Ok(proxy)
+    }
+
+    /// Returns the time between two statistics records.
+    pub fn stats_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.stats_interval_secs
+                .unwrap_or(DEFAULT_STATS_INTERVAL_SECS),
+        )
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_CLUSTER)
+    }
+}
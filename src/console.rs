@@ -0,0 +1,345 @@
+//! Kafka console commands, to check a cluster end to end through a tunnel or not.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::list_offsets_request::{ListOffsetsPartition, ListOffsetsTopic};
+use kafka_protocol::messages::{
+    BrokerId, FetchRequest, ListOffsetsRequest, MetadataRequest, MetadataResponse, TopicName,
+};
+use kafka_protocol::ResponseError;
+
+use crate::doctor::Check;
+use crate::kafka::str_bytes;
+use crate::kafka_client::{KafkaClient, METADATA_VERSION};
+use crate::records::{split_batches, BatchHeader, RecordBatch};
+
+/// Version of the Fetch requests sent by the console, the first one with record batches.
+const FETCH_VERSION: i16 = 4;
+
+/// Version of the ListOffsets requests sent by the console.
+const LIST_OFFSETS_VERSION: i16 = 1;
+
+/// How long a fetch waits for records before the broker answers.
+const FETCH_MAX_WAIT: Duration = Duration::from_millis(500);
+
+/// Maximum bytes fetched from a partition at once.
+const FETCH_MAX_BYTES: i32 = 1024 * 1024;
+
+/// Timestamp asking ListOffsets for the first offset of a partition.
+pub(crate) const EARLIEST_TIMESTAMP: i64 = -2;
+
+/// Timestamp asking ListOffsets for the offset of the next record of a partition.
+pub(crate) const LATEST_TIMESTAMP: i64 = -1;
+
+/// A topic of the cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicInfo {
+    /// Name of the topic.
+    pub name: String,
+
+    /// Number of partitions.
+    pub partitions: usize,
+
+    /// Whether the topic is internal to Kafka, such as `__consumer_offsets`.
+    pub internal: bool,
+}
+
+/// A record read from a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumedRecord {
+    /// Offset of the record in its partition.
+    pub offset: i64,
+
+    /// Timestamp of the record, in milliseconds since the epoch.
+    pub timestamp: i64,
+
+    /// The record key.
+    pub key: Option<Bytes>,
+
+    /// The record value.
+    pub value: Option<Bytes>,
+}
+
+/// A minimal Kafka client listing topics, producing and consuming, for smoke tests.
+///
+/// Brokers are reached at the addresses the bootstrap server advertises in its Metadata
+/// responses. Through a tunnel, these are the rewritten public addresses, so every request
+/// crosses the tunnel like those of any other remote client.
+#[derive(Clone)]
+pub struct KafkaConsole {
+    /// Bootstrap server of the cluster, such as the public address of a tunnel.
+    bootstrap_server: String,
+}
+
+impl KafkaConsole {
+    /// Create a console for the cluster at a bootstrap server.
+    pub fn new(bootstrap_server: &str) -> Self {
+        Self {
+            bootstrap_server: bootstrap_server.to_string(),
+        }
+    }
+
+    /// Check that every broker advertised by the bootstrap server answers, at an address
+    /// rewritten by a tunnel.
+    ///
+    /// Brokers exposed by a tunnel are advertised with the host of its public address, while
+    /// other hosts are those of brokers reached directly.
+    pub async fn check_tunnel(&self) -> Vec<Check> {
+        let metadata = match self.metadata(Some(vec![])).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                return vec![Check::new(
+                    format!("metadata through {}", self.bootstrap_server),
+                    Err(err),
+                    "the bootstrap address is not reachable, check that the tunnel is started \
+                     and the firewall in front of the server",
+                )];
+            }
+        };
+        let mut checks = vec![Check::pass(
+            format!("metadata through {}", self.bootstrap_server),
+            format!("{} broker(s) advertised", metadata.brokers.len()),
+        )];
+
+        let tunnel_host = host(&self.bootstrap_server);
+        for (node_id, broker) in &metadata.brokers {
+            let addr = format!("{}:{}", &*broker.host, broker.port);
+            let answered = async {
+                ensure!(
+                    *broker.host == *tunnel_host,
+                    "advertised host {} is not the tunnel host {tunnel_host}",
+                    &*broker.host
+                );
+                let response = KafkaClient::connect(&addr).await?.api_versions().await?;
+                Ok(format!("{} supported APIs", response.api_keys.len()))
+            };
+            checks.push(Check::new(
+                format!("broker {} at {addr}", node_id.0),
+                answered.await,
+                "the broker is not rewritten or not reachable, check that the bootstrap address \
+                 is the public address printed by `start`",
+            ));
+        }
+        checks
+    }
+
+    /// List the topics of the cluster, sorted by name.
+    pub async fn list_topics(&self) -> Result<Vec<TopicInfo>> {
+        let metadata = self.metadata(None).await?;
+        let mut topics: Vec<_> = metadata
+            .topics
+            .iter()
+            .map(|(name, topic)| TopicInfo {
+                name: name.to_string(),
+                partitions: topic.partitions.len(),
+                internal: topic.is_internal,
+            })
+            .collect();
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(topics)
+    }
+
+    /// Produce values to a partition in a single batch, returning the offset of the first one.
+    pub async fn produce(
+        &self,
+        topic: &str,
+        partition: i32,
+        key: Option<&str>,
+        values: &[String],
+    ) -> Result<i64> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let records = values
+            .iter()
+            .map(|value| {
+                (
+                    key.map(|key| Bytes::from(key.to_string())),
+                    Some(Bytes::from(value.clone())),
+                )
+            })
+            .collect();
+        let records = RecordBatch::new(timestamp, records).encode()?;
+        self.leader(topic, partition)
+            .await?
+            .produce(topic, partition, records)
+            .await
+    }
+
+    /// Consume the records of a partition from an offset, or from its start, until its end or
+    /// `max_records`.
+    pub async fn consume(
+        &self,
+        topic: &str,
+        partition: i32,
+        offset: Option<i64>,
+        max_records: usize,
+    ) -> Result<Vec<ConsumedRecord>> {
+        let mut leader = self.leader(topic, partition).await?;
+        let mut offset = match offset {
+            Some(offset) => offset,
+            None => list_offset(&mut leader, topic, partition, EARLIEST_TIMESTAMP).await?,
+        };
+
+        let mut consumed = vec![];
+        while consumed.len() < max_records {
+            let (high_watermark, records) = fetch(&mut leader, topic, partition, offset).await?;
+            let batches = split_batches(&records);
+            if batches.is_empty() {
+                // an empty fetch below the high watermark is a batch larger than the fetch size
+                ensure!(
+                    offset >= high_watermark,
+                    "no complete batch at offset {offset} of {topic}-{partition}"
+                );
+                break;
+            }
+            for batch in batches {
+                let header = BatchHeader::peek(&batch)?;
+                let next = header.base_offset + header.last_offset_delta as i64 + 1;
+                if !header.is_control() {
+                    let batch = RecordBatch::decode(&batch)?;
+                    for record in batch.records {
+                        let record = ConsumedRecord {
+                            offset: header.base_offset + record.offset_delta as i64,
+                            timestamp: header.base_timestamp + record.timestamp_delta,
+                            key: record.key,
+                            value: record.value,
+                        };
+                        // compressed batches may start before the fetched offset
+                        if record.offset >= offset && consumed.len() < max_records {
+                            consumed.push(record);
+                        }
+                    }
+                }
+                offset = offset.max(next);
+            }
+            if offset >= high_watermark {
+                break;
+            }
+        }
+        Ok(consumed)
+    }
+
+    /// Fetch the metadata of some topics, or of all of them.
+    async fn metadata(&self, topics: Option<Vec<String>>) -> Result<MetadataResponse> {
+        let mut client = KafkaClient::connect(&self.bootstrap_server).await?;
+        match topics {
+            Some(topics) => client.topic_metadata(&topics).await,
+            None => {
+                let mut request = MetadataRequest::default();
+                request.topics = None;
+                client
+                    .send(&request, METADATA_VERSION)
+                    .await
+                    .context("sending Metadata")
+            }
+        }
+    }
+
+    /// Connect to the leader of a partition, at its advertised address.
+    pub(crate) async fn leader(&self, topic: &str, partition: i32) -> Result<KafkaClient> {
+        let metadata = self.metadata(Some(vec![topic.to_string()])).await?;
+        let (_, topic_metadata) = metadata
+            .topics
+            .iter()
+            .find(|(name, _)| ***name == *topic)
+            .with_context(|| format!("topic {topic} not found"))?;
+        if let Some(error) = ResponseError::try_from_code(topic_metadata.error_code) {
+            bail!("metadata of topic {topic} failed with {error:?}");
+        }
+        let leader = topic_metadata
+            .partitions
+            .iter()
+            .find(|p| p.partition_index == partition)
+            .with_context(|| format!("partition {topic}-{partition} not found"))?
+            .leader_id;
+        ensure!(leader.0 >= 0, "no leader for {topic}-{partition}");
+        let broker = metadata
+            .brokers
+            .get(&leader)
+            .with_context(|| format!("unknown broker {}", leader.0))?;
+        KafkaClient::connect(&format!("{}:{}", &*broker.host, broker.port)).await
+    }
+}
+
+/// Returns the offset of a partition at a timestamp, such as [`EARLIEST_TIMESTAMP`].
+pub(crate) async fn list_offset(
+    leader: &mut KafkaClient,
+    topic: &str,
+    partition: i32,
+    timestamp: i64,
+) -> Result<i64> {
+    let mut request = ListOffsetsRequest::default();
+    request.replica_id = BrokerId(-1);
+    let mut list_partition = ListOffsetsPartition::default();
+    list_partition.partition_index = partition;
+    list_partition.timestamp = timestamp;
+    let mut list_topic = ListOffsetsTopic::default();
+    list_topic.name = TopicName(str_bytes(topic.to_string()));
+    list_topic.partitions.push(list_partition);
+    request.topics.push(list_topic);
+
+    let response = leader
+        .send(&request, LIST_OFFSETS_VERSION)
+        .await
+        .context("sending ListOffsets")?;
+    let answered = response
+        .topics
+        .iter()
+        .flat_map(|topic| &topic.partitions)
+        .find(|answered| answered.partition_index == partition)
+        .with_context(|| format!("no offset for {topic}-{partition}"))?;
+    if let Some(error) = ResponseError::try_from_code(answered.error_code) {
+        bail!("ListOffsets of {topic}-{partition} failed with {error:?}");
+    }
+    Ok(answered.offset)
+}
+
+/// Fetch the records of a partition from an offset, returning them with the high watermark.
+pub(crate) async fn fetch(
+    leader: &mut KafkaClient,
+    topic: &str,
+    partition: i32,
+    offset: i64,
+) -> Result<(i64, Bytes)> {
+    let mut request = FetchRequest::default();
+    request.replica_id = BrokerId(-1);
+    request.max_wait_ms = FETCH_MAX_WAIT.as_millis() as i32;
+    request.min_bytes = 1;
+    request.max_bytes = FETCH_MAX_BYTES;
+    let mut fetch_partition = FetchPartition::default();
+    fetch_partition.partition = partition;
+    fetch_partition.fetch_offset = offset;
+    fetch_partition.partition_max_bytes = FETCH_MAX_BYTES;
+    let mut fetch_topic = FetchTopic::default();
+    fetch_topic.topic = TopicName(str_bytes(topic.to_string()));
+    fetch_topic.partitions.push(fetch_partition);
+    request.topics.push(fetch_topic);
+
+    let response = leader
+        .send(&request, FETCH_VERSION)
+        .await
+        .context("sending Fetch")?;
+    let answered = response
+        .responses
+        .into_iter()
+        .flat_map(|topic| topic.partitions)
+        .find(|answered| answered.partition_index == partition)
+        .with_context(|| format!("no records for {topic}-{partition}"))?;
+    if let Some(error) = ResponseError::try_from_code(answered.error_code) {
+        bail!("Fetch of {topic}-{partition} at offset {offset} failed with {error:?}");
+    }
+    Ok((
+        answered.high_watermark,
+        answered.records.unwrap_or_default(),
+    ))
+}
+
+/// Returns the host of a `host:port` address.
+fn host(addr: &str) -> &str {
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host)
+}
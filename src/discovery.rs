@@ -0,0 +1,152 @@
+//! Discovery of the Kafka clusters running on the local machine.
+
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use futures::future::join_all;
+use kafka_protocol::messages::ApiKey;
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::kafka_client::KafkaClient;
+
+/// Ports commonly used by local Kafka clusters, in order of preference.
+pub const COMMON_PORTS: [u16; 6] = [9092, 9093, 9094, 9095, 29092, 19092];
+
+/// Timeout for a port to be recognized as a Kafka broker.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A Kafka cluster answering on a local port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredCluster {
+    /// Address the cluster was found on, usable as a bootstrap server.
+    pub bootstrap_server: String,
+
+    /// Id of the cluster, if the broker sent one.
+    pub cluster_id: Option<String>,
+
+    /// Number of brokers in the cluster.
+    pub brokers: usize,
+}
+
+impl fmt::Display for DiscoveredCluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (cluster {}, {} broker(s))",
+            self.bootstrap_server,
+            self.cluster_id.as_deref().unwrap_or("without id"),
+            self.brokers
+        )
+    }
+}
+
+/// Probe ports of a host for Kafka brokers, returning one entry per cluster.
+///
+/// Each port is sent a real ApiVersions request, then a Metadata request. Ports answering both
+/// are kept, in the order of `ports`, and ports of an already found cluster are skipped.
+pub async fn discover(host: &str, ports: &[u16]) -> Vec<DiscoveredCluster> {
+    let probes = ports.iter().map(|port| {
+        let addr = format!("{host}:{port}");
+        async move {
+            match timeout(PROBE_TIMEOUT, probe(&addr)).await {
+                Ok(Ok(cluster)) => Some(cluster),
+                Ok(Err(err)) => {
+                    debug!(%addr, %err, "no Kafka found");
+                    None
+                }
+                Err(_) => {
+                    debug!(%addr, "timed out probing for Kafka");
+                    None
+                }
+            }
+        }
+    });
+
+    let mut clusters: Vec<DiscoveredCluster> = vec![];
+    for cluster in join_all(probes).await.into_iter().flatten() {
+        let known = cluster.cluster_id.is_some()
+            && clusters
+                .iter()
+                .any(|known| known.cluster_id == cluster.cluster_id);
+        if !known {
+            clusters.push(cluster);
+        }
+    }
+    clusters
+}
+
+/// Check that a port answers as a Kafka broker, and describe its cluster.
+async fn probe(addr: &str) -> Result<DiscoveredCluster> {
+    let mut client = KafkaClient::connect(addr).await?;
+    let versions = client.api_versions().await?;
+    ensure!(
+        versions
+            .api_keys
+            .contains_key(&(ApiKey::MetadataKey as i16)),
+        "Metadata is not supported"
+    );
+    let metadata = client.metadata().await?;
+    Ok(DiscoveredCluster {
+        bootstrap_server: addr.to_string(),
+        cluster_id: metadata.cluster_id.map(|id| id.to_string()),
+        brokers: metadata.brokers.len(),
+    })
+}
+
+/// Pick the cluster to expose, asking on a prompt if given one and several were found.
+///
+/// Without a prompt, there must be exactly one cluster.
+pub fn select<R: BufRead, W: Write>(
+    mut clusters: Vec<DiscoveredCluster>,
+    prompt: Option<(R, W)>,
+) -> Result<DiscoveredCluster> {
+    match clusters.len() {
+        0 => bail!(
+            "no Kafka found on localhost ports {}, use --bootstrap-server",
+            COMMON_PORTS.map(|port| port.to_string()).join(", ")
+        ),
+        1 => return Ok(clusters.remove(0)),
+        _ => (),
+    }
+    let (mut input, mut output) = match prompt {
+        Some(prompt) => prompt,
+        None => bail!(
+            "found several Kafka clusters, use --bootstrap-server to pick one of: {}",
+            clusters
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    writeln!(output, "Found several Kafka clusters:")?;
+    for (i, cluster) in clusters.iter().enumerate() {
+        writeln!(output, "  {}) {cluster}", i + 1)?;
+    }
+    loop {
+        write!(
+            output,
+            "Which one should be exposed? [1-{}] ",
+            clusters.len()
+        )?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line).context("reading the choice")? == 0 {
+            bail!("no cluster chosen");
+        }
+        match line.trim().parse::<usize>() {
+            Ok(choice) if (1..=clusters.len()).contains(&choice) => {
+                return Ok(clusters.remove(choice - 1));
+            }
+            _ => writeln!(
+                output,
+                "Please enter a number between 1 and {}.",
+                clusters.len()
+            )?,
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! End-to-end diagnosis of a tunnel setup.
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+use kafka_protocol::messages::MetadataResponse;
+use tokio::net::lookup_host;
+
+use crate::auth::Authenticator;
+use crate::client::handshake;
+use crate::kafka::KafkaProxy;
+use crate::kafka_client::KafkaClient;
+use crate::shared::CONTROL_PORT;
+
+/// Outcome of a single diagnostic step.
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// What was checked.
+    pub name: String,
+
+    /// Whether the check passed.
+    pub passed: bool,
+
+    /// What was observed, or the error that occurred.
+    pub detail: String,
+
+    /// Suggested fix when the check failed.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    pub(crate) fn pass(name: impl Into<String>, detail: String) -> Self {
+        Self::new(name, Ok(detail), "")
+    }
+
+    pub(crate) fn new(
+        name: impl Into<String>,
+        result: Result<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        match result {
+            Ok(detail) => Self {
+                name: name.into(),
+                passed: true,
+                detail,
+                hint: None,
+            },
+            Err(err) => Self {
+                name: name.into(),
+                passed: false,
+                detail: format!("{err:#}"),
+                hint: Some(hint.into()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.passed { "PASS" } else { "FAIL" };
+        write!(f, "[{status}] {}: {}", self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Diagnoses each step needed to expose a Kafka cluster through a bore server.
+pub struct Doctor {
+    /// Address of the bore server.
+    to: String,
+
+    /// Optional secret used to authenticate with the server.
+    secret: Option<String>,
+
+    /// Local bootstrap server to expose.
+    bootstrap_server: String,
+}
+
+impl Doctor {
+    /// Create a new doctor for a server and a local bootstrap server.
+    pub fn new(to: &str, secret: Option<&str>, bootstrap_server: &str) -> Self {
+        Self {
+            to: to.to_string(),
+            secret: secret.map(str::to_string),
+            bootstrap_server: bootstrap_server.to_string(),
+        }
+    }
+
+    /// Run every check, skipping the ones whose prerequisites failed.
+    pub async fn run(&self) -> Vec<Check> {
+        let mut checks = vec![];
+
+        let bootstrap = Check::new(
+            format!("bootstrap server {}", self.bootstrap_server),
+            api_versions(&self.bootstrap_server).await,
+            "check that Kafka is running and that --bootstrap-server points to a PLAINTEXT listener",
+        );
+        let bootstrap_ok = bootstrap.passed;
+        checks.push(bootstrap);
+
+        let mut brokers_ok = false;
+        if bootstrap_ok {
+            brokers_ok = self.check_brokers(&mut checks).await;
+        }
+
+        let control = Check::new(
+            format!("control connection to {}:{CONTROL_PORT}", self.to),
+            self.control_handshake().await,
+            format!(
+                "check the server address, that TCP port {CONTROL_PORT} is open in your \
+                 firewall, and that --secret matches the server's secret"
+            ),
+        );
+        let control_ok = control.passed;
+        checks.push(control);
+
+        if brokers_ok && control_ok {
+            self.check_tunnel(&mut checks).await;
+        }
+        checks
+    }
+
+    /// Fetch metadata from the bootstrap server and reach every advertised broker.
+    async fn check_brokers(&self, checks: &mut Vec<Check>) -> bool {
+        let metadata = match fetch_metadata(&self.bootstrap_server).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                checks.push(Check::new(
+                    "cluster metadata",
+                    Err(err),
+                    "the bootstrap server answered ApiVersions but not Metadata, is it a Kafka broker?",
+                ));
+                return false;
+            }
+        };
+        checks.push(Check::pass(
+            "cluster metadata",
+            format!(
+                "cluster id {}, {} broker(s)",
+                metadata.cluster_id.as_deref().unwrap_or("<none>"),
+                metadata.brokers.len()
+            ),
+        ));
+
+        let mut all_ok = true;
+        for (node_id, broker) in &metadata.brokers {
+            let addr = format!("{}:{}", broker.host, broker.port);
+            let check = Check::new(
+                format!("broker {} advertised as {addr}", node_id.0),
+                reach_broker(&addr).await,
+                "the broker advertises an address that is not reachable from this machine, \
+                 fix `advertised.listeners` in the broker configuration",
+            );
+            all_ok &= check.passed;
+            checks.push(check);
+        }
+        all_ok
+    }
+
+    /// Authenticate with the server and ask for a public port.
+    async fn control_handshake(&self) -> Result<String> {
+        let auth = self.secret.as_deref().map(Authenticator::new);
+        let (_, remote_port) = handshake(&self.to, auth.as_ref()).await?;
+        Ok(format!("authenticated, server assigned port {remote_port}"))
+    }
+
+    /// Open a real tunnel and check that the rewritten brokers answer through it.
+    async fn check_tunnel(&self, checks: &mut Vec<Check>) {
+        let remote = KafkaProxy::new(&self.to, self.secret.as_deref())
+            .start(&self.bootstrap_server)
+            .await;
+        let remote = match remote {
+            Ok(remote) => remote,
+            Err(err) => {
+                checks.push(Check::new(
+                    "test tunnel",
+                    Err(err),
+                    "the server accepted the handshake but refused the tunnel, check its --min-port",
+                ));
+                return;
+            }
+        };
+
+        let metadata = match fetch_metadata(&remote).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                checks.push(Check::new(
+                    format!("metadata through tunnel {remote}"),
+                    Err(err),
+                    "the public port is not reachable, check the firewall in front of the server",
+                ));
+                return;
+            }
+        };
+        checks.push(Check::pass(
+            format!("metadata through tunnel {remote}"),
+            format!("{} broker(s) rewritten", metadata.brokers.len()),
+        ));
+
+        for (node_id, broker) in &metadata.brokers {
+            let addr = format!("{}:{}", broker.host, broker.port);
+            checks.push(Check::new(
+                format!("broker {} through tunnel {addr}", node_id.0),
+                api_versions(&addr).await,
+                "the rewritten broker address is not reachable, check the firewall in front of \
+                 the server",
+            ));
+        }
+    }
+}
+
+/// Connect to a broker and check that it answers ApiVersions.
+async fn api_versions(addr: &str) -> Result<String> {
+    let response = KafkaClient::connect(addr).await?.api_versions().await?;
+    Ok(format!("{} supported APIs", response.api_keys.len()))
+}
+
+/// Fetch the cluster metadata, without any topic.
+async fn fetch_metadata(addr: &str) -> Result<MetadataResponse> {
+    KafkaClient::connect(addr).await?.metadata().await
+}
+
+/// Resolve a broker address, then check that it answers ApiVersions.
+async fn reach_broker(addr: &str) -> Result<String> {
+    let resolved = lookup_host(addr)
+        .await
+        .with_context(|| format!("could not resolve {addr}"))?
+        .next()
+        .with_context(|| format!("{addr} resolved to no address"))?;
+    let detail = api_versions(addr).await?;
+    Ok(format!("resolved to {resolved}, {detail}"))
+}
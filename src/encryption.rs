@@ -0,0 +1,344 @@
+//! Envelope encryption of the records crossing the tunnels.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use crate::records::{split_batches, BatchHeader, InvalidRecord, RecordBatch, RecordHeader};
+
+/// Header carrying the id of the key a record was encrypted with.
+pub const KEY_ID_HEADER: &str = "x-encryption-key-id";
+
+/// Size of the nonce prepended to every encrypted value.
+const NONCE_SIZE: usize = 12;
+
+/// HKDF info deriving the key of the synthetic nonces from a data encryption key, so that the
+/// same secret is not used both by AES-GCM and by HMAC.
+const NONCE_KEY_INFO: &[u8] = b"conduktor-kafka-proxy synthetic nonce";
+
+/// The key file, as written on disk.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    active: String,
+    keys: HashMap<String, String>,
+    #[serde(default)]
+    encrypt_keys: bool,
+    #[serde(default)]
+    allow_plaintext: bool,
+}
+
+/// A data encryption key.
+struct DataKey {
+    cipher: Aes256Gcm,
+
+    /// Key of the HMAC deriving synthetic nonces, derived from the secret.
+    nonce_key: [u8; 32],
+}
+
+/// The keys loaded from the key file.
+struct KeyRing {
+    active: String,
+    keys: HashMap<String, DataKey>,
+    encrypt_keys: bool,
+    allow_plaintext: bool,
+}
+
+/// Encryption of the records sent to remote clients, and decryption of those they produce.
+///
+/// Keys are loaded from a JSON file mapping key ids to hex-encoded 256-bit AES keys:
+///
+/// ```json
+/// { "active": "2024-06", "keys": { "2024-01": "<64 hex digits>", "2024-06": "<64 hex digits>" } }
+/// ```
+///
+/// Values are encrypted with AES-256-GCM under the active key, whose id is carried in the
+/// [`KEY_ID_HEADER`] header, so that records encrypted with a retired key can still be read as
+/// long as it stays in the file. With `"encrypt_keys": true`, record keys are encrypted as well,
+/// deterministically so that equal keys still land on the same partition. Produced records that
+/// are not encrypted are rejected, unless `"allow_plaintext": true`.
+pub struct Encryption {
+    path: Option<PathBuf>,
+    keys: RwLock<KeyRing>,
+}
+
+impl Encryption {
+    /// Load the keys from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let keys = KeyRing::load(path)?;
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Parse the keys from a JSON document.
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(Self {
+            path: None,
+            keys: RwLock::new(KeyRing::parse(json)?),
+        })
+    }
+
+    /// Load the key file again, keeping the current keys if it is invalid.
+    pub fn reload(&self) -> Result<()> {
+        let path = self
+            .path
+            .as_deref()
+            .context("keys were not loaded from a file")?;
+        let keys = KeyRing::load(path)?;
+        info!(active = %keys.active, count = keys.keys.len(), "reloaded encryption keys");
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Reload the key file on `SIGHUP`, where supported.
+    pub async fn watch(self: Arc<Self>) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            while hup.recv().await.is_some() {
+                if let Err(err) = self.reload() {
+                    warn!(%err, "unable to reload encryption keys, keeping the current ones");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypt the records fetched from a topic partition with the active key.
+    ///
+    /// Every record is encrypted, even one already carrying a key id, whose header is kept after
+    /// the one added. Fails on batches that cannot be encrypted, such as legacy message sets.
+    pub fn encrypt_records(&self, records: &Bytes) -> Result<Bytes> {
+        let keys = self.keys.read().unwrap();
+        let key = &keys.keys[&keys.active];
+
+        let mut out = BytesMut::with_capacity(records.len());
+        for batch in split_batches(records) {
+            if BatchHeader::peek(&batch)?.is_control() {
+                out.put_slice(&batch);
+                continue;
+            }
+            let mut decoded = RecordBatch::decode(&batch)?;
+            for record in &mut decoded.records {
+                if let Some(value) = &record.value {
+                    record.value = Some(key.seal(value, &random_nonce())?.into());
+                }
+                if keys.encrypt_keys {
+                    if let Some(value) = &record.key {
+                        let nonce = key.synthetic_nonce(value);
+                        record.key = Some(key.seal(value, &nonce)?.into());
+                    }
+                }
+                // first, as the first key id is the one decrypting the record
+                record.headers.insert(
+                    0,
+                    RecordHeader {
+                        key: KEY_ID_HEADER.to_string(),
+                        value: Some(Bytes::from(keys.active.clone())),
+                    },
+                );
+            }
+            out.put_slice(&decoded.encode()?);
+        }
+        Ok(out.freeze())
+    }
+
+    /// Decrypt the records produced to a topic partition.
+    ///
+    /// Records are decrypted with the key of their first key id header, which is removed from
+    /// them. Records encrypted with an unknown key, or whose ciphertext was tampered with, are
+    /// rejected, as are records without a key id and legacy message sets, which cannot carry one,
+    /// unless plaintext is allowed.
+    pub fn decrypt_records(&self, records: &Bytes) -> Result<Bytes, InvalidRecord> {
+        let keys = self.keys.read().unwrap();
+        let batches = split_batches(records);
+        let length: usize = batches.iter().map(Bytes::len).sum();
+        if length != records.len() {
+            // the batches split off are complete, the rest is the next one
+            return Err(InvalidRecord {
+                batch_index: None,
+                message: format!("truncated record batch {}", batches.len()),
+            });
+        }
+
+        let mut out = BytesMut::with_capacity(records.len());
+        for (batch_number, batch) in batches.into_iter().enumerate() {
+            let invalid = |batch_index: Option<i32>, message: String| InvalidRecord {
+                batch_index,
+                message,
+            };
+            match BatchHeader::peek(&batch) {
+                Ok(header) if !header.is_control() => (),
+                Ok(_) => {
+                    out.put_slice(&batch);
+                    continue;
+                }
+                // legacy message sets cannot carry a key id, hence are not encrypted
+                Err(_) if keys.allow_plaintext => {
+                    out.put_slice(&batch);
+                    continue;
+                }
+                Err(err) => {
+                    return Err(invalid(
+                        None,
+                        format!("unencrypted record batch {batch_number}: {err:#}"),
+                    ));
+                }
+            }
+            let mut decoded = RecordBatch::decode(&batch).map_err(|err| {
+                invalid(
+                    None,
+                    format!("undecodable record batch {batch_number}: {err:#}"),
+                )
+            })?;
+            let mut changed = false;
+            for (index, record) in decoded.records.iter_mut().enumerate() {
+                let index = index as i32;
+                let invalid = |message: String| invalid(Some(index), message);
+                let position = record
+                    .headers
+                    .iter()
+                    .position(|header| header.key == KEY_ID_HEADER);
+                if let Some(position) = position {
+                    let header = record.headers.remove(position);
+                    let key_id = header
+                        .value
+                        .as_deref()
+                        .map(String::from_utf8_lossy)
+                        .unwrap_or_default();
+                    let key = keys.keys.get(&*key_id).ok_or_else(|| {
+                        invalid(format!(
+                            "record {index} of batch {batch_number} uses unknown key {key_id:?}"
+                        ))
+                    })?;
+                    let open = |value: &Bytes| {
+                        key.open(value).map(Bytes::from).map_err(|err| {
+                            invalid(format!(
+                                "record {index} of batch {batch_number} cannot be decrypted: {err}"
+                            ))
+                        })
+                    };
+                    if let Some(value) = &record.value {
+                        record.value = Some(open(value)?);
+                    }
+                    if keys.encrypt_keys {
+                        if let Some(value) = &record.key {
+                            record.key = Some(open(value)?);
+                        }
+                    }
+                    changed = true;
+                } else if !keys.allow_plaintext {
+                    return Err(invalid(format!(
+                        "record {index} of batch {batch_number} is not encrypted"
+                    )));
+                }
+            }
+            if changed {
+                let encoded = decoded.encode().map_err(|err| {
+                    invalid(None, format!("record batch {batch_number}: {err:#}"))
+                })?;
+                out.put_slice(&encoded);
+            } else {
+                out.put_slice(&batch);
+            }
+        }
+        Ok(out.freeze())
+    }
+}
+
+impl KeyRing {
+    fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading encryption keys from {}", path.display()))?;
+        Self::parse(&json).with_context(|| format!("invalid encryption keys {}", path.display()))
+    }
+
+    fn parse(json: &str) -> Result<Self> {
+        let file: KeyFile = serde_json::from_str(json)?;
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|(id, hex_key)| {
+                let key = DataKey::from_hex(&hex_key).with_context(|| format!("key {id:?}"))?;
+                Ok((id, key))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        ensure!(
+            keys.contains_key(&file.active),
+            "active key {:?} not found",
+            file.active
+        );
+        Ok(Self {
+            active: file.active,
+            keys,
+            encrypt_keys: file.encrypt_keys,
+            allow_plaintext: file.allow_plaintext,
+        })
+    }
+}
+
+impl DataKey {
+    fn from_hex(hex_key: &str) -> Result<Self> {
+        let secret: [u8; 32] = match hex::decode(hex_key.trim())?.try_into() {
+            Ok(secret) => secret,
+            Err(_) => bail!("expected a 256-bit key, as 64 hex digits"),
+        };
+        let cipher = Aes256Gcm::new_from_slice(&secret).expect("valid key size");
+        let mut nonce_key = [0; 32];
+        Hkdf::<Sha256>::new(None, &secret)
+            .expand(NONCE_KEY_INFO, &mut nonce_key)
+            .expect("valid output size");
+        Ok(Self { cipher, nonce_key })
+    }
+
+    /// Encrypt a value, returning the nonce followed by the ciphertext and its tag.
+    fn seal(&self, plaintext: &[u8], nonce: &[u8; NONCE_SIZE]) -> Result<Vec<u8>> {
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(nonce), plaintext)
+            .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt a value sealed by [`DataKey::seal`].
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if sealed.len() < NONCE_SIZE {
+            return Err("value too short");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "authentication failed")
+    }
+
+    /// Derive a nonce from the plaintext, so that equal plaintexts give equal ciphertexts.
+    fn synthetic_nonce(&self, plaintext: &[u8]) -> [u8; NONCE_SIZE] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC can take key of any size");
+        mac.update(plaintext);
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&mac.finalize().into_bytes()[..NONCE_SIZE]);
+        nonce
+    }
+}
+
+/// Generate a random nonce.
+fn random_nonce() -> [u8; NONCE_SIZE] {
+    Aes256Gcm::generate_nonce(&mut OsRng).into()
+}
@@ -0,0 +1,153 @@
+//! Record headers injected into the records produced through a tunnel.
+
+use std::net::IpAddr;
+
+use anyhow::{bail, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use uuid::Uuid;
+
+use crate::records::{split_batches, BatchHeader, RecordBatch, RecordHeader};
+
+/// Placeholders that can appear in header values.
+const PLACEHOLDERS: [&str; 4] = ["connection_id", "tunnel_port", "peer_ip", "traceparent"];
+
+/// The proxied connection a produce request was received on.
+#[derive(Debug, Clone)]
+pub struct InjectionContext {
+    /// Identifier of the connection, assigned by the server.
+    pub connection_id: Uuid,
+
+    /// Public port of the tunnel the connection came through.
+    pub tunnel_port: Option<u16>,
+
+    /// Address of the remote client, when the server sent it.
+    pub peer_ip: Option<IpAddr>,
+}
+
+/// A header to inject, whose value may contain placeholders.
+#[derive(Debug, Clone)]
+struct HeaderTemplate {
+    key: String,
+    value: String,
+}
+
+/// Headers added to every record of the produce requests received through the tunnels.
+///
+/// Each header is given as `KEY=VALUE`, where the value may contain the placeholders
+/// `{connection_id}`, `{tunnel_port}`, `{peer_ip}` and `{traceparent}`, the latter generating
+/// a new W3C trace context for each record. Records that already carry a header with the
+/// same key keep their own value.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderInjection {
+    headers: Vec<HeaderTemplate>,
+}
+
+impl HeaderInjection {
+    /// Parse headers given as `KEY=VALUE`.
+    pub fn parse<S: AsRef<str>>(specs: &[S]) -> Result<Self> {
+        let headers = specs
+            .iter()
+            .map(|spec| {
+                let spec = spec.as_ref();
+                let (key, value) = spec
+                    .split_once('=')
+                    .with_context(|| format!("invalid header {spec:?}, expected KEY=VALUE"))?;
+                if key.is_empty() {
+                    bail!("invalid header {spec:?}, empty key");
+                }
+                check_placeholders(value, &PLACEHOLDERS)
+                    .with_context(|| format!("invalid header {spec:?}"))?;
+                Ok(HeaderTemplate {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { headers })
+    }
+
+    /// Add the headers to every record of a partition of a produce request.
+    ///
+    /// Batch headers are kept as is, so that the sequence numbers of idempotent producers and
+    /// the markers of transactions stay valid. Legacy message sets, which cannot carry headers,
+    /// and control batches are forwarded unchanged.
+    pub fn inject_records(&self, records: &Bytes, context: &InjectionContext) -> Result<Bytes> {
+        let batches = split_batches(records);
+        let length: usize = batches.iter().map(Bytes::len).sum();
+        if length != records.len() {
+            bail!("produce request with a truncated record batch");
+        }
+
+        let headers = self.render(context);
+        let mut out = BytesMut::with_capacity(records.len());
+        for batch in batches {
+            match BatchHeader::peek(&batch) {
+                Ok(header) if !header.is_control() => (),
+                _ => {
+                    out.put_slice(&batch);
+                    continue;
+                }
+            }
+            let mut decoded = RecordBatch::decode(&batch)?;
+            for record in &mut decoded.records {
+                for (key, value) in &headers {
+                    if record.headers.iter().any(|header| &header.key == key) {
+                        continue;
+                    }
+                    let value = value.replace("{traceparent}", &traceparent());
+                    record.headers.push(RecordHeader {
+                        key: key.clone(),
+                        value: Some(value.into()),
+                    });
+                }
+            }
+            out.put_slice(&decoded.encode()?);
+        }
+        Ok(out.freeze())
+    }
+
+    /// Substitute the placeholders known for a connection.
+    fn render(&self, context: &InjectionContext) -> Vec<(String, String)> {
+        let tunnel_port = context
+            .tunnel_port
+            .map(|port| port.to_string())
+            .unwrap_or_default();
+        let peer_ip = context.peer_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        self.headers
+            .iter()
+            .map(|header| {
+                let value = header
+                    .value
+                    .replace("{connection_id}", &context.connection_id.to_string())
+                    .replace("{tunnel_port}", &tunnel_port)
+                    .replace("{peer_ip}", &peer_ip);
+                (header.key.clone(), value)
+            })
+            .collect()
+    }
+}
+
+/// Check that every placeholder of a template is one of `placeholders`.
+pub(crate) fn check_placeholders(value: &str, placeholders: &[&str]) -> Result<()> {
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').context("unclosed placeholder")? + start;
+        let name = &rest[start + 1..end];
+        if !placeholders.contains(&name) {
+            let expected: Vec<_> = placeholders.iter().map(|p| format!("{{{p}}}")).collect();
+            bail!(
+                "unknown placeholder {{{name}}}, expected one of {}",
+                expected.join(", ")
+            );
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+/// Generate a W3C trace context for a new sampled trace.
+fn traceparent() -> String {
+    let trace_id = Uuid::new_v4().simple().to_string();
+    let span_id = &Uuid::new_v4().simple().to_string()[..16];
+    format!("00-{trace_id}-{span_id}-01")
+}
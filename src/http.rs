@@ -0,0 +1,827 @@
+//! HTTP tunnels, routing requests to local services and rewriting the URLs they send back.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{bail, ensure, Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tracing::{debug, info, warn};
+
+use crate::auth::Authenticator;
+use crate::client::{connect_with_timeout, Client, ForwardedConnection, Service};
+
+/// Largest accepted head of an HTTP message.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Longest textual body read in full for its URLs to be rewritten, longer ones are streamed as is.
+const MAX_REWRITTEN_LENGTH: usize = 4 * 1024 * 1024;
+
+/// Methods whose requests can be sent twice, when they have no body.
+const IDEMPOTENT_METHODS: [&str; 5] = ["GET", "HEAD", "OPTIONS", "TRACE", "DELETE"];
+
+/// Response headers carrying URLs.
+const URL_HEADERS: [&str; 2] = ["location", "content-location"];
+
+/// How the length of an HTTP body is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// The message has no body.
+    Empty,
+    /// The body has a `Content-Length`.
+    Length(usize),
+    /// The body uses chunked transfer encoding.
+    Chunked,
+    /// The body runs until the connection is closed.
+    UntilClose,
+}
+
+/// The head of an HTTP/1.x message, whose body is streamed unless the proxy builds or rewrites it.
+#[derive(Debug)]
+struct Message {
+    /// Request or status line, without its line ending.
+    start_line: String,
+    headers: Vec<(String, Vec<u8>)>,
+    framing: Framing,
+    /// The body built or rewritten by the proxy, if any.
+    body: Vec<u8>,
+    /// Whether the connection is closed after this message.
+    close: bool,
+}
+
+/// Which requests a route receives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RouteMatch {
+    /// Every request.
+    Any,
+    /// Requests whose path starts with a prefix, removed before forwarding.
+    Prefix(String),
+    /// Requests whose `Host` header names a host.
+    Host(String),
+}
+
+/// A local HTTP service reachable through a tunnel.
+#[derive(Debug, Clone)]
+pub struct HttpRoute {
+    name: String,
+    matcher: RouteMatch,
+    /// Origin of the local service, such as `http://localhost:8083`.
+    origin: String,
+    host: String,
+    port: u16,
+}
+
+/// The start of a textual body, read to rewrite its URLs.
+enum Buffered {
+    /// The whole body.
+    Complete(Vec<u8>),
+    /// The start of a body too long to be rewritten, with the size of its next chunk if chunked.
+    Partial(Vec<u8>, Option<usize>),
+}
+
+/// A connection to a local service, kept open across requests.
+struct Upstream {
+    read: BufReader<OwnedReadHalf>,
+    write: OwnedWriteHalf,
+}
+
+/// Local HTTP services exposed on a single public port.
+///
+/// Connections are proxied one HTTP/1.1 request at a time, each request going to the route
+/// matching its `Host` header, or else to the route with the longest matching path prefix, which
+/// is removed from the forwarded path. The `Host` header is set to the local service, and
+/// absolute URLs pointing to it in `Location` headers and in textual response bodies are
+/// replaced by the public URL of the route.
+pub struct HttpTunnel {
+    routes: Vec<HttpRoute>,
+
+    /// Public origin of the tunnel, once opened.
+    public: OnceLock<String>,
+}
+
+impl HttpRoute {
+    /// Create a route receiving every request, to a local service given by its URL.
+    pub fn new(name: &str, url: &str) -> Result<Self> {
+        let authority = url
+            .trim_end_matches('/')
+            .strip_prefix("http://")
+            .with_context(|| format!("invalid URL {url} for {name}, expected http://"))?;
+        ensure!(
+            !authority.contains('/'),
+            "invalid URL {url} for {name}, paths are not supported"
+        );
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid port in URL {url} for {name}"))?,
+            ),
+            None => (authority, 80),
+        };
+        ensure!(!host.is_empty(), "invalid URL {url} for {name}");
+        Ok(Self {
+            name: name.to_string(),
+            matcher: RouteMatch::Any,
+            origin: format!("http://{authority}"),
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Parse a route given as `NAME:/PREFIX=URL` or `NAME:HOST=URL`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (name, rest) = spec
+            .split_once(':')
+            .with_context(|| format!("invalid route {spec:?}, expected NAME:MATCH=URL"))?;
+        let (matcher, url) = rest
+            .split_once('=')
+            .with_context(|| format!("invalid route {spec:?}, expected NAME:MATCH=URL"))?;
+        ensure!(!name.is_empty(), "invalid route {spec:?}, empty name");
+        let route = Self::new(name, url)?;
+        if matcher.starts_with('/') {
+            Ok(route.with_prefix(matcher))
+        } else {
+            ensure!(!matcher.is_empty(), "invalid route {spec:?}, empty match");
+            Ok(route.with_host(matcher))
+        }
+    }
+
+    /// Only receive the requests whose path starts with a prefix, such as `/connect`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.matcher = RouteMatch::Prefix(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Only receive the requests whose `Host` header names a host, with any port.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.matcher = RouteMatch::Host(host.to_ascii_lowercase());
+        self
+    }
+
+    /// Returns the path of a request within this route, if it receives the request.
+    fn strip<'a>(&self, path: &'a str, host: Option<&str>) -> Option<&'a str> {
+        match &self.matcher {
+            RouteMatch::Any => Some(path),
+            RouteMatch::Host(expected) => {
+                let host = host?.rsplit_once(':').map_or(host?, |(host, _)| host);
+                host.eq_ignore_ascii_case(expected).then_some(path)
+            }
+            RouteMatch::Prefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                match rest.chars().next() {
+                    None | Some('/') | Some('?') => Some(rest),
+                    Some(_) => None,
+                }
+            }
+        }
+    }
+
+    /// The authority of the local service, as sent in the `Host` header.
+    fn authority(&self) -> String {
+        if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl HttpTunnel {
+    /// Create a tunnel to a list of routes.
+    pub fn new(routes: Vec<HttpRoute>) -> Result<Self> {
+        ensure!(
+            !routes.is_empty(),
+            "HTTP tunnels require at least one route"
+        );
+        for (i, route) in routes.iter().enumerate() {
+            if routes[..i].iter().any(|other| other.name == route.name) {
+                bail!("duplicate HTTP route {}", route.name);
+            }
+        }
+        Ok(Self {
+            routes,
+            public: OnceLock::new(),
+        })
+    }
+
+    /// Open the tunnel through a server, returning its public URL.
+    pub async fn expose(self: &Arc<Self>, to: &str, auth: Option<Authenticator>) -> Result<String> {
+        let service: Arc<dyn Service> = Arc::clone(self) as _;
+        let client = Client::new(to, auth, service)
+            .await
+            .context("creating HTTP tunnel client")?;
+        let public = format!("http://{to}:{}", client.remote_port());
+        if self.public.set(public.clone()).is_err() {
+            bail!("HTTP tunnel already opened");
+        }
+        tokio::spawn(async move {
+            if let Err(err) = client.listen().await {
+                warn!(%err, "HTTP tunnel closed");
+            }
+        });
+        for route in &self.routes {
+            info!(%public, route = route.name, "exposed HTTP service");
+        }
+        Ok(public)
+    }
+
+    /// Forward the requests of a connection to the local services, one at a time.
+    async fn forward(self: Arc<Self>, connection: ForwardedConnection) -> Result<()> {
+        let public = self.public.get().context("HTTP tunnel not opened")?;
+        let (remote_read, mut remote_write) = connection.remote.into_split();
+        let mut remote_read = BufReader::new(Cursor::new(connection.buffered).chain(remote_read));
+        let mut upstreams: HashMap<usize, Upstream> = HashMap::new();
+
+        loop {
+            let mut request = match read_message(&mut remote_read, None).await? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            let Some((index, route, path)) = self.route(&request) else {
+                relay_body(&mut remote_read, &mut io::sink(), request.framing).await?;
+                let response = not_found(&request);
+                remote_write.write_all(&response.to_bytes()).await?;
+                if request.close {
+                    return Ok(());
+                }
+                continue;
+            };
+
+            // URLs of the local service are rewritten to the public URL of the route
+            let public_host = request
+                .header("host")
+                .map(|host| format!("http://{}", String::from_utf8_lossy(host)))
+                .unwrap_or_else(|| public.clone());
+            let public_base = match &route.matcher {
+                RouteMatch::Prefix(prefix) => format!("{public_host}{prefix}"),
+                _ => public_host,
+            };
+            let method = request.method().to_string();
+            request.start_line = format!("{method} {path} {}", request.version());
+            request.set_header("host", route.authority().into_bytes());
+            // responses must stay uncompressed for their URLs to be rewritten
+            request
+                .headers
+                .retain(|(name, _)| !name.eq_ignore_ascii_case("accept-encoding"));
+
+            // like the service would, the proxy lets the client send its body right away
+            let expects_continue = request
+                .header("expect")
+                .is_some_and(|value| value.eq_ignore_ascii_case(b"100-continue"));
+            if expects_continue {
+                request.remove_header("expect");
+                let version = request.version();
+                let interim = format!("{version} 100 Continue\r\n\r\n");
+                remote_write.write_all(interim.as_bytes()).await?;
+            }
+
+            let (mut upstream, reused) = match upstreams.remove(&index) {
+                Some(upstream) => (upstream, true),
+                None => (Upstream::connect(route).await?, false),
+            };
+            let mut response = match upstream.send(&request, &mut remote_read).await {
+                Ok(Some(response)) => response,
+                // the service may close a kept-alive connection just as a request is sent on it
+                _ if reused && request.is_replayable() => {
+                    debug!(route = route.name, "retrying request on a new connection");
+                    upstream = Upstream::connect(route).await?;
+                    upstream
+                        .send(&request, &mut remote_read)
+                        .await?
+                        .with_context(|| format!("{} closed the connection", route.name))?
+                }
+                sent => sent?.with_context(|| format!("{} closed the connection", route.name))?,
+            };
+            // interim responses, such as 103 Early Hints, precede the final one
+            while response.is_interim() {
+                if request.version() != "HTTP/1.0" {
+                    remote_write.write_all(&response.head()).await?;
+                }
+                response = read_message(&mut upstream.read, Some(&method))
+                    .await?
+                    .with_context(|| format!("{} closed the connection", route.name))?;
+            }
+            if response.status() == Some(101) {
+                // the connection now speaks another protocol, such as WebSocket
+                remote_write.write_all(&response.head()).await?;
+                let to_service = async {
+                    io::copy_buf(&mut remote_read, &mut upstream.write).await?;
+                    upstream.write.shutdown().await
+                };
+                let to_client = async {
+                    io::copy_buf(&mut upstream.read, &mut remote_write).await?;
+                    remote_write.shutdown().await
+                };
+                tokio::try_join!(to_service, to_client)?;
+                return Ok(());
+            }
+            let relayed = relay_response(
+                &mut response,
+                &mut upstream.read,
+                &mut remote_write,
+                &route.origin,
+                &public_base,
+            )
+            .await?;
+
+            // a body without length only ends when the connection is closed
+            if request.close || response.close || relayed == Framing::UntilClose {
+                return Ok(());
+            }
+            if response.framing != Framing::UntilClose {
+                upstreams.insert(index, upstream);
+            }
+        }
+    }
+
+    /// Find the route of a request, returning it with the path to forward.
+    fn route(&self, request: &Message) -> Option<(usize, &HttpRoute, String)> {
+        let path = request.path();
+        let host = request
+            .header("host")
+            .and_then(|host| std::str::from_utf8(host).ok());
+        let mut candidates: Vec<_> = self
+            .routes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, route)| {
+                let stripped = route.strip(path, host)?;
+                Some((index, route, stripped))
+            })
+            .collect();
+        // host routes first, then the longest prefix
+        candidates.sort_by_key(|(_, route, _)| match &route.matcher {
+            RouteMatch::Host(_) => (0, 0),
+            RouteMatch::Prefix(prefix) => (1, usize::MAX - prefix.len()),
+            RouteMatch::Any => (2, 0),
+        });
+        let (index, route, path) = candidates.into_iter().next()?;
+        let path = if path.is_empty() || path.starts_with('?') {
+            format!("/{path}")
+        } else {
+            path.to_string()
+        };
+        Some((index, route, path))
+    }
+}
+
+impl Service for HttpTunnel {
+    fn proxy(self: Arc<Self>, connection: ForwardedConnection) -> BoxFuture<'static, Result<()>> {
+        self.forward(connection).boxed()
+    }
+}
+
+impl Upstream {
+    /// Open a connection to the service of a route.
+    async fn connect(route: &HttpRoute) -> Result<Self> {
+        let stream = connect_with_timeout(&route.host, route.port).await?;
+        let (read, write) = stream.into_split();
+        Ok(Self {
+            read: BufReader::new(read),
+            write,
+        })
+    }
+
+    /// Send a request, relaying its body from the remote client, and read the head of the first
+    /// response.
+    ///
+    /// Returns `None` if the service closes the connection before answering.
+    async fn send<R: AsyncBufRead + Unpin>(
+        &mut self,
+        request: &Message,
+        body: &mut R,
+    ) -> Result<Option<Message>> {
+        self.write.write_all(&request.head()).await?;
+        relay_body(body, &mut self.write, request.framing).await?;
+        read_message(&mut self.read, Some(request.method())).await
+    }
+}
+
+/// A response to a request that no route receives.
+fn not_found(request: &Message) -> Message {
+    let body = format!("no route for {}\n", request.path()).into_bytes();
+    Message {
+        start_line: "HTTP/1.1 404 Not Found".to_string(),
+        headers: vec![("Content-Type".to_string(), b"text/plain".to_vec())],
+        framing: Framing::Length(body.len()),
+        body,
+        close: request.close,
+    }
+}
+
+/// Relay a response to the remote client, replacing the URLs of a local service by the public
+/// ones.
+///
+/// Textual bodies are read in full to be rewritten, and sent with a `Content-Length`, unless
+/// they are longer than [`MAX_REWRITTEN_LENGTH`]. Other bodies are streamed with their framing.
+/// Returns the framing of the relayed body.
+async fn relay_response<R, W>(
+    response: &mut Message,
+    reader: &mut R,
+    writer: &mut W,
+    origin: &str,
+    public: &str,
+) -> Result<Framing>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    for (name, value) in &mut response.headers {
+        if URL_HEADERS
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            if let Some(rewritten) = replace_origin(value, origin, public) {
+                *value = rewritten;
+            }
+        }
+    }
+
+    let textual = response.header("content-type").is_some_and(|content_type| {
+        let content_type = String::from_utf8_lossy(content_type).to_ascii_lowercase();
+        content_type.contains("json") || content_type.starts_with("text/")
+    });
+    let encoded = response
+        .header("content-encoding")
+        .is_some_and(|encoding| !encoding.eq_ignore_ascii_case(b"identity"));
+    if !textual || encoded || response.framing == Framing::Empty {
+        writer.write_all(&response.head()).await?;
+        relay_body(reader, writer, response.framing).await?;
+        return Ok(response.framing);
+    }
+
+    match buffer_body(reader, response.framing).await? {
+        Buffered::Complete(body) => {
+            response.body = replace_origin(&body, origin, public).unwrap_or(body);
+            response.framing = Framing::Length(response.body.len());
+            writer.write_all(&response.to_bytes()).await?;
+        }
+        Buffered::Partial(start, next_chunk) => {
+            writer.write_all(&response.head()).await?;
+            if response.framing == Framing::Chunked {
+                if !start.is_empty() {
+                    writer
+                        .write_all(format!("{:x}\r\n", start.len()).as_bytes())
+                        .await?;
+                    writer.write_all(&start).await?;
+                    writer.write_all(b"\r\n").await?;
+                }
+                relay_chunked(reader, writer, next_chunk).await?;
+            } else {
+                writer.write_all(&start).await?;
+                relay_body(reader, writer, response.framing).await?;
+            }
+        }
+    }
+    Ok(response.framing)
+}
+
+/// Replace an origin in a UTF-8 text, returning `None` if it does not appear.
+fn replace_origin(text: &[u8], origin: &str, public: &str) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(text).ok()?;
+    if !text.contains(origin) {
+        return None;
+    }
+    Some(text.replace(origin, public).into_bytes())
+}
+
+impl Message {
+    /// The status code of a response.
+    fn status(&self) -> Option<u16> {
+        self.start_line.split(' ').nth(1)?.parse().ok()
+    }
+
+    /// Whether a response is followed by another one to the same request, unlike the final
+    /// `101 Switching Protocols`.
+    fn is_interim(&self) -> bool {
+        self.status()
+            .is_some_and(|code| (100..200).contains(&code) && code != 101)
+    }
+
+    /// Whether a request can be sent again, having no body that was already relayed.
+    fn is_replayable(&self) -> bool {
+        self.framing == Framing::Empty && IDEMPOTENT_METHODS.contains(&self.method())
+    }
+
+    /// The method of a request.
+    fn method(&self) -> &str {
+        self.start_line.split(' ').next().unwrap_or_default()
+    }
+
+    /// The path of a request, query included.
+    fn path(&self) -> &str {
+        self.start_line.split(' ').nth(1).unwrap_or_default()
+    }
+
+    /// The protocol version of a request, such as `HTTP/1.1`.
+    fn version(&self) -> &str {
+        self.start_line.rsplit(' ').next().unwrap_or_default()
+    }
+
+    fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+    }
+
+    fn set_header(&mut self, name: &str, value: Vec<u8>) {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.headers.push((name.to_string(), value)),
+        }
+    }
+
+    /// Serialize the head of the message, with the framing headers of its framing.
+    fn head(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.start_line.len() + 512);
+        out.extend_from_slice(self.start_line.as_bytes());
+        out.extend_from_slice(b"\r\n");
+        for (name, value) in &self.headers {
+            let framing_header = name.eq_ignore_ascii_case("content-length")
+                || name.eq_ignore_ascii_case("transfer-encoding");
+            if framing_header && self.framing != Framing::Empty {
+                continue;
+            }
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        match self.framing {
+            Framing::Length(length) => {
+                out.extend_from_slice(format!("Content-Length: {length}\r\n").as_bytes())
+            }
+            Framing::Chunked => out.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+            Framing::Empty | Framing::UntilClose => {}
+        }
+        out.extend_from_slice(b"\r\n");
+        out
+    }
+
+    /// Serialize the message with the body built by the proxy.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.head();
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+/// Read the head of an HTTP message, a response to a request with the given method if any.
+///
+/// The body is left in the reader, to be relayed or buffered according to the framing.
+///
+/// Returns `None` if the connection is closed before a new message starts.
+async fn read_message<R>(reader: &mut R, request_method: Option<&str>) -> Result<Option<Message>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let head = match read_head(reader).await? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let (start_line, version, status, parsed) = match request_method {
+        None => {
+            let mut request = httparse::Request::new(&mut headers);
+            ensure!(
+                request.parse(&head)?.is_complete(),
+                "incomplete request head"
+            );
+            let start_line = format!(
+                "{} {} HTTP/1.{}",
+                request.method.unwrap_or_default(),
+                request.path.unwrap_or_default(),
+                request.version.unwrap_or(1)
+            );
+            (start_line, request.version, None, request.headers)
+        }
+        Some(_) => {
+            let mut response = httparse::Response::new(&mut headers);
+            ensure!(
+                response.parse(&head)?.is_complete(),
+                "incomplete response head"
+            );
+            let code = response.code.unwrap_or_default();
+            let start_line = format!(
+                "HTTP/1.{} {} {}",
+                response.version.unwrap_or(1),
+                code,
+                response.reason.unwrap_or_default()
+            );
+            (start_line, response.version, Some(code), response.headers)
+        }
+    };
+    let headers: Vec<_> = parsed
+        .iter()
+        .map(|header| (header.name.to_string(), header.value.to_vec()))
+        .collect();
+
+    let mut message = Message {
+        start_line,
+        headers,
+        framing: Framing::Empty,
+        body: vec![],
+        close: false,
+    };
+    let connection = message
+        .header("connection")
+        .map(|value| String::from_utf8_lossy(value).to_ascii_lowercase())
+        .unwrap_or_default();
+    message.close =
+        connection.contains("close") || (version == Some(0) && !connection.contains("keep-alive"));
+
+    let chunked = message.header("transfer-encoding").is_some_and(|value| {
+        String::from_utf8_lossy(value)
+            .to_ascii_lowercase()
+            .contains("chunked")
+    });
+    let length = match message.header("content-length") {
+        Some(value) => Some(
+            std::str::from_utf8(value)?
+                .trim()
+                .parse::<usize>()
+                .context("invalid Content-Length")?,
+        ),
+        None => None,
+    };
+    let bodyless = request_method == Some("HEAD")
+        || status.is_some_and(|code| (100..200).contains(&code) || code == 204 || code == 304);
+    message.framing = match (bodyless, chunked, length, status) {
+        (true, _, _, _) => Framing::Empty,
+        (_, true, _, _) => Framing::Chunked,
+        (_, _, Some(length), _) => Framing::Length(length),
+        (_, _, None, Some(_)) => Framing::UntilClose,
+        (_, _, None, None) => Framing::Empty,
+    };
+    Ok(Some(message))
+}
+
+/// Read the head of an HTTP message, up to and including the empty line.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut head = vec![];
+    loop {
+        let start = head.len();
+        let n = (&mut *reader)
+            .take((MAX_HEAD_LENGTH + 1 - start) as u64)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if n == 0 {
+            ensure!(
+                head.is_empty(),
+                "connection closed in the middle of an HTTP head"
+            );
+            return Ok(None);
+        }
+        ensure!(head.len() <= MAX_HEAD_LENGTH, "HTTP head too long");
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            if start == 0 {
+                // tolerate empty lines between messages
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// Relay a body as it arrives, keeping its framing.
+async fn relay_body<R, W>(reader: &mut R, writer: &mut W, framing: Framing) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match framing {
+        Framing::Empty => {}
+        Framing::Length(length) => copy_exact(reader, writer, length).await?,
+        Framing::Chunked => relay_chunked(reader, writer, None).await?,
+        Framing::UntilClose => {
+            io::copy_buf(reader, writer).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Relay a chunked body chunk by chunk, starting with a chunk whose size was already read, if
+/// any.
+async fn relay_chunked<R, W>(reader: &mut R, writer: &mut W, first: Option<usize>) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut next = first;
+    loop {
+        let size = match next.take() {
+            Some(size) => size,
+            None => read_chunk_size(reader).await?,
+        };
+        writer.write_all(format!("{size:x}\r\n").as_bytes()).await?;
+        if size == 0 {
+            // the trailers, up to the empty line ending the body
+            loop {
+                let line = read_line(reader).await?;
+                writer.write_all(format!("{line}\r\n").as_bytes()).await?;
+                if line.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+        copy_exact(reader, writer, size).await?;
+        ensure!(read_line(reader).await?.is_empty(), "invalid chunk ending");
+        writer.write_all(b"\r\n").await?;
+    }
+}
+
+/// Copy a number of bytes, failing if the reader ends before.
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, length: usize) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = io::copy_buf(&mut (&mut *reader).take(length as u64), writer).await?;
+    ensure!(
+        copied == length as u64,
+        "connection closed in the middle of an HTTP body"
+    );
+    Ok(())
+}
+
+/// Read a body to rewrite it, stopping before [`MAX_REWRITTEN_LENGTH`] is exceeded.
+async fn buffer_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    framing: Framing,
+) -> Result<Buffered> {
+    match framing {
+        Framing::Empty => Ok(Buffered::Complete(vec![])),
+        Framing::Length(length) if length <= MAX_REWRITTEN_LENGTH => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await?;
+            Ok(Buffered::Complete(body))
+        }
+        Framing::Length(_) => Ok(Buffered::Partial(vec![], None)),
+        Framing::Chunked => {
+            let mut body = vec![];
+            loop {
+                let size = read_chunk_size(reader).await?;
+                if size == 0 {
+                    // skip the trailers
+                    while !read_line(reader).await?.is_empty() {}
+                    return Ok(Buffered::Complete(body));
+                }
+                if body.len() + size > MAX_REWRITTEN_LENGTH {
+                    return Ok(Buffered::Partial(body, Some(size)));
+                }
+                let start = body.len();
+                body.resize(start + size, 0);
+                reader.read_exact(&mut body[start..]).await?;
+                ensure!(read_line(reader).await?.is_empty(), "invalid chunk ending");
+            }
+        }
+        Framing::UntilClose => {
+            let mut body = vec![];
+            (&mut *reader)
+                .take(MAX_REWRITTEN_LENGTH as u64 + 1)
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() > MAX_REWRITTEN_LENGTH {
+                Ok(Buffered::Partial(body, None))
+            } else {
+                Ok(Buffered::Complete(body))
+            }
+        }
+    }
+}
+
+/// Read the size line of a chunk.
+async fn read_chunk_size<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<usize> {
+    let line = read_line(reader).await?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    usize::from_str_radix(size, 16).context("invalid chunk size")
+}
+
+/// Read a line of a chunked body, without its line ending.
+async fn read_line<R: AsyncRead + AsyncBufRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut line = vec![];
+    let n = (&mut *reader)
+        .take(MAX_HEAD_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    ensure!(n > 0, "connection closed in the middle of a chunked body");
+    let line = String::from_utf8(line).context("invalid chunk line")?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
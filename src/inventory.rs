@@ -0,0 +1,158 @@
+//! Inventory of the remote Kafka clients connected through the tunnels.
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::Serialize;
+use tracing::info;
+
+/// Maximum number of clients kept in the inventory.
+const MAX_CLIENTS: usize = 10_000;
+
+/// What a request tells about its client, besides its client id.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Observation<'a> {
+    /// Name of the client library, from `ApiVersions` v3+.
+    pub software_name: Option<&'a str>,
+
+    /// Version of the client library, from `ApiVersions` v3+.
+    pub software_version: Option<&'a str>,
+
+    /// Consumer group joined by the client, from `JoinGroup`.
+    pub group_id: Option<&'a str>,
+}
+
+/// What is known of a remote client.
+#[derive(Debug, Clone)]
+struct RemoteClient {
+    software_name: Option<String>,
+    software_version: Option<String>,
+    groups: BTreeSet<String>,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+}
+
+/// Snapshot of a remote client, as exposed by the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientInfo {
+    /// Name of the cluster the client connected to.
+    pub cluster: String,
+
+    /// The client id sent in the request headers, empty if none.
+    pub client_id: String,
+
+    /// Address of the client, when the server sent it.
+    pub peer_ip: Option<IpAddr>,
+
+    /// Name of the client library, if sent.
+    pub software_name: Option<String>,
+
+    /// Version of the client library, if sent.
+    pub software_version: Option<String>,
+
+    /// Consumer groups joined by the client.
+    pub groups: Vec<String>,
+
+    /// When the client was first seen, in seconds since the epoch.
+    pub first_seen: u64,
+
+    /// When the client last sent a request, in seconds since the epoch.
+    pub last_seen: u64,
+}
+
+/// The distinct remote clients seen in proxied requests, by client id and address.
+///
+/// A client is logged when it first appears. Its library is learned from the `ApiVersions`
+/// requests it sends first on each connection, from version 3, and its consumer groups from
+/// its `JoinGroup` requests.
+#[derive(Default)]
+pub struct ClientInventory {
+    clients: DashMap<(String, Option<IpAddr>), RemoteClient>,
+}
+
+impl ClientInventory {
+    /// Create an empty inventory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a request of a client.
+    pub fn observe(
+        &self,
+        client_id: Option<&str>,
+        peer_ip: Option<IpAddr>,
+        observation: Observation<'_>,
+    ) {
+        let now = SystemTime::now();
+        let key = (client_id.unwrap_or_default().to_string(), peer_ip);
+        // counted before locking the entry, which the count would wait for
+        let full = self.clients.len() >= MAX_CLIENTS;
+        let mut client = match self.clients.entry(key) {
+            Entry::Occupied(client) => client.into_ref(),
+            Entry::Vacant(_) if full => return,
+            Entry::Vacant(entry) => {
+                info!(
+                    client_id = %entry.key().0,
+                    peer_ip = ?peer_ip,
+                    software_name = observation.software_name.unwrap_or_default(),
+                    software_version = observation.software_version.unwrap_or_default(),
+                    "new remote client"
+                );
+                entry.insert(RemoteClient {
+                    software_name: None,
+                    software_version: None,
+                    groups: BTreeSet::new(),
+                    first_seen: now,
+                    last_seen: now,
+                })
+            }
+        };
+        client.last_seen = now;
+        if let Some(name) = observation.software_name {
+            client.software_name = Some(name.to_string());
+        }
+        if let Some(version) = observation.software_version {
+            client.software_version = Some(version.to_string());
+        }
+        if let Some(group_id) = observation.group_id {
+            if !client.groups.contains(group_id) {
+                client.groups.insert(group_id.to_string());
+            }
+        }
+    }
+
+    /// Returns the clients of a cluster, most recently seen first.
+    pub fn snapshot(&self, cluster: &str) -> Vec<ClientInfo> {
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default()
+        };
+        let mut clients: Vec<_> = self
+            .clients
+            .iter()
+            .map(|entry| {
+                let ((client_id, peer_ip), client) = entry.pair();
+                ClientInfo {
+                    cluster: cluster.to_string(),
+                    client_id: client_id.clone(),
+                    peer_ip: *peer_ip,
+                    software_name: client.software_name.clone(),
+                    software_version: client.software_version.clone(),
+                    groups: client.groups.iter().cloned().collect(),
+                    first_seen: secs(client.first_seen),
+                    last_seen: secs(client.last_seen),
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| {
+            b.last_seen
+                .cmp(&a.last_seen)
+                .then_with(|| a.client_id.cmp(&b.client_id))
+        });
+        clients
+    }
+}
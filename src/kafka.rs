@@ -1,33 +1,93 @@
 //! Kafka proxy implementation
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
 use std::mem::size_of;
+use std::net::{IpAddr, SocketAddr};
 use std::result;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime};
 
 use anyhow::*;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use codec::LengthDelimitedCodec;
 use dashmap::DashMap;
-use futures_util::future::try_join_all;
-use futures_util::{StreamExt, TryStreamExt};
+use futures_util::future::{try_join_all, BoxFuture, FutureExt};
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
 use indexmap::IndexMap;
-use kafka_protocol::messages::metadata_response::MetadataResponseBroker;
+use kafka_protocol::messages::metadata_response::{MetadataResponseBroker, MetadataResponseTopic};
 use kafka_protocol::messages::*;
 use kafka_protocol::protocol::buf::ByteBuf;
 use kafka_protocol::protocol::*;
+use kafka_protocol::ResponseError;
+use serde::Serialize;
 use tokio::io;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
 use tokio_util::codec;
-use tracing::debug;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
+use crate::accounting::{Direction, TopicAccounting};
+use crate::acl::{Authorizer, Operation, ResourceType};
 use crate::auth::Authenticator;
-use crate::client::Client;
+use crate::chaos::{Chaos, Fault};
+use crate::client::{connect_with_timeout, Control, ForwardedConnection, Service};
+use crate::client_id::ClientIdRewrite;
+use crate::encryption::Encryption;
+use crate::headers::{HeaderInjection, InjectionContext};
+use crate::inventory::{ClientInfo, ClientInventory, Observation};
+use crate::kafka_client::KafkaClient;
+use crate::lag::LagObserver;
+use crate::limits::ProduceLimits;
+use crate::masking::Masking;
+use crate::records::InvalidRecord;
+use crate::schemas::SchemaValidation;
+use crate::shadow::ShadowWriter;
+use crate::shared::Counted;
+use crate::slowlog::{response_error_code, SlowLog, SlowRequest};
+use crate::topic_policy::TopicPolicy;
+use crate::versions::{clamp_to_decodable, decodable_versions, is_decodable, VersionPolicy};
 
 enum KafkaResponse {
-    Metadata(i16, ResponseHeader, MetadataResponse),
-    UndecodedResponse(BytesMut),
+    ApiVersions(i16, ResponseHeader, Box<ApiVersionsResponse>),
+    Metadata(i16, ResponseHeader, Box<MetadataResponse>),
+    Fetch(i16, ResponseHeader, Box<FetchResponse>, Option<String>),
+    Produce(i16, ResponseHeader, Box<ProduceResponse>),
+    CreateTopics(i16, ResponseHeader, Box<CreateTopicsResponse>),
+    CreatePartitions(i16, ResponseHeader, Box<CreatePartitionsResponse>),
+    UndecodedResponse(Bytes),
+}
+
+/// A response owed to the remote client, in the order of its requests.
+enum Queued {
+    /// A request forwarded to the broker, which answers it, with the injected latency its
+    /// response is held until.
+    Forwarded(Option<time::Instant>),
+
+    /// A request answered by the proxy itself.
+    Synthesized(KafkaResponse),
+}
+
+/// A partition of a produce request rejected by the proxy instead of the broker.
+struct Rejection {
+    topic: TopicName,
+    partition: i32,
+    error: InvalidRecord,
+}
+
+/// Outcome of the checks of a produce request by the proxy.
+enum Checked {
+    /// The request is forwarded, possibly rewritten and without the rejected partitions.
+    Forward(Bytes, Vec<Rejection>),
+
+    /// Every partition was rejected, nothing is forwarded.
+    Rejected(Vec<Rejection>),
 }
 
 struct RequestKeyAndVersion {
@@ -36,22 +96,733 @@ struct RequestKeyAndVersion {
 
     /// The API version of this request.
     pub api_version: i16,
+
+    /// The client id sent in the request header.
+    pub client_id: Option<String>,
+
+    /// When the request was forwarded to the local Kafka.
+    pub sent_at: Instant,
+
+    /// Whether the broker answers the request, which is not the case of `acks=0` produces.
+    pub expects_response: bool,
+
+    /// Partitions removed from a produce request, added back to its response.
+    pub rejections: Vec<Rejection>,
+
+    /// Receives the response of a produce request mirrored to a secondary cluster.
+    pub shadow: Option<oneshot::Sender<Bytes>>,
+
+    /// Fault to inject in the response, for chaos testing.
+    pub fault: Option<Fault>,
+
+    /// Topics removed from a topic creation request by the topic policy, added back to its
+    /// response along with the violation.
+    pub violations: Vec<(TopicName, String)>,
+
+    /// Principal of a metadata request, whose response only lists the topics it may describe.
+    pub topic_access: Option<TopicAccess>,
+
+    /// Principal of a group listing, whose response only lists the groups it may describe.
+    pub group_access: Option<GroupAccess>,
+}
+
+/// The topics of a metadata response visible to the principal of the connection.
+struct TopicAccess {
+    authorizer: Arc<Authorizer>,
+    principal: Option<String>,
+
+    /// Topics named in the request but not allowed, answered with `TOPIC_AUTHORIZATION_FAILED`.
+    denied: Vec<TopicName>,
+}
+
+/// The groups of a group listing visible to the principal of the connection.
+struct GroupAccess {
+    authorizer: Arc<Authorizer>,
+    principal: Option<String>,
+}
+
+impl RequestKeyAndVersion {
+    /// Parse the header of a request frame, length prefix included.
+    ///
+    /// Returns `None` for unknown API keys.
+    fn parse(frame: &[u8]) -> Result<Option<(i32, Self)>> {
+        ensure!(frame.len() >= 12, "request frame too short");
+        let mut buf = &frame[size_of::<u32>()..];
+        let api_key = match ApiKey::try_from(buf.peek_bytes(0..2).get_i16()) {
+            result::Result::Ok(api_key) => api_key,
+            Err(()) => return Ok(None),
+        };
+        let api_version = buf.peek_bytes(2..4).get_i16();
+        let header = RequestHeader::decode(&mut buf, api_key.request_header_version(api_version))?;
+        let expects_response =
+            api_key != ApiKey::ProduceKey || produce_acks(&mut buf, api_version)? != 0;
+        Ok(Some((
+            header.correlation_id,
+            Self {
+                api_key,
+                api_version,
+                client_id: header.client_id.map(|client_id| client_id.to_string()),
+                sent_at: Instant::now(),
+                expects_response,
+                rejections: vec![],
+                shadow: None,
+                fault: None,
+                violations: vec![],
+                topic_access: None,
+                group_access: None,
+            },
+        )))
+    }
+}
+
+/// Read the `acks` field of a produce request body.
+fn produce_acks(buf: &mut &[u8], api_version: i16) -> Result<i16> {
+    if api_version >= 3 {
+        // skip the transactional id, both encodings store `length + 1` with 0 meaning null
+        let len = if api_version >= 9 {
+            read_unsigned_varint(buf)? as usize
+        } else {
+            (buf.try_get_i16()? + 1) as usize
+        };
+        let len = len.saturating_sub(1);
+        ensure!(buf.remaining() >= len, "truncated produce request");
+        buf.advance(len);
+    }
+    Ok(buf.try_get_i16()?)
+}
+
+/// Read an unsigned varint, as used by flexible protocol versions.
+fn read_unsigned_varint(buf: &mut &[u8]) -> Result<u32> {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = buf.try_get_u8()?;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid unsigned varint")
+}
+
+/// Read the length of an array, null arrays being empty.
+fn read_array_len(buf: &mut &[u8], flexible: bool) -> Result<usize> {
+    Ok(if flexible {
+        (read_unsigned_varint(buf)? as usize).saturating_sub(1)
+    } else {
+        buf.try_get_i32()?.max(0) as usize
+    })
+}
+
+/// Read a string, null strings being empty.
+fn read_string(buf: &mut &[u8], flexible: bool) -> Result<String> {
+    let len = if flexible {
+        (read_unsigned_varint(buf)? as usize).saturating_sub(1)
+    } else {
+        buf.try_get_i16()?.max(0) as usize
+    };
+    ensure!(buf.remaining() >= len, "truncated string");
+    let string = String::from_utf8(buf[..len].to_vec())?;
+    buf.advance(len);
+    Ok(string)
+}
+
+/// Skip the tagged fields of a structure, in flexible versions.
+fn skip_tagged_fields(buf: &mut &[u8]) -> Result<()> {
+    for _ in 0..read_unsigned_varint(buf)? {
+        read_unsigned_varint(buf)?; // tag
+        let size = read_unsigned_varint(buf)? as usize;
+        ensure!(buf.remaining() >= size, "truncated tagged field");
+        buf.advance(size);
+    }
+    Ok(())
+}
+
+/// Decode a produce request frame, length prefix included.
+pub(crate) fn decode_produce(frame: &Bytes) -> Result<(i16, RequestHeader, ProduceRequest)> {
+    let mut buf = frame.slice(size_of::<u32>()..);
+    let api_version = buf.peek_bytes(2..4).get_i16();
+    ensure!(
+        is_decodable(ApiKey::ProduceKey, api_version),
+        "unsupported produce version {api_version}"
+    );
+    let header = RequestHeader::decode(
+        &mut buf,
+        ApiKey::ProduceKey.request_header_version(api_version),
+    )?;
+    let request = ProduceRequest::decode(&mut buf, api_version)?;
+    Ok((api_version, header, request))
+}
+
+/// Decode the body of a request frame, length prefix included.
+fn decode_request<R: Decodable>(frame: &Bytes, request: &RequestKeyAndVersion) -> Result<R> {
+    Ok(decode_request_with_header(frame, request)?.1)
+}
+
+/// Decode the header and body of a request frame, length prefix included.
+fn decode_request_with_header<R: Decodable>(
+    frame: &Bytes,
+    request: &RequestKeyAndVersion,
+) -> Result<(RequestHeader, R)> {
+    let mut buf = frame.slice(size_of::<u32>()..);
+    let header = RequestHeader::decode(
+        &mut buf,
+        request.api_key.request_header_version(request.api_version),
+    )?;
+    Ok((header, R::decode(&mut buf, request.api_version)?))
+}
+
+/// Replace the client id in the header of a request frame, keeping its body as is.
+fn rewrite_client_id(
+    frame: &Bytes,
+    request: &RequestKeyAndVersion,
+    client_id: String,
+) -> Result<Bytes> {
+    let mut body = frame.slice(size_of::<u32>()..);
+    let header_version = request.api_key.request_header_version(request.api_version);
+    let mut header = RequestHeader::decode(&mut body, header_version)?;
+    header.client_id = Some(str_bytes(client_id));
+    let mut bytes = BytesMut::with_capacity(frame.len());
+    bytes.put_u32(0); // length, filled below
+    header.encode(&mut bytes, header_version)?;
+    bytes.put_slice(&body);
+    let length = (bytes.len() - size_of::<u32>()) as u32;
+    bytes[..size_of::<u32>()].copy_from_slice(&length.to_be_bytes());
+    Ok(bytes.freeze())
+}
+
+/// Encode a request frame, length prefix included.
+fn encode_request<R: Encodable>(
+    api_key: ApiKey,
+    api_version: i16,
+    header: &RequestHeader,
+    request: &R,
+) -> Result<Bytes> {
+    let mut bytes = BytesMut::new();
+    bytes.put_u32(0); // length, filled below
+    header.encode(&mut bytes, api_key.request_header_version(api_version))?;
+    request.encode(&mut bytes, api_version)?;
+    let length = (bytes.len() - size_of::<u32>()) as u32;
+    bytes[..size_of::<u32>()].copy_from_slice(&length.to_be_bytes());
+    Ok(bytes.freeze())
+}
+
+/// Encode a produce request frame, length prefix included.
+fn encode_produce(
+    api_version: i16,
+    header: &RequestHeader,
+    request: &ProduceRequest,
+) -> Result<Bytes> {
+    encode_request(ApiKey::ProduceKey, api_version, header, request)
+}
+
+/// Rewrite the records of every partition of a produce request frame, length prefix included.
+fn rewrite_produce<F>(frame: &Bytes, mut rewrite: F) -> Result<Bytes>
+where
+    F: FnMut(&TopicName, &Bytes) -> Result<Bytes>,
+{
+    let (api_version, header, mut request) = decode_produce(frame)?;
+    for (topic, data) in request.topic_data.iter_mut() {
+        for partition in &mut data.partition_data {
+            if let Some(records) = &partition.records {
+                partition.records = Some(rewrite(topic, records)?);
+            }
+        }
+    }
+    encode_produce(api_version, &header, &request)
+}
+
+/// Check the records of every partition of a produce request frame, length prefix included.
+///
+/// The check returns the records to forward, or rejects the partition. The request is only
+/// re-encoded if a check changed or rejected records.
+fn check_produce<F>(frame: &Bytes, mut check: F) -> Result<Checked>
+where
+    F: FnMut(&str, &Bytes) -> result::Result<Bytes, InvalidRecord>,
+{
+    let (api_version, header, mut request) = decode_produce(frame)?;
+    let mut rejections = vec![];
+    let mut accepted = 0;
+    let mut changed = false;
+    for (topic, data) in request.topic_data.iter_mut() {
+        let name = topic.to_string();
+        data.partition_data.retain_mut(|partition| {
+            let records = match &partition.records {
+                Some(records) => records,
+                None => {
+                    accepted += 1;
+                    return true;
+                }
+            };
+            match check(&name, records) {
+                result::Result::Ok(checked) => {
+                    if checked != *records {
+                        partition.records = Some(checked);
+                        changed = true;
+                    }
+                    accepted += 1;
+                    true
+                }
+                Err(error) => {
+                    rejections.push(Rejection {
+                        topic: topic.clone(),
+                        partition: partition.index,
+                        error,
+                    });
+                    false
+                }
+            }
+        });
+    }
+
+    if rejections.is_empty() && !changed {
+        return Ok(Checked::Forward(frame.clone(), rejections));
+    }
+    if accepted == 0 {
+        return Ok(Checked::Rejected(rejections));
+    }
+    request
+        .topic_data
+        .retain(|_, data| !data.partition_data.is_empty());
+    let frame = encode_produce(api_version, &header, &request)?;
+    Ok(Checked::Forward(frame, rejections))
+}
+
+/// Answer the rejected partitions of a produce request with `INVALID_RECORD`.
+fn add_rejections(response: &mut ProduceResponse, rejections: Vec<Rejection>) {
+    for rejection in rejections {
+        let message = str_bytes(rejection.error.message);
+        let record_errors = match rejection.error.batch_index {
+            Some(batch_index) => {
+                let mut record_error = produce_response::BatchIndexAndErrorMessage::default();
+                record_error.batch_index = batch_index;
+                record_error.batch_index_error_message = Some(message.clone());
+                vec![record_error]
+            }
+            None => Vec::new(),
+        };
+
+        let mut partition = produce_response::PartitionProduceResponse::default();
+        partition.index = rejection.partition;
+        partition.error_code = ResponseError::InvalidRecord.code();
+        partition.base_offset = -1;
+        partition.log_append_time_ms = -1;
+        partition.log_start_offset = -1;
+        partition.record_errors = record_errors;
+        partition.error_message = Some(message);
+        response
+            .responses
+            .entry(rejection.topic)
+            .or_default()
+            .partition_responses
+            .push(partition);
+    }
+}
+
+/// Parse the name of an API, such as `Produce` or `JoinGroup`.
+pub(crate) fn parse_api_key(name: &str) -> Result<ApiKey> {
+    (0..1000)
+        .filter_map(|key| ApiKey::try_from(key).ok())
+        .find(|api_key| format!("{api_key:?}").strip_suffix("Key") == Some(name))
+        .with_context(|| format!("unknown api {name:?}"))
+}
+
+/// Answer the topics rejected by the topic policy with `POLICY_VIOLATION`.
+fn add_violations(response: &mut KafkaResponse, violations: Vec<(TopicName, String)>) {
+    let error = ResponseError::PolicyViolation.code();
+    for (topic, message) in violations {
+        match response {
+            KafkaResponse::CreateTopics(_, _, response) => {
+                let mut result = create_topics_response::CreatableTopicResult::default();
+                result.error_code = error;
+                result.error_message = Some(str_bytes(message));
+                result.num_partitions = -1;
+                result.replication_factor = -1;
+                response.topics.insert(topic, result);
+            }
+            KafkaResponse::CreatePartitions(_, _, response) => {
+                let mut result = create_partitions_response::CreatePartitionsTopicResult::default();
+                result.name = topic;
+                result.error_code = error;
+                result.error_message = Some(str_bytes(message));
+                response.results.push(result);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Remove the topics a principal may not describe from a metadata response, answering those
+/// it named with `TOPIC_AUTHORIZATION_FAILED`.
+fn hide_topics(response: &mut MetadataResponse, access: TopicAccess) {
+    let principal = access.principal.as_deref();
+    response.topics.retain(|name, _| {
+        access
+            .authorizer
+            .allows(principal, ResourceType::Topic, name, Operation::Describe)
+    });
+    for name in access.denied {
+        let mut topic = MetadataResponseTopic::default();
+        topic.error_code = ResponseError::TopicAuthorizationFailed.code();
+        response.topics.insert(name, topic);
+    }
+}
+
+/// Keep the groups of a listing that the principal may describe, all of them if it may describe
+/// the cluster.
+fn hide_groups(response: &mut ListGroupsResponse, access: GroupAccess) {
+    let principal = access.principal.as_deref();
+    let cluster = ResourceType::Cluster;
+    if access
+        .authorizer
+        .allows(principal, cluster, CLUSTER_RESOURCE, Operation::Describe)
+    {
+        return;
+    }
+    response.groups.retain(|group| {
+        access.authorizer.allows(
+            principal,
+            ResourceType::Group,
+            &group.group_id,
+            Operation::Describe,
+        )
+    });
+}
+
+/// Convert a string to the string type of the protocol.
+pub(crate) fn str_bytes(string: String) -> StrBytes {
+    // a String is valid UTF-8, but the api is lacking this conversion
+    unsafe { StrBytes::from_utf8_unchecked(string.into()) }
+}
+
+/// Encode a response frame, length prefix included.
+fn encode_response<R: Encodable + HeaderVersion>(
+    dst: &mut BytesMut,
+    version: i16,
+    header: &ResponseHeader,
+    response: &R,
+) -> Result<()> {
+    let mut bytes = BytesMut::new();
+    header.encode(&mut bytes, R::header_version(version))?;
+    response.encode(&mut bytes, version)?;
+    dst.put_u32(bytes.len() as u32);
+    dst.put_slice(&bytes);
+    Ok(())
+}
+
+/// Largest request read from remote clients, the default `socket.request.max.bytes` of brokers.
+const MAX_REQUEST_BYTES: usize = 100 * 1024 * 1024;
+
+/// A request read from a remote client.
+enum RequestFrame {
+    /// A request frame, length prefix included.
+    Whole(BytesMut),
+
+    /// A produce request larger than the limit of the proxy, whose records were skipped.
+    Oversized(OversizedProduce),
+}
+
+/// What is kept of a produce request too large to be read: its header, and its partitions
+/// without their records.
+struct OversizedProduce {
+    header: RequestHeader,
+    produce: ProduceRequest,
+
+    /// The exceeded limit.
+    message: String,
+}
+
+/// Splits the requests of remote clients into frames, length prefix included.
+///
+/// Produce requests larger than the limit of the proxy are not buffered: their size is checked
+/// from the length prefix, and their records are skipped as they arrive, keeping only the
+/// partitions they are produced to, to answer them.
+struct RequestCodec {
+    limits: Option<Arc<ProduceLimits>>,
+
+    /// The oversized produce request being read.
+    skipped: Option<SkippedProduce>,
+}
+
+impl RequestCodec {
+    fn new(limits: Option<Arc<ProduceLimits>>) -> Self {
+        Self {
+            limits,
+            skipped: None,
+        }
+    }
+}
+
+impl codec::Decoder for RequestCodec {
+    type Item = RequestFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(skipped) = &mut self.skipped {
+            if !skipped.read(src)? {
+                return Ok(None);
+            }
+            let skipped = self.skipped.take().unwrap();
+            return Ok(Some(RequestFrame::Oversized(OversizedProduce {
+                header: skipped.header.context("truncated produce request")?,
+                produce: skipped.produce,
+                message: skipped.message,
+            })));
+        }
+
+        // the length prefix and the API key
+        if src.len() < 6 {
+            return Ok(None);
+        }
+        let size = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        let api_key = i16::from_be_bytes([src[4], src[5]]);
+        if let (Some(limits), result::Result::Ok(ApiKey::ProduceKey)) =
+            (&self.limits, ApiKey::try_from(api_key))
+        {
+            if let Err(message) = limits.check_request(size) {
+                src.advance(size_of::<u32>());
+                self.skipped = Some(SkippedProduce::new(size, message));
+                return self.decode(src);
+            }
+        }
+        ensure!(
+            size <= MAX_REQUEST_BYTES,
+            "request of {size} bytes is larger than the maximum of {MAX_REQUEST_BYTES}"
+        );
+        let frame_size = size_of::<u32>() + size;
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+        Ok(Some(RequestFrame::Whole(src.split_to(frame_size))))
+    }
+}
+
+/// Where the reading of an oversized produce request is.
+enum SkipStep {
+    /// The header, and the fields before the topics.
+    Start,
+
+    /// The name of a topic and its number of partitions.
+    Topic,
+
+    /// The index of a partition and the size of its records.
+    Partition,
+
+    /// The records of a partition, with the number of bytes left.
+    Records(usize),
+
+    /// The tagged fields after a partition.
+    PartitionEnd,
+
+    /// The tagged fields after a topic.
+    TopicEnd,
+
+    /// Whatever is left of the request.
+    Rest,
+}
+
+/// An oversized produce request, read as it arrives without its records.
+struct SkippedProduce {
+    /// Bytes of the request left to read.
+    remaining: usize,
+    step: SkipStep,
+    api_version: i16,
+    header: Option<RequestHeader>,
+    produce: ProduceRequest,
+    topics_left: usize,
+    partitions_left: usize,
+    topic: TopicName,
+    message: String,
+}
+
+impl SkippedProduce {
+    fn new(size: usize, message: String) -> Self {
+        Self {
+            remaining: size,
+            step: SkipStep::Start,
+            api_version: 0,
+            header: None,
+            produce: ProduceRequest::default(),
+            topics_left: 0,
+            partitions_left: 0,
+            topic: TopicName::default(),
+            message,
+        }
+    }
+
+    /// Read the request as far as possible, returning whether it was read entirely.
+    ///
+    /// Every field but the records is small, and only read once it has arrived entirely.
+    fn read(&mut self, src: &mut BytesMut) -> Result<bool> {
+        loop {
+            let available = src.len().min(self.remaining);
+            match self.step {
+                SkipStep::Records(left) => {
+                    let skipped = left.min(available);
+                    src.advance(skipped);
+                    self.remaining -= skipped;
+                    if skipped < left {
+                        self.step = SkipStep::Records(left - skipped);
+                        return Ok(false);
+                    }
+                    self.step = SkipStep::PartitionEnd;
+                }
+                SkipStep::Rest => {
+                    src.advance(available);
+                    self.remaining -= available;
+                    return Ok(self.remaining == 0);
+                }
+                _ => {
+                    let mut buf = &src[..available];
+                    match self.read_field(&mut buf) {
+                        result::Result::Ok(()) => {
+                            let read = available - buf.len();
+                            src.advance(read);
+                            self.remaining -= read;
+                        }
+                        // the field has not arrived entirely yet
+                        Err(_) if available < self.remaining => return Ok(false),
+                        Err(err) => return Err(err.context("decoding oversized produce request")),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read the field of the current step, and move to the next one.
+    fn read_field(&mut self, buf: &mut &[u8]) -> Result<()> {
+        let flexible = self.api_version >= 9;
+        match self.step {
+            SkipStep::Start => {
+                ensure!(buf.len() >= 4, "truncated request header");
+                let api_version = i16::from_be_bytes([buf[2], buf[3]]);
+                let header = RequestHeader::decode(
+                    buf,
+                    ApiKey::ProduceKey.request_header_version(api_version),
+                )?;
+                let acks = produce_acks(buf, api_version)?;
+                let timeout_ms = buf.try_get_i32()?;
+                let topics = read_array_len(buf, api_version >= 9)?;
+                self.api_version = api_version;
+                self.header = Some(header);
+                self.produce.acks = acks;
+                self.produce.timeout_ms = timeout_ms;
+                self.topics_left = topics;
+                self.step = if topics > 0 {
+                    SkipStep::Topic
+                } else {
+                    SkipStep::Rest
+                };
+            }
+            SkipStep::Topic => {
+                let name = read_string(buf, flexible)?;
+                let partitions = read_array_len(buf, flexible)?;
+                self.topic = TopicName(str_bytes(name));
+                self.produce
+                    .topic_data
+                    .insert(self.topic.clone(), Default::default());
+                self.partitions_left = partitions;
+                self.step = if partitions > 0 {
+                    SkipStep::Partition
+                } else {
+                    SkipStep::TopicEnd
+                };
+            }
+            SkipStep::Partition => {
+                let index = buf.try_get_i32()?;
+                let size = if flexible {
+                    (read_unsigned_varint(buf)? as usize).saturating_sub(1)
+                } else {
+                    buf.try_get_i32()?.max(0) as usize
+                };
+                let mut partition = produce_request::PartitionProduceData::default();
+                partition.index = index;
+                if let Some(topic) = self.produce.topic_data.get_mut(&self.topic) {
+                    topic.partition_data.push(partition);
+                }
+                self.step = SkipStep::Records(size);
+            }
+            SkipStep::PartitionEnd => {
+                if flexible {
+                    skip_tagged_fields(buf)?;
+                }
+                self.partitions_left -= 1;
+                self.step = if self.partitions_left > 0 {
+                    SkipStep::Partition
+                } else {
+                    SkipStep::TopicEnd
+                };
+            }
+            SkipStep::TopicEnd => {
+                if flexible {
+                    skip_tagged_fields(buf)?;
+                }
+                self.topics_left -= 1;
+                self.step = if self.topics_left > 0 {
+                    SkipStep::Topic
+                } else {
+                    SkipStep::Rest
+                };
+            }
+            SkipStep::Records(_) | SkipStep::Rest => {}
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 struct KafkaServerCodec {
     length_codec: LengthDelimitedCodec,
     inflight: Arc<DashMap<i32, RequestKeyAndVersion>>,
+    slow_log: Option<Arc<SlowLog>>,
+    decode_fetch: bool,
+    decode_api_versions: bool,
 }
 
 impl KafkaServerCodec {
-    pub fn new() -> Self {
+    pub fn new(
+        slow_log: Option<Arc<SlowLog>>,
+        decode_fetch: bool,
+        decode_api_versions: bool,
+    ) -> Self {
         Self {
             length_codec: LengthDelimitedCodec::builder()
                 .num_skip(0) // Do not strip frame header
                 .length_adjustment(4)
                 .new_codec(),
             inflight: Arc::new(DashMap::new()),
+            slow_log,
+            decode_fetch,
+            decode_api_versions,
+        }
+    }
+
+    /// Hand a completed request over to the slow log, if enabled, with the error code of its
+    /// response.
+    fn observe(&self, correlation_id: i32, request: &RequestKeyAndVersion, frame: &Bytes) {
+        if let Some(slow_log) = &self.slow_log {
+            // the layout of the versions kafka-protocol does not know is unknown
+            let error_code = if is_decodable(request.api_key, request.api_version) {
+                response_error_code(
+                    request.api_key,
+                    request.api_version,
+                    frame.slice(size_of::<u32>()..),
+                )
+                .unwrap_or_else(|err| {
+                    debug!(%err, "unable to decode response error code");
+                    0
+                })
+            } else {
+                0
+            };
+            slow_log.record(SlowRequest {
+                api_key: request.api_key,
+                api_version: request.api_version,
+                correlation_id,
+                client_id: request.client_id.clone(),
+                elapsed: request.sent_at.elapsed(),
+                error_code,
+            });
         }
     }
 }
@@ -61,23 +832,156 @@ impl codec::Decoder for KafkaServerCodec {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if let Some(mut bytes) = self.length_codec.decode(src)? {
+        if let Some(bytes) = self.length_codec.decode(src)? {
+            let mut bytes = bytes.freeze();
             let correlation_id = bytes.peek_bytes(4..8).get_i32();
-            match self.inflight.remove(&correlation_id) {
-                Some((
-                    _,
-                    RequestKeyAndVersion {
-                        api_key: ApiKey::MetadataKey,
+            let mut request = match self.inflight.remove(&correlation_id) {
+                Some((_, request)) => request,
+                None => return Ok(Some(KafkaResponse::UndecodedResponse(bytes))),
+            };
+            self.observe(correlation_id, &request, &bytes);
+            if let Some(shadow) = request.shadow.take() {
+                let _ = shadow.send(bytes.clone());
+            }
+            match request {
+                // errors are answered with version 0, and forwarded as is
+                RequestKeyAndVersion {
+                    api_key: ApiKey::ApiVersionsKey,
+                    api_version,
+                    ..
+                } if self.decode_api_versions
+                    && is_decodable(ApiKey::ApiVersionsKey, api_version)
+                    && bytes.peek_bytes(8..10).get_i16() == 0 =>
+                {
+                    bytes.advance(size_of::<u32>()); // skip length
+                    let header = ResponseHeader::decode(
+                        &mut bytes,
+                        ApiVersionsResponse::header_version(api_version),
+                    )?;
+                    let response = ApiVersionsResponse::decode(&mut bytes, api_version)?;
+                    Ok(Some(KafkaResponse::ApiVersions(
                         api_version,
-                    },
-                )) => {
+                        header,
+                        Box::new(response),
+                    )))
+                }
+                RequestKeyAndVersion {
+                    api_key: ApiKey::MetadataKey,
+                    api_version,
+                    topic_access,
+                    ..
+                } => {
                     bytes.advance(size_of::<u32>()); // skip length
                     let header = ResponseHeader::decode(
                         &mut bytes,
                         MetadataResponse::header_version(api_version),
                     )?;
-                    let response = MetadataResponse::decode(&mut bytes, api_version)?;
-                    Ok(Some(KafkaResponse::Metadata(api_version, header, response)))
+                    let mut response = MetadataResponse::decode(&mut bytes, api_version)?;
+                    if let Some(access) = topic_access {
+                        hide_topics(&mut response, access);
+                    }
+                    Ok(Some(KafkaResponse::Metadata(
+                        api_version,
+                        header,
+                        Box::new(response),
+                    )))
+                }
+                RequestKeyAndVersion {
+                    api_key: ApiKey::FetchKey,
+                    api_version,
+                    client_id,
+                    fault,
+                    ..
+                } if (self.decode_fetch || fault.is_some())
+                    && is_decodable(ApiKey::FetchKey, api_version) =>
+                {
+                    bytes.advance(size_of::<u32>()); // skip length
+                    let header = ResponseHeader::decode(
+                        &mut bytes,
+                        FetchResponse::header_version(api_version),
+                    )?;
+                    let mut response = FetchResponse::decode(&mut bytes, api_version)?;
+                    if let Some(fault) = fault {
+                        inject_fetch_fault(&mut response, fault);
+                    }
+                    Ok(Some(KafkaResponse::Fetch(
+                        api_version,
+                        header,
+                        Box::new(response),
+                        client_id,
+                    )))
+                }
+                RequestKeyAndVersion {
+                    api_key: ApiKey::ProduceKey,
+                    api_version,
+                    rejections,
+                    fault,
+                    ..
+                } if !rejections.is_empty() || fault.is_some() => {
+                    bytes.advance(size_of::<u32>()); // skip length
+                    let header = ResponseHeader::decode(
+                        &mut bytes,
+                        ProduceResponse::header_version(api_version),
+                    )?;
+                    let mut response = ProduceResponse::decode(&mut bytes, api_version)?;
+                    if let Some(fault) = fault {
+                        inject_produce_fault(&mut response, fault);
+                    }
+                    add_rejections(&mut response, rejections);
+                    Ok(Some(KafkaResponse::Produce(
+                        api_version,
+                        header,
+                        Box::new(response),
+                    )))
+                }
+                RequestKeyAndVersion {
+                    api_key: ApiKey::CreateTopicsKey,
+                    api_version,
+                    violations,
+                    ..
+                } if !violations.is_empty() => {
+                    bytes.advance(size_of::<u32>()); // skip length
+                    let header = ResponseHeader::decode(
+                        &mut bytes,
+                        CreateTopicsResponse::header_version(api_version),
+                    )?;
+                    let response = CreateTopicsResponse::decode(&mut bytes, api_version)?;
+                    let mut response =
+                        KafkaResponse::CreateTopics(api_version, header, Box::new(response));
+                    add_violations(&mut response, violations);
+                    Ok(Some(response))
+                }
+                RequestKeyAndVersion {
+                    api_key: ApiKey::CreatePartitionsKey,
+                    api_version,
+                    violations,
+                    ..
+                } if !violations.is_empty() => {
+                    bytes.advance(size_of::<u32>()); // skip length
+                    let header = ResponseHeader::decode(
+                        &mut bytes,
+                        CreatePartitionsResponse::header_version(api_version),
+                    )?;
+                    let response = CreatePartitionsResponse::decode(&mut bytes, api_version)?;
+                    let mut response =
+                        KafkaResponse::CreatePartitions(api_version, header, Box::new(response));
+                    add_violations(&mut response, violations);
+                    Ok(Some(response))
+                }
+                RequestKeyAndVersion {
+                    api_key: ApiKey::ListGroupsKey,
+                    api_version,
+                    group_access: Some(access),
+                    ..
+                } => {
+                    bytes.advance(size_of::<u32>()); // skip length
+                    let header = ResponseHeader::decode(
+                        &mut bytes,
+                        ListGroupsResponse::header_version(api_version),
+                    )?;
+                    let mut response = ListGroupsResponse::decode(&mut bytes, api_version)?;
+                    hide_groups(&mut response, access);
+                    synthesized(api_version, &header, &response).map(Some)
                 }
                 _ => Ok(Some(KafkaResponse::UndecodedResponse(bytes))),
             }
@@ -92,13 +996,23 @@ impl codec::Encoder<KafkaResponse> for KafkaServerCodec {
 
     fn encode(&mut self, item: KafkaResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
         match item {
+            KafkaResponse::ApiVersions(version, header, response) => {
+                encode_response(dst, version, &header, &*response)?
+            }
             KafkaResponse::Metadata(version, header, response) => {
-                let mut bytes = BytesMut::new();
-                header.encode(&mut bytes, MetadataResponse::header_version(version))?;
-                response.encode(&mut bytes, version)?;
-                // self.length_codec.encode(bytes.get_bytes(bytes.len()), dst)?;
-                dst.put_u32(bytes.len() as u32);
-                dst.put_slice(&bytes);
+                encode_response(dst, version, &header, &*response)?
+            }
+            KafkaResponse::Fetch(version, header, response, _) => {
+                encode_response(dst, version, &header, &*response)?
+            }
+            KafkaResponse::Produce(version, header, response) => {
+                encode_response(dst, version, &header, &*response)?
+            }
+            KafkaResponse::CreateTopics(version, header, response) => {
+                encode_response(dst, version, &header, &*response)?
+            }
+            KafkaResponse::CreatePartitions(version, header, response) => {
+                encode_response(dst, version, &header, &*response)?
             }
             KafkaResponse::UndecodedResponse(bytes) => dst.put_slice(&bytes),
         }
@@ -108,123 +1022,1519 @@ impl codec::Encoder<KafkaResponse> for KafkaServerCodec {
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 /// Represents a kafka broker host/port
-struct KafkaBroker {
+pub(crate) struct KafkaBroker {
     ///local/private host
     pub host: String,
     ///host port
     pub port: u16,
 }
 
-impl KafkaBroker {
-    /// Create a new KafkaBroker
-    pub fn new(host: String, port: u16) -> KafkaBroker {
-        KafkaBroker { host, port }
+impl KafkaBroker {
+    /// Create a new KafkaBroker
+    pub fn new(host: String, port: u16) -> KafkaBroker {
+        KafkaBroker { host, port }
+    }
+}
+
+impl fmt::Display for KafkaBroker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl From<&MetadataResponseBroker> for KafkaBroker {
+    fn from(broker: &MetadataResponseBroker) -> Self {
+        KafkaBroker::new(broker.host.to_string(), broker.port as u16)
+    }
+}
+
+impl FromStr for KafkaBroker {
+    type Err = Error;
+
+    fn from_str(bootstrap_server: &str) -> Result<Self, Self::Err> {
+        let mut split = bootstrap_server.split(':');
+        let host = split.next().ok_or(Error::msg("no host provided"))?;
+        let port = split.next().ok_or(Error::msg("no port provided"))?;
+        let port = port.parse().context("invalid port")?;
+        Ok(KafkaBroker::new(host.to_string(), port))
+    }
+}
+
+/// Replace the records of a fetched partition with an error.
+fn withhold_records(partition: &mut fetch_response::PartitionData, error: ResponseError) {
+    partition.error_code = error.code();
+    partition.records = Some(Bytes::new());
+}
+
+/// Inject an error or throttling in a produce response.
+fn inject_produce_fault(response: &mut ProduceResponse, fault: Fault) {
+    match fault {
+        Fault::Error(error) => {
+            for partition in response
+                .responses
+                .values_mut()
+                .flat_map(|topic| &mut topic.partition_responses)
+            {
+                partition.error_code = error.code();
+                partition.base_offset = -1;
+                partition.log_append_time_ms = -1;
+                partition.log_start_offset = -1;
+            }
+        }
+        Fault::Throttle(delay) => response.throttle_time_ms = delay.as_millis() as i32,
+        Fault::Latency(_) | Fault::Disconnect => {}
+    }
+}
+
+/// Inject an error or throttling in a fetch response.
+fn inject_fetch_fault(response: &mut FetchResponse, fault: Fault) {
+    match fault {
+        Fault::Error(error) => {
+            for partition in response
+                .responses
+                .iter_mut()
+                .flat_map(|topic| &mut topic.partitions)
+            {
+                withhold_records(partition, error);
+                partition.high_watermark = -1;
+                partition.last_stable_offset = -1;
+                partition.log_start_offset = -1;
+            }
+        }
+        Fault::Throttle(delay) => response.throttle_time_ms = delay.as_millis() as i32,
+        Fault::Latency(_) | Fault::Disconnect => {}
+    }
+}
+
+/// Encode a response built by the proxy, for the APIs whose responses are not decoded.
+fn synthesized<R: Encodable + HeaderVersion>(
+    version: i16,
+    header: &ResponseHeader,
+    response: &R,
+) -> Result<KafkaResponse> {
+    let mut bytes = BytesMut::new();
+    encode_response(&mut bytes, version, header, response)?;
+    Ok(KafkaResponse::UndecodedResponse(bytes.freeze()))
+}
+
+/// Write the responses of the proxy owed before the next response of the broker.
+async fn feed_synthesized<S: AsyncWrite + Unpin>(
+    sink: &mut codec::FramedWrite<S, KafkaServerCodec>,
+    pending: &mut VecDeque<Queued>,
+) -> Result<()> {
+    while let Some(Queued::Synthesized(_)) = pending.front() {
+        if let Some(Queued::Synthesized(response)) = pending.pop_front() {
+            sink.feed(response)
+                .await
+                .context("reading/writing to remote server")?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer every partition of a produce request with an error.
+fn produce_error(produce: ProduceRequest, error: i16) -> ProduceResponse {
+    let mut response = ProduceResponse::default();
+    for (topic, data) in produce.topic_data {
+        let partitions = data.partition_data.iter().map(|partition| {
+            let mut response = produce_response::PartitionProduceResponse::default();
+            response.index = partition.index;
+            response.error_code = error;
+            response.base_offset = -1;
+            response.log_append_time_ms = -1;
+            response.log_start_offset = -1;
+            response
+        });
+        let mut topic_response = produce_response::TopicProduceResponse::default();
+        topic_response.partition_responses = partitions.collect();
+        response.responses.insert(topic, topic_response);
+    }
+    response
+}
+
+/// Answer every partition of a produce request exceeding a size limit with `MESSAGE_TOO_LARGE`.
+fn too_large(
+    correlation_id: i32,
+    api_version: i16,
+    produce: ProduceRequest,
+    message: &str,
+) -> KafkaResponse {
+    let mut response = produce_error(produce, ResponseError::MessageTooLarge.code());
+    for partition in response
+        .responses
+        .values_mut()
+        .flat_map(|topic| &mut topic.partition_responses)
+    {
+        partition.error_message = Some(str_bytes(message.to_string()));
+    }
+    let mut header = ResponseHeader::default();
+    header.correlation_id = correlation_id;
+    KafkaResponse::Produce(api_version, header, Box::new(response))
+}
+
+/// Answer every partition of a fetch request with an error.
+fn fetch_error(fetch: FetchRequest, error: i16) -> FetchResponse {
+    let mut response = FetchResponse::default();
+    for topic in fetch.topics {
+        let partitions = topic.partitions.iter().map(|partition| {
+            let mut response = fetch_response::PartitionData::default();
+            response.partition_index = partition.partition;
+            response.error_code = error;
+            response.high_watermark = -1;
+            response.last_stable_offset = -1;
+            response.log_start_offset = -1;
+            response.records = Some(Bytes::new());
+            response
+        });
+        let mut topic_response = fetch_response::FetchableTopicResponse::default();
+        topic_response.topic = topic.topic;
+        topic_response.topic_id = topic.topic_id;
+        topic_response.partitions = partitions.collect();
+        response.responses.push(topic_response);
+    }
+    response
+}
+
+/// Answer a request with `UNSUPPORTED_VERSION`, for the APIs whose response can be built.
+///
+/// `ApiVersions` requests are answered with the versions of `ApiVersions` to retry with, those
+/// the proxy can decode and the policy allows.
+fn unsupported_version(
+    correlation_id: i32,
+    request: &RequestKeyAndVersion,
+    frame: &Bytes,
+    policy: Option<&VersionPolicy>,
+) -> Result<Option<KafkaResponse>> {
+    let error = ResponseError::UnsupportedVersion.code();
+    let mut header = ResponseHeader::default();
+    header.correlation_id = correlation_id;
+    let response = match request.api_key {
+        ApiKey::ProduceKey => {
+            let (_, _, produce) = decode_produce(frame)?;
+            let response = produce_error(produce, error);
+            KafkaResponse::Produce(request.api_version, header, Box::new(response))
+        }
+        ApiKey::FetchKey => {
+            let fetch = decode_request::<FetchRequest>(frame, request)?;
+            let mut response = fetch_error(fetch, error);
+            response.error_code = error;
+            KafkaResponse::Fetch(
+                request.api_version,
+                header,
+                Box::new(response),
+                request.client_id.clone(),
+            )
+        }
+        ApiKey::ApiVersionsKey => {
+            // brokers answer unsupported versions with version 0
+            let mut response = ApiVersionsResponse::default();
+            response.error_code = error;
+            let decodable = decodable_versions(ApiKey::ApiVersionsKey);
+            let mut versions = api_versions_response::ApiVersion::default();
+            versions.min_version = policy
+                .and_then(|policy| policy.minimum(ApiKey::ApiVersionsKey))
+                .map_or(decodable.min, |minimum| minimum.max(decodable.min));
+            versions.max_version = decodable.max;
+            response
+                .api_keys
+                .insert(ApiKey::ApiVersionsKey as i16, versions);
+            synthesized(0, &header, &response)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(response))
+}
+
+/// Answer a request denied by the authorization rules with the error of the denied resource,
+/// for the APIs whose response can be built.
+///
+/// Every topic, partition or group of the request is answered with the error, as nothing of it
+/// reaches the broker.
+fn unauthorized(
+    correlation_id: i32,
+    request: &RequestKeyAndVersion,
+    frame: &Bytes,
+    resource: ResourceType,
+) -> Result<Option<KafkaResponse>> {
+    let error = match resource {
+        ResourceType::Topic => ResponseError::TopicAuthorizationFailed,
+        ResourceType::Group => ResponseError::GroupAuthorizationFailed,
+        ResourceType::TransactionalId => ResponseError::TransactionalIdAuthorizationFailed,
+        ResourceType::Cluster => ResponseError::ClusterAuthorizationFailed,
+    }
+    .code();
+    let version = request.api_version;
+    let mut header = ResponseHeader::default();
+    header.correlation_id = correlation_id;
+    let response = match request.api_key {
+        ApiKey::ProduceKey => {
+            let (_, _, produce) = decode_produce(frame)?;
+            KafkaResponse::Produce(version, header, Box::new(produce_error(produce, error)))
+        }
+        ApiKey::FetchKey => {
+            let fetch = decode_request::<FetchRequest>(frame, request)?;
+            KafkaResponse::Fetch(
+                version,
+                header,
+                Box::new(fetch_error(fetch, error)),
+                request.client_id.clone(),
+            )
+        }
+        ApiKey::ListOffsetsKey => {
+            let list = decode_request::<ListOffsetsRequest>(frame, request)?;
+            let mut response = ListOffsetsResponse::default();
+            for topic in list.topics {
+                let mut topic_response = list_offsets_response::ListOffsetsTopicResponse::default();
+                topic_response.name = topic.name;
+                topic_response.partitions = topic
+                    .partitions
+                    .iter()
+                    .map(|partition| {
+                        let mut response =
+                            list_offsets_response::ListOffsetsPartitionResponse::default();
+                        response.partition_index = partition.partition_index;
+                        response.error_code = error;
+                        response.timestamp = -1;
+                        response.offset = -1;
+                        response.leader_epoch = -1;
+                        response
+                    })
+                    .collect();
+                response.topics.push(topic_response);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::OffsetForLeaderEpochKey => {
+            let epochs = decode_request::<OffsetForLeaderEpochRequest>(frame, request)?;
+            let mut response = OffsetForLeaderEpochResponse::default();
+            for (name, topic) in epochs.topics {
+                let mut result =
+                    offset_for_leader_epoch_response::OffsetForLeaderTopicResult::default();
+                result.partitions = topic
+                    .partitions
+                    .iter()
+                    .map(|partition| {
+                        let mut response =
+                            offset_for_leader_epoch_response::EpochEndOffset::default();
+                        response.partition = partition.partition;
+                        response.error_code = error;
+                        response.leader_epoch = -1;
+                        response.end_offset = -1;
+                        response
+                    })
+                    .collect();
+                response.topics.insert(name, result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::OffsetCommitKey => {
+            let commit = decode_request::<OffsetCommitRequest>(frame, request)?;
+            let mut response = OffsetCommitResponse::default();
+            for topic in commit.topics {
+                let mut topic_response =
+                    offset_commit_response::OffsetCommitResponseTopic::default();
+                topic_response.name = topic.name;
+                topic_response.partitions = topic
+                    .partitions
+                    .iter()
+                    .map(|partition| {
+                        let mut response =
+                            offset_commit_response::OffsetCommitResponsePartition::default();
+                        response.partition_index = partition.partition_index;
+                        response.error_code = error;
+                        response
+                    })
+                    .collect();
+                response.topics.push(topic_response);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::OffsetFetchKey => {
+            let fetch = decode_request::<OffsetFetchRequest>(frame, request)?;
+            let mut response = OffsetFetchResponse::default();
+            // versions before 2 only carry errors by partition
+            let partition_error = |partition_index| {
+                let mut partition = offset_fetch_response::OffsetFetchResponsePartition::default();
+                partition.partition_index = partition_index;
+                partition.committed_offset = -1;
+                partition.committed_leader_epoch = -1;
+                partition.error_code = error;
+                partition
+            };
+            for topic in fetch.topics.unwrap_or_default() {
+                let mut topic_response = offset_fetch_response::OffsetFetchResponseTopic::default();
+                topic_response.name = topic.name;
+                topic_response.partitions = topic
+                    .partition_indexes
+                    .iter()
+                    .map(|&partition_index| partition_error(partition_index))
+                    .collect();
+                response.topics.push(topic_response);
+            }
+            response.error_code = error;
+            for group in fetch.groups {
+                let mut group_response = offset_fetch_response::OffsetFetchResponseGroup::default();
+                group_response.group_id = group.group_id;
+                group_response.error_code = error;
+                response.groups.push(group_response);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::FindCoordinatorKey => {
+            let find = decode_request::<FindCoordinatorRequest>(frame, request)?;
+            let mut response = FindCoordinatorResponse::default();
+            response.error_code = error;
+            response.node_id = BrokerId(-1);
+            response.port = -1;
+            for key in find.coordinator_keys {
+                let mut coordinator = find_coordinator_response::Coordinator::default();
+                coordinator.key = key;
+                coordinator.node_id = BrokerId(-1);
+                coordinator.port = -1;
+                coordinator.error_code = error;
+                response.coordinators.push(coordinator);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::JoinGroupKey => {
+            let mut response = JoinGroupResponse::default();
+            response.error_code = error;
+            response.generation_id = -1;
+            response.protocol_name = Some(StrBytes::default());
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::SyncGroupKey => {
+            let mut response = SyncGroupResponse::default();
+            response.error_code = error;
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::HeartbeatKey => {
+            let mut response = HeartbeatResponse::default();
+            response.error_code = error;
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::LeaveGroupKey => {
+            let mut response = LeaveGroupResponse::default();
+            response.error_code = error;
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::DescribeGroupsKey => {
+            let describe = decode_request::<DescribeGroupsRequest>(frame, request)?;
+            let mut response = DescribeGroupsResponse::default();
+            for group_id in describe.groups {
+                let mut group = describe_groups_response::DescribedGroup::default();
+                group.group_id = group_id;
+                group.error_code = error;
+                response.groups.push(group);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::DeleteGroupsKey => {
+            let delete = decode_request::<DeleteGroupsRequest>(frame, request)?;
+            let mut response = DeleteGroupsResponse::default();
+            for group_id in delete.groups_names {
+                let mut result = delete_groups_response::DeletableGroupResult::default();
+                result.error_code = error;
+                response.results.insert(group_id, result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::CreateTopicsKey => {
+            let create = decode_request::<CreateTopicsRequest>(frame, request)?;
+            let mut response = CreateTopicsResponse::default();
+            for name in create.topics.into_keys() {
+                let mut result = create_topics_response::CreatableTopicResult::default();
+                result.error_code = error;
+                result.num_partitions = -1;
+                result.replication_factor = -1;
+                response.topics.insert(name, result);
+            }
+            KafkaResponse::CreateTopics(version, header, Box::new(response))
+        }
+        ApiKey::CreatePartitionsKey => {
+            let create = decode_request::<CreatePartitionsRequest>(frame, request)?;
+            let mut response = CreatePartitionsResponse::default();
+            for name in create.topics.into_keys() {
+                let mut result = create_partitions_response::CreatePartitionsTopicResult::default();
+                result.name = name;
+                result.error_code = error;
+                response.results.push(result);
+            }
+            KafkaResponse::CreatePartitions(version, header, Box::new(response))
+        }
+        ApiKey::DeleteTopicsKey => {
+            let delete = decode_request::<DeleteTopicsRequest>(frame, request)?;
+            let mut response = DeleteTopicsResponse::default();
+            let topics = delete
+                .topic_names
+                .into_iter()
+                .map(|name| (name, Uuid::nil()))
+                .chain(
+                    delete
+                        .topics
+                        .into_iter()
+                        .map(|topic| (topic.name.unwrap_or_default(), topic.topic_id)),
+                );
+            for (name, topic_id) in topics {
+                let mut result = delete_topics_response::DeletableTopicResult::default();
+                result.topic_id = topic_id;
+                result.error_code = error;
+                response.responses.insert(name, result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::InitProducerIdKey => {
+            let mut response = InitProducerIdResponse::default();
+            response.error_code = error;
+            response.producer_id = ProducerId(-1);
+            response.producer_epoch = -1;
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::AddPartitionsToTxnKey => {
+            let add = decode_request::<AddPartitionsToTxnRequest>(frame, request)?;
+            let mut response = AddPartitionsToTxnResponse::default();
+            for (name, topic) in add.topics {
+                let mut result =
+                    add_partitions_to_txn_response::AddPartitionsToTxnTopicResult::default();
+                for partition in topic.partitions {
+                    let mut partition_result =
+                        add_partitions_to_txn_response::AddPartitionsToTxnPartitionResult::default(
+                        );
+                    partition_result.error_code = error;
+                    result.results.insert(partition, partition_result);
+                }
+                response.results.insert(name, result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::AddOffsetsToTxnKey => {
+            let mut response = AddOffsetsToTxnResponse::default();
+            response.error_code = error;
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::EndTxnKey => {
+            let mut response = EndTxnResponse::default();
+            response.error_code = error;
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::TxnOffsetCommitKey => {
+            let commit = decode_request::<TxnOffsetCommitRequest>(frame, request)?;
+            let mut response = TxnOffsetCommitResponse::default();
+            for topic in commit.topics {
+                let mut topic_response =
+                    txn_offset_commit_response::TxnOffsetCommitResponseTopic::default();
+                topic_response.name = topic.name;
+                topic_response.partitions = topic
+                    .partitions
+                    .iter()
+                    .map(|partition| {
+                        let mut response =
+                            txn_offset_commit_response::TxnOffsetCommitResponsePartition::default();
+                        response.partition_index = partition.partition_index;
+                        response.error_code = error;
+                        response
+                    })
+                    .collect();
+                response.topics.push(topic_response);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::DescribeConfigsKey => {
+            let describe = decode_request::<DescribeConfigsRequest>(frame, request)?;
+            let mut response = DescribeConfigsResponse::default();
+            for resource in describe.resources {
+                let mut result = describe_configs_response::DescribeConfigsResult::default();
+                result.error_code = error;
+                result.resource_type = resource.resource_type;
+                result.resource_name = resource.resource_name;
+                response.results.push(result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::AlterConfigsKey => {
+            let alter = decode_request::<AlterConfigsRequest>(frame, request)?;
+            let mut response = AlterConfigsResponse::default();
+            for resource in alter.resources {
+                let mut result = alter_configs_response::AlterConfigsResourceResponse::default();
+                result.error_code = error;
+                result.resource_type = resource.resource_type;
+                result.resource_name = resource.resource_name;
+                response.responses.push(result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        ApiKey::IncrementalAlterConfigsKey => {
+            let alter = decode_request::<IncrementalAlterConfigsRequest>(frame, request)?;
+            let mut response = IncrementalAlterConfigsResponse::default();
+            for resource in alter.resources {
+                let mut result =
+                    incremental_alter_configs_response::AlterConfigsResourceResponse::default();
+                result.error_code = error;
+                result.resource_type = resource.resource_type;
+                result.resource_name = resource.resource_name;
+                response.responses.push(result);
+            }
+            synthesized(version, &header, &response)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(response))
+}
+
+/// The resource whose permissions cover the configs of a `DescribeConfigs` or `AlterConfigs`
+/// resource, by its config resource type.
+fn config_resource(resource_type: i8, name: &StrBytes) -> (ResourceType, String) {
+    match resource_type {
+        2 => (ResourceType::Topic, name.to_string()),
+        32 => (ResourceType::Group, name.to_string()),
+        // brokers, broker loggers and client metrics
+        _ => (ResourceType::Cluster, CLUSTER_RESOURCE.to_string()),
+    }
+}
+
+/// Answer a SASL handshake or authentication, terminated by the proxy to identify the principal
+/// of the connection.
+///
+/// Only the PLAIN mechanism is offered, and only with the framed authentication of handshake
+/// versions 1 and up. A failed authentication clears the principal of the connection.
+fn authenticate(
+    authorizer: &Authorizer,
+    principal: &mut Option<String>,
+    correlation_id: i32,
+    request: &RequestKeyAndVersion,
+    frame: &Bytes,
+) -> Result<KafkaResponse> {
+    let version = request.api_version;
+    let mut header = ResponseHeader::default();
+    header.correlation_id = correlation_id;
+    if request.api_key == ApiKey::SaslHandshakeKey {
+        let handshake = decode_request::<SaslHandshakeRequest>(frame, request)?;
+        let mut response = SaslHandshakeResponse::default();
+        response.mechanisms = vec![StrBytes::from_str("PLAIN")];
+        if version == 0 {
+            response.error_code = ResponseError::UnsupportedVersion.code();
+        } else if &*handshake.mechanism != "PLAIN" {
+            response.error_code = ResponseError::UnsupportedSaslMechanism.code();
+        }
+        return synthesized(version, &header, &response);
+    }
+    let authentication = decode_request::<SaslAuthenticateRequest>(frame, request)?;
+    let mut response = SaslAuthenticateResponse::default();
+    *principal = authorizer.authenticate(&authentication.auth_bytes);
+    match principal {
+        Some(principal) => {
+            debug!(principal, "authenticated remote client");
+        }
+        None => {
+            warn!(
+                client_id = request.client_id.as_deref().unwrap_or(""),
+                "rejecting invalid SASL credentials"
+            );
+            response.error_code = ResponseError::SaslAuthenticationFailed.code();
+            response.error_message = Some(StrBytes::from_str(
+                "Authentication failed: Invalid username or password",
+            ));
+        }
+    }
+    synthesized(version, &header, &response)
+}
+
+/// A tunnel exposing one broker on a public port of the server.
+struct Tunnel {
+    /// Port that is publicly available on the remote.
+    remote_port: u16,
+
+    /// Node id of the broker, once seen in a metadata response.
+    node_id: Option<i32>,
+
+    /// When the tunnel was opened.
+    opened_at: Instant,
+
+    /// Cancelled once the tunnel is closed.
+    shutdown: CancellationToken,
+}
+
+/// A connection proxied through a tunnel.
+struct ProxiedConnection {
+    /// Broker the connection is forwarded to.
+    broker: KafkaBroker,
+
+    /// Address of the remote client, when the server sent it.
+    peer: Option<SocketAddr>,
+
+    /// When the connection was accepted.
+    started_at: Instant,
+
+    /// Bytes received from the remote client.
+    bytes_received: Arc<AtomicU64>,
+
+    /// Bytes sent back to the remote client.
+    bytes_sent: Arc<AtomicU64>,
+}
+
+/// Unregisters a proxied connection when dropped, adding its traffic to the totals.
+struct ActiveConnectionGuard<'a> {
+    proxy: &'a KafkaProxy,
+    id: Uuid,
+}
+
+impl Drop for ActiveConnectionGuard<'_> {
+    fn drop(&mut self) {
+        if let Some((_, connection)) = self.proxy.active.remove(&self.id) {
+            let totals = &self.proxy.totals;
+            totals.bytes_received.fetch_add(
+                connection.bytes_received.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            totals.bytes_sent.fetch_add(
+                connection.bytes_sent.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+/// Snapshot of a tunnel, as exposed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelInfo {
+    /// Name of the cluster of the broker.
+    pub cluster: String,
+
+    /// Node id of the broker, once seen in a metadata response.
+    pub node_id: Option<i32>,
+
+    /// Local address of the broker.
+    pub local_address: String,
+
+    /// Port that is publicly available on the remote.
+    pub remote_port: u16,
+
+    /// Either `open` or `closed`.
+    pub state: &'static str,
+
+    /// Seconds since the tunnel was opened.
+    pub age_secs: u64,
+}
+
+/// Snapshot of a proxied connection, as exposed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    /// Name of the cluster of the broker.
+    pub cluster: String,
+
+    /// Identifier assigned by the server.
+    pub id: Uuid,
+
+    /// Local address of the broker.
+    pub broker: String,
+
+    /// Address of the remote client, when the server sent it.
+    pub peer: Option<SocketAddr>,
+
+    /// Seconds since the connection was accepted.
+    pub age_secs: u64,
+
+    /// Bytes received from the remote client.
+    pub bytes_received: u64,
+
+    /// Bytes sent back to the remote client.
+    pub bytes_sent: u64,
+}
+
+/// Traffic totals since the proxy started, closed connections included.
+#[derive(Debug, Default)]
+pub struct TrafficTotals {
+    /// Number of proxied connections.
+    pub connections: AtomicU64,
+
+    /// Bytes received from remote clients.
+    pub bytes_received: AtomicU64,
+
+    /// Bytes sent back to remote clients.
+    pub bytes_sent: AtomicU64,
+}
+
+/// Name of the cluster of a proxy that was not named.
+pub const DEFAULT_CLUSTER: &str = "default";
+
+/// Name of the cluster resource in authorization rules, as in Kafka.
+const CLUSTER_RESOURCE: &str = "kafka-cluster";
+
+/// State structure for the kafka proxy.
+pub struct KafkaProxy {
+    /// Destination address of the server.
+    pub to: String,
+
+    /// Optional secret used to authenticate clients.
+    pub auth: Option<Authenticator>,
+
+    /// Control connection to the server, shared by the tunnels.
+    control: Arc<Control>,
+
+    /// Name of the proxied cluster, when several are proxied.
+    name: String,
+
+    /// Id the cluster must have, if pinned.
+    expected_cluster_id: Option<String>,
+
+    /// mapping between local url and tunnel
+    connections: RwLock<HashMap<KafkaBroker, Tunnel>>,
+
+    /// Local bootstrap server, once started.
+    bootstrap: RwLock<Option<KafkaBroker>>,
+
+    /// Connections currently proxied.
+    active: DashMap<Uuid, ProxiedConnection>,
+
+    /// When the cluster topology was last seen in a metadata response.
+    last_metadata: RwLock<Option<SystemTime>>,
+
+    /// Traffic totals since the proxy started.
+    totals: TrafficTotals,
+
+    /// Requests received from remote clients since the proxy started, by API key.
+    requests: DashMap<i16, u64>,
+
+    /// Remote clients seen in proxied requests.
+    inventory: ClientInventory,
+
+    /// Optional log of slow and failed requests.
+    slow_log: Option<Arc<SlowLog>>,
+
+    /// Optional masking of the records in fetch responses.
+    masking: Option<Arc<Masking>>,
+
+    /// Optional headers added to the produced records.
+    headers: Option<Arc<HeaderInjection>>,
+
+    /// Optional schemas the produced records must conform to.
+    schemas: Option<Arc<SchemaValidation>>,
+
+    /// Optional encryption of fetched records and decryption of produced ones.
+    encryption: Option<Arc<Encryption>>,
+
+    /// Optional mirroring of produce requests to a secondary cluster.
+    shadow: Option<Arc<ShadowWriter>>,
+
+    /// Optional totals of the records produced and fetched, by topic.
+    accounting: Option<Arc<TopicAccounting>>,
+
+    /// Optional observation of the lag of the remote consumers.
+    lag: Option<Arc<LagObserver>>,
+
+    /// Optional faults injected in the requests of remote clients.
+    chaos: Option<Arc<Chaos>>,
+
+    /// Optional minimum versions of the requests of remote clients.
+    versions: Option<Arc<VersionPolicy>>,
+
+    /// Optional policy on the topics created by remote clients.
+    topic_policy: Option<Arc<TopicPolicy>>,
+
+    /// Authorization of the requests of remote clients, by principal.
+    acl: Option<Arc<Authorizer>>,
+
+    /// Size limits on the produce requests of remote clients.
+    limits: Option<Arc<ProduceLimits>>,
+
+    /// Client id forwarded to the brokers instead of the one of remote clients.
+    client_id: Option<Arc<ClientIdRewrite>>,
+
+    /// Topic names by id, as seen in metadata responses, to resolve recent fetch versions.
+    topic_names: RwLock<HashMap<Uuid, String>>,
+}
+
+impl KafkaProxy {
+    /// Create a new kafka proxy.
+    pub fn new(to: &str, secret: Option<&str>) -> Self {
+        let auth = secret.map(Authenticator::new);
+
+        Self {
+            to: to.to_string(),
+            control: Arc::new(Control::new(to, auth.clone())),
+            auth,
+            name: DEFAULT_CLUSTER.to_string(),
+            expected_cluster_id: None,
+            connections: HashMap::new().into(),
+            bootstrap: None.into(),
+            active: DashMap::new(),
+            last_metadata: None.into(),
+            totals: TrafficTotals::default(),
+            requests: DashMap::new(),
+            inventory: ClientInventory::new(),
+            slow_log: None,
+            masking: None,
+            headers: None,
+            schemas: None,
+            encryption: None,
+            shadow: None,
+            accounting: None,
+            lag: None,
+            chaos: None,
+            versions: None,
+            topic_policy: None,
+            acl: None,
+            limits: None,
+            client_id: None,
+            topic_names: HashMap::new().into(),
+        }
+    }
+
+    /// Name the proxied cluster.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Open the tunnels through a control connection shared with other proxies of the same
+    /// server and secret.
+    pub fn with_control(mut self, control: Arc<Control>) -> Self {
+        self.control = control;
+        self
+    }
+
+    /// Returns the name of the proxied cluster.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Only expose the cluster with this id, closing every tunnel if another one answers.
+    pub fn with_expected_cluster_id(mut self, cluster_id: &str) -> Self {
+        self.expected_cluster_id = Some(cluster_id.to_string());
+        self
+    }
+
+    /// Log requests that are slow or answered with an error.
+    pub fn with_slow_log(mut self, slow_log: Arc<SlowLog>) -> Self {
+        self.slow_log = Some(slow_log);
+        self
+    }
+
+    /// Mask the values of the records sent in fetch responses.
+    pub fn with_masking(mut self, masking: Masking) -> Self {
+        self.masking = Some(Arc::new(masking));
+        self
+    }
+
+    /// Add headers to every record produced through the tunnels.
+    pub fn with_headers(mut self, headers: HeaderInjection) -> Self {
+        self.headers = Some(Arc::new(headers));
+        self
+    }
+
+    /// Reject produced records that do not match the schemas of their topic.
+    pub fn with_schemas(mut self, schemas: SchemaValidation) -> Self {
+        self.schemas = Some(Arc::new(schemas));
+        self
+    }
+
+    /// Encrypt the records sent in fetch responses, and decrypt the produced ones.
+    pub fn with_encryption(mut self, encryption: Arc<Encryption>) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Mirror produce requests to a secondary cluster, whose writer must be running.
+    pub fn with_shadow(mut self, shadow: Arc<ShadowWriter>) -> Self {
+        self.shadow = Some(shadow);
+        self
+    }
+
+    /// Count the records produced and fetched through the tunnels, by topic.
+    pub fn with_accounting(mut self, accounting: Arc<TopicAccounting>) -> Self {
+        self.accounting = Some(accounting);
+        self
+    }
+
+    /// Returns the totals of the records produced and fetched, if counted.
+    pub fn accounting(&self) -> Option<&TopicAccounting> {
+        self.accounting.as_deref()
+    }
+
+    /// Observe the lag of the remote consumers from their fetch requests and responses.
+    pub fn with_lag(mut self, lag: Arc<LagObserver>) -> Self {
+        self.lag = Some(lag);
+        self
+    }
+
+    /// Returns the lag observer of the remote consumers, if enabled.
+    pub fn lag(&self) -> Option<&LagObserver> {
+        self.lag.as_deref()
+    }
+
+    /// Inject faults in the requests of remote clients, to test how they handle broker problems.
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(Arc::new(chaos));
+        self
+    }
+
+    /// Reject the requests of remote clients below a minimum version per API.
+    pub fn with_version_policy(mut self, versions: VersionPolicy) -> Self {
+        self.versions = Some(Arc::new(versions));
+        self
+    }
+
+    /// Reject the topics created or extended by remote clients that violate a policy.
+    pub fn with_topic_policy(mut self, policy: TopicPolicy) -> Self {
+        self.topic_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Check the requests of remote clients against authorization rules, terminating their
+    /// SASL/PLAIN authentication.
+    pub fn with_acl(mut self, authorizer: Authorizer) -> Self {
+        self.acl = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Reject the produce requests of remote clients exceeding size limits.
+    pub fn with_produce_limits(mut self, limits: ProduceLimits) -> Self {
+        self.limits = Some(Arc::new(limits));
+        self
+    }
+
+    /// Rewrite the client id of the requests of remote clients before forwarding them.
+    pub fn with_client_id(mut self, client_id: ClientIdRewrite) -> Self {
+        self.client_id = Some(Arc::new(client_id));
+        self
+    }
+
+    /// Whether a feature must rewrite or authorize request or response bodies, so only the
+    /// versions kafka-protocol knows can be let through.
+    ///
+    /// Features that only observe bodies, like the slow log, accounting, lag, headers and shadow
+    /// writes, skip the versions they cannot decode instead.
+    fn rewrites_bodies(&self) -> bool {
+        self.masking.is_some()
+            || self.schemas.is_some()
+            || self.encryption.is_some()
+            || self.chaos.is_some()
+            || self.topic_policy.is_some()
+            || self.acl.is_some()
+            || self.limits.is_some()
+            || self.client_id.is_some()
+    }
+
+    /// Start the proxy, returning the remote bootstrap server to use for connecting.
+    pub async fn start(self, bootstrap_servers: &str) -> Result<String> {
+        Arc::new(self).expose(bootstrap_servers).await
+    }
+
+    /// Start a shared proxy, returning the remote bootstrap server to use for connecting.
+    ///
+    /// With an expected cluster id, the id of the bootstrap server is checked before any tunnel
+    /// is opened.
+    pub async fn expose(self: &Arc<Self>, bootstrap_servers: &str) -> Result<String> {
+        let url: KafkaBroker = bootstrap_servers.parse().map_err(anyhow::Error::msg)?;
+        if self.expected_cluster_id.is_some() {
+            let metadata = KafkaClient::connect(bootstrap_servers)
+                .await?
+                .metadata()
+                .await?;
+            self.check_cluster_id(metadata.cluster_id.as_deref())?;
+        }
+        *self.bootstrap.write().unwrap() = Some(url.clone());
+        let remote_port = self.add_connection(url).await?;
+        Ok(format!("{}:{}", &self.to, remote_port))
     }
-}
 
-impl From<&MetadataResponseBroker> for KafkaBroker {
-    fn from(broker: &MetadataResponseBroker) -> Self {
-        KafkaBroker::new(broker.host.to_string(), broker.port as u16)
+    /// Returns a snapshot of the tunnels, sorted by remote port.
+    pub fn tunnels(&self) -> Vec<TunnelInfo> {
+        let connections = self.connections.read().unwrap();
+        let mut tunnels: Vec<_> = connections
+            .iter()
+            .map(|(broker, tunnel)| TunnelInfo {
+                cluster: self.name.clone(),
+                node_id: tunnel.node_id,
+                local_address: broker.to_string(),
+                remote_port: tunnel.remote_port,
+                state: if tunnel.shutdown.is_cancelled() {
+                    "closed"
+                } else {
+                    "open"
+                },
+                age_secs: tunnel.opened_at.elapsed().as_secs(),
+            })
+            .collect();
+        tunnels.sort_by_key(|tunnel| tunnel.remote_port);
+        tunnels
     }
-}
 
-impl FromStr for KafkaBroker {
-    type Err = Error;
+    /// Returns a snapshot of the connections currently proxied, oldest first.
+    pub fn active_connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self
+            .active
+            .iter()
+            .map(|entry| ConnectionInfo {
+                cluster: self.name.clone(),
+                id: *entry.key(),
+                broker: entry.broker.to_string(),
+                peer: entry.peer,
+                age_secs: entry.started_at.elapsed().as_secs(),
+                bytes_received: entry.bytes_received.load(Ordering::Relaxed),
+                bytes_sent: entry.bytes_sent.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_by_key(|connection| Reverse(connection.age_secs));
+        connections
+    }
 
-    fn from_str(bootstrap_server: &str) -> Result<Self, Self::Err> {
-        let mut split = bootstrap_server.split(':');
-        let host = split.next().ok_or(Error::msg("no host provided"))?;
-        let port = split.next().ok_or(Error::msg("no port provided"))?;
-        let port = port.parse().context("invalid port")?;
-        Ok(KafkaBroker::new(host.to_string(), port))
+    /// Returns the traffic totals since the proxy started.
+    pub fn totals(&self) -> &TrafficTotals {
+        &self.totals
     }
-}
 
-/// State structure for the kafka proxy.
-pub struct KafkaProxy {
-    /// Destination address of the server.
-    pub to: String,
+    /// Returns the number of requests received from remote clients since the proxy started, by
+    /// API name, such as `Produce`.
+    pub fn request_counts(&self) -> BTreeMap<String, u64> {
+        self.requests
+            .iter()
+            .filter_map(|entry| {
+                let api_key = ApiKey::try_from(*entry.key()).ok()?;
+                let name = format!("{api_key:?}");
+                Some((
+                    name.strip_suffix("Key").unwrap_or(&name).to_string(),
+                    *entry.value(),
+                ))
+            })
+            .collect()
+    }
 
-    /// Optional secret used to authenticate clients.
-    pub auth: Option<Authenticator>,
+    /// Returns the local bootstrap server, once the proxy is started.
+    pub fn bootstrap_server(&self) -> Option<String> {
+        self.bootstrap
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|bootstrap| bootstrap.to_string())
+    }
 
-    /// mapping between local url and remote port
-    connections: RwLock<HashMap<KafkaBroker, u16>>,
-}
+    /// Returns the remote clients seen in proxied requests, most recently seen first.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.inventory.snapshot(&self.name)
+    }
 
-impl KafkaProxy {
-    /// Create a new kafka proxy.
-    pub fn new(to: &str, secret: Option<&str>) -> Self {
-        let auth = secret.map(Authenticator::new);
+    /// Returns when the cluster topology was last seen in a metadata response.
+    pub fn last_metadata_refresh(&self) -> Option<SystemTime> {
+        *self.last_metadata.read().unwrap()
+    }
 
-        Self {
-            to: to.to_string(),
-            auth,
-            connections: HashMap::new().into(),
-        }
+    /// Fetch the metadata from the bootstrap server, opening tunnels to new or closed brokers.
+    pub async fn refresh(self: &Arc<Self>) -> Result<()> {
+        let bootstrap = self
+            .bootstrap
+            .read()
+            .unwrap()
+            .clone()
+            .context("proxy not started")?;
+        let metadata = KafkaClient::connect(&bootstrap.to_string())
+            .await?
+            .metadata()
+            .await?;
+        self.check_cluster_id(metadata.cluster_id.as_deref())?;
+        self.open_new_broker_connection_if_needed(&metadata.brokers)
+            .await?;
+        self.record_topology(&metadata.brokers);
+        Ok(())
     }
 
-    /// Start the proxy, returning the remote bootstrap server to use for connecting.
-    pub async fn start(self, bootstrap_servers: &str) -> Result<String> {
-        let this = Arc::new(self);
-        let url = bootstrap_servers.parse().map_err(anyhow::Error::msg)?;
-        let remote_port = this.add_connection(url).await?;
-        Ok(format!("{}:{}", &this.to, remote_port))
+    /// Close the tunnel on a remote port along with its connections.
+    ///
+    /// The tunnel is opened again on the next metadata response that lists its broker.
+    pub fn close_tunnel(&self, remote_port: u16) -> bool {
+        let connections = self.connections.read().unwrap();
+        match connections
+            .values()
+            .find(|tunnel| tunnel.remote_port == remote_port)
+        {
+            Some(tunnel) => {
+                info!(remote_port, "closing tunnel");
+                tunnel.shutdown.cancel();
+                true
+            }
+            None => false,
+        }
     }
 
     /// proxy a connection
-    pub(crate) async fn kafka_proxy<S1, S2>(self: &Arc<Self>, local: S1, remote: S2) -> Result<()>
+    pub(crate) async fn kafka_proxy<S1, S2>(
+        self: &Arc<Self>,
+        id: Uuid,
+        broker: KafkaBroker,
+        peer: Option<SocketAddr>,
+        local: S1,
+        remote: S2,
+    ) -> Result<()>
     where
         S1: AsyncRead + AsyncWrite + Unpin,
         S2: AsyncRead + AsyncWrite + Unpin,
     {
+        let bytes_received = Arc::new(AtomicU64::new(0));
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let context = InjectionContext {
+            connection_id: id,
+            tunnel_port: self
+                .connections
+                .read()
+                .unwrap()
+                .get(&broker)
+                .map(|tunnel| tunnel.remote_port),
+            peer_ip: peer.map(|peer| peer.ip()),
+        };
+        self.totals.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.insert(
+            id,
+            ProxiedConnection {
+                broker,
+                peer,
+                started_at: Instant::now(),
+                bytes_received: Arc::clone(&bytes_received),
+                bytes_sent: Arc::clone(&bytes_sent),
+            },
+        );
+
+        // counted on the side of the remote client, once responses are rewritten
         let (local_read, local_write) = io::split(local);
-        let (remote_read, remote_write) = io::split(remote);
-        let codec = KafkaServerCodec::new();
+        let (remote_read, remote_write) = io::split(Counted::new(
+            remote,
+            Arc::clone(&bytes_received),
+            Arc::clone(&bytes_sent),
+        ));
+        let codec = KafkaServerCodec::new(
+            self.slow_log.clone(),
+            self.masking.is_some()
+                || self.encryption.is_some()
+                || self.accounting.is_some()
+                || self.lag.is_some(),
+            self.versions.is_some() || self.rewrites_bodies(),
+        );
+        let (responses, queue) = mpsc::unbounded_channel();
+
+        let _guard = ActiveConnectionGuard { proxy: self, id };
 
         tokio::select! {
-            res = Self::remote_to_local(remote_read, local_write, codec.clone()) => res,
-            res = self.local_to_remote(local_read, remote_write, codec) => res,
+            res = self.remote_to_local(remote_read, local_write, codec.clone(), responses, &context) => res,
+            res = self.local_to_remote(local_read, remote_write, codec, queue) => res,
         }
     }
 
     async fn remote_to_local<S1, S2>(
+        &self,
         remote_read: S1,
         mut local_write: S2,
         upstream_codec: KafkaServerCodec,
+        responses: mpsc::UnboundedSender<Queued>,
+        context: &InjectionContext,
     ) -> Result<()>
     where
         S1: AsyncRead + Unpin,
         S2: AsyncWrite + Unpin,
     {
-        let codec = LengthDelimitedCodec::builder()
-            .num_skip(0) // Do not strip frame header
-            .length_adjustment(4)
-            .new_codec();
-
+        let codec = RequestCodec::new(self.limits.clone());
         let mut source = codec::FramedRead::new(remote_read, codec);
+        let mut principal = self
+            .acl
+            .as_ref()
+            .and_then(|acl| acl.tunnel_principal())
+            .map(str::to_string);
 
-        while let Some(mut bytes) = source
+        while let Some(frame) = source
             .try_next()
             .await
             .context("reading from local Kafka")?
         {
-            let api_key = bytes.peek_bytes(4..6).get_i16();
-            debug!("api_key: {}", api_key);
-            if api_key == ApiKey::MetadataKey as i16 {
-                let api_version = bytes.peek_bytes(6..8).get_i16();
-                let correlation_id = bytes.peek_bytes(8..12).get_i32();
-                debug!("api_version: {}", api_version);
+            let mut bytes = match frame {
+                RequestFrame::Whole(bytes) => bytes.freeze(),
+                RequestFrame::Oversized(OversizedProduce {
+                    header,
+                    produce,
+                    message,
+                }) => {
+                    // counted like the produce requests that are read
+                    self.inventory.observe(
+                        header.client_id.as_deref(),
+                        context.peer_ip,
+                        Observation::default(),
+                    );
+                    *self.requests.entry(ApiKey::ProduceKey as i16).or_default() += 1;
+                    warn!(
+                        correlation_id = header.correlation_id,
+                        client_id = header.client_id.as_deref().unwrap_or(""),
+                        message,
+                        "rejecting oversized produce request"
+                    );
+                    if produce.acks != 0 {
+                        let response = too_large(
+                            header.correlation_id,
+                            header.request_api_version,
+                            produce,
+                            &message,
+                        );
+                        let _ = responses.send(Queued::Synthesized(response));
+                    }
+                    continue;
+                }
+            };
+            let mut expects_response = true;
+            let mut delay = None;
+            // like before requests were inspected, frames that cannot be parsed are forwarded
+            let parsed = match RequestKeyAndVersion::parse(&bytes) {
+                result::Result::Ok(Some(parsed)) => Some(parsed),
+                // unless a feature would let the request through unchecked
+                result::Result::Ok(None) if self.rewrites_bodies() => {
+                    warn!("unknown api key, closing the connection");
+                    return Ok(());
+                }
+                result::Result::Ok(None) => None,
+                Err(err) if self.rewrites_bodies() => {
+                    warn!(%err, "unable to decode request header, closing the connection");
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!(%err, "unable to decode request header, forwarding it as is");
+                    None
+                }
+            };
+            if let Some((correlation_id, mut request)) = parsed {
+                debug!("api_key: {:?}", request.api_key);
+                debug!("api_version: {}", request.api_version);
                 debug!("correlation_id: {}", correlation_id);
-
-                upstream_codec.inflight.insert(
-                    correlation_id,
-                    RequestKeyAndVersion {
-                        api_key: ApiKey::MetadataKey,
-                        api_version,
-                    },
+                self.observe_client(&request, &bytes, context.peer_ip);
+                *self.requests.entry(request.api_key as i16).or_default() += 1;
+                if let Some(versions) = &self.versions {
+                    if !versions.allows(request.api_key, request.api_version) {
+                        versions.reject(
+                            request.client_id.as_deref(),
+                            context.peer_ip,
+                            request.api_key,
+                            request.api_version,
+                        );
+                        if !request.expects_response {
+                            continue;
+                        }
+                        match unsupported_version(correlation_id, &request, &bytes, Some(versions))?
+                        {
+                            Some(response) => {
+                                let _ = responses.send(Queued::Synthesized(response));
+                                continue;
+                            }
+                            // like brokers, close connections whose request cannot be answered
+                            None => return Ok(()),
+                        }
+                    }
+                }
+                if self.rewrites_bodies() && !is_decodable(request.api_key, request.api_version) {
+                    warn!(
+                        correlation_id,
+                        client_id = request.client_id.as_deref().unwrap_or(""),
+                        api_key = ?request.api_key,
+                        api_version = request.api_version,
+                        "rejecting request of an unsupported version"
+                    );
+                    if !request.expects_response {
+                        continue;
+                    }
+                    // a response of an unknown version cannot be built, only ApiVersions is answered
+                    if request.api_key != ApiKey::ApiVersionsKey {
+                        return Ok(());
+                    }
+                    let policy = self.versions.as_deref();
+                    if let Some(response) =
+                        unsupported_version(correlation_id, &request, &bytes, policy)?
+                    {
+                        let _ = responses.send(Queued::Synthesized(response));
+                    }
+                    continue;
+                }
+                if let (Some(limits), ApiKey::ProduceKey) = (&self.limits, request.api_key) {
+                    let exceeded =
+                        Self::check_limits(limits, &bytes).context("decoding produce request")?;
+                    if let Some((message, produce)) = exceeded {
+                        warn!(
+                            correlation_id,
+                            client_id = request.client_id.as_deref().unwrap_or(""),
+                            message,
+                            "rejecting oversized produce request"
+                        );
+                        if request.expects_response {
+                            let response =
+                                too_large(correlation_id, request.api_version, produce, &message);
+                            let _ = responses.send(Queued::Synthesized(response));
+                        }
+                        continue;
+                    }
+                }
+                if let Some(acl) = &self.acl {
+                    match request.api_key {
+                        ApiKey::SaslHandshakeKey | ApiKey::SaslAuthenticateKey => {
+                            let response =
+                                authenticate(acl, &mut principal, correlation_id, &request, &bytes)
+                                    .context("decoding SASL request")?;
+                            let _ = responses.send(Queued::Synthesized(response));
+                            continue;
+                        }
+                        ApiKey::MetadataKey => {
+                            bytes = Self::check_metadata(
+                                acl,
+                                principal.as_deref(),
+                                &mut request,
+                                &bytes,
+                            )
+                            .context("decoding metadata request")?;
+                        }
+                        ApiKey::ListGroupsKey => {
+                            // like brokers, only the groups the principal may describe are listed
+                            request.group_access = Some(GroupAccess {
+                                authorizer: Arc::clone(acl),
+                                principal: principal.clone(),
+                            });
+                        }
+                        _ => {
+                            let denied = self
+                                .check_access(acl, principal.as_deref(), &request, &bytes)
+                                .context("decoding request to authorize")?;
+                            if let Some((resource, name)) = denied {
+                                warn!(
+                                    principal,
+                                    client_id = request.client_id.as_deref().unwrap_or(""),
+                                    api_key = ?request.api_key,
+                                    ?resource,
+                                    name,
+                                    "denying unauthorized request"
+                                );
+                                if !request.expects_response {
+                                    continue;
+                                }
+                                match unauthorized(correlation_id, &request, &bytes, resource)? {
+                                    Some(response) => {
+                                        let _ = responses.send(Queued::Synthesized(response));
+                                        continue;
+                                    }
+                                    // close connections whose denied request cannot be answered
+                                    None => return Ok(()),
+                                }
+                            }
+                        }
+                    }
+                }
+                if let Some(accounting) = &self.accounting {
+                    if request.api_key == ApiKey::ProduceKey
+                        && is_decodable(request.api_key, request.api_version)
+                    {
+                        Self::account_produce(accounting, &request, &bytes);
+                    }
+                }
+                if let Some(lag) = &self.lag {
+                    self.observe_consumer(lag, &request, &bytes);
+                }
+                if let Some(chaos) = &self.chaos {
+                    let fault = self.pick_fault(chaos, &request, &bytes);
+                    if let Some(fault) = fault {
+                        debug!(correlation_id, api_key = ?request.api_key, %fault, "injecting fault");
+                    }
+                    match fault {
+                        Some(Fault::Disconnect) => {
+                            warn!(correlation_id, api_key = ?request.api_key, "chaos: closing the connection");
+                            return Ok(());
+                        }
+                        Some(Fault::Latency(latency)) => delay = Some(latency),
+                        Some(Fault::Throttle(throttle)) => {
+                            delay = Some(throttle);
+                            request.fault = fault;
+                        }
+                        Some(Fault::Error(_)) => request.fault = fault,
+                        None => {}
+                    }
+                }
+                let creates_topics = matches!(
+                    request.api_key,
+                    ApiKey::CreateTopicsKey | ApiKey::CreatePartitionsKey
                 );
-            };
+                if let (Some(policy), true) = (&self.topic_policy, creates_topics) {
+                    let checked = Self::check_topics(policy, &mut request, &bytes)
+                        .context("decoding topic creation request")?;
+                    match checked {
+                        Some(frame) => bytes = frame,
+                        None => {
+                            let mut header = ResponseHeader::default();
+                            header.correlation_id = correlation_id;
+                            let version = request.api_version;
+                            let mut response = match request.api_key {
+                                ApiKey::CreateTopicsKey => {
+                                    KafkaResponse::CreateTopics(version, header, Box::default())
+                                }
+                                _ => {
+                                    KafkaResponse::CreatePartitions(version, header, Box::default())
+                                }
+                            };
+                            add_violations(&mut response, request.violations);
+                            let _ = responses.send(Queued::Synthesized(response));
+                            continue;
+                        }
+                    }
+                }
+                let checks_produce = self.schemas.is_some() || self.encryption.is_some();
+                if checks_produce && request.api_key == ApiKey::ProduceKey {
+                    let checked =
+                        check_produce(&bytes, |topic, records| self.check_records(topic, records))
+                            .context("decoding produce request")?;
+                    match checked {
+                        Checked::Forward(frame, rejections) => {
+                            if !rejections.is_empty() {
+                                warn!(correlation_id, "rejecting invalid records");
+                            }
+                            bytes = frame;
+                            request.rejections = rejections;
+                        }
+                        Checked::Rejected(rejections) => {
+                            warn!(correlation_id, "rejecting invalid records");
+                            if request.expects_response {
+                                let mut header = ResponseHeader::default();
+                                header.correlation_id = correlation_id;
+                                let mut response = ProduceResponse::default();
+                                add_rejections(&mut response, rejections);
+                                let response = KafkaResponse::Produce(
+                                    request.api_version,
+                                    header,
+                                    Box::new(response),
+                                );
+                                let _ = responses.send(Queued::Synthesized(response));
+                            }
+                            continue;
+                        }
+                    }
+                }
+                if let Some(rewrite) = &self.client_id {
+                    let client_id = rewrite.render(
+                        request.client_id.as_deref(),
+                        &self.name,
+                        principal.as_deref(),
+                        context,
+                    );
+                    bytes = rewrite_client_id(&bytes, &request, client_id)
+                        .context("rewriting client id")?;
+                }
+                // produce requests of unknown versions are forwarded as they are
+                if request.api_key == ApiKey::ProduceKey
+                    && is_decodable(request.api_key, request.api_version)
+                {
+                    if let Some(headers) = &self.headers {
+                        bytes = Self::inject_headers(headers, bytes, context);
+                    }
+                    if let Some(shadow) = &self.shadow {
+                        request.shadow = shadow.mirror(bytes.clone(), request.expects_response);
+                    }
+                }
+                expects_response = request.expects_response;
+                if expects_response {
+                    upstream_codec.inflight.insert(correlation_id, request);
+                }
+            }
+            if expects_response {
+                // queued before forwarding, so before the broker can answer
+                let deadline = delay.map(|delay| time::Instant::now() + delay);
+                let _ = responses.send(Queued::Forwarded(deadline));
+            }
             local_write
                 .write_all_buf(&mut bytes)
                 .await
@@ -234,64 +2544,783 @@ impl KafkaProxy {
         Ok(())
     }
 
+    /// Count the records of a produce request, as received from the remote client.
+    fn account_produce(
+        accounting: &TopicAccounting,
+        request: &RequestKeyAndVersion,
+        frame: &Bytes,
+    ) {
+        let (_, _, produce) = match decode_produce(frame) {
+            result::Result::Ok(produce) => produce,
+            Err(err) => {
+                debug!(%err, "unable to decode produce request, not counted");
+                return;
+            }
+        };
+        for (topic, data) in &produce.topic_data {
+            for partition in &data.partition_data {
+                if let Some(records) = &partition.records {
+                    accounting.record(
+                        Direction::Produced,
+                        request.client_id.as_deref(),
+                        topic,
+                        partition.index,
+                        records,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Count the records of a fetch response, as sent to the remote client.
+    fn account_fetch(
+        &self,
+        accounting: &TopicAccounting,
+        version: i16,
+        client_id: Option<&str>,
+        response: &FetchResponse,
+    ) {
+        for topic in &response.responses {
+            let name = self.fetch_topic_name(version, &topic.topic, topic.topic_id);
+            for partition in &topic.partitions {
+                if let Some(records) = &partition.records {
+                    accounting.record(
+                        Direction::Fetched,
+                        client_id,
+                        &name,
+                        partition.partition_index,
+                        records,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Record a request in the inventory of remote clients.
+    fn observe_client(
+        &self,
+        request: &RequestKeyAndVersion,
+        frame: &Bytes,
+        peer_ip: Option<IpAddr>,
+    ) {
+        if !is_decodable(request.api_key, request.api_version) {
+            self.inventory.observe(
+                request.client_id.as_deref(),
+                peer_ip,
+                Observation::default(),
+            );
+            return;
+        }
+        // the client library is only sent from version 3
+        let versions = (request.api_key == ApiKey::ApiVersionsKey && request.api_version >= 3)
+            .then(|| decode_request::<ApiVersionsRequest>(frame, request).ok())
+            .flatten();
+        let join = (request.api_key == ApiKey::JoinGroupKey)
+            .then(|| decode_request::<JoinGroupRequest>(frame, request).ok())
+            .flatten();
+        let observation = Observation {
+            software_name: versions.as_ref().map(|v| &*v.client_software_name),
+            software_version: versions.as_ref().map(|v| &*v.client_software_version),
+            group_id: join.as_ref().map(|join| &**join.group_id),
+        };
+        self.inventory
+            .observe(request.client_id.as_deref(), peer_ip, observation);
+    }
+
+    /// Check the topics of a topic creation request against the policy, keeping the violations
+    /// in the request.
+    ///
+    /// Returns the request to forward, without the rejected topics, or `None` if every topic was
+    /// rejected.
+    fn check_topics(
+        policy: &TopicPolicy,
+        request: &mut RequestKeyAndVersion,
+        frame: &Bytes,
+    ) -> Result<Option<Bytes>> {
+        let mut violations = vec![];
+        let mut keep = |topic: &TopicName, checked: result::Result<(), String>| match checked {
+            result::Result::Ok(()) => true,
+            Err(message) => {
+                violations.push((topic.clone(), message));
+                false
+            }
+        };
+        let (api_key, api_version) = (request.api_key, request.api_version);
+        let forwarded = if api_key == ApiKey::CreateTopicsKey {
+            let (header, mut create) =
+                decode_request_with_header::<CreateTopicsRequest>(frame, request)?;
+            create
+                .topics
+                .retain(|name, topic| keep(name, policy.check_topic(name, topic)));
+            if create.topics.is_empty() {
+                None
+            } else {
+                Some(encode_request(api_key, api_version, &header, &create)?)
+            }
+        } else {
+            let (header, mut create) =
+                decode_request_with_header::<CreatePartitionsRequest>(frame, request)?;
+            create
+                .topics
+                .retain(|name, topic| keep(name, policy.check_partitions(name, topic.count)));
+            if create.topics.is_empty() {
+                None
+            } else {
+                Some(encode_request(api_key, api_version, &header, &create)?)
+            }
+        };
+        if violations.is_empty() {
+            return Ok(Some(frame.clone()));
+        }
+        for (topic, message) in &violations {
+            warn!(
+                client_id = request.client_id.as_deref().unwrap_or(""),
+                topic = &***topic,
+                message,
+                "rejecting topic violating the policy"
+            );
+        }
+        request.violations = violations;
+        Ok(forwarded)
+    }
+
+    /// Check the batches of a produce request against the size limits, returning the first
+    /// exceeded one with the decoded request, to answer it.
+    ///
+    /// The size of the request itself was checked by [`RequestCodec`] before reading it, and the
+    /// request is only decoded when batches are limited.
+    fn check_limits(
+        limits: &ProduceLimits,
+        frame: &Bytes,
+    ) -> Result<Option<(String, ProduceRequest)>> {
+        if limits.max_batch_bytes.is_none() && limits.max_batch_records.is_none() {
+            return Ok(None);
+        }
+        let (_, _, produce) = decode_produce(frame)?;
+        let exceeded = produce.topic_data.iter().find_map(|(topic, data)| {
+            data.partition_data.iter().find_map(|partition| {
+                let records = partition.records.as_ref()?;
+                let message = limits.check_records(records).err()?;
+                Some(format!("{}-{}: {message}", &**topic, partition.index))
+            })
+        });
+        Ok(exceeded.map(|message| (message, produce)))
+    }
+
+    /// Remove the topics a principal may not describe from a metadata request, and have them
+    /// hidden from its response.
+    fn check_metadata(
+        authorizer: &Arc<Authorizer>,
+        principal: Option<&str>,
+        request: &mut RequestKeyAndVersion,
+        frame: &Bytes,
+    ) -> Result<Bytes> {
+        let (header, mut metadata) = decode_request_with_header::<MetadataRequest>(frame, request)?;
+        let mut denied = vec![];
+        if let Some(topics) = &mut metadata.topics {
+            topics.retain(|topic| match &topic.name {
+                Some(name)
+                    if !authorizer.allows(
+                        principal,
+                        ResourceType::Topic,
+                        name,
+                        Operation::Describe,
+                    ) =>
+                {
+                    denied.push(name.clone());
+                    false
+                }
+                _ => true,
+            });
+        }
+        let forwarded = if denied.is_empty() {
+            frame.clone()
+        } else {
+            encode_request(request.api_key, request.api_version, &header, &metadata)?
+        };
+        request.topic_access = Some(TopicAccess {
+            authorizer: Arc::clone(authorizer),
+            principal: principal.map(str::to_string),
+            denied,
+        });
+        Ok(forwarded)
+    }
+
+    /// Check a request against the authorization rules, returning the first resource denied to
+    /// the principal, if any.
+    ///
+    /// Idempotent producers need no more than writing to their topics, but transactional ones
+    /// need to write to their transactional id. Requests not bound to topics, groups or
+    /// transactional ids need to describe the cluster for those only reading it, and to alter it
+    /// otherwise, including the APIs between brokers and controllers.
+    fn check_access(
+        &self,
+        authorizer: &Authorizer,
+        principal: Option<&str>,
+        request: &RequestKeyAndVersion,
+        frame: &Bytes,
+    ) -> Result<Option<(ResourceType, String)>> {
+        use self::Operation::*;
+        use self::ResourceType::*;
+
+        let mut checks: Vec<(ResourceType, String, Operation)> = vec![];
+        match request.api_key {
+            ApiKey::ApiVersionsKey => {}
+            ApiKey::ProduceKey => {
+                let (_, _, produce) = decode_produce(frame)?;
+                let names = produce.topic_data.keys().map(|name| name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Write)));
+            }
+            ApiKey::FetchKey => {
+                let fetch = decode_request::<FetchRequest>(frame, request)?;
+                let names = fetch.topics.iter().map(|topic| {
+                    self.fetch_topic_name(request.api_version, &topic.topic, topic.topic_id)
+                });
+                checks.extend(names.map(|name| (Topic, name, Read)));
+            }
+            ApiKey::ListOffsetsKey => {
+                let list = decode_request::<ListOffsetsRequest>(frame, request)?;
+                let names = list.topics.iter().map(|topic| topic.name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Describe)));
+            }
+            ApiKey::OffsetForLeaderEpochKey => {
+                let epochs = decode_request::<OffsetForLeaderEpochRequest>(frame, request)?;
+                let names = epochs.topics.keys().map(|name| name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Describe)));
+            }
+            ApiKey::CreateTopicsKey => {
+                let create = decode_request::<CreateTopicsRequest>(frame, request)?;
+                let names = create.topics.keys().map(|name| name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Create)));
+            }
+            ApiKey::CreatePartitionsKey => {
+                let create = decode_request::<CreatePartitionsRequest>(frame, request)?;
+                let names = create.topics.keys().map(|name| name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Alter)));
+            }
+            ApiKey::DeleteTopicsKey => {
+                let delete = decode_request::<DeleteTopicsRequest>(frame, request)?;
+                let names = delete
+                    .topic_names
+                    .iter()
+                    .map(|name| name.to_string())
+                    .chain(delete.topics.iter().map(|topic| match &topic.name {
+                        Some(name) => name.to_string(),
+                        None => self.topic_name(topic.topic_id),
+                    }));
+                checks.extend(names.map(|name| (Topic, name, Delete)));
+            }
+            ApiKey::OffsetCommitKey => {
+                let commit = decode_request::<OffsetCommitRequest>(frame, request)?;
+                checks.push((Group, commit.group_id.to_string(), Read));
+                let names = commit.topics.iter().map(|topic| topic.name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Read)));
+            }
+            ApiKey::OffsetFetchKey => {
+                let fetch = decode_request::<OffsetFetchRequest>(frame, request)?;
+                let mut groups = vec![];
+                if request.api_version < 8 {
+                    let names = fetch.topics.into_iter().flatten().map(|topic| topic.name);
+                    groups.push((fetch.group_id, names.collect::<Vec<_>>()));
+                }
+                for group in fetch.groups {
+                    let names = group.topics.into_iter().flatten().map(|topic| topic.name);
+                    groups.push((group.group_id, names.collect()));
+                }
+                for (group_id, names) in groups {
+                    checks.push((Group, group_id.to_string(), Describe));
+                    checks.extend(names.iter().map(|name| (Topic, name.to_string(), Describe)));
+                }
+            }
+            ApiKey::FindCoordinatorKey => {
+                let find = decode_request::<FindCoordinatorRequest>(frame, request)?;
+                // finding a coordinator describes its group or transaction, or else the cluster
+                let resource = match find.key_type {
+                    0 => Group,
+                    1 => TransactionalId,
+                    _ => Cluster,
+                };
+                let keys = Some(find.key)
+                    .filter(|_| request.api_version < 4)
+                    .into_iter()
+                    .chain(find.coordinator_keys);
+                checks.extend(keys.map(|key| match resource {
+                    Cluster => (Cluster, CLUSTER_RESOURCE.to_string(), Describe),
+                    _ => (resource, key.to_string(), Describe),
+                }));
+            }
+            ApiKey::JoinGroupKey => {
+                let join = decode_request::<JoinGroupRequest>(frame, request)?;
+                checks.push((Group, join.group_id.to_string(), Read));
+            }
+            ApiKey::SyncGroupKey => {
+                let sync = decode_request::<SyncGroupRequest>(frame, request)?;
+                checks.push((Group, sync.group_id.to_string(), Read));
+            }
+            ApiKey::HeartbeatKey => {
+                let heartbeat = decode_request::<HeartbeatRequest>(frame, request)?;
+                checks.push((Group, heartbeat.group_id.to_string(), Read));
+            }
+            ApiKey::LeaveGroupKey => {
+                let leave = decode_request::<LeaveGroupRequest>(frame, request)?;
+                checks.push((Group, leave.group_id.to_string(), Read));
+            }
+            ApiKey::DescribeGroupsKey => {
+                let describe = decode_request::<DescribeGroupsRequest>(frame, request)?;
+                let groups = describe.groups.iter().map(|group| group.to_string());
+                checks.extend(groups.map(|group| (Group, group, Describe)));
+            }
+            ApiKey::DeleteGroupsKey => {
+                let delete = decode_request::<DeleteGroupsRequest>(frame, request)?;
+                let groups = delete.groups_names.iter().map(|group| group.to_string());
+                checks.extend(groups.map(|group| (Group, group, Delete)));
+            }
+            ApiKey::OffsetDeleteKey => {
+                let delete = decode_request::<OffsetDeleteRequest>(frame, request)?;
+                checks.push((Group, delete.group_id.to_string(), Delete));
+                let names = delete.topics.keys().map(|name| name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Read)));
+            }
+            ApiKey::DeleteRecordsKey => {
+                let delete = decode_request::<DeleteRecordsRequest>(frame, request)?;
+                let names = delete.topics.iter().map(|topic| topic.name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Delete)));
+            }
+            ApiKey::DescribeProducersKey => {
+                let describe = decode_request::<DescribeProducersRequest>(frame, request)?;
+                let names = describe.topics.iter().map(|topic| topic.name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Read)));
+            }
+            ApiKey::InitProducerIdKey => {
+                let init = decode_request::<InitProducerIdRequest>(frame, request)?;
+                if let Some(transactional_id) = init.transactional_id {
+                    checks.push((TransactionalId, transactional_id.to_string(), Write));
+                }
+            }
+            ApiKey::AddPartitionsToTxnKey => {
+                let add = decode_request::<AddPartitionsToTxnRequest>(frame, request)?;
+                checks.push((TransactionalId, add.transactional_id.to_string(), Write));
+                let names = add.topics.keys().map(|name| name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Write)));
+            }
+            ApiKey::AddOffsetsToTxnKey => {
+                let add = decode_request::<AddOffsetsToTxnRequest>(frame, request)?;
+                checks.push((TransactionalId, add.transactional_id.to_string(), Write));
+                checks.push((Group, add.group_id.to_string(), Read));
+            }
+            ApiKey::TxnOffsetCommitKey => {
+                let commit = decode_request::<TxnOffsetCommitRequest>(frame, request)?;
+                checks.push((TransactionalId, commit.transactional_id.to_string(), Write));
+                checks.push((Group, commit.group_id.to_string(), Read));
+                let names = commit.topics.iter().map(|topic| topic.name.to_string());
+                checks.extend(names.map(|name| (Topic, name, Read)));
+            }
+            ApiKey::EndTxnKey => {
+                let end = decode_request::<EndTxnRequest>(frame, request)?;
+                checks.push((TransactionalId, end.transactional_id.to_string(), Write));
+            }
+            ApiKey::DescribeTransactionsKey => {
+                let describe = decode_request::<DescribeTransactionsRequest>(frame, request)?;
+                let ids = describe.transactional_ids.iter().map(|id| id.to_string());
+                checks.extend(ids.map(|id| (TransactionalId, id, Describe)));
+            }
+            ApiKey::DescribeConfigsKey => {
+                let describe = decode_request::<DescribeConfigsRequest>(frame, request)?;
+                let resources = describe.resources.iter().map(|resource| {
+                    config_resource(resource.resource_type, &resource.resource_name)
+                });
+                checks.extend(resources.map(|(resource, name)| (resource, name, Describe)));
+            }
+            ApiKey::AlterConfigsKey => {
+                let alter = decode_request::<AlterConfigsRequest>(frame, request)?;
+                let resources = alter.resources.iter().map(|resource| {
+                    config_resource(resource.resource_type, &resource.resource_name)
+                });
+                checks.extend(resources.map(|(resource, name)| (resource, name, Alter)));
+            }
+            ApiKey::IncrementalAlterConfigsKey => {
+                let alter = decode_request::<IncrementalAlterConfigsRequest>(frame, request)?;
+                let resources = alter.resources.iter().map(|resource| {
+                    config_resource(resource.resource_type, &resource.resource_name)
+                });
+                checks.extend(resources.map(|(resource, name)| (resource, name, Alter)));
+            }
+            ApiKey::DescribeClusterKey
+            | ApiKey::DescribeAclsKey
+            | ApiKey::DescribeLogDirsKey
+            | ApiKey::DescribeDelegationTokenKey
+            | ApiKey::DescribeClientQuotasKey
+            | ApiKey::DescribeUserScramCredentialsKey
+            | ApiKey::DescribeQuorumKey
+            | ApiKey::ListPartitionReassignmentsKey
+            | ApiKey::ListTransactionsKey => {
+                checks.push((Cluster, CLUSTER_RESOURCE.to_string(), Describe));
+            }
+            _ => checks.push((Cluster, CLUSTER_RESOURCE.to_string(), Alter)),
+        }
+        Ok(checks
+            .into_iter()
+            .find(|(resource, name, operation)| {
+                !authorizer.allows(principal, *resource, name, *operation)
+            })
+            .map(|(resource, name, _)| (resource, name)))
+    }
+
+    /// Draw the fault to inject in a request, reading its topics only if a rule needs them.
+    fn pick_fault(
+        &self,
+        chaos: &Chaos,
+        request: &RequestKeyAndVersion,
+        frame: &Bytes,
+    ) -> Option<Fault> {
+        let mut topics = vec![];
+        if chaos.matches_topics(request.api_key) {
+            let decoded = match request.api_key {
+                ApiKey::ProduceKey => decode_produce(frame).map(|(_, _, produce)| {
+                    topics = produce
+                        .topic_data
+                        .keys()
+                        .map(|name| name.to_string())
+                        .collect();
+                }),
+                ApiKey::FetchKey => decode_request::<FetchRequest>(frame, request).map(|fetch| {
+                    topics = fetch
+                        .topics
+                        .iter()
+                        .map(|topic| {
+                            self.fetch_topic_name(request.api_version, &topic.topic, topic.topic_id)
+                        })
+                        .collect();
+                }),
+                _ => Ok(()),
+            };
+            if let Err(err) = decoded {
+                debug!(api_key = ?request.api_key, %err, "unable to decode request topics");
+            }
+        }
+        chaos.pick(request.api_key, &topics)
+    }
+
+    /// Learn the fetch offsets and consumer group of a remote consumer from its requests.
+    fn observe_consumer(&self, lag: &LagObserver, request: &RequestKeyAndVersion, frame: &Bytes) {
+        // from Fetch v15, the replica id moved out of the fields known to kafka-protocol
+        if !is_decodable(request.api_key, request.api_version) {
+            return;
+        }
+        let client_id = request.client_id.as_deref();
+        let observed = match request.api_key {
+            ApiKey::FetchKey => decode_request::<FetchRequest>(frame, request).map(|fetch| {
+                // followers fetching from the leader are not consumers
+                if fetch.replica_id.0 >= 0 {
+                    return;
+                }
+                for topic in &fetch.topics {
+                    let name =
+                        self.fetch_topic_name(request.api_version, &topic.topic, topic.topic_id);
+                    for partition in &topic.partitions {
+                        lag.observe_fetch(
+                            client_id,
+                            &name,
+                            partition.partition,
+                            partition.fetch_offset,
+                        );
+                    }
+                }
+            }),
+            ApiKey::JoinGroupKey => decode_request::<JoinGroupRequest>(frame, request)
+                .map(|join| lag.observe_group(client_id, &join.group_id)),
+            ApiKey::HeartbeatKey => decode_request::<HeartbeatRequest>(frame, request)
+                .map(|heartbeat| lag.observe_group(client_id, &heartbeat.group_id)),
+            ApiKey::OffsetCommitKey => decode_request::<OffsetCommitRequest>(frame, request)
+                .map(|commit| lag.observe_group(client_id, &commit.group_id)),
+            _ => return,
+        };
+        if let Err(err) = observed {
+            debug!(api_key = ?request.api_key, %err, "unable to decode request, lag not observed");
+        }
+    }
+
+    /// Returns the name of a topic of a fetch request or response, resolving the ids of recent
+    /// versions. Unknown ids are returned as is.
+    fn fetch_topic_name(&self, version: i16, name: &TopicName, id: Uuid) -> String {
+        if version >= 13 {
+            self.topic_name(id)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Returns the name of a topic by id, as seen in metadata responses. Unknown ids are
+    /// returned as is.
+    fn topic_name(&self, id: Uuid) -> String {
+        self.topic_names
+            .read()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| id.to_string())
+    }
+
+    /// Decrypt the records produced to a topic partition, then validate them.
+    fn check_records(&self, topic: &str, records: &Bytes) -> result::Result<Bytes, InvalidRecord> {
+        let records = match &self.encryption {
+            Some(encryption) => encryption.decrypt_records(records)?,
+            None => records.clone(),
+        };
+        if let Some(schemas) = &self.schemas {
+            schemas.validate_records(topic, &records)?;
+        }
+        result::Result::Ok(records)
+    }
+
+    /// Add headers to the records of a produce request, forwarding it unchanged on failure.
+    fn inject_headers(
+        headers: &HeaderInjection,
+        frame: Bytes,
+        context: &InjectionContext,
+    ) -> Bytes {
+        let rewritten = rewrite_produce(&frame, |topic, records| {
+            Ok(headers
+                .inject_records(records, context)
+                .unwrap_or_else(|err| {
+                    warn!(topic = %topic.0, %err, "unable to inject headers, forwarding records as is");
+                    records.clone()
+                }))
+        });
+        rewritten.unwrap_or_else(|err| {
+            warn!(%err, "unable to decode produce request, forwarding it as is");
+            frame
+        })
+    }
+
     async fn local_to_remote<S1, S2>(
         self: &Arc<Self>,
         local_read: S1,
         remote_write: S2,
         codec: KafkaServerCodec,
+        mut queue: mpsc::UnboundedReceiver<Queued>,
     ) -> Result<()>
     where
         S1: AsyncRead + Unpin,
         S2: AsyncWrite + Unpin,
     {
-        let source = codec::FramedRead::new(local_read, codec);
-        let sink = codec::FramedWrite::new(remote_write, KafkaServerCodec::new());
-
-        source
-            .then(|item| {
-                let this = Arc::clone(self);
-                async move {
-                    match item {
-                        result::Result::Ok(KafkaResponse::Metadata(version, header, response)) => {
-                            Ok(KafkaResponse::Metadata(
-                                version,
-                                header,
-                                this.adapt_metadata(response)
-                                    .await
-                                    .context("rewriting metadata response")?,
-                            ))
+        let mut source = codec::FramedRead::new(local_read, codec);
+        let mut sink =
+            codec::FramedWrite::new(remote_write, KafkaServerCodec::new(None, false, false));
+        let mut pending = VecDeque::new();
+
+        loop {
+            tokio::select! {
+                response = source.next() => {
+                    let response = match response {
+                        Some(response) => response.context("decoding kafka request")?,
+                        None => return Ok(()),
+                    };
+                    // the request was queued before being forwarded, hence before this response
+                    while let result::Result::Ok(queued) = queue.try_recv() {
+                        pending.push_back(queued);
+                    }
+                    // requests rejected before the one answered come first
+                    feed_synthesized(&mut sink, &mut pending).await?;
+                    let Some(Queued::Forwarded(deadline)) = pending.pop_front() else {
+                        bail!("response from the broker without a forwarded request");
+                    };
+                    let response = self.adapt_response(response).await?;
+                    if let Some(deadline) = deadline {
+                        // the responses before the delayed one are not held back
+                        sink.flush()
+                            .await
+                            .context("reading/writing to remote server")?;
+                        time::sleep_until(deadline).await;
+                    }
+                    sink.feed(response)
+                        .await
+                        .context("reading/writing to remote server")?;
+                }
+                Some(queued) = queue.recv() => pending.push_back(queued),
+            }
+
+            // answer requests rejected by the proxy once the broker answered the previous ones
+            feed_synthesized(&mut sink, &mut pending).await?;
+            sink.flush()
+                .await
+                .context("reading/writing to remote server")?;
+        }
+    }
+
+    /// Rewrite a response from the broker before it is sent to the remote client.
+    async fn adapt_response(self: &Arc<Self>, response: KafkaResponse) -> Result<KafkaResponse> {
+        match response {
+            KafkaResponse::ApiVersions(version, header, mut response) => {
+                if let Some(versions) = &self.versions {
+                    versions.trim(&mut response);
+                }
+                if self.rewrites_bodies() {
+                    clamp_to_decodable(&mut response);
+                }
+                Ok(KafkaResponse::ApiVersions(version, header, response))
+            }
+            KafkaResponse::Metadata(version, header, response) => Ok(KafkaResponse::Metadata(
+                version,
+                header,
+                Box::new(
+                    self.adapt_metadata(version, *response)
+                        .await
+                        .context("rewriting metadata response")?,
+                ),
+            )),
+            KafkaResponse::Fetch(version, header, mut response, client_id) => {
+                self.protect_fetch(version, &mut response);
+                if let Some(accounting) = &self.accounting {
+                    self.account_fetch(accounting, version, client_id.as_deref(), &response);
+                }
+                if let Some(lag) = &self.lag {
+                    for topic in &response.responses {
+                        let name = self.fetch_topic_name(version, &topic.topic, topic.topic_id);
+                        for partition in &topic.partitions {
+                            lag.observe_high_watermark(
+                                client_id.as_deref(),
+                                &name,
+                                partition.partition_index,
+                                partition.high_watermark,
+                            );
                         }
-                        other => other.context("decoding kafka request"),
                     }
                 }
-            })
-            .forward(sink)
-            .await
-            .context("reading/writing to remote server")?;
-        Ok(())
+                Ok(KafkaResponse::Fetch(version, header, response, client_id))
+            }
+            other => Ok(other),
+        }
     }
 
     async fn adapt_metadata(
         self: &Arc<Self>,
+        version: i16,
         mut metadata: MetadataResponse,
     ) -> Result<MetadataResponse> {
+        // versions before 2 do not carry the cluster id
+        if version >= 2 {
+            self.check_cluster_id(metadata.cluster_id.as_deref())?;
+        }
         self.open_new_broker_connection_if_needed(&metadata.brokers)
             .await?;
+        self.record_topology(&metadata.brokers);
+        self.record_topic_ids(&metadata.topics);
 
         let connections = self.connections.read().unwrap();
         for broker in metadata.brokers.values_mut() {
             debug!("broker: {:?}", broker);
             let url = KafkaBroker::new(broker.host.to_string(), broker.port as u16);
-            broker.host = unsafe { StrBytes::from_utf8_unchecked(self.to.clone().into()) }; // self.to is a String so it's safe, but the api is lacking this conversion.
-            broker.port = *connections
+            broker.host = str_bytes(self.to.clone());
+            broker.port = connections
                 .get(&url)
                 .ok_or(Error::msg("unable to forward broker connection"))?
-                as i32;
+                .remote_port as i32;
         }
         Ok(metadata)
     }
 
-    /// Open a new connection to a broker if needed (if the broker is not already in the ref list)
+    /// Check the id of the cluster that answered, closing every tunnel if it is not the expected one.
+    fn check_cluster_id(&self, cluster_id: Option<&str>) -> Result<()> {
+        let expected = match &self.expected_cluster_id {
+            Some(expected) if cluster_id != Some(expected.as_str()) => expected,
+            _ => return Ok(()),
+        };
+        let found = cluster_id.unwrap_or("none");
+        error!(cluster = %self.name, %expected, %found, "unexpected cluster id, closing every tunnel");
+        for tunnel in self.connections.read().unwrap().values() {
+            tunnel.shutdown.cancel();
+        }
+        bail!(
+            "cluster {} has id {found} instead of the expected {expected}",
+            self.name
+        )
+    }
+
+    /// Remember the node ids of the brokers and when they were last seen.
+    fn record_topology(&self, brokers: &IndexMap<BrokerId, MetadataResponseBroker>) {
+        let mut connections = self.connections.write().unwrap();
+        for (node_id, broker) in brokers {
+            if let Some(tunnel) = connections.get_mut(&KafkaBroker::from(broker)) {
+                tunnel.node_id = Some(node_id.0);
+            }
+        }
+        *self.last_metadata.write().unwrap() = Some(SystemTime::now());
+    }
+
+    /// Remember the names of topics by id, for fetch versions that only send topic ids.
+    fn record_topic_ids(&self, topics: &IndexMap<TopicName, MetadataResponseTopic>) {
+        let mut topic_names = self.topic_names.write().unwrap();
+        for (name, topic) in topics {
+            if !topic.topic_id.is_nil() {
+                topic_names.insert(topic.topic_id, name.to_string());
+            }
+        }
+    }
+
+    /// Mask, then encrypt, the records of a fetch response in place.
+    ///
+    /// Both fail closed: partitions that cannot be protected are answered with an error and
+    /// without records, and topics whose id is unknown to masking with `UNKNOWN_TOPIC_ID` so
+    /// that the client refreshes its metadata through the proxy.
+    fn protect_fetch(&self, version: i16, response: &mut FetchResponse) {
+        if self.masking.is_none() && self.encryption.is_none() {
+            return;
+        }
+        let topic_names = self.topic_names.read().unwrap();
+        for topic in &mut response.responses {
+            let name = if version >= 13 {
+                topic_names.get(&topic.topic_id).cloned()
+            } else {
+                Some(topic.topic.to_string())
+            };
+            let masking = match (&self.masking, &name) {
+                (Some(masking), Some(name)) if masking.applies_to(name) => Some(masking),
+                (Some(_), None) => {
+                    warn!(topic_id = %topic.topic_id, "unknown topic id, withholding records");
+                    for partition in &mut topic.partitions {
+                        withhold_records(partition, ResponseError::UnknownTopicId);
+                    }
+                    continue;
+                }
+                _ => None,
+            };
+            if masking.is_none() && self.encryption.is_none() {
+                continue;
+            }
+            let name = name.unwrap_or_default();
+            for partition in &mut topic.partitions {
+                let records = match &partition.records {
+                    Some(records) if !records.is_empty() => records,
+                    _ => continue,
+                };
+                let protected = masking
+                    .map_or_else(
+                        || Ok(records.clone()),
+                        |masking| masking.mask_records(&name, records),
+                    )
+                    .and_then(|records| match &self.encryption {
+                        Some(encryption) => encryption.encrypt_records(&records),
+                        None => Ok(records),
+                    });
+                match protected {
+                    result::Result::Ok(records) => partition.records = Some(records),
+                    Err(err) => {
+                        warn!(
+                            topic = %name,
+                            partition = partition.partition_index,
+                            %err,
+                            "unable to mask or encrypt records, withholding them"
+                        );
+                        withhold_records(partition, ResponseError::UnknownServerError);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Open a new connection to a broker if needed (if the broker is not already in the ref list,
+    /// or if its tunnel was closed)
     async fn open_new_broker_connection_if_needed(
         self: &Arc<Self>,
         brokers: &IndexMap<BrokerId, MetadataResponseBroker>,
@@ -302,8 +3331,9 @@ impl KafkaProxy {
             let connections = self.connections.read().unwrap();
             for broker in brokers.values() {
                 let local_url = KafkaBroker::from(broker);
-                if !connections.contains_key(&local_url) {
-                    unknown_brokers.push(local_url);
+                match connections.get(&local_url) {
+                    Some(tunnel) if !tunnel.shutdown.is_cancelled() => (),
+                    _ => unknown_brokers.push(local_url),
                 }
             }
         }
@@ -317,19 +3347,48 @@ impl KafkaProxy {
         Ok(())
     }
 
-    /// Add open a new connection to the bore server (because a new broker was detected)
+    /// Open a new tunnel through the control connection (because a new broker was detected)
     async fn add_connection(self: &Arc<Self>, url: KafkaBroker) -> Result<u16> {
-        let client = Client::new(&url.host, url.port, Arc::clone(self))
-            .await
-            .context("creating client")?;
+        let service = Arc::new(BrokerService {
+            proxy: Arc::clone(self),
+            broker: url.clone(),
+        });
+        let opened = self.control.open(service).await.context("opening tunnel")?;
 
-        let remote_port = client.remote_port();
-        self.connections.write().unwrap().insert(url, remote_port);
+        let tunnel = Tunnel {
+            remote_port: opened.remote_port,
+            node_id: None,
+            opened_at: Instant::now(),
+            shutdown: opened.shutdown,
+        };
+        if let Some(previous) = self.connections.write().unwrap().insert(url, tunnel) {
+            previous.shutdown.cancel();
+        }
+        Ok(opened.remote_port)
+    }
+}
 
-        tokio::spawn(
-            // Process each socket concurrently.
-            client.listen_boxed(),
-        );
-        Ok(remote_port)
+/// The connections of the tunnel to a broker.
+struct BrokerService {
+    proxy: Arc<KafkaProxy>,
+    broker: KafkaBroker,
+}
+
+impl Service for BrokerService {
+    fn proxy(self: Arc<Self>, connection: ForwardedConnection) -> BoxFuture<'static, Result<()>> {
+        async move {
+            let mut local = connect_with_timeout(&self.broker.host, self.broker.port).await?;
+            local.write_all(&connection.buffered).await?;
+            self.proxy
+                .kafka_proxy(
+                    connection.id,
+                    self.broker.clone(),
+                    connection.peer,
+                    local,
+                    connection.remote,
+                )
+                .await
+        }
+        .boxed()
     }
 }
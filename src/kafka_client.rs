@@ -0,0 +1,165 @@
+//! Minimal Kafka client, used to talk to brokers outside of proxied connections.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::metadata_request::MetadataRequestTopic;
+use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+use kafka_protocol::messages::{
+    ApiVersionsRequest, ApiVersionsResponse, MetadataRequest, MetadataResponse, ProduceRequest,
+    RequestHeader, ResponseHeader, TopicName,
+};
+use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion, Request, StrBytes};
+use kafka_protocol::ResponseError;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::kafka::str_bytes;
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Client id sent in the header of every request.
+pub const CLIENT_ID: &str = "conduktor-kafka-proxy";
+
+/// Version of the ApiVersions requests sent by the client.
+pub const API_VERSIONS_VERSION: i16 = 2;
+
+/// Version of the Metadata requests sent by the client.
+pub const METADATA_VERSION: i16 = 4;
+
+/// Version of the Produce requests sent by the client, the first one with record batches.
+pub const PRODUCE_VERSION: i16 = 3;
+
+/// Timeout for a broker to answer a request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A single connection to a Kafka broker, sending one request at a time.
+pub struct KafkaClient {
+    /// Length-delimited frames exchanged with the broker.
+    stream: Framed<TcpStream, LengthDelimitedCodec>,
+
+    /// Correlation id of the next request.
+    correlation_id: i32,
+}
+
+impl KafkaClient {
+    /// Connect to a broker given as `host:port`.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let stream = match timeout(NETWORK_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        }
+        .with_context(|| format!("could not connect to {addr}"))?;
+        Ok(Self {
+            stream: Framed::new(stream, LengthDelimitedCodec::new()),
+            correlation_id: 0,
+        })
+    }
+
+    /// Send a request and wait for its response.
+    pub async fn send<R: Request>(&mut self, request: &R, api_version: i16) -> Result<R::Response> {
+        let correlation_id = self.correlation_id;
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+
+        let mut header = RequestHeader::default();
+        header.request_api_key = R::KEY;
+        header.request_api_version = api_version;
+        header.correlation_id = correlation_id;
+        header.client_id = Some(StrBytes::from_str(CLIENT_ID));
+
+        let mut bytes = BytesMut::new();
+        header.encode(&mut bytes, R::header_version(api_version))?;
+        request.encode(&mut bytes, api_version)?;
+        self.stream
+            .send(bytes.freeze())
+            .await
+            .context("sending request")?;
+
+        let mut frame = timeout(REQUEST_TIMEOUT, self.stream.next())
+            .await
+            .context("timed out waiting for response")?
+            .context("connection closed by broker")?
+            .context("reading response")?;
+        let header = ResponseHeader::decode(&mut frame, R::Response::header_version(api_version))?;
+        ensure!(
+            header.correlation_id == correlation_id,
+            "unexpected correlation id {} (expected {correlation_id})",
+            header.correlation_id
+        );
+        Ok(R::Response::decode(&mut frame, api_version)?)
+    }
+
+    /// Fetch the API versions supported by the broker, failing on an error response.
+    pub async fn api_versions(&mut self) -> Result<ApiVersionsResponse> {
+        let response = self
+            .send(&ApiVersionsRequest::default(), API_VERSIONS_VERSION)
+            .await
+            .context("sending ApiVersions")?;
+        ensure!(
+            response.error_code == 0,
+            "ApiVersions failed with error code {}",
+            response.error_code
+        );
+        Ok(response)
+    }
+
+    /// Fetch the cluster metadata, without any topic.
+    pub async fn metadata(&mut self) -> Result<MetadataResponse> {
+        let mut request = MetadataRequest::default();
+        request.topics = Some(vec![]);
+        self.send(&request, METADATA_VERSION)
+            .await
+            .context("sending Metadata")
+    }
+
+    /// Fetch the metadata of some topics, along with the brokers.
+    pub async fn topic_metadata(&mut self, topics: &[String]) -> Result<MetadataResponse> {
+        let mut request = MetadataRequest::default();
+        request.topics = Some(
+            topics
+                .iter()
+                .map(|topic| {
+                    let mut request_topic = MetadataRequestTopic::default();
+                    request_topic.name = Some(TopicName(str_bytes(topic.clone())));
+                    request_topic
+                })
+                .collect(),
+        );
+        self.send(&request, METADATA_VERSION)
+            .await
+            .context("sending Metadata")
+    }
+
+    /// Write record batches to a partition led by the broker, waiting for the leader to
+    /// acknowledge them, and returning the offset of the first record.
+    pub async fn produce(&mut self, topic: &str, partition: i32, records: Bytes) -> Result<i64> {
+        let mut partition_data = PartitionProduceData::default();
+        partition_data.index = partition;
+        partition_data.records = Some(records);
+        let mut topic_data = TopicProduceData::default();
+        topic_data.partition_data.push(partition_data);
+        let mut request = ProduceRequest::default();
+        request.acks = 1;
+        request.timeout_ms = REQUEST_TIMEOUT.as_millis() as i32;
+        request
+            .topic_data
+            .insert(TopicName(str_bytes(topic.to_string())), topic_data);
+
+        let response = self
+            .send(&request, PRODUCE_VERSION)
+            .await
+            .context("sending Produce")?;
+        let answered = response
+            .responses
+            .values()
+            .flat_map(|topic| &topic.partition_responses)
+            .find(|answered| answered.index == partition)
+            .with_context(|| format!("no response for {topic}-{partition}"))?;
+        if let Some(error) = ResponseError::try_from_code(answered.error_code) {
+            bail!("Produce to {topic}-{partition} failed with {error:?}");
+        }
+        Ok(answered.base_offset)
+    }
+}
@@ -0,0 +1,219 @@
+//! Lag of the remote consumers, observed from the fetch requests and responses they exchange.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::time::interval_at;
+use tracing::{info, warn};
+
+/// Partitions that were not fetched for this long are forgotten.
+const STALE_AFTER: Duration = Duration::from_secs(600);
+
+/// A partition fetched by a client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct LagKey {
+    client_id: String,
+    topic: String,
+    partition: i32,
+}
+
+/// What is known of a partition fetched by a client.
+#[derive(Debug, Clone)]
+struct Position {
+    /// Offset of the last fetch request.
+    fetch_offset: i64,
+
+    /// High watermark of the last fetch response, once received.
+    high_watermark: Option<i64>,
+
+    /// Whether the lag is past the threshold, so that it is only warned about once.
+    lagging: bool,
+
+    /// When the partition was last fetched.
+    updated_at: Instant,
+}
+
+/// Lag of a consumer on a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerLag {
+    /// The client id sent in the fetch requests, empty if none.
+    pub client_id: String,
+
+    /// The consumer group of the client, once seen in a group request.
+    pub group_id: Option<String>,
+
+    /// Name of the topic, or its id when the name is unknown.
+    pub topic: String,
+
+    /// Index of the partition.
+    pub partition: i32,
+
+    /// Offset the client last fetched from.
+    pub fetch_offset: i64,
+
+    /// High watermark of the partition, as last answered to the client.
+    pub high_watermark: i64,
+
+    /// Records between the fetch offset and the high watermark.
+    pub lag: i64,
+}
+
+/// Lag of the consumers fetching through the tunnels, by client, group and partition.
+///
+/// Fetch requests give the offset each client consumes from, and fetch responses the high
+/// watermark of the partition. Fetch requests do not carry the consumer group, which is learned
+/// from the `JoinGroup`, `Heartbeat` and `OffsetCommit` requests of the same client id.
+pub struct LagObserver {
+    /// Lag past which a warning is logged, in records.
+    threshold: Option<i64>,
+
+    /// Consumer group of each client id.
+    groups: DashMap<String, String>,
+
+    /// Positions of the clients on the partitions they fetch.
+    positions: DashMap<LagKey, Position>,
+}
+
+impl LagObserver {
+    /// Create an observer, warning when a lag grows past a number of records.
+    pub fn new(threshold: Option<u64>) -> Self {
+        Self {
+            threshold: threshold.map(|threshold| threshold as i64),
+            groups: DashMap::new(),
+            positions: DashMap::new(),
+        }
+    }
+
+    /// Remember the consumer group of a client.
+    pub fn observe_group(&self, client_id: Option<&str>, group_id: &str) {
+        self.groups.insert(
+            client_id.unwrap_or_default().to_string(),
+            group_id.to_string(),
+        );
+    }
+
+    /// Remember the offset a client fetches a partition from.
+    pub fn observe_fetch(&self, client_id: Option<&str>, topic: &str, partition: i32, offset: i64) {
+        let key = LagKey {
+            client_id: client_id.unwrap_or_default().to_string(),
+            topic: topic.to_string(),
+            partition,
+        };
+        let mut position = self.positions.entry(key).or_insert_with(|| Position {
+            fetch_offset: offset,
+            high_watermark: None,
+            lagging: false,
+            updated_at: Instant::now(),
+        });
+        position.fetch_offset = offset;
+        position.updated_at = Instant::now();
+    }
+
+    /// Update the lag of a client with the high watermark answered for a partition.
+    pub fn observe_high_watermark(
+        &self,
+        client_id: Option<&str>,
+        topic: &str,
+        partition: i32,
+        high_watermark: i64,
+    ) {
+        if high_watermark < 0 {
+            return;
+        }
+        let client_id = client_id.unwrap_or_default();
+        let key = LagKey {
+            client_id: client_id.to_string(),
+            topic: topic.to_string(),
+            partition,
+        };
+        let Some(mut position) = self.positions.get_mut(&key) else {
+            return;
+        };
+        position.high_watermark = Some(high_watermark);
+        let lag = (high_watermark - position.fetch_offset).max(0);
+        let Some(threshold) = self.threshold else {
+            return;
+        };
+        let group_id = self.groups.get(client_id).map(|group| group.clone());
+        if lag >= threshold && !position.lagging {
+            position.lagging = true;
+            warn!(
+                client_id,
+                group_id = group_id.as_deref().unwrap_or_default(),
+                topic,
+                partition,
+                lag,
+                threshold,
+                "consumer lag past threshold"
+            );
+        } else if lag < threshold && position.lagging {
+            position.lagging = false;
+            info!(
+                client_id,
+                group_id = group_id.as_deref().unwrap_or_default(),
+                topic,
+                partition,
+                lag,
+                "consumer lag back under threshold"
+            );
+        }
+    }
+
+    /// Returns the lag of every client on the partitions it recently fetched, largest first.
+    pub fn snapshot(&self) -> Vec<ConsumerLag> {
+        self.positions
+            .retain(|_, position| position.updated_at.elapsed() < STALE_AFTER);
+        let mut lags: Vec<_> = self
+            .positions
+            .iter()
+            .filter_map(|entry| {
+                let (key, position) = entry.pair();
+                let high_watermark = position.high_watermark?;
+                Some(ConsumerLag {
+                    client_id: key.client_id.clone(),
+                    group_id: self.groups.get(&key.client_id).map(|group| group.clone()),
+                    topic: key.topic.clone(),
+                    partition: key.partition,
+                    fetch_offset: position.fetch_offset,
+                    high_watermark,
+                    lag: (high_watermark - position.fetch_offset).max(0),
+                })
+            })
+            .collect();
+        lags.sort_by(|a, b| {
+            b.lag.cmp(&a.lag).then_with(|| {
+                (&a.client_id, &a.topic, a.partition).cmp(&(&b.client_id, &b.topic, b.partition))
+            })
+        });
+        lags
+    }
+
+    /// Print the lag of every client on the partitions it recently fetched.
+    pub fn log_summary(&self, cluster: &str) {
+        let lags = self.snapshot();
+        info!(cluster, partitions = lags.len(), "consumer lag summary");
+        for lag in lags {
+            info!(
+                cluster,
+                client_id = %lag.client_id,
+                group_id = lag.group_id.as_deref().unwrap_or_default(),
+                topic = %lag.topic,
+                partition = lag.partition,
+                fetch_offset = lag.fetch_offset,
+                high_watermark = lag.high_watermark,
+                lag = lag.lag,
+                "consumer lag"
+            );
+        }
+    }
+
+    /// Print the summary periodically.
+    pub async fn report(self: Arc<Self>, cluster: String, period: Duration) {
+        let mut ticker = interval_at((Instant::now() + period).into(), period);
+        loop {
+            ticker.tick().await;
+            self.log_summary(&cluster);
+        }
+    }
+}
@@ -14,11 +14,36 @@
 
 #![warn(missing_docs)]
 
+pub mod accounting;
+pub mod acl;
+pub mod admin;
 pub mod auth;
+pub mod bench;
+pub mod chaos;
 pub mod client;
+pub mod client_id;
+pub mod clusters;
+pub mod console;
+pub mod discovery;
+pub mod doctor;
+pub mod encryption;
+pub mod headers;
+pub mod http;
+pub mod inventory;
 pub mod kafka;
+pub mod kafka_client;
+pub mod lag;
+pub mod limits;
+pub mod masking;
+pub mod records;
+pub mod schemas;
 pub mod server;
+pub mod shadow;
 pub mod shared;
+pub mod slowlog;
+pub mod stats;
+pub mod topic_policy;
+pub mod versions;
 
 /// bore server
 pub const CONDUKTOR_BORE_SERVER: &str = "bore.pub";
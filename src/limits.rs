@@ -0,0 +1,67 @@
+//! Size limits on the produce requests of remote clients.
+
+use std::result;
+
+use bytes::Bytes;
+
+use crate::records::{split_batches, BatchHeader};
+
+/// Limits on produce requests, rejected by the proxy with `MESSAGE_TOO_LARGE` instead of being
+/// forwarded.
+///
+/// The limits are checked from the length of the request and the headers of its batches, before
+/// the request is decoded further, and without decompressing any record. The record count of
+/// legacy message sets is not checked.
+#[derive(Debug, Clone, Default)]
+pub struct ProduceLimits {
+    /// Maximum size of a produce request, in bytes.
+    pub max_request_bytes: Option<usize>,
+
+    /// Maximum size of a record batch, in bytes.
+    pub max_batch_bytes: Option<usize>,
+
+    /// Maximum number of records in a batch.
+    pub max_batch_records: Option<usize>,
+}
+
+impl ProduceLimits {
+    /// Returns whether no limit is set.
+    pub fn is_empty(&self) -> bool {
+        self.max_request_bytes.is_none()
+            && self.max_batch_bytes.is_none()
+            && self.max_batch_records.is_none()
+    }
+
+    /// Check the size of a produce request, returning the exceeded limit, if any.
+    pub fn check_request(&self, size: usize) -> result::Result<(), String> {
+        match self.max_request_bytes {
+            Some(max) if size > max => Err(format!(
+                "Request of {size} bytes is larger than the maximum of {max}."
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the batches produced to a partition, returning the first exceeded limit, if any.
+    pub fn check_records(&self, records: &Bytes) -> result::Result<(), String> {
+        for batch in split_batches(records) {
+            if let Some(max) = self.max_batch_bytes {
+                if batch.len() > max {
+                    return Err(format!(
+                        "Batch of {} bytes is larger than the maximum of {max}.",
+                        batch.len()
+                    ));
+                }
+            }
+            if let (Some(max), Ok(header)) = (self.max_batch_records, BatchHeader::peek(&batch)) {
+                let count = header.records_count.max(0) as usize;
+                if count > max {
+                    return Err(format!(
+                        "Batch of {count} records has more than the maximum of {max}."
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,29 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
 use clap::{Parser, Subcommand};
-use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::accounting::TopicAccounting;
+use conduktor_kafka_proxy::admin::AdminApi;
+use conduktor_kafka_proxy::auth::Authenticator;
+use conduktor_kafka_proxy::bench::{Bench, BenchConfig};
+use conduktor_kafka_proxy::client::Control;
+use conduktor_kafka_proxy::clusters::ClusterConfig;
+use conduktor_kafka_proxy::console::KafkaConsole;
+use conduktor_kafka_proxy::discovery::{self, COMMON_PORTS};
+use conduktor_kafka_proxy::doctor::Doctor;
+use conduktor_kafka_proxy::http::{HttpRoute, HttpTunnel};
+use conduktor_kafka_proxy::lag::LagObserver;
 use conduktor_kafka_proxy::server::Server;
+use conduktor_kafka_proxy::slowlog::SlowLog;
+use conduktor_kafka_proxy::stats::StatsPublisher;
 use conduktor_kafka_proxy::CONDUKTOR_BORE_SERVER;
+use tokio::net::TcpListener;
 use tracing::info;
 
 #[derive(Parser, Debug)]
@@ -13,9 +34,175 @@ struct Args {
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)] // parsed once
 enum Command {
     /// Starts a local LocalProxy to the remote server.
     Start {
+        /// The local host to expose, discovered among common local ports if not given.
+        #[clap(short, long, value_name = "BOOTSTRAP_SERVER")]
+        bootstrap_server: Option<String>,
+
+        /// Refuse to expose the cluster unless its Metadata responses carry this cluster id.
+        #[clap(long, value_name = "CLUSTER_ID")]
+        expect_cluster_id: Option<String>,
+
+        /// Optional secret for authentication.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// The remote server to expose the tunnels on.
+        #[clap(long, env = "BORE_SERVER", default_value = CONDUKTOR_BORE_SERVER)]
+        server: String,
+
+        /// Log requests slower than this many milliseconds, along with error responses.
+        #[clap(long, value_name = "MILLIS")]
+        slow_request_ms: Option<u64>,
+
+        /// Sliding window in seconds over which slow requests are summarized.
+        #[clap(long, value_name = "SECS", default_value_t = 300)]
+        slow_window_secs: u64,
+
+        /// Print a summary of the slowest clients every this many seconds (also on SIGUSR1).
+        #[clap(long, value_name = "SECS")]
+        slow_summary_secs: Option<u64>,
+
+        /// Count the bytes and records produced and fetched by topic, partition and client, in
+        /// metrics and in a summary printed at shutdown.
+        #[clap(long)]
+        topic_accounting: bool,
+
+        /// Observe the lag of the remote consumers from their fetch requests and responses, in
+        /// metrics and logs.
+        #[clap(long)]
+        consumer_lag: bool,
+
+        /// Warn when a consumer lags this many records behind the high watermark.
+        #[clap(long, value_name = "RECORDS", requires = "consumer_lag")]
+        lag_warn_records: Option<u64>,
+
+        /// Print the lag of every consumer every this many seconds.
+        #[clap(long, value_name = "SECS", requires = "consumer_lag")]
+        lag_summary_secs: Option<u64>,
+
+        /// Serve a local HTTP/JSON admin API on this address, e.g. 127.0.0.1:9999.
+        #[clap(long, value_name = "ADDR")]
+        admin_addr: Option<SocketAddr>,
+
+        /// Expose the clusters listed in this JSON file, each with its own options, instead of
+        /// a single one.
+        #[clap(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = [
+                "bootstrap_server",
+                "expect_cluster_id",
+                "masking_rules",
+                "inject_headers",
+                "schemas",
+                "encryption_keys",
+                "shadow_bootstrap_server",
+                "chaos_rules",
+                "min_versions",
+                "topic_policy",
+                "acl_rules",
+                "max_produce_request_bytes",
+                "max_batch_bytes",
+                "max_batch_records",
+                "client_id",
+                "stats_topic",
+                "stats_interval_secs",
+            ]
+        )]
+        clusters: Option<PathBuf>,
+
+        /// Mask fields of the fetched records with the rules of this JSON file.
+        #[clap(long, value_name = "FILE")]
+        masking_rules: Option<PathBuf>,
+
+        /// Add a header to every produced record, e.g. `x-origin=tunnel` (repeatable).
+        #[clap(long = "inject-header", value_name = "KEY=VALUE")]
+        inject_headers: Vec<String>,
+
+        /// Reject produced records that do not match the schemas listed in this JSON file.
+        #[clap(long, value_name = "FILE")]
+        schemas: Option<PathBuf>,
+
+        /// Decrypt produced records and encrypt fetched ones with the keys of this JSON file
+        /// (reloaded on SIGHUP).
+        #[clap(long, value_name = "FILE")]
+        encryption_keys: Option<PathBuf>,
+
+        /// Also send a copy of every produce request to the cluster at this bootstrap server,
+        /// logging the partitions whose outcome differs.
+        #[clap(long, value_name = "BOOTSTRAP_SERVER")]
+        shadow_bootstrap_server: Option<String>,
+
+        /// Inject faults in the requests of remote clients with the rules of this JSON file, to
+        /// test how they handle broker problems.
+        #[clap(long, value_name = "FILE")]
+        chaos_rules: Option<PathBuf>,
+
+        /// Reject the requests below this version of an API with `UNSUPPORTED_VERSION`, e.g.
+        /// `Produce=3` (repeatable).
+        #[clap(long = "min-version", value_name = "API=VERSION")]
+        min_versions: Vec<String>,
+
+        /// Reject the topics created or extended by remote clients that violate the policy of
+        /// this JSON file.
+        #[clap(long, value_name = "FILE")]
+        topic_policy: Option<PathBuf>,
+
+        /// Authorize the requests of remote clients by principal with the rules of this JSON
+        /// file, terminating their SASL/PLAIN authentication.
+        #[clap(long, value_name = "FILE")]
+        acl_rules: Option<PathBuf>,
+
+        /// Reject the produce requests of remote clients larger than this many bytes with
+        /// `MESSAGE_TOO_LARGE`.
+        #[clap(long, value_name = "BYTES")]
+        max_produce_request_bytes: Option<usize>,
+
+        /// Reject the produce requests of remote clients with a record batch larger than this
+        /// many bytes with `MESSAGE_TOO_LARGE`.
+        #[clap(long, value_name = "BYTES")]
+        max_batch_bytes: Option<usize>,
+
+        /// Reject the produce requests of remote clients with a record batch of more than this
+        /// many records with `MESSAGE_TOO_LARGE`.
+        #[clap(long, value_name = "RECORDS")]
+        max_batch_records: Option<usize>,
+
+        /// Forward the requests of remote clients with this client id, e.g.
+        /// `tunnel-{cluster}-{original}`, or a fixed value.
+        #[clap(long, value_name = "TEMPLATE")]
+        client_id: Option<String>,
+
+        /// Publish the traffic statistics of the proxy as JSON records to this topic of the
+        /// local cluster, e.g. `_tunnel_stats`.
+        #[clap(long, value_name = "TOPIC")]
+        stats_topic: Option<String>,
+
+        /// Seconds between two statistics records, 60 if not given.
+        #[clap(
+            long,
+            value_name = "SECS",
+            requires = "stats_topic",
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        stats_interval_secs: Option<u64>,
+
+        /// Also expose the Schema Registry at this URL, e.g. http://localhost:8081.
+        #[clap(long, value_name = "URL")]
+        schema_registry: Option<String>,
+
+        /// Expose a local HTTP service on a shared port, routed by path prefix or host, e.g.
+        /// `connect:/connect=http://localhost:8083` (repeatable).
+        #[clap(long = "http-route", value_name = "NAME:MATCH=URL")]
+        http_routes: Vec<String>,
+    },
+
+    /// Diagnoses the tunnel setup, from the local cluster to the remote server.
+    Doctor {
         /// The local host to expose.
         #[clap(
             short,
@@ -28,6 +215,92 @@ enum Command {
         /// Optional secret for authentication.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
+
+        /// The remote server to expose the tunnels on.
+        #[clap(long, env = "BORE_SERVER", default_value = CONDUKTOR_BORE_SERVER)]
+        server: String,
+    },
+
+    /// Lists the topics of a cluster, e.g. through the public address of a tunnel.
+    Topics {
+        #[clap(subcommand)]
+        command: TopicsCommand,
+    },
+
+    /// Produces values to a partition, in a single batch.
+    Produce {
+        #[clap(flatten)]
+        target: Target,
+
+        /// The topic to produce to.
+        topic: String,
+
+        /// The partition to produce to.
+        #[clap(short, long, default_value_t = 0)]
+        partition: i32,
+
+        /// The key of every record.
+        #[clap(short, long)]
+        key: Option<String>,
+
+        /// The values to produce, one record each.
+        #[clap(required = true)]
+        values: Vec<String>,
+    },
+
+    /// Consumes the records of a partition until its end, printing their offset, key and value.
+    Consume {
+        #[clap(flatten)]
+        target: Target,
+
+        /// The topic to consume from.
+        topic: String,
+
+        /// The partition to consume from.
+        #[clap(short, long, default_value_t = 0)]
+        partition: i32,
+
+        /// The offset to consume from, the start of the partition if not given.
+        #[clap(short, long)]
+        offset: Option<i64>,
+
+        /// Stop after this many records.
+        #[clap(short = 'n', long, default_value_t = 100)]
+        max_records: usize,
+    },
+
+    /// Produces messages to a partition and consumes them back, reporting throughput and latency,
+    /// e.g. to compare a tunnel with a direct connection to the cluster.
+    Bench {
+        #[clap(flatten)]
+        target: Target,
+
+        /// The topic to produce to, which should have no other producer.
+        topic: String,
+
+        /// The partition to produce to.
+        #[clap(short, long, default_value_t = 0)]
+        partition: i32,
+
+        /// Size of every message, in bytes.
+        #[clap(long, value_name = "BYTES", default_value_t = 1024, value_parser = clap::value_parser!(u64).range(8..))]
+        message_bytes: u64,
+
+        /// Messages sent in every produce request.
+        #[clap(long, value_name = "RECORDS", default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+        batch_records: u64,
+
+        /// Messages produced per second, as fast as possible if not given.
+        #[clap(long, value_name = "MESSAGES", value_parser = clap::value_parser!(u64).range(1..))]
+        rate: Option<u64>,
+
+        /// How long to produce, in seconds.
+        #[clap(long, value_name = "SECS", default_value_t = 10)]
+        duration_secs: u64,
+
+        /// Do not consume the messages back, nor measure their end-to-end latency.
+        #[clap(long)]
+        produce_only: bool,
     },
 
     /// Runs the remote proxy server.
@@ -42,18 +315,308 @@ enum Command {
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum TopicsCommand {
+    /// Lists the topics with their number of partitions.
+    List {
+        #[clap(flatten)]
+        target: Target,
+    },
+}
+
+/// The cluster the console commands talk to.
+#[derive(clap::Args, Debug)]
+struct Target {
+    /// The bootstrap server of the cluster, such as the public address of a tunnel.
+    #[clap(
+        short,
+        long,
+        value_name = "BOOTSTRAP_SERVER",
+        default_value = "localhost:9092"
+    )]
+    bootstrap_server: String,
+
+    /// First check that every broker is advertised at an address rewritten by the tunnel, and
+    /// answers there.
+    #[clap(long)]
+    via_tunnel: bool,
+}
+
+impl Target {
+    /// Returns a console for the cluster, after checking the tunnel if asked to.
+    async fn console(&self) -> Result<KafkaConsole> {
+        let console = KafkaConsole::new(&self.bootstrap_server);
+        if self.via_tunnel {
+            let checks = console.check_tunnel().await;
+            for check in &checks {
+                eprintln!("{check}");
+            }
+            let failed = checks.iter().filter(|check| !check.passed).count();
+            if failed > 0 {
+                bail!("{failed} check(s) failed");
+            }
+        }
+        Ok(console)
+    }
+}
+
 #[tokio::main]
 async fn run(command: Command) -> Result<()> {
     match command {
         Command::Start {
+            bootstrap_server,
+            expect_cluster_id,
+            secret,
+            server,
+            slow_request_ms,
+            slow_window_secs,
+            slow_summary_secs,
+            topic_accounting,
+            consumer_lag,
+            lag_warn_records,
+            lag_summary_secs,
+            admin_addr,
+            clusters,
+            masking_rules,
+            inject_headers,
+            schemas,
+            encryption_keys,
+            shadow_bootstrap_server,
+            chaos_rules,
+            min_versions,
+            topic_policy,
+            acl_rules,
+            max_produce_request_bytes,
+            max_batch_bytes,
+            max_batch_records,
+            client_id,
+            stats_topic,
+            stats_interval_secs,
+            schema_registry,
+            http_routes,
+        } => {
+            let clusters = match clusters {
+                Some(path) => ClusterConfig::from_file(&path)?,
+                None => vec![ClusterConfig {
+                    bootstrap_server,
+                    expect_cluster_id,
+                    masking_rules,
+                    inject_headers,
+                    schemas,
+                    encryption_keys,
+                    shadow_bootstrap_server,
+                    chaos_rules,
+                    min_versions,
+                    topic_policy,
+                    acl_rules,
+                    max_produce_request_bytes,
+                    max_batch_bytes,
+                    max_batch_records,
+                    client_id,
+                    stats_topic,
+                    stats_interval_secs,
+                    ..ClusterConfig::default()
+                }],
+            };
+            let slow_log = slow_request_ms.map(|slow_request_ms| {
+                let slow_log = Arc::new(SlowLog::new(
+                    Duration::from_millis(slow_request_ms),
+                    Duration::from_secs(slow_window_secs),
+                ));
+                tokio::spawn(
+                    Arc::clone(&slow_log).report(slow_summary_secs.map(Duration::from_secs)),
+                );
+                slow_log
+            });
+            let mut proxies = vec![];
+            // clusters authenticating with the same secret share a control connection
+            let mut controls = HashMap::new();
+            for cluster in &clusters {
+                let cluster_secret = cluster.secret.as_ref().or(secret.as_ref());
+                let control = controls.entry(cluster_secret).or_insert_with(|| {
+                    let auth = cluster_secret.map(|secret| Authenticator::new(secret));
+                    Arc::new(Control::new(&server, auth))
+                });
+                let mut proxy = cluster
+                    .build(&server, secret.as_deref())
+                    .with_context(|| format!("invalid cluster {}", cluster.name))?
+                    .with_control(Arc::clone(control));
+                if let Some(slow_log) = &slow_log {
+                    proxy = proxy.with_slow_log(Arc::clone(slow_log));
+                }
+                if topic_accounting {
+                    proxy = proxy.with_accounting(Arc::new(TopicAccounting::new()));
+                }
+                if consumer_lag {
+                    let lag = Arc::new(LagObserver::new(lag_warn_records));
+                    if let Some(secs) = lag_summary_secs {
+                        tokio::spawn(
+                            Arc::clone(&lag)
+                                .report(cluster.name.clone(), Duration::from_secs(secs)),
+                        );
+                    }
+                    proxy = proxy.with_lag(lag);
+                }
+                let proxy = Arc::new(proxy);
+                if let Some(topic) = &cluster.stats_topic {
+                    let publisher = StatsPublisher::new(topic, cluster.stats_interval());
+                    tokio::spawn(publisher.run(Arc::clone(&proxy)));
+                }
+                proxies.push(proxy);
+            }
+            let registry = match schema_registry {
+                Some(url) => Some(HttpTunnel::new(vec![HttpRoute::new(
+                    "schema-registry",
+                    &url,
+                )?])?),
+                None => None,
+            };
+            let http = if http_routes.is_empty() {
+                None
+            } else {
+                let routes = http_routes
+                    .iter()
+                    .map(|spec| HttpRoute::parse(spec))
+                    .collect::<Result<_>>()?;
+                Some(HttpTunnel::new(routes)?)
+            };
+            let admin = match admin_addr {
+                Some(addr) => Some(TcpListener::bind(addr).await?),
+                None => None,
+            };
+            for (cluster, proxy) in clusters.iter().zip(&proxies) {
+                let bootstrap_server = match &cluster.bootstrap_server {
+                    Some(bootstrap_server) => bootstrap_server.clone(),
+                    None => {
+                        let mut found = discovery::discover("localhost", &COMMON_PORTS).await;
+                        if let Some(expected) = &cluster.expect_cluster_id {
+                            found.retain(|c| c.cluster_id.as_ref() == Some(expected));
+                        }
+                        let stdin = std::io::stdin();
+                        let prompt = stdin
+                            .is_terminal()
+                            .then(|| (stdin.lock(), std::io::stderr()));
+                        let found = discovery::select(found, prompt)?;
+                        info!("Discovered {}", found);
+                        found.bootstrap_server
+                    }
+                };
+                let remote = proxy.expose(&bootstrap_server).await?;
+                info!("Started proxy for {} on {}", cluster.name, remote);
+            }
+            let auth = secret.as_deref().map(Authenticator::new);
+            if let Some(registry) = registry {
+                let public = Arc::new(registry).expose(&server, auth.clone()).await?;
+                info!("Started schema registry proxy on {}", public);
+            }
+            if let Some(http) = http {
+                let public = Arc::new(http).expose(&server, auth).await?;
+                info!(
+                    "Started HTTP proxy on {} ({})",
+                    public,
+                    http_routes.join(", ")
+                );
+            }
+            if let Some(listener) = admin {
+                let mut proxies = proxies.iter().cloned();
+                let first = proxies.next().expect("at least one cluster");
+                let api = proxies.fold(AdminApi::new(first), AdminApi::with_proxy);
+                tokio::spawn(api.serve(listener));
+            }
+            shutdown_signal().await?;
+            for proxy in &proxies {
+                if let Some(accounting) = proxy.accounting() {
+                    accounting.log_summary(proxy.name());
+                }
+            }
+        }
+        Command::Doctor {
             bootstrap_server,
             secret,
+            server,
         } => {
-            let remote = KafkaProxy::new(CONDUKTOR_BORE_SERVER, secret.as_deref())
-                .start(&bootstrap_server)
+            let checks = Doctor::new(&server, secret.as_deref(), &bootstrap_server)
+                .run()
+                .await;
+            for check in &checks {
+                println!("{check}");
+            }
+            let failed = checks.iter().filter(|check| !check.passed).count();
+            if failed > 0 {
+                bail!("{failed} check(s) failed");
+            }
+        }
+        Command::Topics {
+            command: TopicsCommand::List { target },
+        } => {
+            for topic in target.console().await?.list_topics().await? {
+                let internal = if topic.internal { "\tinternal" } else { "" };
+                println!("{}\t{}{internal}", topic.name, topic.partitions);
+            }
+        }
+        Command::Produce {
+            target,
+            topic,
+            partition,
+            key,
+            values,
+        } => {
+            let offset = target
+                .console()
+                .await?
+                .produce(&topic, partition, key.as_deref(), &values)
+                .await?;
+            println!(
+                "Produced {} record(s) to {topic}-{partition} at offset {offset}",
+                values.len()
+            );
+        }
+        Command::Consume {
+            target,
+            topic,
+            partition,
+            offset,
+            max_records,
+        } => {
+            let records = target
+                .console()
+                .await?
+                .consume(&topic, partition, offset, max_records)
                 .await?;
-            info!("Started proxy on {}", remote);
-            futures::pending!();
+            let text = |bytes: &Option<Bytes>| match bytes {
+                Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                None => "<null>".to_string(),
+            };
+            for record in &records {
+                println!(
+                    "{}\t{}\t{}",
+                    record.offset,
+                    text(&record.key),
+                    text(&record.value)
+                );
+            }
+        }
+        Command::Bench {
+            target,
+            topic,
+            partition,
+            message_bytes,
+            batch_records,
+            rate,
+            duration_secs,
+            produce_only,
+        } => {
+            let config = BenchConfig {
+                topic,
+                partition,
+                message_bytes: message_bytes as usize,
+                batch_records: batch_records as usize,
+                rate,
+                duration: Duration::from_secs(duration_secs),
+                consume: !produce_only,
+            };
+            let report = Bench::new(target.console().await?, config).run().await?;
+            println!("{report}");
         }
         Command::Server { min_port, secret } => {
             Server::new(min_port, secret.as_deref()).listen().await?;
@@ -63,6 +626,22 @@ async fn run(command: Command) -> Result<()> {
     Ok(())
 }
 
+/// Wait for Ctrl-C, or `SIGTERM` where supported.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = term.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    info!("shutting down");
+    Ok(())
+}
+
 fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     run(Args::parse().command)
@@ -0,0 +1,337 @@
+//! Field-level masking of the records sent to remote clients.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::records::{split_batches, BatchHeader, RecordBatch};
+
+/// Replacement of redacted values.
+pub const REDACTED: &str = "****";
+
+/// What to do with a value matched by a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskAction {
+    /// Replace the value with the hex-encoded SHA-256 of its text.
+    Hash,
+    /// Replace the value with a fixed placeholder.
+    Redact,
+    /// Remove the field, or the matched text.
+    Drop,
+}
+
+/// A rule as written in the rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    topic: String,
+    path: Option<String>,
+    regex: Option<String>,
+    action: MaskAction,
+}
+
+/// A step of a JSON path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathSegment {
+    /// A field of an object.
+    Field(String),
+    /// An element of an array.
+    Index(usize),
+    /// Every field of an object or element of an array.
+    Wildcard,
+}
+
+/// Which part of a record value a rule matches.
+#[derive(Debug)]
+enum Target {
+    /// A JSON path, only applied to values that are JSON documents.
+    Path(Vec<PathSegment>),
+    /// A regex, applied to values that are UTF-8 text.
+    Regex(Regex),
+}
+
+/// A masking rule for the topics matching a regex.
+#[derive(Debug)]
+struct MaskingRule {
+    topic: Regex,
+    target: Target,
+    action: MaskAction,
+}
+
+/// Masking rules applied to the values of fetched records, before they leave the machine.
+///
+/// Rules are loaded from a JSON array, for instance:
+///
+/// ```json
+/// [
+///   { "topic": "customers.*", "path": "$.email", "action": "hash" },
+///   { "topic": "customers.*", "path": "$.cards[*].number", "action": "redact" },
+///   { "topic": "logs", "regex": "\\d{3}-\\d{2}-\\d{4}", "action": "drop" }
+/// ]
+/// ```
+///
+/// The topic regex must match the whole topic name. Every matching rule is applied, in order.
+#[derive(Debug, Default)]
+pub struct Masking {
+    rules: Vec<MaskingRule>,
+}
+
+impl Masking {
+    /// Load the rules from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading masking rules from {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("invalid masking rules {}", path.display()))
+    }
+
+    /// Parse the rules from a JSON array.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let configs: Vec<RuleConfig> = serde_json::from_str(json)?;
+        let rules = configs
+            .into_iter()
+            .enumerate()
+            .map(|(i, config)| MaskingRule::new(config).with_context(|| format!("rule {i}")))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Returns whether any rule applies to a topic.
+    pub fn applies_to(&self, topic: &str) -> bool {
+        self.rules.iter().any(|rule| rule.topic.is_match(topic))
+    }
+
+    /// Mask a record value, returning `None` if no rule changed it.
+    pub fn mask_value(&self, topic: &str, value: &[u8]) -> Option<Vec<u8>> {
+        let rules: Vec<_> = self.rules_for(topic).collect();
+        mask_value(&rules, value)
+    }
+
+    /// Mask the values of the records fetched from a topic partition.
+    ///
+    /// Batches left unchanged are copied as is, others are re-encoded with their original
+    /// compression. Fails on batches that cannot be masked, such as legacy message sets.
+    pub fn mask_records(&self, topic: &str, records: &Bytes) -> Result<Bytes> {
+        let rules: Vec<_> = self.rules_for(topic).collect();
+        if rules.is_empty() {
+            return Ok(records.clone());
+        }
+
+        let mut out = BytesMut::with_capacity(records.len());
+        for batch in split_batches(records) {
+            if BatchHeader::peek(&batch)?.is_control() {
+                out.put_slice(&batch);
+                continue;
+            }
+            let mut decoded = RecordBatch::decode(&batch)?;
+            let mut changed = false;
+            for record in &mut decoded.records {
+                let masked = record
+                    .value
+                    .as_ref()
+                    .and_then(|value| mask_value(&rules, value));
+                if let Some(masked) = masked {
+                    record.value = Some(masked.into());
+                    changed = true;
+                }
+            }
+            if changed {
+                out.put_slice(&decoded.encode()?);
+            } else {
+                out.put_slice(&batch);
+            }
+        }
+        Ok(out.freeze())
+    }
+
+    fn rules_for<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a MaskingRule> {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.topic.is_match(topic))
+    }
+}
+
+impl MaskingRule {
+    fn new(config: RuleConfig) -> Result<Self> {
+        let topic = Regex::new(&format!("^(?:{})$", config.topic)).context("invalid topic")?;
+        let target = match (config.path, config.regex) {
+            (Some(path), None) => Target::Path(parse_path(&path)?),
+            (None, Some(regex)) => Target::Regex(Regex::new(&regex).context("invalid regex")?),
+            _ => bail!("exactly one of `path` and `regex` is required"),
+        };
+        Ok(Self {
+            topic,
+            target,
+            action: config.action,
+        })
+    }
+}
+
+/// Apply rules to a value, returning `None` if none changed it.
+fn mask_value(rules: &[&MaskingRule], value: &[u8]) -> Option<Vec<u8>> {
+    let mut json: Option<Value> = None;
+    let mut text: Option<String> = None;
+    let mut changed = false;
+
+    for rule in rules {
+        match &rule.target {
+            Target::Path(path) => {
+                if json.is_none() {
+                    let source = text.as_deref().map_or(value, str::as_bytes);
+                    json = serde_json::from_slice(source).ok();
+                    if json.is_some() {
+                        text = None;
+                    }
+                }
+                if let Some(json) = &mut json {
+                    changed |= apply_path(json, path, rule.action);
+                }
+            }
+            Target::Regex(regex) => {
+                if text.is_none() {
+                    text = match json.take() {
+                        Some(json) => Some(json.to_string()),
+                        None => String::from_utf8(value.to_vec()).ok(),
+                    };
+                }
+                if let Some(text) = &mut text {
+                    if regex.is_match(text) {
+                        *text = regex
+                            .replace_all(text, |captures: &regex::Captures| {
+                                replacement(&captures[0], rule.action)
+                            })
+                            .into_owned();
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    if !changed {
+        return None;
+    }
+    match (json, text) {
+        (Some(json), _) => Some(json.to_string().into_bytes()),
+        (_, Some(text)) => Some(text.into_bytes()),
+        _ => None,
+    }
+}
+
+/// The replacement of a matched text.
+fn replacement(matched: &str, action: MaskAction) -> String {
+    match action {
+        MaskAction::Hash => hex::encode(Sha256::digest(matched.as_bytes())),
+        MaskAction::Redact => REDACTED.to_string(),
+        MaskAction::Drop => String::new(),
+    }
+}
+
+/// Apply an action to the values at a path, returning whether any was found.
+fn apply_path(value: &mut Value, path: &[PathSegment], action: MaskAction) -> bool {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            let text = match &*value {
+                Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            *value = Value::String(replacement(&text, action));
+            return true;
+        }
+    };
+
+    if rest.is_empty() && action == MaskAction::Drop {
+        return match (segment, value) {
+            (PathSegment::Field(field), Value::Object(object)) => object.remove(field).is_some(),
+            (PathSegment::Index(index), Value::Array(array)) if *index < array.len() => {
+                array.remove(*index);
+                true
+            }
+            (PathSegment::Wildcard, Value::Object(object)) if !object.is_empty() => {
+                object.clear();
+                true
+            }
+            (PathSegment::Wildcard, Value::Array(array)) if !array.is_empty() => {
+                array.clear();
+                true
+            }
+            _ => false,
+        };
+    }
+
+    match (segment, value) {
+        (PathSegment::Field(field), Value::Object(object)) => object
+            .get_mut(field)
+            .is_some_and(|child| apply_path(child, rest, action)),
+        (PathSegment::Index(index), Value::Array(array)) => array
+            .get_mut(*index)
+            .is_some_and(|child| apply_path(child, rest, action)),
+        (PathSegment::Wildcard, Value::Object(object)) => {
+            object.values_mut().fold(false, |found, child| {
+                apply_path(child, rest, action) | found
+            })
+        }
+        (PathSegment::Wildcard, Value::Array(array)) => {
+            array.iter_mut().fold(false, |found, child| {
+                apply_path(child, rest, action) | found
+            })
+        }
+        _ => false,
+    }
+}
+
+/// Parse a JSON path such as `$.customer.cards[*].number`.
+fn parse_path(path: &str) -> Result<Vec<PathSegment>> {
+    let mut rest = path
+        .strip_prefix('$')
+        .context("JSON paths must start with `$`")?;
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix('.') {
+            let end = tail.find(['.', '[']).unwrap_or(tail.len());
+            let field = &tail[..end];
+            ensure!(!field.is_empty(), "empty field in JSON path {path}");
+            segments.push(match field {
+                "*" => PathSegment::Wildcard,
+                field => PathSegment::Field(field.to_string()),
+            });
+            rest = &tail[end..];
+        } else if let Some(tail) = rest.strip_prefix('[') {
+            let end = tail
+                .find(']')
+                .with_context(|| format!("unclosed bracket in JSON path {path}"))?;
+            let index = &tail[..end];
+            segments.push(match index {
+                "*" => PathSegment::Wildcard,
+                index => match index.strip_prefix(['\'', '"']) {
+                    Some(quoted) => PathSegment::Field(
+                        quoted
+                            .strip_suffix(['\'', '"'])
+                            .with_context(|| format!("unclosed quote in JSON path {path}"))?
+                            .to_string(),
+                    ),
+                    None => PathSegment::Index(
+                        index
+                            .parse()
+                            .with_context(|| format!("invalid index in JSON path {path}"))?,
+                    ),
+                },
+            });
+            rest = &tail[end + 1..];
+        } else {
+            bail!("invalid JSON path {path}");
+        }
+    }
+    ensure!(
+        !segments.is_empty(),
+        "JSON path {path} matches the whole value"
+    );
+    Ok(segments)
+}
@@ -0,0 +1,495 @@
+//! Record batches (magic v2), decoded and encoded while keeping the batch header intact.
+//!
+//! Rewriting records through [`kafka_protocol::records`] recomputes the whole batch header,
+//! losing details such as the last offset delta of compacted batches or the timestamp type.
+//! Here only the records are replaced, then the batch is recompressed and its CRC recomputed.
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::records::CASTAGNOLI;
+
+/// Size of the batch header, up to the records count included.
+pub const BATCH_HEADER_SIZE: usize = 61;
+
+/// Size of the fields preceding the batch length, which are not counted in it.
+const LOG_OVERHEAD: usize = 12;
+
+/// Offset of the magic byte, in both legacy message sets and record batches.
+const MAGIC_OFFSET: usize = 16;
+
+/// Offset of the attributes, where the CRC computation starts.
+const ATTRIBUTES_OFFSET: usize = 21;
+
+/// Offset of the producer id, followed by the producer epoch and base sequence.
+const PRODUCER_ID_OFFSET: usize = 43;
+
+/// Magic header of the snappy framing used by the Java clients.
+const XERIAL_MAGIC: [u8; 8] = [0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+
+/// Uncompressed size of the snappy blocks written in the xerial framing.
+const XERIAL_BLOCK_SIZE: usize = 32 * 1024;
+
+/// Largest size of the records of a batch once decompressed, so that a small compressed batch
+/// cannot use up the memory of the proxy.
+pub const MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+
+/// The compression codecs of a record batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// No compression.
+    None,
+    /// gzip compression.
+    Gzip,
+    /// Snappy compression.
+    Snappy,
+    /// LZ4 compression.
+    Lz4,
+    /// Zstandard compression.
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the compression codec stored in the batch attributes.
+    pub fn from_attributes(attributes: i16) -> Result<Self> {
+        Ok(match attributes & 0x07 {
+            0 => Compression::None,
+            1 => Compression::Gzip,
+            2 => Compression::Snappy,
+            3 => Compression::Lz4,
+            4 => Compression::Zstd,
+            other => bail!("unknown compression codec {other}"),
+        })
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => data.to_vec(),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Compression::Snappy => {
+                let mut out = XERIAL_MAGIC.to_vec();
+                out.put_i32(1); // version
+                out.put_i32(1); // minimum compatible version
+                for block in data.chunks(XERIAL_BLOCK_SIZE) {
+                    let compressed = snap::raw::Encoder::new().compress_vec(block)?;
+                    out.put_i32(compressed.len() as i32);
+                    out.extend_from_slice(&compressed);
+                }
+                out
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => zstd::encode_all(data, 0)?,
+        })
+    }
+
+    /// Decompress the records of a batch, failing beyond [`MAX_DECOMPRESSED_BYTES`].
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            Compression::None => out.extend_from_slice(data),
+            Compression::Gzip => read_limited(flate2::read::GzDecoder::new(data), &mut out)?,
+            Compression::Snappy if data.starts_with(&XERIAL_MAGIC) => {
+                let mut blocks = data.get(16..).context("truncated snappy header")?;
+                while blocks.has_remaining() {
+                    ensure!(blocks.remaining() >= 4, "truncated snappy block");
+                    let len = blocks.get_i32() as usize;
+                    ensure!(blocks.remaining() >= len, "truncated snappy block");
+                    // the decompressed length is read from the block before allocating it
+                    let decompressed = snap::raw::decompress_len(&blocks[..len])?;
+                    check_decompressed(out.len() + decompressed)?;
+                    out.extend(snap::raw::Decoder::new().decompress_vec(&blocks[..len])?);
+                    blocks.advance(len);
+                }
+            }
+            Compression::Snappy => {
+                check_decompressed(snap::raw::decompress_len(data)?)?;
+                out = snap::raw::Decoder::new().decompress_vec(data)?;
+            }
+            Compression::Lz4 => read_limited(lz4_flex::frame::FrameDecoder::new(data), &mut out)?,
+            Compression::Zstd => read_limited(zstd::stream::read::Decoder::new(data)?, &mut out)?,
+        }
+        Ok(out)
+    }
+}
+
+/// Read a decompressing reader to the end, failing beyond [`MAX_DECOMPRESSED_BYTES`].
+fn read_limited<R: Read>(reader: R, out: &mut Vec<u8>) -> Result<()> {
+    reader
+        .take(MAX_DECOMPRESSED_BYTES as u64 + 1)
+        .read_to_end(out)?;
+    check_decompressed(out.len())
+}
+
+fn check_decompressed(len: usize) -> Result<()> {
+    ensure!(
+        len <= MAX_DECOMPRESSED_BYTES,
+        "record batch larger than {MAX_DECOMPRESSED_BYTES} bytes once decompressed"
+    );
+    Ok(())
+}
+
+/// Splits the records of a partition into complete batches.
+///
+/// Brokers may cut the last batch of a fetch response short; such a partial batch is dropped,
+/// as clients would ignore it anyway.
+pub fn split_batches(records: &Bytes) -> Vec<Bytes> {
+    let mut batches = vec![];
+    let mut offset = 0;
+    while records.len() - offset >= LOG_OVERHEAD {
+        let length = (&records[offset + 8..offset + LOG_OVERHEAD]).get_i32();
+        let end = offset + LOG_OVERHEAD + length.max(0) as usize;
+        if length < 0 || end > records.len() {
+            break;
+        }
+        batches.push(records.slice(offset..end));
+        offset = end;
+    }
+    batches
+}
+
+/// Clears the producer id, epoch and base sequence of the batches of a partition.
+///
+/// The batches can then be appended by a cluster that does not know their producer. Only the
+/// CRCs are recomputed, the records are not decompressed. Legacy message sets are kept as is.
+pub fn without_producer(records: &Bytes) -> Bytes {
+    let mut out = BytesMut::with_capacity(records.len());
+    for batch in split_batches(records) {
+        let start = out.len();
+        out.put_slice(&batch);
+        if batch.len() < BATCH_HEADER_SIZE || batch[MAGIC_OFFSET] != 2 {
+            continue;
+        }
+        let mut producer = &mut out[start + PRODUCER_ID_OFFSET..start + BATCH_HEADER_SIZE];
+        producer.put_i64(-1);
+        producer.put_i16(-1);
+        producer.put_i32(-1);
+        let crc = CASTAGNOLI.checksum(&out[start + ATTRIBUTES_OFFSET..]);
+        out[start + MAGIC_OFFSET + 1..start + ATTRIBUTES_OFFSET]
+            .copy_from_slice(&crc.to_be_bytes());
+    }
+    out.freeze()
+}
+
+/// The fields of a batch header, readable without decompressing the records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchHeader {
+    /// Offset of the first record of the batch.
+    pub base_offset: i64,
+    /// Byte length of the batch, from the partition leader epoch to the end.
+    pub batch_length: i32,
+    /// Epoch of the partition leader that appended the batch.
+    pub partition_leader_epoch: i32,
+    /// Format of the batch, only 2 is supported.
+    pub magic: i8,
+    /// Compression, timestamp type, transactional and control flags.
+    pub attributes: i16,
+    /// Offset of the last record, relative to the base offset.
+    pub last_offset_delta: i32,
+    /// Timestamp of the first record.
+    pub base_timestamp: i64,
+    /// Largest timestamp of the batch.
+    pub max_timestamp: i64,
+    /// Identifier of an idempotent or transactional producer, or -1.
+    pub producer_id: i64,
+    /// Epoch of the producer, or -1.
+    pub producer_epoch: i16,
+    /// Sequence number of the first record, or -1.
+    pub base_sequence: i32,
+    /// Number of records in the batch.
+    pub records_count: i32,
+}
+
+impl BatchHeader {
+    /// Read the header of a batch.
+    pub fn peek(batch: &[u8]) -> Result<Self> {
+        ensure!(batch.len() > MAGIC_OFFSET, "truncated record batch");
+        let magic = batch[MAGIC_OFFSET] as i8;
+        ensure!(magic == 2, "unsupported record batch magic {magic}");
+        ensure!(batch.len() >= BATCH_HEADER_SIZE, "truncated record batch");
+
+        let mut buf = batch;
+        let base_offset = buf.get_i64();
+        let batch_length = buf.get_i32();
+        let partition_leader_epoch = buf.get_i32();
+        buf.advance(5); // magic and crc
+        Ok(Self {
+            base_offset,
+            batch_length,
+            partition_leader_epoch,
+            magic,
+            attributes: buf.get_i16(),
+            last_offset_delta: buf.get_i32(),
+            base_timestamp: buf.get_i64(),
+            max_timestamp: buf.get_i64(),
+            producer_id: buf.get_i64(),
+            producer_epoch: buf.get_i16(),
+            base_sequence: buf.get_i32(),
+            records_count: buf.get_i32(),
+        })
+    }
+
+    /// Returns the compression codec of the batch.
+    pub fn compression(&self) -> Result<Compression> {
+        Compression::from_attributes(self.attributes)
+    }
+
+    /// Returns whether the batch is part of a transaction.
+    pub fn is_transactional(&self) -> bool {
+        self.attributes & 0x10 != 0
+    }
+
+    /// Returns whether the batch holds transaction markers rather than user records.
+    pub fn is_control(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+}
+
+/// A header of a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordHeader {
+    /// The header key.
+    pub key: String,
+    /// The header value.
+    pub value: Option<Bytes>,
+}
+
+/// A record, with its offset and timestamp relative to its batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Unused record attributes.
+    pub attributes: i8,
+    /// Timestamp relative to the base timestamp of the batch.
+    pub timestamp_delta: i64,
+    /// Offset relative to the base offset of the batch.
+    pub offset_delta: i32,
+    /// The record key.
+    pub key: Option<Bytes>,
+    /// The record value.
+    pub value: Option<Bytes>,
+    /// The record headers.
+    pub headers: Vec<RecordHeader>,
+}
+
+impl Record {
+    fn decode(buf: &mut Bytes) -> Result<Self> {
+        let length = get_varint(buf)?;
+        ensure!(
+            length >= 0 && buf.remaining() >= length as usize,
+            "truncated record"
+        );
+        let mut buf = buf.split_to(length as usize);
+        ensure!(buf.has_remaining(), "truncated record");
+        let attributes = buf.get_i8();
+        let timestamp_delta = get_varlong(&mut buf)?;
+        let offset_delta = get_varint(&mut buf)?;
+        let key = get_bytes(&mut buf)?;
+        let value = get_bytes(&mut buf)?;
+        let count = get_varint(&mut buf)?;
+        // the count comes from the wire, every header takes at least a byte
+        let mut headers = Vec::with_capacity((count.max(0) as usize).min(buf.remaining()));
+        for _ in 0..count {
+            let key = get_bytes(&mut buf)?.context("null header key")?;
+            headers.push(RecordHeader {
+                key: String::from_utf8(key.to_vec()).context("invalid header key")?,
+                value: get_bytes(&mut buf)?,
+            });
+        }
+        Ok(Self {
+            attributes,
+            timestamp_delta,
+            offset_delta,
+            key,
+            value,
+            headers,
+        })
+    }
+
+    fn encode(&self, out: &mut BytesMut) {
+        let mut body = BytesMut::new();
+        body.put_i8(self.attributes);
+        put_varlong(&mut body, self.timestamp_delta);
+        put_varint(&mut body, self.offset_delta);
+        put_bytes(&mut body, self.key.as_deref());
+        put_bytes(&mut body, self.value.as_deref());
+        put_varint(&mut body, self.headers.len() as i32);
+        for header in &self.headers {
+            put_bytes(&mut body, Some(header.key.as_bytes()));
+            put_bytes(&mut body, header.value.as_deref());
+        }
+        put_varint(out, body.len() as i32);
+        out.put_slice(&body);
+    }
+}
+
+/// Why the records of a partition were rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRecord {
+    /// Index of the offending record within its batch, like the `batch_index` of Kafka's
+    /// `RecordError`, or `None` when the whole batch is at fault.
+    pub batch_index: Option<i32>,
+
+    /// Description of the error, sent back to the producer.
+    pub message: String,
+}
+
+/// A decoded record batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordBatch {
+    /// The batch header, written back as is apart from its length and CRC.
+    pub header: BatchHeader,
+    /// The records of the batch.
+    pub records: Vec<Record>,
+}
+
+impl RecordBatch {
+    /// Create an uncompressed batch of records with a key and a value, all with the same
+    /// timestamp, and without producer.
+    pub fn new(timestamp: i64, records: Vec<(Option<Bytes>, Option<Bytes>)>) -> Self {
+        Self {
+            header: BatchHeader {
+                base_offset: 0,
+                batch_length: 0,
+                partition_leader_epoch: -1,
+                magic: 2,
+                attributes: 0,
+                last_offset_delta: records.len().saturating_sub(1) as i32,
+                base_timestamp: timestamp,
+                max_timestamp: timestamp,
+                producer_id: -1,
+                producer_epoch: -1,
+                base_sequence: -1,
+                records_count: records.len() as i32,
+            },
+            records: records
+                .into_iter()
+                .enumerate()
+                .map(|(offset_delta, (key, value))| Record {
+                    attributes: 0,
+                    timestamp_delta: 0,
+                    offset_delta: offset_delta as i32,
+                    key,
+                    value,
+                    headers: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    /// Decode a single complete batch, checking its CRC.
+    pub fn decode(batch: &Bytes) -> Result<Self> {
+        let header = BatchHeader::peek(batch)?;
+        let end = LOG_OVERHEAD + header.batch_length.max(0) as usize;
+        ensure!(
+            batch.len() >= end && end >= BATCH_HEADER_SIZE,
+            "truncated record batch"
+        );
+        let crc = (&batch[MAGIC_OFFSET + 1..ATTRIBUTES_OFFSET]).get_u32();
+        ensure!(
+            CASTAGNOLI.checksum(&batch[ATTRIBUTES_OFFSET..end]) == crc,
+            "record batch CRC mismatch"
+        );
+
+        let payload = header
+            .compression()?
+            .decompress(&batch[BATCH_HEADER_SIZE..end])
+            .context("decompressing record batch")?;
+        let mut payload = Bytes::from(payload);
+        // the count comes from the wire, every record takes at least a byte
+        let count = (header.records_count.max(0) as usize).min(payload.remaining());
+        let mut records = Vec::with_capacity(count);
+        for _ in 0..header.records_count {
+            records.push(Record::decode(&mut payload)?);
+        }
+        Ok(Self { header, records })
+    }
+
+    /// Encode the batch with its original compression, updating its length, count and CRC.
+    pub fn encode(&self) -> Result<Bytes> {
+        let mut payload = BytesMut::new();
+        for record in &self.records {
+            record.encode(&mut payload);
+        }
+        let payload = self.header.compression()?.compress(&payload)?;
+
+        let header = &self.header;
+        let mut out = BytesMut::with_capacity(BATCH_HEADER_SIZE + payload.len());
+        out.put_i64(header.base_offset);
+        out.put_i32((BATCH_HEADER_SIZE - LOG_OVERHEAD + payload.len()) as i32);
+        out.put_i32(header.partition_leader_epoch);
+        out.put_i8(header.magic);
+        out.put_u32(0); // crc, filled below
+        out.put_i16(header.attributes);
+        out.put_i32(header.last_offset_delta);
+        out.put_i64(header.base_timestamp);
+        out.put_i64(header.max_timestamp);
+        out.put_i64(header.producer_id);
+        out.put_i16(header.producer_epoch);
+        out.put_i32(header.base_sequence);
+        out.put_i32(self.records.len() as i32);
+        out.put_slice(&payload);
+
+        let crc = CASTAGNOLI.checksum(&out[ATTRIBUTES_OFFSET..]);
+        out[MAGIC_OFFSET + 1..ATTRIBUTES_OFFSET].copy_from_slice(&crc.to_be_bytes());
+        Ok(out.freeze())
+    }
+}
+
+fn get_varlong(buf: &mut Bytes) -> Result<i64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        ensure!(buf.has_remaining(), "truncated varint");
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    bail!("invalid varint")
+}
+
+fn get_varint(buf: &mut Bytes) -> Result<i32> {
+    let value = get_varlong(buf)?;
+    i32::try_from(value).context("varint out of range")
+}
+
+fn get_bytes(buf: &mut Bytes) -> Result<Option<Bytes>> {
+    let length = get_varint(buf)?;
+    if length < 0 {
+        return Ok(None);
+    }
+    ensure!(buf.remaining() >= length as usize, "truncated record field");
+    Ok(Some(buf.split_to(length as usize)))
+}
+
+fn put_varlong(buf: &mut BytesMut, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn put_varint(buf: &mut BytesMut, value: i32) {
+    put_varlong(buf, value as i64);
+}
+
+fn put_bytes(buf: &mut BytesMut, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            put_varint(buf, bytes.len() as i32);
+            buf.put_slice(bytes);
+        }
+        None => put_varint(buf, -1),
+    }
+}
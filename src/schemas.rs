@@ -0,0 +1,232 @@
+//! Validation of produced record values against local schema files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use apache_avro::Schema as AvroSchema;
+use bytes::Bytes;
+use jsonschema::JSONSchema;
+use protobuf::reflect::{FileDescriptor, MessageDescriptor};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::records::{split_batches, BatchHeader, InvalidRecord, RecordBatch};
+
+/// Format of a schema file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SchemaType {
+    Json,
+    Avro,
+    Protobuf,
+}
+
+/// A schema as written in the schemas file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaConfig {
+    topic: String,
+    #[serde(rename = "type")]
+    schema_type: SchemaType,
+    schema: PathBuf,
+    message: Option<String>,
+}
+
+/// A compiled schema.
+enum Validator {
+    Json(Box<JSONSchema>),
+    Avro(Box<AvroSchema>),
+    Protobuf(MessageDescriptor),
+}
+
+/// A schema for the topics matching a regex.
+struct TopicSchema {
+    topic: Regex,
+    name: String,
+    validator: Validator,
+}
+
+/// Schemas that the values of produced records must conform to.
+///
+/// Schemas are listed in a JSON file, with paths relative to that file:
+///
+/// ```json
+/// [
+///   { "topic": "orders.*", "type": "json", "schema": "order.schema.json" },
+///   { "topic": "payments", "type": "avro", "schema": "payment.avsc" },
+///   { "topic": "users", "type": "protobuf", "schema": "user.proto", "message": "acme.User" }
+/// ]
+/// ```
+///
+/// The topic regex must match the whole topic name. Values must match every schema of their
+/// topic, and null values (tombstones) are always accepted.
+#[derive(Default)]
+pub struct SchemaValidation {
+    schemas: Vec<TopicSchema>,
+}
+
+impl SchemaValidation {
+    /// Load the schemas listed in a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading schemas from {}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_json(&json, base).with_context(|| format!("invalid schemas {}", path.display()))
+    }
+
+    /// Parse a list of schemas, resolving their paths against a base directory.
+    pub fn from_json(json: &str, base: &Path) -> Result<Self> {
+        let configs: Vec<SchemaConfig> = serde_json::from_str(json)?;
+        let schemas = configs
+            .into_iter()
+            .map(|config| {
+                let path = base.join(&config.schema);
+                TopicSchema::new(config, &path)
+                    .with_context(|| format!("invalid schema {}", path.display()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { schemas })
+    }
+
+    /// Returns whether any schema applies to a topic.
+    pub fn applies_to(&self, topic: &str) -> bool {
+        self.schemas
+            .iter()
+            .any(|schema| schema.topic.is_match(topic))
+    }
+
+    /// Check the values of the records produced to a topic partition.
+    ///
+    /// Records that cannot be decoded, such as legacy message sets, are rejected as well.
+    pub fn validate_records(&self, topic: &str, records: &Bytes) -> Result<(), InvalidRecord> {
+        let schemas: Vec<_> = self.schemas_for(topic).collect();
+        if schemas.is_empty() {
+            return Ok(());
+        }
+
+        for (batch_number, batch) in split_batches(records).into_iter().enumerate() {
+            let invalid = |message: String| InvalidRecord {
+                batch_index: None,
+                message,
+            };
+            match BatchHeader::peek(&batch) {
+                Ok(header) if header.is_control() => continue,
+                Ok(_) => (),
+                Err(err) => {
+                    return Err(invalid(format!(
+                        "undecodable record batch {batch_number}: {err}"
+                    )))
+                }
+            }
+            let decoded = RecordBatch::decode(&batch).map_err(|err| {
+                invalid(format!("undecodable record batch {batch_number}: {err:#}"))
+            })?;
+            for (index, record) in decoded.records.iter().enumerate() {
+                let index = index as i32;
+                if let Some(value) = &record.value {
+                    for schema in &schemas {
+                        schema.validate(value).map_err(|err| InvalidRecord {
+                            batch_index: Some(index),
+                            message: format!(
+                                "record {index} of batch {batch_number} does not match schema {}: {err}",
+                                schema.name
+                            ),
+                        })?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn schemas_for<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a TopicSchema> {
+        self.schemas
+            .iter()
+            .filter(move |schema| schema.topic.is_match(topic))
+    }
+}
+
+impl TopicSchema {
+    fn new(config: SchemaConfig, path: &Path) -> Result<Self> {
+        let topic = Regex::new(&format!("^(?:{})$", config.topic)).context("invalid topic")?;
+        if config.message.is_some() && config.schema_type != SchemaType::Protobuf {
+            bail!("`message` only applies to protobuf schemas");
+        }
+        let validator = match config.schema_type {
+            SchemaType::Json => {
+                let schema = serde_json::from_str(&fs::read_to_string(path)?)?;
+                let schema =
+                    JSONSchema::compile(&schema).map_err(|err| anyhow::anyhow!("{err}"))?;
+                Validator::Json(Box::new(schema))
+            }
+            SchemaType::Avro => {
+                Validator::Avro(Box::new(AvroSchema::parse_str(&fs::read_to_string(path)?)?))
+            }
+            SchemaType::Protobuf => {
+                let message = config
+                    .message
+                    .as_deref()
+                    .context("protobuf schemas require a `message`")?;
+                Validator::Protobuf(protobuf_message(path, message)?)
+            }
+        };
+        let name = config.schema.display().to_string();
+        Ok(Self {
+            topic,
+            name,
+            validator,
+        })
+    }
+
+    fn validate(&self, value: &[u8]) -> Result<(), String> {
+        match &self.validator {
+            Validator::Json(schema) => {
+                let value: serde_json::Value =
+                    serde_json::from_slice(value).map_err(|err| format!("invalid JSON: {err}"))?;
+                let result = schema.validate(&value);
+                if let Err(mut errors) = result {
+                    if let Some(err) = errors.next() {
+                        return Err(format!("{err} at `{}`", err.instance_path));
+                    }
+                }
+                Ok(())
+            }
+            Validator::Avro(schema) => {
+                let mut reader = value;
+                apache_avro::from_avro_datum(schema, &mut reader, None)
+                    .map_err(|err| format!("invalid Avro: {err}"))?;
+                if !reader.is_empty() {
+                    return Err(format!("{} trailing bytes after Avro datum", reader.len()));
+                }
+                Ok(())
+            }
+            Validator::Protobuf(descriptor) => {
+                let message = descriptor
+                    .parse_from_bytes(value)
+                    .map_err(|err| format!("invalid protobuf: {err}"))?;
+                if !message.is_initialized_dyn() {
+                    return Err("missing required protobuf fields".to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parse a `.proto` file, along with its imports, and find a message in it.
+fn protobuf_message(path: &Path, message: &str) -> Result<MessageDescriptor> {
+    let include = path.parent().unwrap_or_else(|| Path::new("."));
+    let parsed = protobuf_parse::Parser::new()
+        .pure()
+        .include(include)
+        .input(path)
+        .parse_and_typecheck()?;
+    let files = FileDescriptor::new_dynamic_fds(parsed.file_descriptors, &[])?;
+    files
+        .iter()
+        .find_map(|file| {
+            file.message_by_full_name(&format!(".{}", message.trim_start_matches('.')))
+        })
+        .with_context(|| format!("message {message} not found"))
+}
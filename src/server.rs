@@ -1,5 +1,6 @@
 //! Server implementation for the `bore` service.
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -8,13 +9,18 @@ use anyhow::Result;
 use dashmap::DashMap;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep, timeout};
 use tracing::{info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 use crate::auth::Authenticator;
 use crate::shared::{proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
 
+/// Interval between heartbeats on control connections.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
 /// State structure for the server.
 pub struct Server {
     /// The minimum TCP port that can be forwarded.
@@ -76,50 +82,41 @@ impl Server {
                 warn!("unexpected authenticate");
                 Ok(())
             }
-            Some(ClientMessage::Hello(port)) => {
-                if port != 0 && port < self.min_port {
-                    warn!(?port, "client port number too low");
+            Some(hello @ (ClientMessage::Hello(port) | ClientMessage::HelloWithPeers(port))) => {
+                let peers = matches!(hello, ClientMessage::HelloWithPeers(_));
+                let Some((listener, port)) = self.bind(&mut stream, port).await? else {
                     return Ok(());
-                }
-                info!(?port, "new client");
-                let listener = match TcpListener::bind(("0.0.0.0", port)).await {
-                    Ok(listener) => listener,
-                    Err(_) => {
-                        warn!(?port, "could not bind to local port");
-                        stream
-                            .send(ServerMessage::Error("port already in use".into()))
-                            .await?;
-                        return Ok(());
-                    }
                 };
-                let port = listener.local_addr()?.port();
-                stream.send(ServerMessage::Hello(port)).await?;
 
                 loop {
                     if stream.send(ServerMessage::Heartbeat).await.is_err() {
                         // Assume that the TCP connection has been dropped.
                         return Ok(());
                     }
-                    const TIMEOUT: Duration = Duration::from_millis(500);
-                    if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
+                    if let Ok(result) = timeout(HEARTBEAT_INTERVAL, listener.accept()).await {
                         let (stream2, addr) = result?;
                         info!(?addr, ?port, "new connection");
-
-                        let id = Uuid::new_v4();
-                        let conns = Arc::clone(&self.conns);
-
-                        conns.insert(id, stream2);
-                        tokio::spawn(async move {
-                            // Remove stale entries to avoid memory leaks.
-                            sleep(Duration::from_secs(10)).await;
-                            if conns.remove(&id).is_some() {
-                                warn!(%id, "removed stale connection");
-                            }
-                        });
-                        stream.send(ServerMessage::Connection(id)).await?;
+                        let id = self.store(stream2);
+                        // older clients cannot deserialize the peer address
+                        let message = if peers {
+                            ServerMessage::ConnectionFrom(id, addr)
+                        } else {
+                            ServerMessage::Connection(id)
+                        };
+                        stream.send(message).await?;
                     }
                 }
             }
+            Some(ClientMessage::HelloShared(port)) => {
+                let Some((listener, port)) = self.bind(&mut stream, port).await? else {
+                    return Ok(());
+                };
+                self.serve_shared(stream, listener, port).await
+            }
+            Some(ClientMessage::Open(_) | ClientMessage::Close(_)) => {
+                warn!("unexpected message before hello");
+                Ok(())
+            }
             Some(ClientMessage::Accept(id)) => {
                 info!(%id, "forwarding connection");
                 match self.conns.remove(&id) {
@@ -139,6 +136,130 @@ impl Server {
             }
         }
     }
+
+    /// Listen on a public port for a client, answering it with the port actually bound.
+    ///
+    /// Returns `None` if the port cannot be forwarded, after telling the client why.
+    async fn bind(
+        &self,
+        stream: &mut Delimited<TcpStream>,
+        port: u16,
+    ) -> Result<Option<(TcpListener, u16)>> {
+        if port != 0 && port < self.min_port {
+            warn!(?port, "client port number too low");
+            stream
+                .send(ServerMessage::Error("port number too low".into()))
+                .await?;
+            return Ok(None);
+        }
+        info!(?port, "new client");
+        let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => listener,
+            Err(_) => {
+                warn!(?port, "could not bind to local port");
+                stream
+                    .send(ServerMessage::Error("port already in use".into()))
+                    .await?;
+                return Ok(None);
+            }
+        };
+        let port = listener.local_addr()?.port();
+        stream.send(ServerMessage::Hello(port)).await?;
+        Ok(Some((listener, port)))
+    }
+
+    /// Store an incoming connection until the client accepts it, returning its id.
+    fn store(&self, stream2: TcpStream) -> Uuid {
+        let id = Uuid::new_v4();
+        let conns = Arc::clone(&self.conns);
+
+        conns.insert(id, stream2);
+        tokio::spawn(async move {
+            // Remove stale entries to avoid memory leaks.
+            sleep(Duration::from_secs(10)).await;
+            if conns.remove(&id).is_some() {
+                warn!(%id, "removed stale connection");
+            }
+        });
+        id
+    }
+
+    /// Forward the public ports a client opens and closes on a shared control connection, until
+    /// it is dropped.
+    async fn serve_shared(
+        &self,
+        mut stream: Delimited<TcpStream>,
+        listener: TcpListener,
+        port: u16,
+    ) -> Result<()> {
+        let (accepted_tx, mut accepted) = mpsc::channel(16);
+        // listening tasks are aborted once the control connection is dropped
+        let mut listening = JoinSet::new();
+        let mut listeners = HashMap::new();
+        listeners.insert(
+            port,
+            listening.spawn(accept(listener, port, accepted_tx.clone())),
+        );
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Some((stream2, addr, port)) = accepted.recv() => {
+                    info!(?addr, ?port, "new connection");
+                    let id = self.store(stream2);
+                    stream.send(ServerMessage::ConnectionTo(id, addr, port)).await?;
+                }
+                message = stream.recv() => match message? {
+                    // a port that cannot be opened fails its open only, the client was told why
+                    Some(ClientMessage::Open(port)) => {
+                        if let Some((listener, port)) = self.bind(&mut stream, port).await? {
+                            listeners.insert(
+                                port,
+                                listening.spawn(accept(listener, port, accepted_tx.clone())),
+                            );
+                        }
+                    }
+                    Some(ClientMessage::Close(port)) => {
+                        info!(?port, "closing port");
+                        if let Some(listener) = listeners.remove(&port) {
+                            listener.abort();
+                        }
+                    }
+                    Some(_) => {
+                        warn!("unexpected message on shared control connection");
+                        return Ok(());
+                    }
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Hand the connections accepted on a public port over to its shared control connection.
+async fn accept(
+    listener: TcpListener,
+    port: u16,
+    accepted: mpsc::Sender<(TcpStream, SocketAddr, u16)>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                if accepted.send((stream, addr, port)).await.is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                warn!(%err, ?port, "could not accept connection");
+                return;
+            }
+        }
+    }
 }
 
 impl Default for Server {
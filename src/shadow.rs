@@ -0,0 +1,329 @@
+//! Mirroring of produce requests to a secondary cluster, comparing its answers to the primary's.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+use kafka_protocol::messages::produce_request::TopicProduceData;
+use kafka_protocol::messages::{BrokerId, ProduceRequest, ProduceResponse, ResponseHeader};
+use kafka_protocol::protocol::{Decodable, HeaderVersion};
+use kafka_protocol::ResponseError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::kafka::decode_produce;
+use crate::kafka_client::{KafkaClient, REQUEST_TIMEOUT};
+use crate::records::without_producer;
+
+/// Number of produce requests waiting to be mirrored before new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A produce request to mirror, along with the response of the primary cluster, if any.
+struct Mirrored {
+    frame: Bytes,
+    primary: Option<oneshot::Receiver<Bytes>>,
+}
+
+/// Outcome of a partition written to a cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    /// The cluster answered with an error code, 0 for success.
+    Answered(i16),
+
+    /// The partition could not be written.
+    Failed(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Answered(0) => write!(f, "NONE"),
+            Self::Answered(code) => match ResponseError::try_from_code(*code) {
+                Some(error) => write!(f, "{error:?}"),
+                None => write!(f, "error code {code}"),
+            },
+            Self::Failed(err) => write!(f, "failed: {err}"),
+        }
+    }
+}
+
+/// Mirrors the produce requests of the proxied connections to a secondary cluster.
+///
+/// Copies are sent in the background, one request at a time so that records keep their order,
+/// and routed to the leaders of the secondary cluster. The error code of each partition is then
+/// compared to the one of the primary cluster, and differences are logged. The primary path
+/// never waits for the secondary: when the queue is full, copies are dropped.
+///
+/// Batches are stripped of their producer id, which belongs to the primary cluster, and
+/// transactional requests are not mirrored.
+pub struct ShadowWriter {
+    /// Bootstrap server of the secondary cluster.
+    bootstrap: String,
+
+    /// Requests waiting to be mirrored.
+    sender: mpsc::Sender<Mirrored>,
+
+    /// Receiving end of the queue, taken once running.
+    receiver: Mutex<Option<mpsc::Receiver<Mirrored>>>,
+}
+
+impl ShadowWriter {
+    /// Create a writer mirroring to the cluster at a bootstrap server.
+    pub fn new(bootstrap: &str) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        Self {
+            bootstrap: bootstrap.to_string(),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    /// Returns the bootstrap server of the secondary cluster.
+    pub fn bootstrap(&self) -> &str {
+        &self.bootstrap
+    }
+
+    /// Queue a copy of a produce request frame, length prefix included.
+    ///
+    /// When the primary cluster answers the request, its response frame should be sent to the
+    /// returned sender for comparison.
+    pub(crate) fn mirror(
+        &self,
+        frame: Bytes,
+        expects_response: bool,
+    ) -> Option<oneshot::Sender<Bytes>> {
+        let (primary, receiver) = if expects_response {
+            let (sender, receiver) = oneshot::channel();
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+        let mirrored = Mirrored {
+            frame,
+            primary: receiver,
+        };
+        match self.sender.try_send(mirrored) {
+            Ok(()) => primary,
+            Err(_) => {
+                warn!(secondary = %self.bootstrap, "shadow write queue full, dropping a copy");
+                None
+            }
+        }
+    }
+
+    /// Mirror the queued requests until the writer is dropped.
+    pub async fn run(self: Arc<Self>) {
+        let receiver = self.receiver.lock().unwrap().take();
+        let Some(mut receiver) = receiver else {
+            return;
+        };
+        let mut secondary = Secondary::new(&self.bootstrap);
+        while let Some(mirrored) = receiver.recv().await {
+            if let Err(err) = secondary.mirror(mirrored).await {
+                warn!(secondary = %self.bootstrap, "shadow write failed: {err:#}");
+            }
+        }
+    }
+}
+
+/// Connections to the secondary cluster, and the leaders of its partitions.
+struct Secondary {
+    bootstrap: String,
+    brokers: HashMap<BrokerId, String>,
+    leaders: HashMap<(String, i32), BrokerId>,
+    clients: HashMap<BrokerId, KafkaClient>,
+}
+
+impl Secondary {
+    fn new(bootstrap: &str) -> Self {
+        Self {
+            bootstrap: bootstrap.to_string(),
+            brokers: HashMap::new(),
+            leaders: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Write a copy of a request, then compare the outcome with the primary's.
+    async fn mirror(&mut self, mirrored: Mirrored) -> Result<()> {
+        let (version, _, mut request) =
+            decode_produce(&mirrored.frame).context("decoding produce request")?;
+        if request.transactional_id.is_some() {
+            debug!("transactional produce request not mirrored");
+            return Ok(());
+        }
+        // the copy is always acknowledged, to know its outcome
+        request.acks = request.acks.max(1);
+        for data in request.topic_data.values_mut() {
+            for partition in &mut data.partition_data {
+                partition.records = partition.records.as_ref().map(without_producer);
+            }
+        }
+        let secondary = self.write(version, request).await?;
+
+        let Some(primary) = mirrored.primary else {
+            return Ok(());
+        };
+        let primary = match timeout(REQUEST_TIMEOUT, primary).await {
+            Ok(Ok(frame)) => outcomes(version, frame)?,
+            _ => {
+                debug!("primary response not received, shadow write not compared");
+                return Ok(());
+            }
+        };
+        for ((topic, partition), secondary) in &secondary {
+            let primary = primary
+                .get(&(topic.clone(), *partition))
+                .cloned()
+                .unwrap_or_else(|| Outcome::Failed("partition missing".to_string()));
+            if primary != *secondary {
+                warn!(
+                    %topic,
+                    partition,
+                    %primary,
+                    %secondary,
+                    "shadow write differs from the primary"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the partitions of a request to their leaders, returning the outcome of each.
+    async fn write(
+        &mut self,
+        version: i16,
+        request: ProduceRequest,
+    ) -> Result<BTreeMap<(String, i32), Outcome>> {
+        let unknown: Vec<String> = request
+            .topic_data
+            .iter()
+            .filter(|(topic, data)| {
+                data.partition_data
+                    .iter()
+                    .any(|p| !self.leaders.contains_key(&(topic.0.to_string(), p.index)))
+            })
+            .map(|(topic, _)| topic.0.to_string())
+            .collect();
+        if !unknown.is_empty() {
+            self.refresh(&unknown).await?;
+        }
+
+        let mut outcomes = BTreeMap::new();
+        let mut by_leader: HashMap<BrokerId, ProduceRequest> = HashMap::new();
+        for (topic, data) in &request.topic_data {
+            for partition in &data.partition_data {
+                let key = (topic.0.to_string(), partition.index);
+                match self.leaders.get(&key) {
+                    Some(leader) => {
+                        let copy = by_leader.entry(*leader).or_insert_with(|| {
+                            let mut copy = request.clone();
+                            copy.topic_data.clear();
+                            copy
+                        });
+                        copy.topic_data
+                            .entry(topic.clone())
+                            .or_insert_with(TopicProduceData::default)
+                            .partition_data
+                            .push(partition.clone());
+                    }
+                    None => {
+                        outcomes.insert(key, Outcome::Failed("no leader".to_string()));
+                    }
+                }
+            }
+        }
+
+        for (leader, copy) in by_leader {
+            let response = match self.client(leader).await {
+                Ok(client) => client.send(&copy, version).await,
+                Err(err) => Err(err),
+            };
+            match response {
+                Ok(response) => {
+                    for (topic, data) in response.responses {
+                        for partition in data.partition_responses {
+                            let stale = [
+                                ResponseError::NotLeaderOrFollower.code(),
+                                ResponseError::UnknownTopicOrPartition.code(),
+                            ];
+                            let key = (topic.0.to_string(), partition.index);
+                            if stale.contains(&partition.error_code) {
+                                self.leaders.remove(&key);
+                            }
+                            outcomes.insert(key, Outcome::Answered(partition.error_code));
+                        }
+                    }
+                }
+                Err(err) => {
+                    self.clients.remove(&leader);
+                    for (topic, data) in &copy.topic_data {
+                        for partition in &data.partition_data {
+                            let key = (topic.0.to_string(), partition.index);
+                            self.leaders.remove(&key);
+                            outcomes.insert(key, Outcome::Failed(format!("{err:#}")));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Fetch the leaders of the partitions of some topics.
+    async fn refresh(&mut self, topics: &[String]) -> Result<()> {
+        let metadata = KafkaClient::connect(&self.bootstrap)
+            .await?
+            .topic_metadata(topics)
+            .await?;
+        for (id, broker) in &metadata.brokers {
+            self.brokers
+                .insert(*id, format!("{}:{}", &*broker.host, broker.port));
+        }
+        for (topic, data) in &metadata.topics {
+            for partition in &data.partitions {
+                if partition.leader_id.0 >= 0 {
+                    self.leaders.insert(
+                        (topic.0.to_string(), partition.partition_index),
+                        partition.leader_id,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the connection to a broker, opening it if needed.
+    async fn client(&mut self, id: BrokerId) -> Result<&mut KafkaClient> {
+        if !self.clients.contains_key(&id) {
+            let addr = self
+                .brokers
+                .get(&id)
+                .with_context(|| format!("unknown broker {}", id.0))?;
+            let client = KafkaClient::connect(addr).await?;
+            self.clients.insert(id, client);
+        }
+        Ok(self.clients.get_mut(&id).unwrap())
+    }
+}
+
+/// Read the outcome of each partition from a produce response frame, length prefix included.
+fn outcomes(version: i16, mut frame: Bytes) -> Result<HashMap<(String, i32), Outcome>> {
+    frame.advance(size_of::<u32>());
+    ResponseHeader::decode(&mut frame, ProduceResponse::header_version(version))?;
+    let response = ProduceResponse::decode(&mut frame, version)?;
+    let mut outcomes = HashMap::new();
+    for (topic, data) in response.responses {
+        for partition in data.partition_responses {
+            outcomes.insert(
+                (topic.0.to_string(), partition.index),
+                Outcome::Answered(partition.error_code),
+            );
+        }
+    }
+    Ok(outcomes)
+}
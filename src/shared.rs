@@ -1,12 +1,17 @@
 //! Shared data structures, utilities, and protocol definitions.
 
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
 
 use tokio::time::timeout;
 use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
@@ -33,6 +38,20 @@ pub enum ClientMessage {
 
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// Initial client message specifying a port to forward, from clients that understand
+    /// [`ServerMessage::ConnectionFrom`].
+    HelloWithPeers(u16),
+
+    /// Initial client message specifying a first port to forward, on a control connection that
+    /// can forward more ports and receives [`ServerMessage::ConnectionTo`].
+    HelloShared(u16),
+
+    /// Asks to forward another port on a shared control connection.
+    Open(u16),
+
+    /// Stops forwarding a public port of a shared control connection.
+    Close(u16),
 }
 
 /// A message from the server on the control connection.
@@ -41,7 +60,8 @@ pub enum ServerMessage {
     /// Authentication challenge, sent as the first message, if enabled.
     Challenge(Uuid),
 
-    /// Response to a client's initial message, with actual public port.
+    /// Response to a client's initial message or to an [`ClientMessage::Open`], with actual
+    /// public port.
     Hello(u16),
 
     /// No-op used to test if the client is still reachable.
@@ -50,7 +70,18 @@ pub enum ServerMessage {
     /// Asks the client to accept a forwarded TCP connection.
     Connection(Uuid),
 
-    /// Indicates a server error that terminates the connection.
+    /// Asks the client to accept a forwarded TCP connection, from a remote peer address.
+    ///
+    /// Only sent to clients that said hello with [`ClientMessage::HelloWithPeers`], as older
+    /// clients cannot deserialize it.
+    ConnectionFrom(Uuid, SocketAddr),
+
+    /// Asks the client to accept a forwarded TCP connection, from a remote peer address, on one
+    /// of the public ports of a shared control connection.
+    ConnectionTo(Uuid, SocketAddr, u16),
+
+    /// Indicates a server error that terminates the connection, or on a shared control
+    /// connection, that fails the oldest [`ClientMessage::Open`] not yet answered.
     Error(String),
 }
 
@@ -114,3 +145,57 @@ where
     }?;
     Ok(())
 }
+
+/// Stream wrapper counting the bytes read from and written to the inner stream.
+pub struct Counted<S> {
+    inner: S,
+    read: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl<S> Counted<S> {
+    /// Wrap a stream, adding every byte read and written to counters.
+    pub fn new(inner: S, read: Arc<AtomicU64>, written: Arc<AtomicU64>) -> Self {
+        Self {
+            inner,
+            read,
+            written,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.read.fetch_add(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.written.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
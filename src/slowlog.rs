@@ -0,0 +1,277 @@
+//! Slow-request and error-response logging for the Kafka proxy.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use kafka_protocol::messages::*;
+use kafka_protocol::protocol::Decodable;
+use tokio::time::interval_at;
+use tracing::{info, warn};
+
+/// Maximum number of entries kept in the sliding window.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Number of offenders printed in a summary.
+const SUMMARY_SIZE: usize = 10;
+
+/// A proxied request that was slower than the threshold or answered with an error.
+#[derive(Debug, Clone)]
+pub struct SlowRequest {
+    /// The API key of this request.
+    pub api_key: ApiKey,
+
+    /// The API version of this request.
+    pub api_version: i16,
+
+    /// The correlation id of this request.
+    pub correlation_id: i32,
+
+    /// The client id sent in the request header.
+    pub client_id: Option<String>,
+
+    /// Time between forwarding the request and receiving its response.
+    pub elapsed: Duration,
+
+    /// First non-zero error code found in the response, or zero.
+    pub error_code: i16,
+}
+
+/// Aggregated slow or failed requests of one client for one API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offender {
+    /// The client id sent in the request headers.
+    pub client_id: Option<String>,
+
+    /// The API key of the requests.
+    pub api_key: ApiKey,
+
+    /// Number of slow or failed requests in the window.
+    pub count: usize,
+
+    /// Number of requests answered with an error in the window.
+    pub errors: usize,
+
+    /// Slowest response time in the window.
+    pub max_elapsed: Duration,
+}
+
+/// Sliding window of slow and failed requests.
+pub struct SlowLog {
+    /// Requests taking longer than this are logged.
+    threshold: Duration,
+
+    /// How long entries are kept for the summary.
+    window: Duration,
+
+    /// Entries in the window, oldest first.
+    entries: Mutex<VecDeque<(Instant, SlowRequest)>>,
+}
+
+impl SlowLog {
+    /// Create a new slow log with a latency threshold and a summary window.
+    pub fn new(threshold: Duration, window: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Record a completed request, logging and keeping it if it was slow or failed.
+    pub fn record(&self, request: SlowRequest) {
+        let slow = request.elapsed >= self.threshold;
+        if !slow && request.error_code == 0 {
+            return;
+        }
+        warn!(
+            api_key = ?request.api_key,
+            api_version = request.api_version,
+            correlation_id = request.correlation_id,
+            client_id = request.client_id.as_deref().unwrap_or(""),
+            elapsed_ms = request.elapsed.as_millis() as u64,
+            error_code = request.error_code,
+            "{}",
+            if slow { "slow request" } else { "error response" }
+        );
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        Self::prune(&mut entries, now, self.window);
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back((now, request));
+    }
+
+    /// Returns the worst offenders in the window, slowest first.
+    pub fn worst_offenders(&self, limit: usize) -> Vec<Offender> {
+        let mut entries = self.entries.lock().unwrap();
+        Self::prune(&mut entries, Instant::now(), self.window);
+
+        let mut offenders: HashMap<(Option<String>, i16), Offender> = HashMap::new();
+        for (_, request) in entries.iter() {
+            let offender = offenders
+                .entry((request.client_id.clone(), request.api_key as i16))
+                .or_insert_with(|| Offender {
+                    client_id: request.client_id.clone(),
+                    api_key: request.api_key,
+                    count: 0,
+                    errors: 0,
+                    max_elapsed: Duration::ZERO,
+                });
+            offender.count += 1;
+            if request.error_code != 0 {
+                offender.errors += 1;
+            }
+            offender.max_elapsed = offender.max_elapsed.max(request.elapsed);
+        }
+
+        let mut offenders: Vec<_> = offenders.into_values().collect();
+        offenders.sort_by(|a, b| {
+            b.max_elapsed
+                .cmp(&a.max_elapsed)
+                .then(b.count.cmp(&a.count))
+        });
+        offenders.truncate(limit);
+        offenders
+    }
+
+    /// Print a summary of the worst offenders in the window.
+    pub fn log_summary(&self) {
+        let offenders = self.worst_offenders(SUMMARY_SIZE);
+        info!(
+            window_secs = self.window.as_secs(),
+            offenders = offenders.len(),
+            "slow request summary"
+        );
+        for offender in offenders {
+            info!(
+                client_id = offender.client_id.as_deref().unwrap_or(""),
+                api_key = ?offender.api_key,
+                count = offender.count,
+                errors = offender.errors,
+                max_elapsed_ms = offender.max_elapsed.as_millis() as u64,
+                "slow request offender"
+            );
+        }
+    }
+
+    /// Print the summary periodically, and on `SIGUSR1` where supported.
+    pub async fn report(self: Arc<Self>, period: Option<Duration>) -> Result<()> {
+        #[cfg(unix)]
+        let mut usr1 =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        let mut ticker = period.map(|period| interval_at((Instant::now() + period).into(), period));
+
+        loop {
+            let tick = async {
+                match ticker.as_mut() {
+                    Some(ticker) => {
+                        ticker.tick().await;
+                    }
+                    None => futures::future::pending::<()>().await,
+                }
+            };
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tick => (),
+                _ = usr1.recv() => (),
+            }
+            #[cfg(not(unix))]
+            tick.await;
+
+            self.log_summary();
+        }
+    }
+
+    fn prune(entries: &mut VecDeque<(Instant, SlowRequest)>, now: Instant, window: Duration) {
+        while let Some((at, _)) = entries.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            entries.pop_front();
+        }
+    }
+}
+
+/// Returns the first non-zero error code carried by a response body, or zero.
+///
+/// Only the most common APIs are inspected, other responses are reported as successful. The
+/// records of fetch responses are left as they are, only the errors of their partitions are read.
+pub(crate) fn response_error_code(
+    api_key: ApiKey,
+    api_version: i16,
+    mut body: Bytes,
+) -> Result<i16> {
+    let buf = &mut body;
+    ResponseHeader::decode(buf, api_key.response_header_version(api_version))?;
+    let codes: Vec<i16> = match api_key {
+        ApiKey::ProduceKey => ProduceResponse::decode(buf, api_version)?
+            .responses
+            .values()
+            .flat_map(|topic| topic.partition_responses.iter().map(|p| p.error_code))
+            .collect(),
+        ApiKey::FetchKey => {
+            let response = FetchResponse::decode(buf, api_version)?;
+            let partitions = response
+                .responses
+                .iter()
+                .flat_map(|topic| topic.partitions.iter().map(|p| p.error_code));
+            std::iter::once(response.error_code)
+                .chain(partitions)
+                .collect()
+        }
+        ApiKey::ListOffsetsKey => ListOffsetsResponse::decode(buf, api_version)?
+            .topics
+            .iter()
+            .flat_map(|topic| topic.partitions.iter().map(|p| p.error_code))
+            .collect(),
+        ApiKey::MetadataKey => MetadataResponse::decode(buf, api_version)?
+            .topics
+            .values()
+            .map(|topic| topic.error_code)
+            .collect(),
+        ApiKey::OffsetCommitKey => OffsetCommitResponse::decode(buf, api_version)?
+            .topics
+            .iter()
+            .flat_map(|topic| topic.partitions.iter().map(|p| p.error_code))
+            .collect(),
+        ApiKey::OffsetFetchKey => {
+            let response = OffsetFetchResponse::decode(buf, api_version)?;
+            let partitions = response
+                .topics
+                .iter()
+                .flat_map(|topic| topic.partitions.iter().map(|p| p.error_code));
+            let groups = response.groups.iter().map(|group| group.error_code);
+            std::iter::once(response.error_code)
+                .chain(partitions)
+                .chain(groups)
+                .collect()
+        }
+        ApiKey::FindCoordinatorKey => {
+            let response = FindCoordinatorResponse::decode(buf, api_version)?;
+            let coordinators = response.coordinators.iter().map(|c| c.error_code);
+            std::iter::once(response.error_code)
+                .chain(coordinators)
+                .collect()
+        }
+        ApiKey::JoinGroupKey => vec![JoinGroupResponse::decode(buf, api_version)?.error_code],
+        ApiKey::HeartbeatKey => vec![HeartbeatResponse::decode(buf, api_version)?.error_code],
+        ApiKey::LeaveGroupKey => vec![LeaveGroupResponse::decode(buf, api_version)?.error_code],
+        ApiKey::SyncGroupKey => vec![SyncGroupResponse::decode(buf, api_version)?.error_code],
+        ApiKey::ApiVersionsKey => vec![ApiVersionsResponse::decode(buf, api_version)?.error_code],
+        ApiKey::CreateTopicsKey => CreateTopicsResponse::decode(buf, api_version)?
+            .topics
+            .values()
+            .map(|topic| topic.error_code)
+            .collect(),
+        ApiKey::InitProducerIdKey => {
+            vec![InitProducerIdResponse::decode(buf, api_version)?.error_code]
+        }
+        _ => vec![],
+    };
+    Ok(codes.into_iter().find(|code| *code != 0).unwrap_or(0))
+}
@@ -0,0 +1,241 @@
+//! Publication of the traffic statistics of a proxy to a topic of the cluster it exposes.
+
+use std::collections::BTreeMap;
+use std::slice;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::time::{interval_at, Instant};
+use tracing::{debug, warn};
+
+use crate::accounting::Direction;
+use crate::inventory::ClientInfo;
+use crate::kafka::{KafkaProxy, TunnelInfo};
+use crate::kafka_client::KafkaClient;
+use crate::records::RecordBatch;
+
+/// Seconds between two statistics records, unless configured.
+pub const DEFAULT_STATS_INTERVAL_SECS: u64 = 60;
+
+/// Partition the statistics are written to.
+const PARTITION: i32 = 0;
+
+/// Bytes and records produced and fetched on a topic, all clients and partitions together.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TopicThroughput {
+    /// Bytes of record batches produced by remote clients.
+    pub produced_bytes: u64,
+
+    /// Records produced by remote clients.
+    pub produced_records: u64,
+
+    /// Bytes of record batches fetched by remote clients.
+    pub fetched_bytes: u64,
+
+    /// Records fetched by remote clients.
+    pub fetched_records: u64,
+}
+
+/// Statistics of a proxied cluster, as published.
+///
+/// Counters are totals since the proxy started, closed connections included, so that rates are
+/// computed from consecutive records.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyStats {
+    /// Name of the cluster.
+    pub cluster: String,
+
+    /// When the statistics were collected, in milliseconds since the epoch.
+    pub timestamp_ms: u64,
+
+    /// Requests received from remote clients, by API name.
+    pub requests: BTreeMap<String, u64>,
+
+    /// Connections currently proxied.
+    pub connections: u64,
+
+    /// Connections proxied since the proxy started.
+    pub connections_total: u64,
+
+    /// Bytes received from remote clients.
+    pub bytes_received: u64,
+
+    /// Bytes sent back to remote clients.
+    pub bytes_sent: u64,
+
+    /// Traffic by topic, only counted with topic accounting.
+    pub topics: BTreeMap<String, TopicThroughput>,
+
+    /// The remote clients seen in proxied requests, most recently seen first.
+    pub clients: Vec<ClientInfo>,
+
+    /// The tunnels of the brokers, sorted by remote port.
+    pub tunnels: Vec<TunnelInfo>,
+
+    /// Whether the proxy is started, with every tunnel open.
+    pub healthy: bool,
+
+    /// When the cluster topology was last seen in a metadata response, in seconds since the
+    /// epoch.
+    pub last_metadata_refresh: Option<u64>,
+}
+
+impl ProxyStats {
+    /// Collect the statistics of a proxy.
+    pub fn collect(proxy: &KafkaProxy) -> Self {
+        let active = proxy.active_connections();
+        let totals = proxy.totals();
+        let mut topics: BTreeMap<String, TopicThroughput> = BTreeMap::new();
+        for (key, counts) in proxy.accounting().map(|a| a.snapshot()).unwrap_or_default() {
+            let topic = topics.entry(key.topic).or_default();
+            match key.direction {
+                Direction::Produced => {
+                    topic.produced_bytes += counts.bytes;
+                    topic.produced_records += counts.records;
+                }
+                Direction::Fetched => {
+                    topic.fetched_bytes += counts.bytes;
+                    topic.fetched_records += counts.records;
+                }
+            }
+        }
+        let tunnels = proxy.tunnels();
+        Self {
+            cluster: proxy.name().to_string(),
+            timestamp_ms: since_epoch(SystemTime::now()).as_millis() as u64,
+            requests: proxy.request_counts(),
+            connections: active.len() as u64,
+            connections_total: totals.connections.load(Ordering::Relaxed),
+            bytes_received: active.iter().map(|c| c.bytes_received).sum::<u64>()
+                + totals.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: active.iter().map(|c| c.bytes_sent).sum::<u64>()
+                + totals.bytes_sent.load(Ordering::Relaxed),
+            topics,
+            clients: proxy.clients(),
+            healthy: proxy.bootstrap_server().is_some()
+                && !tunnels.is_empty()
+                && tunnels.iter().all(|tunnel| tunnel.state == "open"),
+            tunnels,
+            last_metadata_refresh: proxy
+                .last_metadata_refresh()
+                .map(|time| since_epoch(time).as_secs()),
+        }
+    }
+
+    /// Encode the statistics as a record batch of a single record, keyed by the cluster name.
+    pub fn encode(&self) -> Result<Bytes> {
+        let value = serde_json::to_vec(self)?;
+        let key = Bytes::from(self.cluster.clone());
+        RecordBatch::new(
+            self.timestamp_ms as i64,
+            vec![(Some(key), Some(value.into()))],
+        )
+        .encode()
+    }
+}
+
+/// Publishes the statistics of a proxy as JSON records to a topic of the cluster it exposes.
+///
+/// Records are written to the first partition of the topic, keyed by the cluster name, through
+/// a connection of its own to the partition leader. A round that fails is logged and skipped,
+/// the leader being looked up again on the next one.
+pub struct StatsPublisher {
+    /// Topic the statistics are written to.
+    topic: String,
+
+    /// Time between two records.
+    period: Duration,
+
+    /// Connection to the leader of the partition, once found.
+    leader: Option<KafkaClient>,
+}
+
+impl StatsPublisher {
+    /// Create a publisher writing to a topic every period.
+    pub fn new(topic: &str, period: Duration) -> Self {
+        Self {
+            topic: topic.to_string(),
+            period,
+            leader: None,
+        }
+    }
+
+    /// Publish the statistics of a proxy every period, once it is started.
+    pub async fn run(mut self, proxy: Arc<KafkaProxy>) {
+        let mut ticker = interval_at(Instant::now() + self.period, self.period);
+        loop {
+            ticker.tick().await;
+            let Some(bootstrap) = proxy.bootstrap_server() else {
+                debug!(
+                    cluster = proxy.name(),
+                    "proxy not started, statistics not published"
+                );
+                continue;
+            };
+            let stats = ProxyStats::collect(&proxy);
+            if let Err(err) = self.publish(&bootstrap, &stats).await {
+                warn!(
+                    cluster = proxy.name(),
+                    topic = %self.topic,
+                    "could not publish statistics: {err:#}"
+                );
+            }
+        }
+    }
+
+    /// Write the statistics to the topic of the cluster at a bootstrap server.
+    pub async fn publish(&mut self, bootstrap: &str, stats: &ProxyStats) -> Result<()> {
+        let records = stats.encode()?;
+        let leader = match &mut self.leader {
+            Some(leader) => leader,
+            None => self.leader.insert(self.connect(bootstrap).await?),
+        };
+        let written = leader.produce(&self.topic, PARTITION, records).await;
+        if written.is_err() {
+            self.leader = None;
+        }
+        written.map(|_| ())
+    }
+
+    /// Connect to the leader of the partition.
+    async fn connect(&self, bootstrap: &str) -> Result<KafkaClient> {
+        let metadata = KafkaClient::connect(bootstrap)
+            .await?
+            .topic_metadata(slice::from_ref(&self.topic))
+            .await?;
+        let topic = metadata
+            .topics
+            .iter()
+            .find(|(name, _)| *name.0 == *self.topic)
+            .map(|(_, topic)| topic)
+            .with_context(|| format!("topic {} not found", self.topic))?;
+        if topic.error_code != 0 {
+            bail!(
+                "metadata of topic {} failed with error code {}",
+                self.topic,
+                topic.error_code
+            );
+        }
+        let leader = topic
+            .partitions
+            .iter()
+            .find(|partition| partition.partition_index == PARTITION)
+            .map(|partition| partition.leader_id)
+            .filter(|leader| leader.0 >= 0)
+            .with_context(|| format!("no leader for {}-{PARTITION}", self.topic))?;
+        let broker = metadata
+            .brokers
+            .get(&leader)
+            .with_context(|| format!("unknown broker {}", leader.0))?;
+        KafkaClient::connect(&format!("{}:{}", &*broker.host, broker.port)).await
+    }
+}
+
+/// Time elapsed since the epoch.
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
@@ -0,0 +1,124 @@
+//! Policy checked by the proxy on the topics created by remote clients.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::result;
+
+use anyhow::{Context, Result};
+use kafka_protocol::messages::create_topics_request::CreatableTopic;
+use regex::Regex;
+use serde::Deserialize;
+
+/// The policy as written in the policy file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyConfig {
+    max_partitions: Option<i32>,
+    replication_factor: Option<i16>,
+    allowed_configs: Option<Vec<String>>,
+    name_pattern: Option<String>,
+}
+
+/// Limits on the topics created through the tunnels, checked before `CreateTopics` and
+/// `CreatePartitions` requests are forwarded.
+///
+/// The policy is loaded from a JSON object, whose fields are all optional:
+///
+/// ```json
+/// {
+///   "max_partitions": 12,
+///   "replication_factor": 3,
+///   "allowed_configs": ["retention.ms", "cleanup.policy"],
+///   "name_pattern": "[a-z][a-z0-9._-]*"
+/// }
+/// ```
+///
+/// The name pattern must match the whole topic name. Topics relying on the broker default
+/// partition count are accepted, but not those relying on its default replication factor
+/// when one is required.
+#[derive(Debug)]
+pub struct TopicPolicy {
+    max_partitions: Option<i32>,
+    replication_factor: Option<i16>,
+    allowed_configs: Option<HashSet<String>>,
+    name_pattern: Option<(String, Regex)>,
+}
+
+impl TopicPolicy {
+    /// Load the policy from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("reading topic policy from {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("invalid topic policy {}", path.display()))
+    }
+
+    /// Parse the policy from a JSON object.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let config: PolicyConfig = serde_json::from_str(json)?;
+        let name_pattern = match config.name_pattern {
+            Some(pattern) => {
+                let regex =
+                    Regex::new(&format!("^(?:{pattern})$")).context("invalid name_pattern")?;
+                Some((pattern, regex))
+            }
+            None => None,
+        };
+        Ok(Self {
+            max_partitions: config.max_partitions,
+            replication_factor: config.replication_factor,
+            allowed_configs: config
+                .allowed_configs
+                .map(|configs| configs.into_iter().collect()),
+            name_pattern,
+        })
+    }
+
+    /// Check a topic to create, returning the violation of the policy, if any.
+    pub fn check_topic(&self, name: &str, topic: &CreatableTopic) -> result::Result<(), String> {
+        if let Some((pattern, regex)) = &self.name_pattern {
+            if !regex.is_match(name) {
+                return Err(format!(
+                    "Topic name {name} does not match the pattern {pattern}."
+                ));
+            }
+        }
+        // explicit assignments replace the partition count and replication factor
+        let (partitions, replication_factor) = match topic.assignments.values().next() {
+            Some(assignment) => (
+                topic.assignments.len() as i32,
+                assignment.broker_ids.len() as i16,
+            ),
+            None => (topic.num_partitions, topic.replication_factor),
+        };
+        self.check_partitions(name, partitions)?;
+        if let Some(required) = self.replication_factor {
+            if replication_factor != required {
+                return Err(match replication_factor {
+                    -1 => format!("Topic {name} must set a replication factor of {required}."),
+                    _ => format!(
+                        "Topic {name} has a replication factor of {replication_factor} instead of {required}."
+                    ),
+                });
+            }
+        }
+        if let Some(allowed) = &self.allowed_configs {
+            if let Some(key) = topic.configs.keys().find(|key| !allowed.contains(&***key)) {
+                return Err(format!("Config {} is not allowed on topic {name}.", &**key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the partition count of a topic, returning the violation of the policy, if any.
+    ///
+    /// A count of -1 stands for the broker default, which is accepted.
+    pub fn check_partitions(&self, name: &str, partitions: i32) -> result::Result<(), String> {
+        match self.max_partitions {
+            Some(max) if partitions > max => Err(format!(
+                "Topic {name} would have {partitions} partitions, more than the maximum of {max}."
+            )),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Minimum API versions required from remote clients.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use kafka_protocol::messages::*;
+use kafka_protocol::protocol::{Message, VersionRange};
+use tracing::warn;
+
+use crate::kafka::parse_api_key;
+
+/// Maximum number of rejected clients remembered, so that each is only logged once.
+const MAX_REJECTED: usize = 10_000;
+
+/// Requests below a minimum version per API, rejected by the proxy with `UNSUPPORTED_VERSION`.
+///
+/// Minimums are given as `API=VERSION`, for instance `Produce=3` and `Fetch=4` to spare the
+/// brokers the down-conversion of records for old clients. The `ApiVersions` responses of the
+/// brokers are trimmed to the allowed versions, so that compliant clients never negotiate
+/// older ones. Each client sending a rejected request is logged once.
+#[derive(Debug, Default)]
+pub struct VersionPolicy {
+    /// Minimum version by API key.
+    minimums: BTreeMap<i16, i16>,
+
+    /// Client ids and addresses already logged.
+    rejected: Mutex<HashSet<(String, Option<IpAddr>)>>,
+}
+
+impl VersionPolicy {
+    /// Parse minimum versions given as `API=VERSION`.
+    pub fn parse<S: AsRef<str>>(specs: &[S]) -> Result<Self> {
+        let mut minimums = BTreeMap::new();
+        for spec in specs {
+            let spec = spec.as_ref();
+            let (api, version) = spec
+                .split_once('=')
+                .with_context(|| format!("invalid minimum {spec:?}, expected API=VERSION"))?;
+            let api_key = parse_api_key(api)?;
+            let version: i16 = version
+                .parse()
+                .with_context(|| format!("invalid version in {spec:?}"))?;
+            ensure!(version >= 0, "invalid version in {spec:?}");
+            minimums.insert(api_key as i16, version);
+        }
+        Ok(Self {
+            minimums,
+            rejected: Mutex::default(),
+        })
+    }
+
+    /// Returns the minimum version of an API, if any.
+    pub fn minimum(&self, api_key: ApiKey) -> Option<i16> {
+        self.minimums.get(&(api_key as i16)).copied()
+    }
+
+    /// Returns whether a request version is allowed.
+    pub fn allows(&self, api_key: ApiKey, version: i16) -> bool {
+        self.minimum(api_key)
+            .is_none_or(|minimum| version >= minimum)
+    }
+
+    /// Log a rejected request, once per client.
+    pub fn reject(
+        &self,
+        client_id: Option<&str>,
+        peer_ip: Option<IpAddr>,
+        api_key: ApiKey,
+        version: i16,
+    ) {
+        let client_id = client_id.unwrap_or_default();
+        let mut rejected = self.rejected.lock().unwrap();
+        if rejected.len() >= MAX_REJECTED || !rejected.insert((client_id.to_string(), peer_ip)) {
+            return;
+        }
+        warn!(
+            client_id,
+            peer_ip = ?peer_ip,
+            ?api_key,
+            version,
+            minimum = self.minimum(api_key).unwrap_or_default(),
+            "rejecting requests below the minimum version"
+        );
+    }
+
+    /// Remove the disallowed versions from an `ApiVersions` response.
+    ///
+    /// APIs whose every supported version is below the minimum are removed.
+    pub fn trim(&self, response: &mut ApiVersionsResponse) {
+        response.api_keys.retain(|api_key, versions| {
+            let Some(&minimum) = self.minimums.get(api_key) else {
+                return true;
+            };
+            versions.min_version = versions.min_version.max(minimum);
+            versions.min_version <= versions.max_version
+        });
+    }
+}
+
+/// Returns the versions of the requests and responses of an API that the proxy can decode.
+pub fn decodable_versions(api_key: ApiKey) -> VersionRange {
+    match api_key {
+        ApiKey::ProduceKey => ProduceRequest::VERSIONS,
+        ApiKey::FetchKey => FetchRequest::VERSIONS,
+        ApiKey::ListOffsetsKey => ListOffsetsRequest::VERSIONS,
+        ApiKey::MetadataKey => MetadataRequest::VERSIONS,
+        ApiKey::LeaderAndIsrKey => LeaderAndIsrRequest::VERSIONS,
+        ApiKey::StopReplicaKey => StopReplicaRequest::VERSIONS,
+        ApiKey::UpdateMetadataKey => UpdateMetadataRequest::VERSIONS,
+        ApiKey::ControlledShutdownKey => ControlledShutdownRequest::VERSIONS,
+        ApiKey::OffsetCommitKey => OffsetCommitRequest::VERSIONS,
+        ApiKey::OffsetFetchKey => OffsetFetchRequest::VERSIONS,
+        ApiKey::FindCoordinatorKey => FindCoordinatorRequest::VERSIONS,
+        ApiKey::JoinGroupKey => JoinGroupRequest::VERSIONS,
+        ApiKey::HeartbeatKey => HeartbeatRequest::VERSIONS,
+        ApiKey::LeaveGroupKey => LeaveGroupRequest::VERSIONS,
+        ApiKey::SyncGroupKey => SyncGroupRequest::VERSIONS,
+        ApiKey::DescribeGroupsKey => DescribeGroupsRequest::VERSIONS,
+        ApiKey::ListGroupsKey => ListGroupsRequest::VERSIONS,
+        ApiKey::SaslHandshakeKey => SaslHandshakeRequest::VERSIONS,
+        ApiKey::ApiVersionsKey => ApiVersionsRequest::VERSIONS,
+        ApiKey::CreateTopicsKey => CreateTopicsRequest::VERSIONS,
+        ApiKey::DeleteTopicsKey => DeleteTopicsRequest::VERSIONS,
+        ApiKey::DeleteRecordsKey => DeleteRecordsRequest::VERSIONS,
+        ApiKey::InitProducerIdKey => InitProducerIdRequest::VERSIONS,
+        ApiKey::OffsetForLeaderEpochKey => OffsetForLeaderEpochRequest::VERSIONS,
+        ApiKey::AddPartitionsToTxnKey => AddPartitionsToTxnRequest::VERSIONS,
+        ApiKey::AddOffsetsToTxnKey => AddOffsetsToTxnRequest::VERSIONS,
+        ApiKey::EndTxnKey => EndTxnRequest::VERSIONS,
+        ApiKey::WriteTxnMarkersKey => WriteTxnMarkersRequest::VERSIONS,
+        ApiKey::TxnOffsetCommitKey => TxnOffsetCommitRequest::VERSIONS,
+        ApiKey::DescribeAclsKey => DescribeAclsRequest::VERSIONS,
+        ApiKey::CreateAclsKey => CreateAclsRequest::VERSIONS,
+        ApiKey::DeleteAclsKey => DeleteAclsRequest::VERSIONS,
+        ApiKey::DescribeConfigsKey => DescribeConfigsRequest::VERSIONS,
+        ApiKey::AlterConfigsKey => AlterConfigsRequest::VERSIONS,
+        ApiKey::AlterReplicaLogDirsKey => AlterReplicaLogDirsRequest::VERSIONS,
+        ApiKey::DescribeLogDirsKey => DescribeLogDirsRequest::VERSIONS,
+        ApiKey::SaslAuthenticateKey => SaslAuthenticateRequest::VERSIONS,
+        ApiKey::CreatePartitionsKey => CreatePartitionsRequest::VERSIONS,
+        ApiKey::CreateDelegationTokenKey => CreateDelegationTokenRequest::VERSIONS,
+        ApiKey::RenewDelegationTokenKey => RenewDelegationTokenRequest::VERSIONS,
+        ApiKey::ExpireDelegationTokenKey => ExpireDelegationTokenRequest::VERSIONS,
+        ApiKey::DescribeDelegationTokenKey => DescribeDelegationTokenRequest::VERSIONS,
+        ApiKey::DeleteGroupsKey => DeleteGroupsRequest::VERSIONS,
+        ApiKey::ElectLeadersKey => ElectLeadersRequest::VERSIONS,
+        ApiKey::IncrementalAlterConfigsKey => IncrementalAlterConfigsRequest::VERSIONS,
+        ApiKey::AlterPartitionReassignmentsKey => AlterPartitionReassignmentsRequest::VERSIONS,
+        ApiKey::ListPartitionReassignmentsKey => ListPartitionReassignmentsRequest::VERSIONS,
+        ApiKey::OffsetDeleteKey => OffsetDeleteRequest::VERSIONS,
+        ApiKey::DescribeClientQuotasKey => DescribeClientQuotasRequest::VERSIONS,
+        ApiKey::AlterClientQuotasKey => AlterClientQuotasRequest::VERSIONS,
+        ApiKey::DescribeUserScramCredentialsKey => DescribeUserScramCredentialsRequest::VERSIONS,
+        ApiKey::AlterUserScramCredentialsKey => AlterUserScramCredentialsRequest::VERSIONS,
+        ApiKey::VoteKey => VoteRequest::VERSIONS,
+        ApiKey::BeginQuorumEpochKey => BeginQuorumEpochRequest::VERSIONS,
+        ApiKey::EndQuorumEpochKey => EndQuorumEpochRequest::VERSIONS,
+        ApiKey::DescribeQuorumKey => DescribeQuorumRequest::VERSIONS,
+        ApiKey::AlterPartitionKey => AlterPartitionRequest::VERSIONS,
+        ApiKey::UpdateFeaturesKey => UpdateFeaturesRequest::VERSIONS,
+        ApiKey::EnvelopeKey => EnvelopeRequest::VERSIONS,
+        ApiKey::FetchSnapshotKey => FetchSnapshotRequest::VERSIONS,
+        ApiKey::DescribeClusterKey => DescribeClusterRequest::VERSIONS,
+        ApiKey::DescribeProducersKey => DescribeProducersRequest::VERSIONS,
+        ApiKey::BrokerRegistrationKey => BrokerRegistrationRequest::VERSIONS,
+        ApiKey::BrokerHeartbeatKey => BrokerHeartbeatRequest::VERSIONS,
+        ApiKey::UnregisterBrokerKey => UnregisterBrokerRequest::VERSIONS,
+        ApiKey::DescribeTransactionsKey => DescribeTransactionsRequest::VERSIONS,
+        ApiKey::ListTransactionsKey => ListTransactionsRequest::VERSIONS,
+        ApiKey::AllocateProducerIdsKey => AllocateProducerIdsRequest::VERSIONS,
+    }
+}
+
+/// Returns whether the proxy can decode the requests and responses of an API at a version.
+pub fn is_decodable(api_key: ApiKey, version: i16) -> bool {
+    let versions = decodable_versions(api_key);
+    (versions.min..=versions.max).contains(&version)
+}
+
+/// Cap the versions of an `ApiVersions` response to those the proxy can decode.
+///
+/// Newer versions may move fields around, and would be misread. APIs unknown to the proxy, or
+/// none of whose versions it can decode, are removed.
+pub fn clamp_to_decodable(response: &mut ApiVersionsResponse) {
+    response.api_keys.retain(|&api_key, versions| {
+        let Ok(api_key) = ApiKey::try_from(api_key) else {
+            return false;
+        };
+        let decodable = decodable_versions(api_key);
+        versions.min_version = versions.min_version.max(decodable.min);
+        versions.max_version = versions.max_version.min(decodable.max);
+        versions.min_version <= versions.max_version
+    });
+}
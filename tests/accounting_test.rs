@@ -0,0 +1,82 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use conduktor_kafka_proxy::accounting::{Direction, TopicAccounting, TrafficCounts, TrafficKey};
+use indexmap::IndexMap;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+
+/// A single batch of `count` records.
+fn batch(count: usize, compression: Compression) -> Result<Bytes> {
+    let records: Vec<_> = (0..count)
+        .map(|i| Record {
+            transactional: false,
+            control: false,
+            partition_leader_epoch: 0,
+            producer_id: -1,
+            producer_epoch: -1,
+            timestamp_type: TimestampType::Creation,
+            offset: i as i64,
+            sequence: i as i32,
+            timestamp: 0,
+            key: None,
+            value: Some(Bytes::from(vec![b'x'; 100])),
+            headers: IndexMap::new(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut buf,
+        records.iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression,
+        },
+    )?;
+    Ok(buf.freeze())
+}
+
+#[test]
+fn counts_bytes_and_records_from_batch_headers() -> Result<()> {
+    let accounting = TopicAccounting::new();
+    let mut produced = BytesMut::from(&batch(2, Compression::Gzip)?[..]);
+    produced.extend_from_slice(&batch(3, Compression::None)?);
+    let produced = produced.freeze();
+    let fetched = batch(4, Compression::None)?;
+    // a batch cut short by the broker is counted in bytes only
+    let mut truncated = BytesMut::from(&fetched[..]);
+    truncated.extend_from_slice(&fetched[..fetched.len() - 1]);
+    let truncated = truncated.freeze();
+
+    accounting.record(Direction::Produced, Some("app"), "orders", 0, &produced);
+    accounting.record(Direction::Produced, Some("app"), "orders", 0, &produced);
+    accounting.record(Direction::Fetched, None, "orders", 1, &truncated);
+    accounting.record(Direction::Fetched, None, "orders", 2, &Bytes::new());
+
+    let key = |direction, client_id: &str, partition| TrafficKey {
+        direction,
+        client_id: client_id.to_string(),
+        topic: "orders".to_string(),
+        partition,
+    };
+    assert_eq!(
+        accounting.snapshot(),
+        [
+            (
+                key(Direction::Produced, "app", 0),
+                TrafficCounts {
+                    bytes: 2 * produced.len() as u64,
+                    records: 10,
+                }
+            ),
+            (
+                key(Direction::Fetched, "", 1),
+                TrafficCounts {
+                    bytes: truncated.len() as u64,
+                    records: 4,
+                }
+            ),
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,269 @@
+mod common;
+
+use std::mem::size_of;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use common::{
+    connect, produce_error, produce_request, receive, request, spawn_server, FakeBroker,
+    PRODUCE_VERSION,
+};
+use conduktor_kafka_proxy::acl::{Authorizer, Operation, ResourceType};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::describe_configs_request::DescribeConfigsResource;
+use kafka_protocol::messages::{
+    ApiKey, DescribeConfigsRequest, DescribeConfigsResponse, InitProducerIdRequest,
+    InitProducerIdResponse, ListGroupsRequest, ListGroupsResponse, ProduceResponse,
+    SaslAuthenticateRequest, SaslAuthenticateResponse, SaslHandshakeRequest, SaslHandshakeResponse,
+    TransactionalId,
+};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::ResponseError;
+use tokio::time;
+
+const HANDSHAKE_VERSION: i16 = 1;
+const AUTHENTICATE_VERSION: i16 = 2;
+
+const RULES: &str = r#"{
+    "users": { "alice": "alice-secret" },
+    "tunnel_principal": "staging",
+    "rules": [
+        { "principal": "alice", "permission": "allow", "resource": "topic", "name": "orders.*", "operations": ["write"] },
+        { "principal": "*", "permission": "allow", "resource": "group", "operations": ["read"] },
+        { "principal": "*", "permission": "deny", "resource": "group", "name": "admin-.*", "operations": ["all"] },
+        { "principal": "*", "permission": "allow", "resource": "transactional_id", "name": "orders-.*", "operations": ["write"] }
+    ]
+}"#;
+
+#[test]
+fn authorizes_by_principal() -> Result<()> {
+    let acl = Authorizer::from_json(RULES)?;
+    let alice = Some("alice");
+    assert!(acl.allows(alice, ResourceType::Topic, "orders.eu", Operation::Write));
+    // writing implies describing
+    assert!(acl.allows(alice, ResourceType::Topic, "orders.eu", Operation::Describe));
+    assert!(!acl.allows(alice, ResourceType::Topic, "orders.eu", Operation::Read));
+    assert!(!acl.allows(alice, ResourceType::Topic, "payments", Operation::Write));
+    assert!(acl.allows(
+        Some("staging"),
+        ResourceType::Group,
+        "billing",
+        Operation::Read
+    ));
+    assert!(!acl.allows(
+        Some("staging"),
+        ResourceType::Group,
+        "admin-tools",
+        Operation::Read
+    ));
+    assert!(!acl.allows(None, ResourceType::Group, "billing", Operation::Read));
+    assert!(acl.allows(
+        Some("staging"),
+        ResourceType::TransactionalId,
+        "orders-tx",
+        Operation::Write
+    ));
+
+    assert_eq!(acl.tunnel_principal(), Some("staging"));
+    assert_eq!(
+        acl.authenticate(b"\0alice\0alice-secret").as_deref(),
+        Some("alice")
+    );
+    assert_eq!(
+        acl.authenticate(b"alice\0alice\0alice-secret").as_deref(),
+        Some("alice")
+    );
+    assert_eq!(acl.authenticate(b"\0alice\0wrong"), None);
+    assert_eq!(acl.authenticate(b"bob\0alice\0alice-secret"), None);
+    assert_eq!(acl.authenticate(b"\0mallory\0"), None);
+
+    for invalid in [
+        r#"{ "rules": [{ "principal": "a", "permission": "allow", "resource": "topic", "operations": [] }] }"#,
+        r#"{ "rules": [{ "principal": "a", "permission": "allow", "resource": "queue", "operations": ["read"] }] }"#,
+        r#"{ "rules": [{ "principal": "a", "permission": "allow", "resource": "topic", "name": "[", "operations": ["read"] }] }"#,
+        r#"{ "user": {}, "rules": [] }"#,
+    ] {
+        assert!(Authorizer::from_json(invalid).is_err(), "{invalid}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn enforces_rules_on_tunneled_requests() -> Result<()> {
+    let broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let proxy = KafkaProxy::new("localhost", None).with_acl(Authorizer::from_json(RULES)?);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    // the tunnel principal cannot write anything
+    client
+        .send(produce_request(1, "orders", vec![(0, None)])?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 1, PRODUCE_VERSION).await?;
+    assert_eq!(
+        produce_error(&response, "orders"),
+        ResponseError::TopicAuthorizationFailed.code()
+    );
+
+    let mut handshake = SaslHandshakeRequest::default();
+    handshake.mechanism = StrBytes::from_str("PLAIN");
+    client
+        .send(request(
+            ApiKey::SaslHandshakeKey,
+            HANDSHAKE_VERSION,
+            2,
+            &handshake,
+        )?)
+        .await?;
+    let response: SaslHandshakeResponse = receive(&mut client, 2, HANDSHAKE_VERSION).await?;
+    assert_eq!(response.error_code, 0);
+
+    let mut authenticate = SaslAuthenticateRequest::default();
+    authenticate.auth_bytes = Bytes::from_static(b"\0alice\0wrong");
+    client
+        .send(request(
+            ApiKey::SaslAuthenticateKey,
+            AUTHENTICATE_VERSION,
+            3,
+            &authenticate,
+        )?)
+        .await?;
+    let response: SaslAuthenticateResponse = receive(&mut client, 3, AUTHENTICATE_VERSION).await?;
+    assert_eq!(
+        response.error_code,
+        ResponseError::SaslAuthenticationFailed.code()
+    );
+
+    authenticate.auth_bytes = Bytes::from_static(b"\0alice\0alice-secret");
+    client
+        .send(request(
+            ApiKey::SaslAuthenticateKey,
+            AUTHENTICATE_VERSION,
+            4,
+            &authenticate,
+        )?)
+        .await?;
+    let response: SaslAuthenticateResponse = receive(&mut client, 4, AUTHENTICATE_VERSION).await?;
+    assert_eq!(response.error_code, 0);
+
+    // alice writes to her topics only, and denied requests never reach the broker
+    client
+        .send(produce_request(5, "payments", vec![(0, None)])?)
+        .await?;
+    client
+        .send(produce_request(6, "orders", vec![(0, None)])?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 5, PRODUCE_VERSION).await?;
+    assert_eq!(
+        produce_error(&response, "payments"),
+        ResponseError::TopicAuthorizationFailed.code()
+    );
+    let response: ProduceResponse = receive(&mut client, 6, PRODUCE_VERSION).await?;
+    assert_eq!(produce_error(&response, "orders"), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn authorizes_transactions_configs_and_group_listings() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let proxy = KafkaProxy::new("localhost", None).with_acl(Authorizer::from_json(RULES)?);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    let mut init = InitProducerIdRequest::default();
+    init.transactional_id = Some(TransactionalId(StrBytes::from_str("payments-tx")));
+    client
+        .send(request(ApiKey::InitProducerIdKey, 1, 1, &init)?)
+        .await?;
+    let response: InitProducerIdResponse = receive(&mut client, 1, 1).await?;
+    assert_eq!(
+        response.error_code,
+        ResponseError::TransactionalIdAuthorizationFailed.code()
+    );
+
+    // topic configs need describing the topic
+    let mut resource = DescribeConfigsResource::default();
+    resource.resource_type = 2;
+    resource.resource_name = StrBytes::from_str("payments");
+    let mut describe = DescribeConfigsRequest::default();
+    describe.resources.push(resource);
+    client
+        .send(request(ApiKey::DescribeConfigsKey, 1, 2, &describe)?)
+        .await?;
+    let response: DescribeConfigsResponse = receive(&mut client, 2, 1).await?;
+    assert_eq!(
+        response.results[0].error_code,
+        ResponseError::TopicAuthorizationFailed.code()
+    );
+
+    // the groups the principal cannot describe are not listed
+    client
+        .send(request(
+            ApiKey::ListGroupsKey,
+            3,
+            3,
+            &ListGroupsRequest::default(),
+        )?)
+        .await?;
+    let response: ListGroupsResponse = receive(&mut client, 3, 3).await?;
+    let groups: Vec<_> = response
+        .groups
+        .iter()
+        .map(|group| &*group.group_id)
+        .collect();
+    assert_eq!(groups, ["billing"]);
+
+    init.transactional_id = Some(TransactionalId(StrBytes::from_str("orders-tx")));
+    client
+        .send(request(ApiKey::InitProducerIdKey, 1, 4, &init)?)
+        .await?;
+    let (header, _) = broker.next_request(ApiKey::InitProducerIdKey).await?;
+    assert_eq!(header.correlation_id, 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn counts_the_bytes_of_rewritten_responses() -> Result<()> {
+    let broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let proxy =
+        Arc::new(KafkaProxy::new("localhost", None).with_acl(Authorizer::from_json(RULES)?));
+    let remote = proxy.expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    // the groups the principal cannot describe are removed from the response
+    client
+        .send(request(
+            ApiKey::ListGroupsKey,
+            3,
+            1,
+            &ListGroupsRequest::default(),
+        )?)
+        .await?;
+    let frame = client.next().await.context("no response")??;
+    drop(client);
+
+    let totals = proxy.totals();
+    time::timeout(Duration::from_secs(5), async {
+        while totals.connections.load(Ordering::Relaxed) == 0
+            || !proxy.active_connections().is_empty()
+        {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert_eq!(
+        totals.bytes_sent.load(Ordering::Relaxed),
+        (size_of::<u32>() + frame.len()) as u64
+    );
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use conduktor_kafka_proxy::admin::AdminApi;
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serve the admin API of two proxies that are not started, returning its address.
+async fn spawn_admin() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let proxy = Arc::new(KafkaProxy::new("localhost", None));
+    let staging = Arc::new(KafkaProxy::new("localhost", None).with_name("staging"));
+    tokio::spawn(AdminApi::new(proxy).with_proxy(staging).serve(listener));
+    Ok(addr)
+}
+
+/// Send a request without body, returning the raw response.
+async fn request(addr: &str, method: &str, path: &str) -> Result<String> {
+    request_with_headers(addr, method, path, &format!("Host: {addr}\r\n")).await
+}
+
+/// Send a request without body with its own headers, returning the raw response.
+async fn request_with_headers(
+    addr: &str,
+    method: &str,
+    path: &str,
+    headers: &str,
+) -> Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!("{method} {path} HTTP/1.1\r\n{headers}\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn lists_tunnels_and_connections() -> Result<()> {
+    let addr = spawn_admin().await?;
+
+    let response = request(&addr, "GET", "/tunnels").await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with("[]"), "{response}");
+
+    let response = request(&addr, "GET", "/connections").await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+    let response = request(&addr, "GET", "/").await?;
+    assert!(response.contains(r#""name": "staging""#), "{response}");
+
+    let response = request(&addr, "GET", "/metrics").await?;
+    assert_eq!(
+        response
+            .matches("# TYPE kafka_proxy_connections gauge")
+            .count(),
+        1,
+        "{response}"
+    );
+    assert!(
+        response.contains("kafka_proxy_connections{cluster=\"default\"} 0"),
+        "{response}"
+    );
+    assert!(
+        response.contains("kafka_proxy_connections{cluster=\"staging\"} 0"),
+        "{response}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_unknown_routes() -> Result<()> {
+    let addr = spawn_admin().await?;
+
+    let response = request(&addr, "POST", "/tunnels/1234/close").await?;
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+    let response = request(&addr, "POST", "/tunnels/abc/close").await?;
+    assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+
+    let response = request(&addr, "DELETE", "/tunnels").await?;
+    assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+
+    let response = request(&addr, "POST", "/refresh").await?;
+    assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn refuses_requests_from_other_origins() -> Result<()> {
+    let addr = spawn_admin().await?;
+    let port = addr.rsplit(':').next().unwrap();
+
+    // a web page of another origin, or reaching the API through its own name
+    for headers in [
+        format!("Host: {addr}\r\nOrigin: http://attacker.example\r\n"),
+        format!("Host: attacker.example:{port}\r\n"),
+        String::new(),
+    ] {
+        let response = request_with_headers(&addr, "POST", "/tunnels/1234/close", &headers).await?;
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    }
+
+    // the API itself, by address or by name
+    for headers in [
+        format!("Host: {addr}\r\nOrigin: http://{addr}\r\n"),
+        format!("Host: localhost:{port}\r\n"),
+    ] {
+        let response = request_with_headers(&addr, "POST", "/tunnels/1234/close", &headers).await?;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use conduktor_kafka_proxy::bench::{Bench, BenchConfig, Latencies};
+use conduktor_kafka_proxy::console::KafkaConsole;
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::records::BatchHeader;
+use conduktor_kafka_proxy::server::Server;
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::fetch_response::{FetchableTopicResponse, PartitionData};
+use kafka_protocol::messages::list_offsets_response::{
+    ListOffsetsPartitionResponse, ListOffsetsTopicResponse,
+};
+use kafka_protocol::messages::metadata_response::{
+    MetadataResponseBroker, MetadataResponsePartition, MetadataResponseTopic,
+};
+use kafka_protocol::messages::produce_response::{PartitionProduceResponse, TopicProduceResponse};
+use kafka_protocol::messages::{
+    ApiKey, BrokerId, FetchRequest, FetchResponse, ListOffsetsRequest, ListOffsetsResponse,
+    MetadataRequest, MetadataResponse, ProduceRequest, ProduceResponse, RequestHeader,
+    ResponseHeader,
+};
+use kafka_protocol::protocol::{Decodable, Encodable, StrBytes};
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+#[test]
+fn computes_latency_percentiles() {
+    let samples = (1..=1000).rev().map(Duration::from_millis).collect();
+    let latencies = Latencies::from_samples(samples);
+    assert_eq!(latencies.count, 1000);
+    assert_eq!(latencies.p50, Duration::from_millis(500));
+    assert_eq!(latencies.p99, Duration::from_millis(990));
+    assert_eq!(latencies.p999, Duration::from_millis(999));
+    assert_eq!(latencies.max, Duration::from_millis(1000));
+
+    let single = Latencies::from_samples(vec![Duration::from_millis(7)]);
+    assert_eq!(single.p50, Duration::from_millis(7));
+    assert_eq!(single.p999, Duration::from_millis(7));
+    assert_eq!(Latencies::from_samples(vec![]), Latencies::default());
+}
+
+/// The batches of the single partition of the fake broker.
+type Log = Arc<Mutex<Vec<Bytes>>>;
+
+/// Answer a request to a single-broker cluster with one partition, appending produced batches
+/// to `log`, fetching them back and listing the end of the log as the offset.
+fn answer(api_key: ApiKey, version: i16, mut frame: Bytes, port: u16, log: &Log) -> Result<Bytes> {
+    let mut bytes = BytesMut::new();
+    match api_key {
+        ApiKey::MetadataKey => {
+            let request = MetadataRequest::decode(&mut frame, version)?;
+            let mut response = MetadataResponse::default();
+            let mut broker = MetadataResponseBroker::default();
+            broker.host = StrBytes::from_str("127.0.0.1");
+            broker.port = port as i32;
+            response.brokers.insert(BrokerId(0), broker);
+            for topic in request.topics.unwrap_or_default() {
+                let mut partition = MetadataResponsePartition::default();
+                partition.leader_id = BrokerId(0);
+                let mut topic_response = MetadataResponseTopic::default();
+                topic_response.partitions.push(partition);
+                response.topics.insert(topic.name.unwrap(), topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ProduceKey => {
+            let request = ProduceRequest::decode(&mut frame, version)?;
+            let mut response = ProduceResponse::default();
+            let mut log = log.lock().unwrap();
+            for (name, topic) in request.topic_data {
+                let mut topic_response = TopicProduceResponse::default();
+                for partition in topic.partition_data {
+                    let base_offset = next_offset(&log)?;
+                    let mut batch = BytesMut::from(&partition.records.unwrap()[..]);
+                    (&mut batch[..8]).put_i64(base_offset);
+                    log.push(batch.freeze());
+                    let mut partition_response = PartitionProduceResponse::default();
+                    partition_response.index = partition.index;
+                    partition_response.base_offset = base_offset;
+                    topic_response.partition_responses.push(partition_response);
+                }
+                response.responses.insert(name, topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ListOffsetsKey => {
+            let request = ListOffsetsRequest::decode(&mut frame, version)?;
+            let mut response = ListOffsetsResponse::default();
+            for topic in request.topics {
+                let mut topic_response = ListOffsetsTopicResponse::default();
+                topic_response.name = topic.name;
+                for partition in topic.partitions {
+                    let mut partition_response = ListOffsetsPartitionResponse::default();
+                    partition_response.partition_index = partition.partition_index;
+                    partition_response.offset = next_offset(&log.lock().unwrap())?;
+                    topic_response.partitions.push(partition_response);
+                }
+                response.topics.push(topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::FetchKey => {
+            let request = FetchRequest::decode(&mut frame, version)?;
+            let mut response = FetchResponse::default();
+            let log = log.lock().unwrap();
+            for topic in request.topics {
+                let mut topic_response = FetchableTopicResponse::default();
+                topic_response.topic = topic.topic;
+                for partition in topic.partitions {
+                    let mut records = BytesMut::new();
+                    for batch in log.iter() {
+                        let header = BatchHeader::peek(batch)?;
+                        if header.base_offset + header.last_offset_delta as i64
+                            >= partition.fetch_offset
+                        {
+                            records.extend_from_slice(batch);
+                        }
+                    }
+                    let mut partition_response = PartitionData::default();
+                    partition_response.partition_index = partition.partition;
+                    partition_response.high_watermark = next_offset(&log)?;
+                    partition_response.records = Some(records.freeze());
+                    topic_response.partitions.push(partition_response);
+                }
+                response.responses.push(topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        _ => unimplemented!("{api_key:?}"),
+    }
+    Ok(bytes.freeze())
+}
+
+/// Offset of the next record appended to the log.
+fn next_offset(log: &[Bytes]) -> Result<i64> {
+    Ok(match log.last() {
+        Some(batch) => {
+            let header = BatchHeader::peek(batch)?;
+            header.base_offset + header.last_offset_delta as i64 + 1
+        }
+        None => 0,
+    })
+}
+
+async fn fake_broker(listener: TcpListener) -> Result<()> {
+    let port = listener.local_addr()?.port();
+    let log = Log::default();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let log = Arc::clone(&log);
+        tokio::spawn(async move {
+            let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+            while let Some(Ok(mut frame)) = framed.next().await {
+                let api_key = ApiKey::try_from(i16::from_be_bytes([frame[0], frame[1]])).unwrap();
+                let version = i16::from_be_bytes([frame[2], frame[3]]);
+                let header =
+                    RequestHeader::decode(&mut frame, api_key.request_header_version(version))?;
+                let mut response_header = ResponseHeader::default();
+                response_header.correlation_id = header.correlation_id;
+                let mut bytes = BytesMut::new();
+                response_header.encode(&mut bytes, api_key.response_header_version(version))?;
+                bytes.extend_from_slice(&answer(api_key, version, frame.freeze(), port, &log)?);
+                framed.send(bytes.freeze()).await?;
+            }
+            anyhow::Ok(())
+        });
+    }
+}
+
+#[tokio::test]
+async fn benchmarks_a_tunnel() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(fake_broker(listener));
+    tokio::spawn(Server::new(1024, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let remote = Arc::new(KafkaProxy::new("localhost", None))
+        .expose(&format!("127.0.0.1:{port}"))
+        .await?;
+    let console = KafkaConsole::new(&remote);
+    // records already in the partition are not consumed
+    let values = ["before".to_string()];
+    console.produce("bench", 0, None, &values).await?;
+
+    let config = BenchConfig {
+        message_bytes: 64,
+        batch_records: 10,
+        rate: Some(200),
+        duration: Duration::from_millis(500),
+        ..BenchConfig::default()
+    };
+    let report = Bench::new(console.clone(), config).run().await?;
+    assert_eq!(report.errors, 0);
+    assert!(report.produced > 0 && report.produced <= 100, "{report}");
+    assert_eq!(report.latency.count as u64 * 10, report.produced);
+    let consumer = report.consumer.as_ref().unwrap();
+    assert_eq!(consumer.messages, report.produced, "{report}");
+    assert_eq!(consumer.latency.count as u64, report.produced);
+    assert!(report.to_string().contains("messages of 64 bytes"));
+
+    let records = console.consume("bench", 0, Some(1), 1).await?;
+    let value = records[0].value.as_ref().unwrap();
+    assert_eq!(value.len(), 64);
+    assert!(value[8..].iter().all(|&b| b == b'x'));
+    Ok(())
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use common::{connect, produce_request, receive, spawn_server, FakeBroker, PRODUCE_VERSION};
+use conduktor_kafka_proxy::chaos::{Chaos, Fault};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use futures_util::SinkExt;
+use kafka_protocol::messages::{ApiKey, ProduceResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::ResponseError;
+
+#[test]
+fn parses_chaos_rules() -> Result<()> {
+    let chaos = Chaos::from_json(
+        r#"[
+            { "api": "Produce", "topic": "orders.*", "fault": { "error": "NOT_LEADER_OR_FOLLOWER" } },
+            { "api": "Fetch", "probability": 0, "fault": { "throttle_ms": 2000 } },
+            { "api": "Fetch", "fault": { "latency_ms": 50 } },
+            { "api": "Metadata", "fault": "disconnect" }
+        ]"#,
+    )?;
+    let orders = ["orders.eu".to_string()];
+    assert_eq!(
+        chaos.pick(ApiKey::ProduceKey, &orders),
+        Some(Fault::Error(ResponseError::NotLeaderOrFollower))
+    );
+    assert_eq!(chaos.pick(ApiKey::ProduceKey, &["audit".to_string()]), None);
+    // the throttling is never drawn
+    assert_eq!(
+        chaos.pick(ApiKey::FetchKey, &[]),
+        Some(Fault::Latency(Duration::from_millis(50)))
+    );
+    assert_eq!(
+        chaos.pick(ApiKey::MetadataKey, &[]),
+        Some(Fault::Disconnect)
+    );
+    assert_eq!(chaos.pick(ApiKey::JoinGroupKey, &[]), None);
+    assert!(chaos.matches_topics(ApiKey::ProduceKey));
+    assert!(!chaos.matches_topics(ApiKey::FetchKey));
+
+    for invalid in [
+        r#"[{ "api": "Produce", "fault": { "error": "NOT_AN_ERROR" } }]"#,
+        r#"[{ "api": "Producer", "fault": "disconnect" }]"#,
+        r#"[{ "api": "Metadata", "fault": { "error": "REQUEST_TIMED_OUT" } }]"#,
+        r#"[{ "fault": { "throttle_ms": 100 } }]"#,
+        r#"[{ "api": "Metadata", "topic": "orders", "fault": "disconnect" }]"#,
+        r#"[{ "probability": 1.5, "fault": "disconnect" }]"#,
+        r#"[{ "fault": "explode" }]"#,
+    ] {
+        assert!(Chaos::from_json(invalid).is_err(), "{invalid}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn injects_faults_in_responses() -> Result<()> {
+    let broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let chaos = Chaos::from_json(
+        r#"[
+            { "api": "Produce", "topic": "orders", "fault": { "throttle_ms": 200 } }
+        ]"#,
+    )?;
+    let proxy = KafkaProxy::new("localhost", None).with_chaos(chaos);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    let sent_at = Instant::now();
+    client
+        .send(produce_request(1, "orders", vec![(0, None)])?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 1, PRODUCE_VERSION).await?;
+    assert!(sent_at.elapsed() >= Duration::from_millis(200));
+    assert_eq!(response.throttle_time_ms, 200);
+
+    let chaos = Chaos::from_json(
+        r#"[
+            { "api": "Produce", "fault": { "error": "NOT_LEADER_OR_FOLLOWER" } }
+        ]"#,
+    )?;
+    let proxy = KafkaProxy::new("localhost", None).with_chaos(chaos);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+    client
+        .send(produce_request(2, "orders", vec![(0, None)])?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 2, PRODUCE_VERSION).await?;
+    let partition =
+        &response.responses[&TopicName(StrBytes::from_str("orders"))].partition_responses[0];
+    assert_eq!(
+        partition.error_code,
+        ResponseError::NotLeaderOrFollower.code()
+    );
+    assert_eq!(partition.base_offset, -1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delays_responses_without_holding_requests() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let chaos = Chaos::from_json(
+        r#"[
+            { "api": "Produce", "topic": "orders", "fault": { "latency_ms": 500 } }
+        ]"#,
+    )?;
+    let proxy = KafkaProxy::new("localhost", None).with_chaos(chaos);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    let sent_at = Instant::now();
+    client
+        .send(produce_request(1, "orders", vec![(0, None)])?)
+        .await?;
+    client
+        .send(produce_request(2, "payments", vec![(0, None)])?)
+        .await?;
+    // both requests reach the broker right away
+    for correlation_id in [1, 2] {
+        let (header, _) = broker.next_request(ApiKey::ProduceKey).await?;
+        assert_eq!(header.correlation_id, correlation_id);
+    }
+    assert!(sent_at.elapsed() < Duration::from_millis(500));
+
+    // and their responses are held in order
+    for correlation_id in [1, 2] {
+        let _: ProduceResponse = receive(&mut client, correlation_id, PRODUCE_VERSION).await?;
+        assert!(sent_at.elapsed() >= Duration::from_millis(500));
+    }
+    Ok(())
+}
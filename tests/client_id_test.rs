@@ -0,0 +1,85 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use common::{connect, encode_request, spawn_server, FakeBroker};
+use conduktor_kafka_proxy::client_id::ClientIdRewrite;
+use conduktor_kafka_proxy::headers::InjectionContext;
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use futures_util::SinkExt;
+use kafka_protocol::messages::{ApiKey, GroupId, HeartbeatRequest, RequestHeader};
+use kafka_protocol::protocol::StrBytes;
+use uuid::Uuid;
+
+const HEARTBEAT_VERSION: i16 = 4;
+
+#[test]
+fn renders_client_ids() -> Result<()> {
+    let context = InjectionContext {
+        connection_id: Uuid::nil(),
+        tunnel_port: Some(41234),
+        peer_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 3, 12))),
+    };
+    let rewrite = ClientIdRewrite::parse("tunnel-{cluster}-{original}")?;
+    assert_eq!(
+        rewrite.render(Some("billing"), "staging", None, &context),
+        "tunnel-staging-billing"
+    );
+    assert_eq!(
+        rewrite.render(None, "staging", None, &context),
+        "tunnel-staging-"
+    );
+    // placeholders sent by clients are not expanded
+    assert_eq!(
+        rewrite.render(Some("{peer_ip}"), "staging", None, &context),
+        "tunnel-staging-{peer_ip}"
+    );
+
+    let rewrite = ClientIdRewrite::parse("{principal}@{peer_ip}:{tunnel_port}")?;
+    assert_eq!(
+        rewrite.render(Some("billing"), "staging", Some("alice"), &context),
+        "alice@10.0.3.12:41234"
+    );
+    let pinned = ClientIdRewrite::parse("tunnel")?;
+    assert_eq!(
+        pinned.render(Some("billing"), "staging", None, &context),
+        "tunnel"
+    );
+
+    assert!(ClientIdRewrite::parse("tunnel-{name}").is_err());
+    assert!(ClientIdRewrite::parse("tunnel-{cluster").is_err());
+    Ok(())
+}
+
+fn heartbeat_request(client_id: &'static str) -> Result<Bytes> {
+    let mut header = RequestHeader::default();
+    header.request_api_key = ApiKey::HeartbeatKey as i16;
+    header.request_api_version = HEARTBEAT_VERSION;
+    header.correlation_id = 1;
+    header.client_id = Some(StrBytes::from_str(client_id));
+
+    let mut request = HeartbeatRequest::default();
+    request.group_id = GroupId(StrBytes::from_str("billing-consumers"));
+    request.generation_id = 7;
+    encode_request(header, &request)
+}
+
+#[tokio::test]
+async fn rewrites_forwarded_client_ids() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let proxy = KafkaProxy::new("localhost", None)
+        .with_name("staging")
+        .with_client_id(ClientIdRewrite::parse("tunnel-{cluster}-{original}")?);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+    client.send(heartbeat_request("billing")?).await?;
+    let (header, request): (_, HeartbeatRequest) = broker.next(ApiKey::HeartbeatKey).await?;
+    assert_eq!(header.client_id.as_deref(), Some("tunnel-staging-billing"));
+    assert_eq!(&*request.group_id, "billing-consumers");
+    Ok(())
+}
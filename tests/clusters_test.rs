@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use conduktor_kafka_proxy::clusters::ClusterConfig;
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::{MetadataResponse, RequestHeader, ResponseHeader};
+use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion, StrBytes};
+use tokio::net::TcpListener;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Start a broker answering every request with the metadata of a cluster, returning its address.
+async fn fake_broker(cluster_id: &'static str) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+        while let Some(Ok(mut frame)) = framed.next().await {
+            let version = i16::from_be_bytes([frame[2], frame[3]]);
+            let header = RequestHeader::decode(&mut frame, 1)?;
+            let mut response_header = ResponseHeader::default();
+            response_header.correlation_id = header.correlation_id;
+            let mut response = MetadataResponse::default();
+            response.cluster_id = Some(StrBytes::from_str(cluster_id));
+
+            let mut bytes = BytesMut::new();
+            response_header.encode(&mut bytes, MetadataResponse::header_version(version))?;
+            response.encode(&mut bytes, version)?;
+            framed.send(bytes.freeze()).await?;
+        }
+        anyhow::Ok(())
+    });
+    Ok(addr)
+}
+
+#[test]
+fn parses_clusters() {
+    let json = r#"[
+        { "name": "staging", "bootstrap_server": "localhost:9092", "schemas": "schemas.json" },
+        { "name": "tests", "secret": "other", "inject_headers": ["x-origin=tests"] }
+    ]"#;
+    let clusters = ClusterConfig::from_json(json, Path::new("/etc/proxy")).unwrap();
+    assert_eq!(
+        clusters,
+        [
+            ClusterConfig {
+                bootstrap_server: Some("localhost:9092".to_string()),
+                schemas: Some("/etc/proxy/schemas.json".into()),
+                ..ClusterConfig::new("staging")
+            },
+            ClusterConfig {
+                secret: Some("other".to_string()),
+                inject_headers: vec!["x-origin=tests".to_string()],
+                ..ClusterConfig::new("tests")
+            },
+        ]
+    );
+}
+
+#[test]
+fn rejects_invalid_clusters() {
+    let base = Path::new("");
+    for json in [
+        "[]",
+        r#"[{ "name": "a" }, { "name": "a" }]"#,
+        r#"[{ "name": "with space" }]"#,
+        r#"[{ "name": "a", "masking": "rules.json" }]"#,
+    ] {
+        assert!(ClusterConfig::from_json(json, base).is_err(), "{json}");
+    }
+}
+
+#[tokio::test]
+async fn refuses_an_unexpected_cluster() -> Result<()> {
+    let bootstrap = fake_broker("staging-id").await?;
+    let proxy = KafkaProxy::new("localhost", None)
+        .with_name("prod")
+        .with_expected_cluster_id("prod-id");
+    let proxy = Arc::new(proxy);
+
+    let err = proxy.expose(&bootstrap).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "cluster prod has id staging-id instead of the expected prod-id"
+    );
+    assert!(proxy.tunnels().is_empty());
+    Ok(())
+}
@@ -0,0 +1,328 @@
+//! Helpers shared by the tests of proxied requests: a fake broker, request encoding and a client
+//! of the tunnels.
+
+// every test file only uses some of them
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use conduktor_kafka_proxy::server::Server;
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::api_versions_response::ApiVersion;
+use kafka_protocol::messages::create_topics_response::CreatableTopicResult;
+use kafka_protocol::messages::fetch_response::{FetchableTopicResponse, PartitionData};
+use kafka_protocol::messages::list_groups_response::ListedGroup;
+use kafka_protocol::messages::metadata_response::{
+    MetadataResponseBroker, MetadataResponsePartition, MetadataResponseTopic,
+};
+use kafka_protocol::messages::produce_request::{PartitionProduceData, TopicProduceData};
+use kafka_protocol::messages::produce_response::{PartitionProduceResponse, TopicProduceResponse};
+use kafka_protocol::messages::{
+    ApiKey, ApiVersionsResponse, BrokerId, CreateTopicsRequest, CreateTopicsResponse, FetchRequest,
+    FetchResponse, GroupId, ListGroupsResponse, MetadataRequest, MetadataResponse, ProduceRequest,
+    ProduceResponse, RequestHeader, ResponseHeader, TopicName,
+};
+use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion, StrBytes};
+use kafka_protocol::ResponseError;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+pub const PRODUCE_VERSION: i16 = 8;
+
+/// Versions advertised by the fake broker, beyond those kafka-protocol can decode.
+pub const BROKER_VERSIONS: [(ApiKey, i16, i16); 3] = [
+    (ApiKey::ProduceKey, 0, 11),
+    (ApiKey::FetchKey, 0, 17),
+    (ApiKey::ApiVersionsKey, 0, 4),
+];
+
+/// How long a test waits for a response or a forwarded request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A remote client connected to the public port of a tunnel.
+pub type Client = Framed<TcpStream, LengthDelimitedCodec>;
+
+/// Spawn the server, giving some time for the control port TcpListener to start.
+pub async fn spawn_server() {
+    tokio::spawn(Server::new(1024, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+/// Connect a remote client to a tunnel.
+pub async fn connect(remote: &str) -> Result<Client> {
+    Ok(Framed::new(
+        TcpStream::connect(remote).await?,
+        LengthDelimitedCodec::new(),
+    ))
+}
+
+/// Encode a request with its header, without the length prefix.
+pub fn encode_request<R: Encodable>(header: RequestHeader, request: &R) -> Result<Bytes> {
+    let api_key = ApiKey::try_from(header.request_api_key).unwrap();
+    let version = header.request_api_version;
+    let mut bytes = BytesMut::new();
+    header.encode(&mut bytes, api_key.request_header_version(version))?;
+    request.encode(&mut bytes, version)?;
+    Ok(bytes.freeze())
+}
+
+/// Encode a request without client id.
+pub fn request<R: Encodable>(
+    api_key: ApiKey,
+    version: i16,
+    correlation_id: i32,
+    request: &R,
+) -> Result<Bytes> {
+    let mut header = RequestHeader::default();
+    header.request_api_key = api_key as i16;
+    header.request_api_version = version;
+    header.correlation_id = correlation_id;
+    encode_request(header, request)
+}
+
+/// A produce request with `acks=-1` to partitions of a topic, with their records.
+pub fn produce(topic: &'static str, partitions: Vec<(i32, Option<Bytes>)>) -> ProduceRequest {
+    let mut topic_data = TopicProduceData::default();
+    for (index, records) in partitions {
+        let mut partition = PartitionProduceData::default();
+        partition.index = index;
+        partition.records = records;
+        topic_data.partition_data.push(partition);
+    }
+    let mut request = ProduceRequest::default();
+    request.acks = -1;
+    request
+        .topic_data
+        .insert(TopicName(StrBytes::from_str(topic)), topic_data);
+    request
+}
+
+/// Encode a produce request to partitions of a topic, at [`PRODUCE_VERSION`].
+pub fn produce_request(
+    correlation_id: i32,
+    topic: &'static str,
+    partitions: Vec<(i32, Option<Bytes>)>,
+) -> Result<Bytes> {
+    request(
+        ApiKey::ProduceKey,
+        PRODUCE_VERSION,
+        correlation_id,
+        &produce(topic, partitions),
+    )
+}
+
+/// Receive the next response frame, its length prefix removed.
+pub async fn next_response(client: &mut Client) -> Result<BytesMut> {
+    Ok(time::timeout(TIMEOUT, client.next()).await?.unwrap()?)
+}
+
+/// Receive a response, checking its correlation id.
+pub async fn receive<R: Decodable + HeaderVersion>(
+    client: &mut Client,
+    correlation_id: i32,
+    version: i16,
+) -> Result<R> {
+    let mut response = next_response(client).await?;
+    let header = ResponseHeader::decode(&mut response, R::header_version(version))?;
+    assert_eq!(header.correlation_id, correlation_id);
+    let decoded = R::decode(&mut response, version)?;
+    assert!(!response.has_remaining());
+    Ok(decoded)
+}
+
+/// Error code of the first partition of a topic in a produce response.
+pub fn produce_error(response: &ProduceResponse, topic: &'static str) -> i16 {
+    response.responses[&TopicName(StrBytes::from_str(topic))].partition_responses[0].error_code
+}
+
+/// A single-broker cluster, sending every request it receives to the test.
+///
+/// Metadata requests are answered with one partition led by the broker for each topic, produce
+/// requests accept every partition and topic creations create every topic. Other requests are
+/// not answered.
+pub struct FakeBroker {
+    /// Address of the broker.
+    pub addr: String,
+
+    /// The requests received, with their header, in the order of each connection.
+    requests: mpsc::UnboundedReceiver<(RequestHeader, Bytes)>,
+}
+
+impl FakeBroker {
+    /// Spawn a broker answering right away.
+    pub async fn spawn() -> Result<Self> {
+        Self::start(Some(Duration::ZERO)).await
+    }
+
+    /// Spawn a broker answering every request after a delay.
+    pub async fn slow(delay: Duration) -> Result<Self> {
+        Self::start(Some(delay)).await
+    }
+
+    /// Spawn a broker that never answers, for requests that must not reach it.
+    pub async fn silent() -> Result<Self> {
+        Self::start(None).await
+    }
+
+    async fn start(answer_after: Option<Duration>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (sink, requests) = mpsc::unbounded_channel();
+        tokio::spawn(Self::listen(listener, sink, answer_after));
+        Ok(Self { addr, requests })
+    }
+
+    /// The next request of an API, skipping the others.
+    pub async fn next_request(&mut self, api_key: ApiKey) -> Result<(RequestHeader, Bytes)> {
+        loop {
+            let (header, body) = time::timeout(TIMEOUT, self.requests.recv()).await?.unwrap();
+            if header.request_api_key == api_key as i16 {
+                return Ok((header, body));
+            }
+        }
+    }
+
+    /// The next request of an API, decoded.
+    pub async fn next<R: Decodable>(&mut self, api_key: ApiKey) -> Result<(RequestHeader, R)> {
+        let (header, mut body) = self.next_request(api_key).await?;
+        let request = R::decode(&mut body, header.request_api_version)?;
+        Ok((header, request))
+    }
+
+    /// Whether no request was received since the last one taken.
+    pub fn is_idle(&mut self) -> bool {
+        self.requests.try_recv().is_err()
+    }
+
+    async fn listen(
+        listener: TcpListener,
+        sink: mpsc::UnboundedSender<(RequestHeader, Bytes)>,
+        answer_after: Option<Duration>,
+    ) -> Result<()> {
+        let port = listener.local_addr()?.port();
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let sink = sink.clone();
+            tokio::spawn(async move {
+                let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+                while let Some(Ok(frame)) = framed.next().await {
+                    let mut frame = frame.freeze();
+                    let api_key =
+                        ApiKey::try_from(i16::from_be_bytes([frame[0], frame[1]])).unwrap();
+                    let version = i16::from_be_bytes([frame[2], frame[3]]);
+                    let header =
+                        RequestHeader::decode(&mut frame, api_key.request_header_version(version))?;
+                    let _ = sink.send((header.clone(), frame.clone()));
+                    let Some(delay) = answer_after else {
+                        continue;
+                    };
+                    let Some(response) = answer(api_key, version, frame, port)? else {
+                        continue;
+                    };
+                    time::sleep(delay).await;
+                    let mut response_header = ResponseHeader::default();
+                    response_header.correlation_id = header.correlation_id;
+                    let mut bytes = BytesMut::new();
+                    response_header.encode(&mut bytes, api_key.response_header_version(version))?;
+                    bytes.extend_from_slice(&response);
+                    framed.send(bytes.freeze()).await?;
+                }
+                anyhow::Ok(())
+            });
+        }
+    }
+}
+
+/// The response body of the fake broker to a request, if it answers it.
+fn answer(api_key: ApiKey, version: i16, mut body: Bytes, port: u16) -> Result<Option<Bytes>> {
+    let mut bytes = BytesMut::new();
+    match api_key {
+        ApiKey::MetadataKey => {
+            let request = MetadataRequest::decode(&mut body, version)?;
+            let mut response = MetadataResponse::default();
+            let mut broker = MetadataResponseBroker::default();
+            broker.host = StrBytes::from_str("127.0.0.1");
+            broker.port = port as i32;
+            response.brokers.insert(BrokerId(0), broker);
+            for topic in request.topics.unwrap_or_default() {
+                let mut partition = MetadataResponsePartition::default();
+                partition.leader_id = BrokerId(0);
+                let mut topic_response = MetadataResponseTopic::default();
+                topic_response.partitions.push(partition);
+                response.topics.insert(topic.name.unwrap(), topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ProduceKey => {
+            let request = ProduceRequest::decode(&mut body, version)?;
+            let mut response = ProduceResponse::default();
+            for (name, topic) in request.topic_data {
+                let mut topic_response = TopicProduceResponse::default();
+                for partition in topic.partition_data {
+                    let mut partition_response = PartitionProduceResponse::default();
+                    partition_response.index = partition.index;
+                    topic_response.partition_responses.push(partition_response);
+                }
+                response.responses.insert(name, topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ApiVersionsKey => {
+            let mut response = ApiVersionsResponse::default();
+            for (api_key, min_version, max_version) in BROKER_VERSIONS {
+                let mut versions = ApiVersion::default();
+                versions.min_version = min_version;
+                versions.max_version = max_version;
+                response.api_keys.insert(api_key as i16, versions);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::FetchKey => {
+            // negative offsets are out of range, others have no records yet
+            let request = FetchRequest::decode(&mut body, version)?;
+            let mut response = FetchResponse::default();
+            for topic in request.topics {
+                let mut topic_response = FetchableTopicResponse::default();
+                topic_response.topic = topic.topic;
+                topic_response.topic_id = topic.topic_id;
+                for partition in topic.partitions {
+                    let mut data = PartitionData::default();
+                    data.partition_index = partition.partition;
+                    if partition.fetch_offset < 0 {
+                        data.error_code = ResponseError::OffsetOutOfRange.code();
+                    }
+                    topic_response.partitions.push(data);
+                }
+                response.responses.push(topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ListGroupsKey => {
+            let mut response = ListGroupsResponse::default();
+            for group_id in ["billing", "admin-tools"] {
+                let mut group = ListedGroup::default();
+                group.group_id = GroupId(StrBytes::from_str(group_id));
+                group.protocol_type = StrBytes::from_str("consumer");
+                response.groups.push(group);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::CreateTopicsKey => {
+            let request = CreateTopicsRequest::decode(&mut body, version)?;
+            let mut response = CreateTopicsResponse::default();
+            for (name, topic) in request.topics {
+                let mut result = CreatableTopicResult::default();
+                result.num_partitions = topic.num_partitions;
+                result.replication_factor = topic.replication_factor;
+                response.topics.insert(name, result);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        _ => return Ok(None),
+    }
+    Ok(Some(bytes.freeze()))
+}
@@ -0,0 +1,215 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use conduktor_kafka_proxy::console::KafkaConsole;
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::records::BatchHeader;
+use conduktor_kafka_proxy::server::Server;
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::fetch_response::{FetchableTopicResponse, PartitionData};
+use kafka_protocol::messages::list_offsets_response::{
+    ListOffsetsPartitionResponse, ListOffsetsTopicResponse,
+};
+use kafka_protocol::messages::metadata_response::{
+    MetadataResponseBroker, MetadataResponsePartition, MetadataResponseTopic,
+};
+use kafka_protocol::messages::produce_response::{PartitionProduceResponse, TopicProduceResponse};
+use kafka_protocol::messages::{
+    ApiKey, ApiVersionsResponse, BrokerId, FetchRequest, FetchResponse, ListOffsetsRequest,
+    ListOffsetsResponse, MetadataRequest, MetadataResponse, ProduceRequest, ProduceResponse,
+    RequestHeader, ResponseHeader, TopicName,
+};
+use kafka_protocol::protocol::{Decodable, Encodable, StrBytes};
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// The batches of the single partition of the fake broker.
+type Log = Arc<Mutex<Vec<Bytes>>>;
+
+/// Answer a request to a single-broker cluster with one partition of `orders`, appending
+/// produced batches to `log` and fetching them back.
+fn answer(api_key: ApiKey, version: i16, mut frame: Bytes, port: u16, log: &Log) -> Result<Bytes> {
+    let mut bytes = BytesMut::new();
+    match api_key {
+        ApiKey::ApiVersionsKey => {
+            ApiVersionsResponse::default().encode(&mut bytes, version)?;
+        }
+        ApiKey::MetadataKey => {
+            let request = MetadataRequest::decode(&mut frame, version)?;
+            let mut response = MetadataResponse::default();
+            let mut broker = MetadataResponseBroker::default();
+            broker.host = StrBytes::from_str("127.0.0.1");
+            broker.port = port as i32;
+            response.brokers.insert(BrokerId(0), broker);
+            let names = match request.topics {
+                Some(topics) => topics.into_iter().filter_map(|t| t.name).collect(),
+                None => vec![
+                    TopicName(StrBytes::from_str("orders")),
+                    TopicName(StrBytes::from_str("__consumer_offsets")),
+                ],
+            };
+            for name in names {
+                let mut partition = MetadataResponsePartition::default();
+                partition.leader_id = BrokerId(0);
+                let mut topic = MetadataResponseTopic::default();
+                topic.is_internal = name.starts_with("__");
+                topic.partitions.push(partition);
+                response.topics.insert(name, topic);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ProduceKey => {
+            let request = ProduceRequest::decode(&mut frame, version)?;
+            let mut response = ProduceResponse::default();
+            let mut log = log.lock().unwrap();
+            for (name, topic) in request.topic_data {
+                let mut topic_response = TopicProduceResponse::default();
+                for partition in topic.partition_data {
+                    let base_offset = next_offset(&log)?;
+                    let mut batch = BytesMut::from(&partition.records.unwrap()[..]);
+                    (&mut batch[..8]).put_i64(base_offset);
+                    log.push(batch.freeze());
+                    let mut partition_response = PartitionProduceResponse::default();
+                    partition_response.index = partition.index;
+                    partition_response.base_offset = base_offset;
+                    topic_response.partition_responses.push(partition_response);
+                }
+                response.responses.insert(name, topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::ListOffsetsKey => {
+            let request = ListOffsetsRequest::decode(&mut frame, version)?;
+            let mut response = ListOffsetsResponse::default();
+            for topic in request.topics {
+                let mut topic_response = ListOffsetsTopicResponse::default();
+                topic_response.name = topic.name;
+                for partition in topic.partitions {
+                    let mut partition_response = ListOffsetsPartitionResponse::default();
+                    partition_response.partition_index = partition.partition_index;
+                    partition_response.offset = 0;
+                    topic_response.partitions.push(partition_response);
+                }
+                response.topics.push(topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        ApiKey::FetchKey => {
+            let request = FetchRequest::decode(&mut frame, version)?;
+            let mut response = FetchResponse::default();
+            let log = log.lock().unwrap();
+            for topic in request.topics {
+                let mut topic_response = FetchableTopicResponse::default();
+                topic_response.topic = topic.topic;
+                for partition in topic.partitions {
+                    let mut records = BytesMut::new();
+                    for batch in log.iter() {
+                        let header = BatchHeader::peek(batch)?;
+                        if header.base_offset + header.last_offset_delta as i64
+                            >= partition.fetch_offset
+                        {
+                            records.extend_from_slice(batch);
+                        }
+                    }
+                    let mut partition_response = PartitionData::default();
+                    partition_response.partition_index = partition.partition;
+                    partition_response.high_watermark = next_offset(&log)?;
+                    partition_response.records = Some(records.freeze());
+                    topic_response.partitions.push(partition_response);
+                }
+                response.responses.push(topic_response);
+            }
+            response.encode(&mut bytes, version)?;
+        }
+        _ => unimplemented!("{api_key:?}"),
+    }
+    Ok(bytes.freeze())
+}
+
+/// Offset of the next record appended to the log.
+fn next_offset(log: &[Bytes]) -> Result<i64> {
+    Ok(match log.last() {
+        Some(batch) => {
+            let header = BatchHeader::peek(batch)?;
+            header.base_offset + header.last_offset_delta as i64 + 1
+        }
+        None => 0,
+    })
+}
+
+async fn fake_broker(listener: TcpListener) -> Result<()> {
+    let port = listener.local_addr()?.port();
+    let log = Log::default();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let log = Arc::clone(&log);
+        tokio::spawn(async move {
+            let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+            while let Some(Ok(mut frame)) = framed.next().await {
+                let api_key = ApiKey::try_from(i16::from_be_bytes([frame[0], frame[1]])).unwrap();
+                let version = i16::from_be_bytes([frame[2], frame[3]]);
+                let header =
+                    RequestHeader::decode(&mut frame, api_key.request_header_version(version))?;
+                let mut response_header = ResponseHeader::default();
+                response_header.correlation_id = header.correlation_id;
+                let mut bytes = BytesMut::new();
+                response_header.encode(&mut bytes, api_key.response_header_version(version))?;
+                bytes.extend_from_slice(&answer(api_key, version, frame.freeze(), port, &log)?);
+                framed.send(bytes.freeze()).await?;
+            }
+            anyhow::Ok(())
+        });
+    }
+}
+
+#[tokio::test]
+async fn produces_and_consumes_through_a_tunnel() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(fake_broker(listener));
+    tokio::spawn(Server::new(1024, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let remote = Arc::new(KafkaProxy::new("localhost", None))
+        .expose(&format!("127.0.0.1:{port}"))
+        .await?;
+    let console = KafkaConsole::new(&remote);
+    let checks = console.check_tunnel().await;
+    assert_eq!(checks.len(), 2, "{checks:?}");
+    assert!(checks.iter().all(|check| check.passed), "{checks:?}");
+
+    // brokers reached directly are not rewritten
+    let direct = KafkaConsole::new(&format!("localhost:{port}"));
+    let checks = direct.check_tunnel().await;
+    assert!(checks[0].passed);
+    assert!(!checks[1].passed, "{checks:?}");
+
+    let topics = console.list_topics().await?;
+    let names: Vec<_> = topics.iter().map(|t| (&*t.name, t.internal)).collect();
+    assert_eq!(names, [("__consumer_offsets", true), ("orders", false)]);
+
+    let values = ["first".to_string(), "second".to_string()];
+    assert_eq!(console.produce("orders", 0, Some("eu"), &values).await?, 0);
+    assert_eq!(console.produce("orders", 0, None, &values[..1]).await?, 2);
+
+    let records = console.consume("orders", 0, None, 100).await?;
+    let read: Vec<_> = records
+        .iter()
+        .map(|r| (r.offset, r.key.as_deref(), r.value.as_deref().unwrap()))
+        .collect();
+    assert_eq!(
+        read,
+        [
+            (0, Some(&b"eu"[..]), &b"first"[..]),
+            (1, Some(&b"eu"[..]), &b"second"[..]),
+            (2, None, &b"first"[..]),
+        ]
+    );
+    let records = console.consume("orders", 0, Some(1), 1).await?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].offset, 1);
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use conduktor_kafka_proxy::discovery::{discover, select, DiscoveredCluster};
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::api_versions_response::ApiVersion;
+use kafka_protocol::messages::metadata_response::MetadataResponseBroker;
+use kafka_protocol::messages::{
+    ApiKey, ApiVersionsResponse, BrokerId, MetadataResponse, RequestHeader, ResponseHeader,
+};
+use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion, StrBytes};
+use tokio::net::TcpListener;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Start a broker of a cluster with the given id and number of brokers, returning its port.
+async fn fake_broker(cluster_id: &'static str, brokers: i32) -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(serve(listener, cluster_id, brokers));
+    Ok(port)
+}
+
+async fn serve(listener: TcpListener, cluster_id: &'static str, brokers: i32) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+            while let Some(Ok(mut frame)) = framed.next().await {
+                let api_key = i16::from_be_bytes([frame[0], frame[1]]);
+                let version = i16::from_be_bytes([frame[2], frame[3]]);
+                let api_key = ApiKey::try_from(api_key).unwrap();
+                let header =
+                    RequestHeader::decode(&mut frame, api_key.request_header_version(version))?;
+                let mut response_header = ResponseHeader::default();
+                response_header.correlation_id = header.correlation_id;
+
+                let mut bytes = BytesMut::new();
+                match api_key {
+                    ApiKey::ApiVersionsKey => {
+                        let mut response = ApiVersionsResponse::default();
+                        for key in [ApiKey::ApiVersionsKey, ApiKey::MetadataKey] {
+                            let mut api = ApiVersion::default();
+                            api.max_version = 9;
+                            response.api_keys.insert(key as i16, api);
+                        }
+                        response_header
+                            .encode(&mut bytes, ApiVersionsResponse::header_version(version))?;
+                        response.encode(&mut bytes, version)?;
+                    }
+                    _ => {
+                        let mut response = MetadataResponse::default();
+                        response.cluster_id = Some(StrBytes::from_str(cluster_id));
+                        for id in 0..brokers {
+                            let mut broker = MetadataResponseBroker::default();
+                            broker.host = StrBytes::from_str("localhost");
+                            broker.port = 9092 + id;
+                            response.brokers.insert(BrokerId(id), broker);
+                        }
+                        response_header
+                            .encode(&mut bytes, MetadataResponse::header_version(version))?;
+                        response.encode(&mut bytes, version)?;
+                    }
+                }
+                framed.send(bytes.freeze()).await?;
+            }
+            anyhow::Ok(())
+        });
+    }
+}
+
+/// A port nothing listens on.
+async fn closed_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[tokio::test]
+async fn discovers_one_entry_per_cluster() -> Result<()> {
+    let closed = closed_port().await?;
+    let first = fake_broker("cluster-a", 3).await?;
+    let second = fake_broker("cluster-a", 3).await?;
+    let other = fake_broker("cluster-b", 1).await?;
+
+    let clusters = discover("127.0.0.1", &[closed, first, second, other]).await;
+    assert_eq!(
+        clusters,
+        [
+            DiscoveredCluster {
+                bootstrap_server: format!("127.0.0.1:{first}"),
+                cluster_id: Some("cluster-a".to_string()),
+                brokers: 3,
+            },
+            DiscoveredCluster {
+                bootstrap_server: format!("127.0.0.1:{other}"),
+                cluster_id: Some("cluster-b".to_string()),
+                brokers: 1,
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn selects_a_cluster() {
+    let cluster = |port: u16| DiscoveredCluster {
+        bootstrap_server: format!("localhost:{port}"),
+        cluster_id: None,
+        brokers: 1,
+    };
+    let no_prompt: Option<(Cursor<Vec<u8>>, Vec<u8>)> = None;
+
+    assert!(select(vec![], no_prompt.clone()).is_err());
+    assert_eq!(
+        select(vec![cluster(9092)], no_prompt.clone()).unwrap(),
+        cluster(9092)
+    );
+    let err = select(vec![cluster(9092), cluster(9093)], no_prompt).unwrap_err();
+    assert!(err.to_string().contains("localhost:9093"), "{err}");
+
+    let mut output = vec![];
+    let chosen = select(
+        vec![cluster(9092), cluster(9093)],
+        Some((Cursor::new(b"7\n2\n".to_vec()), &mut output)),
+    )
+    .unwrap();
+    assert_eq!(chosen, cluster(9093));
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("2) localhost:9093"), "{output}");
+    assert!(output.contains("Please enter a number"), "{output}");
+}
@@ -1,6 +1,8 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use futures::future::{BoxFuture, FutureExt};
 use lazy_static::lazy_static;
 use rskafka::client::partition::Compression;
 use rskafka::client::ClientBuilder;
@@ -10,12 +12,17 @@ use rstest::*;
 use testcontainers::images::kafka;
 use testcontainers::images::kafka::Kafka;
 use testcontainers::{clients, Container};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 use tokio::time;
 
+use conduktor_kafka_proxy::auth::Authenticator;
+use conduktor_kafka_proxy::client::{Control, ForwardedConnection, Service};
+use conduktor_kafka_proxy::console::KafkaConsole;
+use conduktor_kafka_proxy::doctor::Doctor;
 use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::shared::{ClientMessage, Delimited, ServerMessage};
 use conduktor_kafka_proxy::{server::Server, shared::CONTROL_PORT};
 
 lazy_static! {
@@ -32,7 +39,7 @@ async fn spawn_server(secret: Option<&str>) {
 }
 
 /// Start a Kafka container, returning the container and its bootstrap servers.
-fn start_kafka(docker: &clients::Cli) -> (Container<Kafka>, String) {
+fn start_kafka(docker: &clients::Cli) -> (Container<'_, Kafka>, String) {
     let kafka_node = docker.run(Kafka::default());
     let local_port = kafka_node.get_host_port_ipv4(kafka::KAFKA_PORT);
     (kafka_node, format!("127.0.0.1:{}", local_port))
@@ -143,6 +150,65 @@ async fn invalid_address() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[cfg_attr(not(feature = "integration_tests"), ignore)]
+async fn doctor_passes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let docker = clients::Cli::default();
+    let (_kafka_node, bootstrap_servers) = start_kafka(&docker);
+    spawn_server(Some(TEST_SECRET)).await;
+
+    let checks = Doctor::new("localhost", Some(TEST_SECRET), &bootstrap_servers)
+        .run()
+        .await;
+    for check in &checks {
+        assert!(check.passed, "{check}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "integration_tests"), ignore)]
+async fn console_round_trip() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let docker = clients::Cli::default();
+    let (_kafka_node, bootstrap_servers) = start_kafka(&docker);
+    spawn_server(None).await;
+
+    let remote = spawn_proxy(None, &bootstrap_servers).await?;
+    let console = KafkaConsole::new(&remote);
+    for check in console.check_tunnel().await {
+        assert!(check.passed, "{check}");
+    }
+    let values = vec!["Message 0".to_string(), "Message 1".to_string()];
+    let offset = console
+        .produce("console-topic", 0, Some("Key"), &values)
+        .await?;
+    let records = console
+        .consume("console-topic", 0, Some(offset), 10)
+        .await?;
+    let consumed: Vec<_> = records.iter().map(|r| r.value.clone().unwrap()).collect();
+    assert_eq!(consumed, ["Message 0", "Message 1"]);
+    assert!(console
+        .list_topics()
+        .await?
+        .iter()
+        .any(|topic| topic.name == "console-topic"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn doctor_reports_unreachable_kafka() {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let checks = Doctor::new("localhost", None, "localhost:1").run().await;
+    assert_eq!(checks.len(), 2, "tunnel checks should be skipped");
+    assert!(!checks[0].passed, "{}", checks[0]);
+    assert!(checks[0].hint.is_some());
+    assert!(checks[1].passed, "{}", checks[1]);
+}
+
 #[tokio::test]
 async fn very_long_frame() -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
@@ -160,3 +226,185 @@ async fn very_long_frame() -> Result<()> {
     }
     panic!("did not exit after a 1 MB frame");
 }
+
+#[tokio::test]
+async fn peer_addresses_only_sent_when_asked() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    async fn first_connection(hello: ClientMessage) -> Result<ServerMessage> {
+        let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+        control.send(hello).await?;
+        let port = match control.recv_timeout().await? {
+            Some(ServerMessage::Hello(port)) => port,
+            message => panic!("unexpected {message:?}"),
+        };
+        let _remote = TcpStream::connect(("localhost", port)).await?;
+        loop {
+            match control.recv_timeout().await? {
+                Some(ServerMessage::Heartbeat) => continue,
+                Some(message) => return Ok(message),
+                None => panic!("unexpected EOF"),
+            }
+        }
+    }
+
+    // clients that predate peer addresses cannot deserialize them
+    let legacy = first_connection(ClientMessage::Hello(0)).await?;
+    assert!(matches!(legacy, ServerMessage::Connection(_)), "{legacy:?}");
+    let current = first_connection(ClientMessage::HelloWithPeers(0)).await?;
+    assert!(
+        matches!(current, ServerMessage::ConnectionFrom(_, peer) if peer.ip().is_loopback()),
+        "{current:?}"
+    );
+    Ok(())
+}
+
+/// Answers every connection with a greeting of its own.
+struct Greeter(&'static str);
+
+impl Service for Greeter {
+    fn proxy(
+        self: Arc<Self>,
+        mut connection: ForwardedConnection,
+    ) -> BoxFuture<'static, Result<()>> {
+        async move {
+            connection.remote.write_all(self.0.as_bytes()).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Read the greeting of a tunnel.
+async fn greeting(port: u16) -> Result<String> {
+    let mut stream = TcpStream::connect(("localhost", port)).await?;
+    let mut greeting = String::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_string(&mut greeting)).await??;
+    Ok(greeting)
+}
+
+#[tokio::test]
+async fn tunnels_share_a_control_connection() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(Some(TEST_SECRET)).await;
+
+    let control = Control::new("localhost", Some(Authenticator::new(TEST_SECRET)));
+    let staging = control.open(Arc::new(Greeter("staging"))).await?;
+    let tests = control.open(Arc::new(Greeter("tests"))).await?;
+    assert_ne!(staging.remote_port, tests.remote_port);
+    assert_eq!(greeting(staging.remote_port).await?, "staging");
+    assert_eq!(greeting(tests.remote_port).await?, "tests");
+
+    // closing a tunnel closes its port only
+    staging.shutdown.cancel();
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(("localhost", staging.remote_port))
+        .await
+        .is_err());
+    assert_eq!(greeting(tests.remote_port).await?, "tests");
+    assert!(!tests.shutdown.is_cancelled());
+    Ok(())
+}
+
+#[tokio::test]
+async fn shared_control_connection_forwards_several_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::HelloShared(0)).await?;
+    control.send(ClientMessage::Open(0)).await?;
+    let mut ports = vec![];
+    while ports.len() < 2 {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Hello(port)) => ports.push(port),
+            Some(ServerMessage::Heartbeat) => continue,
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+
+    let _remote = TcpStream::connect(("localhost", ports[1])).await?;
+    loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::ConnectionTo(_, peer, port)) => {
+                assert!(peer.ip().is_loopback());
+                assert_eq!(port, ports[1]);
+                return Ok(());
+            }
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn failed_open_keeps_the_shared_control_connection() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::HelloShared(0)).await?;
+    // below the minimum port of the server
+    control.send(ClientMessage::Open(1)).await?;
+    let mut messages = vec![];
+    while messages.len() < 2 {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(message) => messages.push(message),
+            None => panic!("unexpected EOF"),
+        }
+    }
+    let port = match &messages[..] {
+        [ServerMessage::Hello(port), ServerMessage::Error(_)] => *port,
+        messages => panic!("unexpected {messages:?}"),
+    };
+
+    // the first port is still forwarded
+    let _remote = TcpStream::connect(("localhost", port)).await?;
+    loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::ConnectionTo(_, _, to)) => {
+                assert_eq!(to, port);
+                return Ok(());
+            }
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn failed_open_keeps_the_other_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // a server forwarding a single port per control connection
+    let listener = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut control = Delimited::new(stream);
+        let _: Option<ClientMessage> = control.recv().await?;
+        control.send(ServerMessage::Hello(40_000)).await?;
+        while let Some(message) = control.recv::<ClientMessage>().await? {
+            if let ClientMessage::Open(_) = message {
+                control
+                    .send(ServerMessage::Error("no more ports".into()))
+                    .await?;
+            }
+        }
+        anyhow::Ok(())
+    });
+
+    let control = Control::new("localhost", None);
+    let first = control.open(Arc::new(Greeter("first"))).await?;
+    assert_eq!(first.remote_port, 40_000);
+    let err = control
+        .open(Arc::new(Greeter("second")))
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("no more ports"), "{err}");
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(!first.shutdown.is_cancelled());
+    Ok(())
+}
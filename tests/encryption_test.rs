@@ -0,0 +1,152 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use conduktor_kafka_proxy::encryption::{Encryption, KEY_ID_HEADER};
+use indexmap::IndexMap;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+
+const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY_2: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+
+fn keys(active: &str, keys: &[(&str, &str)], encrypt_keys: bool) -> Result<Encryption> {
+    let keys: serde_json::Map<_, _> = keys
+        .iter()
+        .map(|(id, key)| (id.to_string(), key.to_string().into()))
+        .collect();
+    let json = serde_json::json!({ "active": active, "keys": keys, "encrypt_keys": encrypt_keys });
+    Encryption::from_json(&json.to_string())
+}
+
+fn batch(values: &[Option<&str>]) -> Result<Bytes> {
+    let records: Vec<_> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| Record {
+            transactional: false,
+            control: false,
+            partition_leader_epoch: 0,
+            producer_id: 42,
+            producer_epoch: 1,
+            timestamp_type: TimestampType::Creation,
+            offset: i as i64,
+            sequence: i as i32,
+            timestamp: 1_700_000_000_000,
+            key: Some(Bytes::from("user-1")),
+            value: value.map(|value| Bytes::from(value.to_string())),
+            headers: IndexMap::new(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut buf,
+        records.iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression: Compression::Gzip,
+        },
+    )?;
+    Ok(buf.freeze())
+}
+
+#[test]
+fn encrypts_and_decrypts_records() -> Result<()> {
+    let encryption = keys("k1", &[("k1", KEY_1)], true)?;
+    let plain = batch(&[Some("hello"), None])?;
+
+    let encrypted = encryption.encrypt_records(&plain)?;
+    let records = RecordBatchDecoder::decode(&mut encrypted.clone())?;
+    assert_eq!(
+        records[0].headers[KEY_ID_HEADER].as_deref(),
+        Some(&b"k1"[..])
+    );
+    assert_ne!(records[0].value.as_deref(), Some(&b"hello"[..]));
+    assert_eq!(records[1].value, None);
+    // keys are encrypted deterministically, to keep partitioning stable
+    assert_ne!(records[0].key.as_deref(), Some(&b"user-1"[..]));
+    assert_eq!(records[0].key, records[1].key);
+    assert_eq!(records[1].sequence, 1);
+
+    // records stored encrypted are encrypted again, keeping their key id
+    let twice = encryption.encrypt_records(&encrypted)?;
+    let records = RecordBatchDecoder::decode(&mut twice.clone())?;
+    assert_ne!(records[0].value.as_deref(), Some(&b"hello"[..]));
+    assert_eq!(encryption.decrypt_records(&twice).unwrap(), encrypted);
+
+    let decrypted = encryption.decrypt_records(&encrypted).unwrap();
+    let records = RecordBatchDecoder::decode(&mut decrypted.clone())?;
+    assert_eq!(records[0].value.as_deref(), Some(&b"hello"[..]));
+    assert_eq!(records[0].key.as_deref(), Some(&b"user-1"[..]));
+    assert!(records[0].headers.is_empty());
+
+    // plaintext records are rejected
+    let err = encryption.decrypt_records(&plain).unwrap_err();
+    assert_eq!(err.message, "record 0 of batch 0 is not encrypted");
+    Ok(())
+}
+
+#[test]
+fn forwards_plaintext_when_allowed() -> Result<()> {
+    let json =
+        serde_json::json!({ "active": "k1", "keys": { "k1": KEY_1 }, "allow_plaintext": true });
+    let encryption = Encryption::from_json(&json.to_string())?;
+    let plain = batch(&[Some("hello")])?;
+    assert_eq!(encryption.decrypt_records(&plain).unwrap(), plain);
+
+    let encrypted = encryption.encrypt_records(&plain)?;
+    let decrypted = encryption.decrypt_records(&encrypted).unwrap();
+    let records = RecordBatchDecoder::decode(&mut decrypted.clone())?;
+    assert_eq!(records[0].value.as_deref(), Some(&b"hello"[..]));
+    Ok(())
+}
+
+#[test]
+fn rotates_keys_by_id() -> Result<()> {
+    let old = keys("k1", &[("k1", KEY_1)], false)?;
+    let rotated = keys("k2", &[("k1", KEY_1), ("k2", KEY_2)], false)?;
+    let retired = keys("k2", &[("k2", KEY_2)], false)?;
+
+    let mut records = BytesMut::new();
+    records.extend_from_slice(&rotated.encrypt_records(&batch(&[Some("new")])?)?);
+    records.extend_from_slice(&old.encrypt_records(&batch(&[Some("old")])?)?);
+    let records = records.freeze();
+
+    let decrypted = rotated.decrypt_records(&records).unwrap();
+    let values: Vec<_> = RecordBatchDecoder::decode(&mut decrypted.clone())?
+        .into_iter()
+        .map(|record| record.value.unwrap())
+        .collect();
+    assert_eq!(values, ["new", "old"]);
+
+    // the index of the record within its batch, the second one
+    let err = retired.decrypt_records(&records).unwrap_err();
+    assert_eq!(err.batch_index, Some(0));
+    assert!(
+        err.message.contains("of batch 1 uses unknown key"),
+        "{}",
+        err.message
+    );
+
+    // a ciphertext encrypted under another key does not authenticate
+    let forged = keys("k2", &[("k2", KEY_1)], false)?;
+    let err = forged.decrypt_records(&records).unwrap_err();
+    assert_eq!(err.batch_index, Some(0));
+    assert!(
+        err.message.contains("cannot be decrypted"),
+        "{}",
+        err.message
+    );
+
+    // an error of the whole second batch names it, without a record index
+    let truncated = records.slice(..records.len() - 1);
+    let err = rotated.decrypt_records(&truncated).unwrap_err();
+    assert_eq!(err.batch_index, None);
+    assert_eq!(err.message, "truncated record batch 1");
+    Ok(())
+}
+
+#[test]
+fn rejects_invalid_key_files() {
+    assert!(keys("k2", &[("k1", KEY_1)], false).is_err());
+    assert!(keys("k1", &[("k1", "abcd")], false).is_err());
+}
@@ -0,0 +1,81 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use conduktor_kafka_proxy::headers::{HeaderInjection, InjectionContext};
+use indexmap::IndexMap;
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+use uuid::Uuid;
+
+#[test]
+fn injects_headers_into_transactional_batches() -> Result<()> {
+    let mut existing = IndexMap::new();
+    existing.insert(StrBytes::from_str("x-origin"), Some(Bytes::from("app")));
+    let records: Vec<_> = (0..3)
+        .map(|i| Record {
+            transactional: true,
+            control: false,
+            partition_leader_epoch: -1,
+            producer_id: 42,
+            producer_epoch: 3,
+            timestamp_type: TimestampType::Creation,
+            offset: i,
+            sequence: 10 + i as i32,
+            timestamp: 1_700_000_000_000,
+            key: None,
+            value: Some(Bytes::from(format!("value-{i}"))),
+            headers: if i == 0 {
+                existing.clone()
+            } else {
+                IndexMap::new()
+            },
+        })
+        .collect();
+    let mut batch = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut batch,
+        records.iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression: Compression::Gzip,
+        },
+    )?;
+
+    let injection = HeaderInjection::parse(&[
+        "x-origin=tunnel",
+        "x-peer={peer_ip}:{tunnel_port}",
+        "traceparent={traceparent}",
+    ])?;
+    let context = InjectionContext {
+        connection_id: Uuid::new_v4(),
+        tunnel_port: Some(4321),
+        peer_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))),
+    };
+    let injected = injection.inject_records(&batch.freeze(), &context)?;
+
+    let decoded = RecordBatchDecoder::decode(&mut injected.clone())?;
+    assert_eq!(decoded.len(), 3);
+    for (i, record) in decoded.iter().enumerate() {
+        assert!(record.transactional);
+        assert_eq!(record.producer_id, 42);
+        assert_eq!(record.producer_epoch, 3);
+        assert_eq!(record.sequence, 10 + i as i32);
+        let header = |key: &'static str| record.headers[&StrBytes::from_str(key)].clone().unwrap();
+        let origin = if i == 0 { "app" } else { "tunnel" };
+        assert_eq!(header("x-origin"), origin);
+        assert_eq!(header("x-peer"), "10.0.0.7:4321");
+        assert_eq!(header("traceparent").len(), 55);
+    }
+    Ok(())
+}
+
+#[test]
+fn rejects_invalid_headers() {
+    assert!(HeaderInjection::parse(&["x-origin"]).is_err());
+    assert!(HeaderInjection::parse(&["=tunnel"]).is_err());
+    assert!(HeaderInjection::parse(&["x-origin={unknown}"]).is_err());
+    assert!(HeaderInjection::parse(&["x-origin={peer_ip"]).is_err());
+}
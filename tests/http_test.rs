@@ -0,0 +1,324 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use conduktor_kafka_proxy::http::{HttpRoute, HttpTunnel};
+use conduktor_kafka_proxy::server::Server;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// A service answering every request with its own URL, in a chunked body.
+async fn fake_service(listener: TcpListener) -> Result<()> {
+    let origin = format!("http://{}", listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let origin = origin.clone();
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            loop {
+                let mut request_line = String::new();
+                if stream.read_line(&mut request_line).await? == 0 {
+                    return anyhow::Ok(());
+                }
+                let path = request_line
+                    .split(' ')
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+                let mut host = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await?;
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(value) = lower.strip_prefix("host: ") {
+                        host = value.trim().to_string();
+                    }
+                    if let Some(value) = lower.strip_prefix("content-length: ") {
+                        length = value.trim().parse()?;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut request_body = vec![0; length];
+                stream.read_exact(&mut request_body).await?;
+
+                let body = format!(r#"{{"self":"{origin}{path}","host":"{host}"}}"#);
+                let (first, second) = body.split_at(10);
+                let response = format!(
+                    "HTTP/1.1 201 Created\r\nlocation: {origin}{path}\r\n\
+                     content-type: application/json\r\ntransfer-encoding: chunked\r\n\r\n\
+                     {:x}\r\n{first}\r\n{:x}\r\n{second}\r\n0\r\n\r\n",
+                    first.len(),
+                    second.len(),
+                );
+                stream.get_mut().write_all(response.as_bytes()).await?;
+            }
+        });
+    }
+}
+
+async fn spawn_service() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(fake_service(listener));
+    Ok(addr)
+}
+
+/// Send a request, returning the head and body of its response.
+async fn request(
+    stream: &mut BufReader<TcpStream>,
+    path: &str,
+    host: &str,
+) -> Result<(String, String)> {
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\n\
+         Accept-Encoding: gzip\r\nContent-Length: 2\r\n\r\n{{}}"
+    );
+    stream.get_mut().write_all(request.as_bytes()).await?;
+
+    let response = async {
+        let mut head = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length: ") {
+                length = value.trim().parse()?;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await?;
+        anyhow::Ok((head, String::from_utf8(body)?))
+    };
+    time::timeout(Duration::from_secs(5), response).await?
+}
+
+#[tokio::test]
+async fn routes_requests_and_rewrites_urls() -> Result<()> {
+    let registry = spawn_service().await?;
+    let connect = spawn_service().await?;
+    let ksql = spawn_service().await?;
+    tokio::spawn(Server::new(1024, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // a single route receiving every request
+    let tunnel = HttpTunnel::new(vec![HttpRoute::new(
+        "schema-registry",
+        &format!("http://{registry}/"),
+    )?])?;
+    let public = Arc::new(tunnel).expose("localhost", None).await?;
+    let authority = public.strip_prefix("http://").unwrap();
+
+    let mut stream = BufReader::new(TcpStream::connect(authority).await?);
+    for _ in 0..2 {
+        let (head, body) = request(&mut stream, "/subjects/orders/versions", authority).await?;
+        assert!(head.starts_with("HTTP/1.1 201 Created\r\n"), "{head}");
+        assert!(
+            head.contains(&format!("location: {public}/subjects/orders/versions\r\n")),
+            "{head}"
+        );
+        assert!(!head.contains("transfer-encoding"), "{head}");
+        assert_eq!(
+            body,
+            format!(r#"{{"self":"{public}/subjects/orders/versions","host":"{registry}"}}"#)
+        );
+    }
+
+    // several routes sharing a port
+    let tunnel = HttpTunnel::new(vec![
+        HttpRoute::parse(&format!("connect:/connect=http://{connect}"))?,
+        HttpRoute::parse(&format!("ksql:/ksql=http://{ksql}"))?,
+        HttpRoute::parse(&format!("ksql-host:ksql.example.com=http://{ksql}"))?,
+    ])?;
+    let public = Arc::new(tunnel).expose("localhost", None).await?;
+    let authority = public.strip_prefix("http://").unwrap();
+
+    let mut stream = BufReader::new(TcpStream::connect(authority).await?);
+    let (_, body) = request(&mut stream, "/connect/connectors", authority).await?;
+    assert_eq!(
+        body,
+        format!(r#"{{"self":"{public}/connect/connectors","host":"{connect}"}}"#)
+    );
+    let (_, body) = request(&mut stream, "/ksql?pretty", authority).await?;
+    assert_eq!(
+        body,
+        format!(r#"{{"self":"{public}/ksql/?pretty","host":"{ksql}"}}"#)
+    );
+    let (_, body) = request(&mut stream, "/info", "ksql.example.com:80").await?;
+    assert_eq!(
+        body,
+        format!(r#"{{"self":"http://ksql.example.com:80/info","host":"{ksql}"}}"#)
+    );
+    let (head, _) = request(&mut stream, "/connectors", authority).await?;
+    assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"), "{head}");
+    let (head, _) = request(&mut stream, "/connect/connectors", authority).await?;
+    assert!(head.starts_with("HTTP/1.1 201 Created\r\n"), "{head}");
+    Ok(())
+}
+
+/// A service answering every request with its body, in a chunked body.
+async fn echo_service(listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length: ") {
+                    length = value.trim().parse()?;
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            let mut response = b"HTTP/1.1 200 OK\r\ncontent-type: application/octet-stream\r\n\
+                                 transfer-encoding: chunked\r\n\r\n"
+                .to_vec();
+            for chunk in body.chunks(64 * 1024) {
+                response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                response.extend_from_slice(chunk);
+                response.extend_from_slice(b"\r\n");
+            }
+            response.extend_from_slice(b"0\r\n\r\n");
+            stream.get_mut().write_all(&response).await?;
+            anyhow::Ok(())
+        });
+    }
+}
+
+#[tokio::test]
+async fn streams_bodies() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let echo = listener.local_addr()?;
+    tokio::spawn(echo_service(listener));
+    tokio::spawn(Server::new(1024, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let tunnel = HttpTunnel::new(vec![HttpRoute::new("echo", &format!("http://{echo}"))?])?;
+    let public = Arc::new(tunnel).expose("localhost", None).await?;
+    let authority = public.strip_prefix("http://").unwrap();
+    let mut stream = BufReader::new(TcpStream::connect(authority).await?);
+
+    let length = 8 * 1024 * 1024;
+    let head =
+        format!("POST /upload HTTP/1.1\r\nHost: {authority}\r\nContent-Length: {length}\r\n\r\n");
+    stream.get_mut().write_all(head.as_bytes()).await?;
+    stream.get_mut().write_all(&vec![b'x'; length]).await?;
+
+    let response = async {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line.to_ascii_lowercase());
+        }
+        let mut body = Vec::new();
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).await?;
+            let size = usize::from_str_radix(size.trim_end(), 16)?;
+            let mut chunk = vec![0; size + 2];
+            stream.read_exact(&mut chunk).await?;
+            if size == 0 {
+                return anyhow::Ok((head, body));
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    };
+    let (head, body) = time::timeout(Duration::from_secs(5), response).await??;
+    // binary bodies keep their chunks instead of being read in full
+    assert!(head.starts_with("http/1.1 200 ok\r\n"), "{head}");
+    assert!(head.contains("transfer-encoding: chunked\r\n"), "{head}");
+    assert_eq!(body.len(), length);
+    assert!(body.iter().all(|&byte| byte == b'x'));
+    Ok(())
+}
+
+/// A service sending early hints before every response, and closing idle connections right
+/// after answering, without saying so.
+async fn hinting_service(listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            stream
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 103 Early Hints\r\nlink: </style.css>\r\n\r\n\
+                      HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+                )
+                .await?;
+            anyhow::Ok(())
+        });
+    }
+}
+
+#[tokio::test]
+async fn relays_interim_responses_and_retries_idle_connections() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let service = listener.local_addr()?;
+    tokio::spawn(hinting_service(listener));
+    tokio::spawn(Server::new(1024, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let tunnel = HttpTunnel::new(vec![HttpRoute::new("hints", &format!("http://{service}"))?])?;
+    let public = Arc::new(tunnel).expose("localhost", None).await?;
+    let authority = public.strip_prefix("http://").unwrap();
+    let mut stream = BufReader::new(TcpStream::connect(authority).await?);
+
+    // the second request goes to the connection the service closed, and is sent again
+    for _ in 0..2 {
+        let request = format!("GET /hints HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+        stream.get_mut().write_all(request.as_bytes()).await?;
+        let response = async {
+            let mut head = String::new();
+            let mut length = 0;
+            let mut ends = 0;
+            while ends < 2 {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length: ") {
+                    length = value.trim().parse()?;
+                }
+                if line == "\r\n" {
+                    ends += 1;
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            anyhow::Ok((head, String::from_utf8(body)?))
+        };
+        let (head, body) = time::timeout(Duration::from_secs(5), response).await??;
+        assert!(
+            head.starts_with(
+                "HTTP/1.1 103 Early Hints\r\nlink: </style.css>\r\n\r\nHTTP/1.1 200 OK\r\n"
+            ),
+            "{head}"
+        );
+        assert_eq!(body, "ok");
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::net::IpAddr;
+
+use conduktor_kafka_proxy::inventory::{ClientInventory, Observation};
+
+#[test]
+fn keeps_distinct_remote_clients() {
+    let inventory = ClientInventory::new();
+    let first: IpAddr = "10.0.3.12".parse().unwrap();
+    let second: IpAddr = "10.0.3.13".parse().unwrap();
+
+    inventory.observe(
+        Some("billing-1"),
+        Some(first),
+        Observation {
+            software_name: Some("apache-kafka-java"),
+            software_version: Some("3.6.1"),
+            ..Default::default()
+        },
+    );
+    inventory.observe(
+        Some("billing-1"),
+        Some(first),
+        Observation {
+            group_id: Some("billing"),
+            ..Default::default()
+        },
+    );
+    // groups are only listed once
+    inventory.observe(
+        Some("billing-1"),
+        Some(first),
+        Observation {
+            group_id: Some("billing"),
+            ..Default::default()
+        },
+    );
+    // the same client id from another address is another client
+    inventory.observe(Some("billing-1"), Some(second), Observation::default());
+    inventory.observe(None, None, Observation::default());
+
+    let clients = inventory.snapshot("default");
+    assert_eq!(clients.len(), 3);
+    let billing = clients
+        .iter()
+        .find(|client| client.peer_ip == Some(first))
+        .unwrap();
+    assert_eq!(billing.cluster, "default");
+    assert_eq!(billing.client_id, "billing-1");
+    assert_eq!(billing.software_name.as_deref(), Some("apache-kafka-java"));
+    assert_eq!(billing.software_version.as_deref(), Some("3.6.1"));
+    assert_eq!(billing.groups, ["billing"]);
+    assert!(billing.first_seen <= billing.last_seen);
+
+    let other = clients
+        .iter()
+        .find(|client| client.peer_ip == Some(second))
+        .unwrap();
+    assert_eq!(other.software_name, None);
+    assert!(other.groups.is_empty());
+    assert!(clients.iter().any(|client| client.client_id.is_empty()));
+}
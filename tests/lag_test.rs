@@ -0,0 +1,83 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::{connect, request, spawn_server, FakeBroker};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::lag::{ConsumerLag, LagObserver};
+use futures_util::SinkExt;
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::{ApiKey, FetchRequest, TopicName};
+use kafka_protocol::protocol::StrBytes;
+
+#[test]
+fn computes_lag_per_client_and_partition() {
+    let lag = LagObserver::new(Some(100));
+    lag.observe_group(Some("billing-1"), "billing");
+    lag.observe_fetch(Some("billing-1"), "orders", 0, 10);
+    lag.observe_fetch(Some("billing-1"), "orders", 1, 500);
+    lag.observe_fetch(Some("audit"), "orders", 0, 40);
+    // partitions are only reported once a response gave their high watermark
+    lag.observe_fetch(Some("audit"), "orders", 1, 0);
+
+    lag.observe_high_watermark(Some("billing-1"), "orders", 0, 250);
+    lag.observe_high_watermark(Some("billing-1"), "orders", 1, 500);
+    lag.observe_high_watermark(Some("audit"), "orders", 0, 250);
+    // errors have no high watermark, and unknown partitions are ignored
+    lag.observe_high_watermark(Some("audit"), "orders", 1, -1);
+    lag.observe_high_watermark(Some("other"), "orders", 0, 250);
+
+    let entry =
+        |client_id: &str, group_id: Option<&str>, partition, fetch_offset, lag| ConsumerLag {
+            client_id: client_id.to_string(),
+            group_id: group_id.map(str::to_string),
+            topic: "orders".to_string(),
+            partition,
+            fetch_offset,
+            high_watermark: fetch_offset + lag,
+            lag,
+        };
+    assert_eq!(
+        lag.snapshot(),
+        [
+            entry("billing-1", Some("billing"), 0, 10, 240),
+            entry("audit", None, 0, 40, 210),
+            entry("billing-1", Some("billing"), 1, 500, 0),
+        ]
+    );
+
+    // the next fetch moves the consumer forward
+    lag.observe_fetch(Some("audit"), "orders", 0, 250);
+    lag.observe_high_watermark(Some("audit"), "orders", 0, 260);
+    assert_eq!(lag.snapshot()[1], entry("audit", None, 0, 250, 10));
+}
+
+#[tokio::test]
+async fn ignores_fetches_of_unknown_versions() -> Result<()> {
+    let mut broker = FakeBroker::silent().await?;
+    spawn_server().await;
+
+    let lag = Arc::new(LagObserver::new(None));
+    let proxy = KafkaProxy::new("localhost", None).with_lag(Arc::clone(&lag));
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    // Fetch v15 dropped the replica id, so its fields cannot be read as those of v13
+    let mut partition = FetchPartition::default();
+    partition.fetch_offset = 42;
+    let mut topic = FetchTopic::default();
+    topic.topic = TopicName(StrBytes::from_str("orders"));
+    topic.partitions.push(partition);
+    let mut fetch = FetchRequest::default();
+    fetch.topics.push(topic);
+    client
+        .send(request(ApiKey::FetchKey, 15, 1, &fetch)?)
+        .await?;
+
+    // the request is forwarded as is, without being observed
+    let (header, _) = broker.next_request(ApiKey::FetchKey).await?;
+    assert_eq!(header.request_api_version, 15);
+    assert!(lag.snapshot().is_empty());
+    Ok(())
+}
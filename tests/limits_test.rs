@@ -0,0 +1,173 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use common::{
+    connect, produce, produce_error, produce_request, receive, spawn_server, FakeBroker,
+    PRODUCE_VERSION,
+};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::limits::ProduceLimits;
+use futures_util::SinkExt;
+use indexmap::IndexMap;
+use kafka_protocol::messages::{ApiKey, ProduceRequest, ProduceResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+use kafka_protocol::ResponseError;
+use tokio::io::AsyncWriteExt;
+
+/// A single batch of `count` records of 100 bytes.
+fn batch(count: usize) -> Result<Bytes> {
+    let records: Vec<_> = (0..count)
+        .map(|i| Record {
+            transactional: false,
+            control: false,
+            partition_leader_epoch: 0,
+            producer_id: -1,
+            producer_epoch: -1,
+            timestamp_type: TimestampType::Creation,
+            offset: i as i64,
+            sequence: i as i32,
+            timestamp: 0,
+            key: None,
+            value: Some(Bytes::from(vec![b'x'; 100])),
+            headers: IndexMap::new(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut buf,
+        records.iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression: Compression::None,
+        },
+    )?;
+    Ok(buf.freeze())
+}
+
+#[test]
+fn checks_batch_headers() -> Result<()> {
+    let limits = ProduceLimits {
+        max_request_bytes: Some(10_000),
+        max_batch_bytes: Some(2_000),
+        max_batch_records: Some(10),
+    };
+    assert!(!limits.is_empty());
+    assert!(ProduceLimits::default().is_empty());
+    assert_eq!(limits.check_request(10_000), Ok(()));
+    assert_eq!(
+        limits.check_request(10_001),
+        Err("Request of 10001 bytes is larger than the maximum of 10000.".to_string())
+    );
+
+    let mut records = BytesMut::from(&batch(2)?[..]);
+    records.extend_from_slice(&batch(10)?);
+    assert_eq!(limits.check_records(&records.freeze()), Ok(()));
+    assert_eq!(
+        limits.check_records(&batch(11)?),
+        Err("Batch of 11 records has more than the maximum of 10.".to_string())
+    );
+
+    let limits = ProduceLimits {
+        max_batch_bytes: Some(1_000),
+        ..ProduceLimits::default()
+    };
+    let large = batch(10)?;
+    assert_eq!(
+        limits.check_records(&large),
+        Err(format!(
+            "Batch of {} bytes is larger than the maximum of 1000.",
+            large.len()
+        ))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_oversized_produce_requests() -> Result<()> {
+    // a broker that never answers, oversized requests must not reach it
+    let broker = FakeBroker::silent().await?;
+    spawn_server().await;
+
+    let limits = ProduceLimits {
+        max_batch_records: Some(10),
+        ..ProduceLimits::default()
+    };
+    let proxy = KafkaProxy::new("localhost", None).with_produce_limits(limits);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    client
+        .send(produce_request(9, "events", vec![(3, Some(batch(50)?))])?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 9, PRODUCE_VERSION).await?;
+    let partition =
+        &response.responses[&TopicName(StrBytes::from_str("events"))].partition_responses[0];
+    assert_eq!(partition.index, 3);
+    assert_eq!(partition.error_code, ResponseError::MessageTooLarge.code());
+    assert_eq!(partition.base_offset, -1);
+    assert_eq!(
+        partition.error_message.as_deref(),
+        Some("events-3: Batch of 50 records has more than the maximum of 10.")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn skips_the_records_of_oversized_requests() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let limits = ProduceLimits {
+        max_request_bytes: Some(1024 * 1024),
+        ..ProduceLimits::default()
+    };
+    let proxy = Arc::new(KafkaProxy::new("localhost", None).with_produce_limits(limits));
+    let remote = proxy.expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    // larger than the frames of the codec of the client, hence written as is
+    let large = Bytes::from(vec![0; 9 * 1024 * 1024]);
+    for (correlation_id, version) in [(1, PRODUCE_VERSION), (2, 9)] {
+        let request = produce("events", vec![(3, Some(large.clone())), (4, None)]);
+        let frame = common::request(ApiKey::ProduceKey, version, correlation_id, &request)?;
+        let stream = client.get_mut();
+        stream.write_u32(frame.len() as u32).await?;
+        stream.write_all(&frame).await?;
+    }
+
+    for (correlation_id, version) in [(1, PRODUCE_VERSION), (2, 9)] {
+        let response: ProduceResponse = receive(&mut client, correlation_id, version).await?;
+        let partitions = &response.responses[&TopicName(StrBytes::from_str("events"))];
+        let indexes: Vec<_> = partitions
+            .partition_responses
+            .iter()
+            .map(|partition| (partition.index, partition.error_code))
+            .collect();
+        let too_large = ResponseError::MessageTooLarge.code();
+        assert_eq!(indexes, [(3, too_large), (4, too_large)]);
+        assert!(partitions.partition_responses[0]
+            .error_message
+            .as_deref()
+            .unwrap()
+            .ends_with("is larger than the maximum of 1048576."));
+    }
+    // rejected requests still count, and their clients are known
+    assert_eq!(proxy.request_counts()["Produce"], 2);
+    assert_eq!(proxy.clients().len(), 1);
+
+    // the connection is still usable, and only the small request reached the broker
+    client
+        .send(produce_request(3, "events", vec![(3, Some(batch(1)?))])?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 3, PRODUCE_VERSION).await?;
+    assert_eq!(produce_error(&response, "events"), 0);
+    let (header, _): (_, ProduceRequest) = broker.next(ApiKey::ProduceKey).await?;
+    assert_eq!(header.correlation_id, 3);
+    Ok(())
+}
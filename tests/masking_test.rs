@@ -0,0 +1,150 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use conduktor_kafka_proxy::masking::Masking;
+use conduktor_kafka_proxy::records::{split_batches, RecordBatch, MAX_DECOMPRESSED_BYTES};
+use indexmap::IndexMap;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions,
+    TimestampType, CASTAGNOLI,
+};
+
+/// Encode a batch with the reference encoder.
+fn encode_batch(values: &[&str], compression: Compression) -> Result<Bytes> {
+    let records: Vec<_> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| Record {
+            transactional: false,
+            control: false,
+            partition_leader_epoch: 3,
+            producer_id: 42,
+            producer_epoch: 1,
+            timestamp_type: TimestampType::Creation,
+            offset: 100 + i as i64,
+            sequence: 7 + i as i32,
+            timestamp: 1_700_000_000_000 + i as i64,
+            key: Some(Bytes::from(format!("key-{i}"))),
+            value: Some(Bytes::from(value.to_string())),
+            headers: IndexMap::new(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut buf,
+        records.iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression,
+        },
+    )?;
+    Ok(buf.freeze())
+}
+
+fn values(records: &[Record]) -> Vec<String> {
+    records
+        .iter()
+        .map(|record| String::from_utf8_lossy(record.value.as_ref().unwrap()).into_owned())
+        .collect()
+}
+
+#[test]
+fn masks_json_fields_and_keeps_batches_valid() -> Result<()> {
+    let masking = Masking::from_json(
+        r#"[
+            { "topic": "customers.*", "path": "$.email", "action": "hash" },
+            { "topic": "customers.*", "path": "$.cards[*].number", "action": "redact" },
+            { "topic": "customers.*", "path": "$.ssn", "action": "drop" },
+            { "topic": "customers.*", "regex": "secret-\\d+", "action": "redact" }
+        ]"#,
+    )?;
+
+    let mut records = BytesMut::new();
+    records.extend_from_slice(&encode_batch(
+        &[
+            r#"{"email":"jane@example.com","ssn":"123","cards":[{"number":"4111"}]}"#,
+            "plain secret-42 text",
+        ],
+        Compression::Gzip,
+    )?);
+    records.extend_from_slice(&encode_batch(&[r#"{"name":"bob"}"#], Compression::None)?);
+    // a partial batch, as brokers send when hitting the fetch size limit
+    let partial = encode_batch(&["{}"], Compression::None)?;
+    records.extend_from_slice(&partial[..partial.len() - 3]);
+
+    assert!(masking.applies_to("customers-eu"));
+    assert!(!masking.applies_to("orders"));
+    let masked = masking.mask_records("customers-eu", &records.freeze())?;
+
+    assert_eq!(split_batches(&masked).len(), 2);
+    let decoded = RecordBatchDecoder::decode(&mut masked.clone())?;
+    assert_eq!(
+        values(&decoded),
+        [
+            format!(
+                r#"{{"email":"{}","cards":[{{"number":"****"}}]}}"#,
+                hex_sha256("jane@example.com")
+            ),
+            "plain **** text".to_string(),
+            r#"{"name":"bob"}"#.to_string(),
+        ]
+    );
+    assert_eq!(decoded[0].offset, 100);
+    assert_eq!(decoded[1].sequence, 8);
+    assert_eq!(decoded[1].producer_id, 42);
+    assert_eq!(decoded[0].key.as_deref(), Some(&b"key-0"[..]));
+    Ok(())
+}
+
+#[test]
+fn recompresses_with_every_codec() -> Result<()> {
+    let batch = encode_batch(&["a", "b"], Compression::None)?;
+    let mut decoded = RecordBatch::decode(&batch)?;
+    for codec in 1..=4 {
+        decoded.header.attributes = codec;
+        let encoded = decoded.encode()?;
+        let roundtrip = RecordBatch::decode(&encoded)?;
+        assert_eq!(roundtrip.header.attributes, codec);
+        assert_eq!(roundtrip.records, decoded.records, "codec {codec}");
+    }
+    Ok(())
+}
+
+#[test]
+fn rejects_decompression_bombs() -> Result<()> {
+    let mut batch = RecordBatch::decode(&encode_batch(&["a"], Compression::None)?)?;
+    batch.records[0].value = Some(Bytes::from(vec![0; MAX_DECOMPRESSED_BYTES + 1]));
+    for codec in 1..=4 {
+        batch.header.attributes = codec;
+        let err = RecordBatch::decode(&batch.encode()?).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("once decompressed"),
+            "codec {codec}: {err:#}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn rejects_oversized_record_counts() -> Result<()> {
+    let mut batch = BytesMut::from(&encode_batch(&["a"], Compression::None)?[..]);
+    // records count, then the CRC of the attributes onwards
+    batch[57..61].copy_from_slice(&i32::MAX.to_be_bytes());
+    let crc = CASTAGNOLI.checksum(&batch[21..]);
+    batch[17..21].copy_from_slice(&crc.to_be_bytes());
+    assert!(RecordBatch::decode(&batch.freeze()).is_err());
+    Ok(())
+}
+
+#[test]
+fn rejects_invalid_rules() {
+    assert!(Masking::from_json(r#"[{ "topic": "t", "action": "hash" }]"#).is_err());
+    assert!(
+        Masking::from_json(r#"[{ "topic": "t", "path": "email", "action": "hash" }]"#).is_err()
+    );
+    assert!(Masking::from_json(r#"[{ "topic": "t", "path": "$.a", "action": "x" }]"#).is_err());
+}
+
+fn hex_sha256(text: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(text.as_bytes()))
+}
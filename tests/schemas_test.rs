@@ -0,0 +1,183 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use common::{connect, receive, spawn_server, FakeBroker, PRODUCE_VERSION};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::schemas::SchemaValidation;
+use futures_util::SinkExt;
+use indexmap::IndexMap;
+use kafka_protocol::messages::ProduceResponse;
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+use uuid::Uuid;
+
+/// Write files to a new temporary directory, returning its path.
+fn write_files(files: &[(&str, &str)]) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("schemas-test-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+    for (name, content) in files {
+        fs::write(dir.join(name), content)?;
+    }
+    Ok(dir)
+}
+
+fn batch(values: &[&[u8]]) -> Result<Bytes> {
+    let records: Vec<_> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| Record {
+            transactional: false,
+            control: false,
+            partition_leader_epoch: 0,
+            producer_id: -1,
+            producer_epoch: -1,
+            timestamp_type: TimestampType::Creation,
+            offset: i as i64,
+            sequence: -1,
+            timestamp: 0,
+            key: None,
+            value: Some(Bytes::copy_from_slice(value)),
+            headers: IndexMap::new(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut buf,
+        records.iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression: Compression::Snappy,
+        },
+    )?;
+    Ok(buf.freeze())
+}
+
+#[test]
+fn validates_every_schema_type() -> Result<()> {
+    let dir = write_files(&[
+        (
+            "schemas.json",
+            r#"[
+                { "topic": "orders", "type": "json", "schema": "order.json" },
+                { "topic": "payments", "type": "avro", "schema": "payment.avsc" },
+                { "topic": "users", "type": "protobuf", "schema": "user.proto", "message": "acme.User" }
+            ]"#,
+        ),
+        (
+            "order.json",
+            r#"{ "type": "object", "required": ["id"], "properties": { "id": { "type": "integer" } } }"#,
+        ),
+        (
+            "payment.avsc",
+            r#"{ "type": "record", "name": "Payment", "fields": [{ "name": "amount", "type": "long" }] }"#,
+        ),
+        (
+            "user.proto",
+            "syntax = \"proto3\";\npackage acme;\nmessage User { string name = 1; int32 age = 2; }\n",
+        ),
+    ])?;
+    let schemas = SchemaValidation::from_file(&dir.join("schemas.json"))?;
+    assert!(schemas.applies_to("orders"));
+    assert!(!schemas.applies_to("orders-2"));
+
+    assert!(schemas
+        .validate_records("orders", &batch(&[br#"{"id":1}"#, br#"{"id":2}"#])?)
+        .is_ok());
+    let err = schemas
+        .validate_records("orders", &batch(&[br#"{"id":1}"#, br#"{"id":"x"}"#])?)
+        .unwrap_err();
+    // every record is a batch of its own, the index is the one within the batch
+    assert_eq!(err.batch_index, Some(0));
+    assert!(
+        err.message
+            .starts_with("record 0 of batch 1 does not match schema order.json"),
+        "{}",
+        err.message
+    );
+
+    // zig-zag encoded long 21, then a truncated datum
+    assert!(schemas
+        .validate_records("payments", &batch(&[&[42]])?)
+        .is_ok());
+    assert!(schemas
+        .validate_records("payments", &batch(&[&[0x80]])?)
+        .is_err());
+
+    assert!(schemas
+        .validate_records("users", &batch(&[b"\x0a\x03bob\x10\x05"])?)
+        .is_ok());
+    assert!(schemas
+        .validate_records("users", &batch(&[b"\x0a\xff"])?)
+        .is_err());
+
+    // topics without schemas are not checked
+    assert!(schemas
+        .validate_records("other", &Bytes::from("junk"))
+        .is_ok());
+    Ok(())
+}
+
+fn produce_request(correlation_id: i32, partitions: &[(i32, &[u8])]) -> Result<Bytes> {
+    let partitions = partitions
+        .iter()
+        .map(|(index, value)| Ok((*index, Some(batch(&[value])?))))
+        .collect::<Result<_>>()?;
+    common::produce_request(correlation_id, "orders", partitions)
+}
+
+/// Error codes of a produce response, by partition.
+fn error_codes(response: ProduceResponse) -> Vec<(i32, i16)> {
+    let mut codes: Vec<_> = response
+        .responses
+        .values()
+        .flat_map(|topic| &topic.partition_responses)
+        .map(|partition| (partition.index, partition.error_code))
+        .collect();
+    codes.sort();
+    codes
+}
+
+#[tokio::test]
+async fn rejects_invalid_records_in_order() -> Result<()> {
+    let dir = write_files(&[
+        (
+            "schemas.json",
+            r#"[{ "topic": "orders", "type": "json", "schema": "order.json" }]"#,
+        ),
+        ("order.json", r#"{ "type": "object", "required": ["id"] }"#),
+    ])?;
+    // a slow broker, so that rejected requests wait for the responses to the previous ones
+    let broker = FakeBroker::slow(Duration::from_millis(100)).await?;
+    spawn_server().await;
+
+    let proxy = KafkaProxy::new("localhost", None)
+        .with_schemas(SchemaValidation::from_file(&dir.join("schemas.json"))?);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+
+    let mut client = connect(&remote).await?;
+    let valid: &[u8] = br#"{"id":1}"#;
+    let invalid: &[u8] = b"{}";
+    client.send(produce_request(1, &[(0, valid)])?).await?;
+    client.send(produce_request(2, &[(0, invalid)])?).await?;
+    client
+        .send(produce_request(3, &[(0, valid), (1, invalid)])?)
+        .await?;
+
+    let mut responses = vec![];
+    for correlation_id in 1..=3 {
+        let response = receive(&mut client, correlation_id, PRODUCE_VERSION).await?;
+        responses.push(error_codes(response));
+    }
+    assert_eq!(
+        responses,
+        [vec![(0, 0)], vec![(0, 87)], vec![(0, 0), (1, 87)]]
+    );
+    Ok(())
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use common::{connect, produce_request, receive, spawn_server, FakeBroker, PRODUCE_VERSION};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::records::{BatchHeader, RecordBatch};
+use conduktor_kafka_proxy::shadow::ShadowWriter;
+use futures_util::SinkExt;
+use indexmap::IndexMap;
+use kafka_protocol::messages::{ApiKey, ProduceRequest, ProduceResponse};
+use kafka_protocol::records::{
+    Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType,
+};
+
+/// A batch of an idempotent producer.
+fn batch(value: &[u8]) -> Result<Bytes> {
+    let record = Record {
+        transactional: false,
+        control: false,
+        partition_leader_epoch: 0,
+        producer_id: 42,
+        producer_epoch: 3,
+        timestamp_type: TimestampType::Creation,
+        offset: 0,
+        sequence: 7,
+        timestamp: 0,
+        key: None,
+        value: Some(Bytes::copy_from_slice(value)),
+        headers: IndexMap::new(),
+    };
+    let mut buf = BytesMut::new();
+    RecordBatchEncoder::encode(
+        &mut buf,
+        [record].iter(),
+        &RecordEncodeOptions {
+            version: 2,
+            compression: Compression::Gzip,
+        },
+    )?;
+    Ok(buf.freeze())
+}
+
+/// The records of the next produce request received by a broker.
+async fn produced_records(broker: &mut FakeBroker) -> Result<Bytes> {
+    let (_, produce): (_, ProduceRequest) = broker.next(ApiKey::ProduceKey).await?;
+    let data = produce.topic_data.into_values().next().unwrap();
+    Ok(data.partition_data[0].records.clone().unwrap())
+}
+
+#[tokio::test]
+async fn mirrors_produce_requests() -> Result<()> {
+    let mut primary = FakeBroker::spawn().await?;
+    let mut secondary = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let shadow = Arc::new(ShadowWriter::new(&secondary.addr));
+    tokio::spawn(Arc::clone(&shadow).run());
+    // a secondary that is down must not affect the primary either
+    let unreachable = Arc::new(ShadowWriter::new("127.0.0.1:1"));
+    tokio::spawn(Arc::clone(&unreachable).run());
+
+    for shadow in [shadow, unreachable] {
+        let proxy = KafkaProxy::new("localhost", None).with_shadow(shadow);
+        let remote = Arc::new(proxy).expose(&primary.addr).await?;
+        let mut client = connect(&remote).await?;
+        client
+            .send(produce_request(
+                1,
+                "orders",
+                vec![(0, Some(batch(b"order")?))],
+            )?)
+            .await?;
+        let _: ProduceResponse = receive(&mut client, 1, PRODUCE_VERSION).await?;
+
+        // the primary receives the batch as produced
+        let records = produced_records(&mut primary).await?;
+        assert_eq!(BatchHeader::peek(&records)?.producer_id, 42);
+    }
+
+    // the secondary receives it without the producer of the primary cluster
+    let records = produced_records(&mut secondary).await?;
+    let batch = RecordBatch::decode(&records)?;
+    assert_eq!(
+        (
+            batch.header.producer_id,
+            batch.header.producer_epoch,
+            batch.header.base_sequence
+        ),
+        (-1, -1, -1)
+    );
+    assert_eq!(batch.records[0].value.as_deref(), Some(&b"order"[..]));
+    assert!(secondary.is_idle());
+    Ok(())
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use common::{connect, receive, request, spawn_server, FakeBroker};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::slowlog::{SlowLog, SlowRequest};
+use futures_util::SinkExt;
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::{ApiKey, FetchRequest, FetchResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+
+/// A fetch version without a top-level error code.
+const FETCH_VERSION: i16 = 4;
+
+fn slow_request(client_id: &str, api_key: ApiKey, elapsed_ms: u64, error_code: i16) -> SlowRequest {
+    SlowRequest {
+        api_key,
+        api_version: 0,
+        correlation_id: 0,
+        client_id: Some(client_id.to_string()),
+        elapsed: Duration::from_millis(elapsed_ms),
+        error_code,
+    }
+}
+
+#[test]
+fn fast_successful_requests_are_ignored() {
+    let slow_log = SlowLog::new(Duration::from_millis(100), Duration::from_secs(60));
+    slow_log.record(slow_request("fast", ApiKey::FetchKey, 10, 0));
+    assert!(slow_log.worst_offenders(10).is_empty());
+}
+
+#[test]
+fn worst_offenders_are_sorted_by_latency() {
+    let slow_log = SlowLog::new(Duration::from_millis(100), Duration::from_secs(60));
+    slow_log.record(slow_request("consumer", ApiKey::FetchKey, 150, 0));
+    slow_log.record(slow_request("consumer", ApiKey::FetchKey, 900, 0));
+    slow_log.record(slow_request("producer", ApiKey::ProduceKey, 400, 0));
+    slow_log.record(slow_request("producer", ApiKey::ProduceKey, 5, 6));
+
+    let offenders = slow_log.worst_offenders(10);
+    assert_eq!(offenders.len(), 2);
+
+    assert_eq!(offenders[0].client_id.as_deref(), Some("consumer"));
+    assert_eq!(offenders[0].count, 2);
+    assert_eq!(offenders[0].errors, 0);
+    assert_eq!(offenders[0].max_elapsed, Duration::from_millis(900));
+
+    assert_eq!(offenders[1].client_id.as_deref(), Some("producer"));
+    assert_eq!(offenders[1].count, 2);
+    assert_eq!(offenders[1].errors, 1);
+
+    assert_eq!(slow_log.worst_offenders(1).len(), 1);
+}
+
+/// Fetch a partition of `orders` through a proxy logging requests slower than a threshold.
+async fn fetch(threshold: Duration, fetch_offset: i64) -> Result<Arc<SlowLog>> {
+    let broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let slow_log = Arc::new(SlowLog::new(threshold, Duration::from_secs(60)));
+    let proxy = KafkaProxy::new("localhost", None).with_slow_log(Arc::clone(&slow_log));
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    let mut partition = FetchPartition::default();
+    partition.fetch_offset = fetch_offset;
+    let mut topic = FetchTopic::default();
+    topic.topic = TopicName(StrBytes::from_str("orders"));
+    topic.partitions.push(partition);
+    let mut fetch = FetchRequest::default();
+    fetch.replica_id = (-1).into();
+    fetch.topics.push(topic);
+    client
+        .send(request(ApiKey::FetchKey, FETCH_VERSION, 1, &fetch)?)
+        .await?;
+    let _: FetchResponse = receive(&mut client, 1, FETCH_VERSION).await?;
+    Ok(slow_log)
+}
+
+#[tokio::test]
+async fn logs_slow_and_failed_fetches() -> Result<()> {
+    let slow_log = fetch(Duration::ZERO, -1).await?;
+    let offenders = slow_log.worst_offenders(10);
+    assert_eq!(offenders.len(), 1);
+    assert_eq!(offenders[0].api_key, ApiKey::FetchKey);
+    assert_eq!(offenders[0].errors, 1);
+
+    let slow_log = fetch(Duration::ZERO, 0).await?;
+    assert_eq!(slow_log.worst_offenders(10)[0].errors, 0);
+
+    // fast responses are logged for their errors only
+    let slow_log = fetch(Duration::from_secs(60), -1).await?;
+    let offenders = slow_log.worst_offenders(10);
+    assert_eq!(offenders.len(), 1);
+    assert_eq!(offenders[0].count, 1);
+    assert_eq!(offenders[0].errors, 1);
+    let slow_log = fetch(Duration::from_secs(60), 0).await?;
+    assert!(slow_log.worst_offenders(10).is_empty());
+    Ok(())
+}
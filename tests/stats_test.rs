@@ -0,0 +1,78 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use common::{connect, spawn_server, FakeBroker};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::records::RecordBatch;
+use conduktor_kafka_proxy::stats::{ProxyStats, StatsPublisher};
+use futures_util::SinkExt;
+use kafka_protocol::messages::{ApiKey, GroupId, HeartbeatRequest, ProduceRequest};
+use kafka_protocol::protocol::StrBytes;
+use serde_json::Value;
+use tokio::time;
+
+const HEARTBEAT_VERSION: i16 = 4;
+
+#[test]
+fn encodes_stats_as_a_record() -> Result<()> {
+    let proxy = KafkaProxy::new("localhost", None).with_name("staging");
+    let stats = ProxyStats::collect(&proxy);
+    // not started yet
+    assert!(!stats.healthy);
+
+    let batch = RecordBatch::decode(&stats.encode()?)?;
+    assert_eq!(batch.records.len(), 1);
+    assert_eq!(batch.header.base_timestamp, stats.timestamp_ms as i64);
+    let record = &batch.records[0];
+    assert_eq!(record.key.as_deref(), Some(&b"staging"[..]));
+    let value: Value = serde_json::from_slice(record.value.as_deref().unwrap())?;
+    assert_eq!(value["cluster"], "staging");
+    assert_eq!(value["connections_total"], 0);
+    assert_eq!(value["requests"], serde_json::json!({}));
+    Ok(())
+}
+
+fn heartbeat_request() -> Result<Bytes> {
+    let mut request = HeartbeatRequest::default();
+    request.group_id = GroupId(StrBytes::from_str("billing-consumers"));
+    common::request(ApiKey::HeartbeatKey, HEARTBEAT_VERSION, 1, &request)
+}
+
+#[tokio::test]
+async fn publishes_stats_to_the_local_cluster() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let proxy = Arc::new(KafkaProxy::new("localhost", None).with_name("staging"));
+    let remote = proxy.expose(&broker.addr).await?;
+    let publisher = StatsPublisher::new("_tunnel_stats", Duration::from_millis(100));
+    tokio::spawn(publisher.run(Arc::clone(&proxy)));
+
+    let mut client = connect(&remote).await?;
+    client.send(heartbeat_request()?).await?;
+
+    // records published before the heartbeat went through do not count it yet
+    let stats = time::timeout(Duration::from_secs(5), async {
+        loop {
+            let (_, produce): (_, ProduceRequest) = broker.next(ApiKey::ProduceKey).await?;
+            let (topic, data) = produce.topic_data.first().unwrap();
+            assert_eq!(&**topic, "_tunnel_stats");
+            let records = data.partition_data[0].records.as_ref().unwrap();
+            let batch = RecordBatch::decode(records)?;
+            let value: Value = serde_json::from_slice(batch.records[0].value.as_deref().unwrap())?;
+            if value["requests"]["Heartbeat"] == 1 {
+                return anyhow::Ok(value);
+            }
+        }
+    })
+    .await??;
+    assert_eq!(stats["cluster"], "staging");
+    assert_eq!(stats["connections"], 1);
+    assert_eq!(stats["healthy"], true);
+    assert_eq!(stats["tunnels"][0]["state"], "open");
+    Ok(())
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use common::{connect, receive, spawn_server, FakeBroker};
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::topic_policy::TopicPolicy;
+use futures_util::SinkExt;
+use kafka_protocol::messages::create_topics_request::{CreatableTopic, CreateableTopicConfig};
+use kafka_protocol::messages::{ApiKey, CreateTopicsRequest, CreateTopicsResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::ResponseError;
+
+const CREATE_TOPICS_VERSION: i16 = 5;
+
+const POLICY: &str = r#"{
+    "max_partitions": 12,
+    "replication_factor": 3,
+    "allowed_configs": ["retention.ms"],
+    "name_pattern": "[a-z][a-z0-9.-]*"
+}"#;
+
+fn topic(partitions: i32, replication_factor: i16, configs: &[&'static str]) -> CreatableTopic {
+    let mut topic = CreatableTopic::default();
+    topic.num_partitions = partitions;
+    topic.replication_factor = replication_factor;
+    for config in configs {
+        topic
+            .configs
+            .insert(StrBytes::from_str(config), CreateableTopicConfig::default());
+    }
+    topic
+}
+
+#[test]
+fn checks_created_topics() -> Result<()> {
+    let policy = TopicPolicy::from_json(POLICY)?;
+    assert_eq!(
+        policy.check_topic("orders", &topic(6, 3, &["retention.ms"])),
+        Ok(())
+    );
+    assert_eq!(policy.check_topic("orders", &topic(-1, 3, &[])), Ok(()));
+    assert_eq!(
+        policy.check_topic("orders", &topic(200, 3, &[])),
+        Err("Topic orders would have 200 partitions, more than the maximum of 12.".to_string())
+    );
+    assert_eq!(
+        policy.check_topic("orders", &topic(6, 1, &[])),
+        Err("Topic orders has a replication factor of 1 instead of 3.".to_string())
+    );
+    assert!(policy.check_topic("orders", &topic(6, -1, &[])).is_err());
+    assert_eq!(
+        policy.check_topic("orders", &topic(6, 3, &["cleanup.policy"])),
+        Err("Config cleanup.policy is not allowed on topic orders.".to_string())
+    );
+    assert!(policy.check_topic("Orders_V2", &topic(6, 3, &[])).is_err());
+    assert!(policy.check_partitions("orders", 12).is_ok());
+    assert!(policy.check_partitions("orders", 13).is_err());
+
+    assert!(TopicPolicy::from_json(r#"{ "max_partition": 12 }"#).is_err());
+    assert!(TopicPolicy::from_json(r#"{ "name_pattern": "[" }"#).is_err());
+    Ok(())
+}
+
+fn create_topics_request(topics: Vec<(&'static str, CreatableTopic)>) -> Result<Bytes> {
+    let mut request = CreateTopicsRequest::default();
+    for (name, topic) in topics {
+        request
+            .topics
+            .insert(TopicName(StrBytes::from_str(name)), topic);
+    }
+    common::request(ApiKey::CreateTopicsKey, CREATE_TOPICS_VERSION, 3, &request)
+}
+
+#[tokio::test]
+async fn rejects_violating_topics() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let proxy =
+        KafkaProxy::new("localhost", None).with_topic_policy(TopicPolicy::from_json(POLICY)?);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+    client
+        .send(create_topics_request(vec![
+            ("orders", topic(6, 3, &[])),
+            ("load-test", topic(200, 1, &[])),
+        ])?)
+        .await?;
+    let response: CreateTopicsResponse = receive(&mut client, 3, CREATE_TOPICS_VERSION).await?;
+
+    // only the compliant topic reaches the broker
+    let (_, created): (_, CreateTopicsRequest) = broker.next(ApiKey::CreateTopicsKey).await?;
+    let names: Vec<_> = created.topics.keys().map(|name| name.to_string()).collect();
+    assert_eq!(names, ["orders"]);
+    let result = |name: &'static str| &response.topics[&TopicName(StrBytes::from_str(name))];
+    assert_eq!(result("orders").error_code, 0);
+    assert_eq!(
+        result("load-test").error_code,
+        ResponseError::PolicyViolation.code()
+    );
+    assert_eq!(
+        result("load-test").error_message.as_deref(),
+        Some("Topic load-test would have 200 partitions, more than the maximum of 12.")
+    );
+    Ok(())
+}
@@ -0,0 +1,216 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use common::{
+    connect, produce, produce_error, receive, request, spawn_server, FakeBroker, BROKER_VERSIONS,
+    PRODUCE_VERSION,
+};
+use conduktor_kafka_proxy::accounting::TopicAccounting;
+use conduktor_kafka_proxy::acl::Authorizer;
+use conduktor_kafka_proxy::kafka::KafkaProxy;
+use conduktor_kafka_proxy::slowlog::SlowLog;
+use conduktor_kafka_proxy::versions::VersionPolicy;
+use futures_util::{SinkExt, StreamExt};
+use kafka_protocol::messages::api_versions_response::ApiVersion;
+use kafka_protocol::messages::{
+    ApiKey, ApiVersionsRequest, ApiVersionsResponse, FetchRequest, ProduceResponse,
+};
+use kafka_protocol::ResponseError;
+use tokio::time;
+
+fn api_versions(keys: &[(ApiKey, i16, i16)]) -> ApiVersionsResponse {
+    let mut response = ApiVersionsResponse::default();
+    for &(api_key, min_version, max_version) in keys {
+        let mut version = ApiVersion::default();
+        version.min_version = min_version;
+        version.max_version = max_version;
+        response.api_keys.insert(api_key as i16, version);
+    }
+    response
+}
+
+#[test]
+fn trims_api_versions() -> Result<()> {
+    let policy = VersionPolicy::parse(&["Produce=3", "Fetch=4", "SaslHandshake=2"])?;
+    assert_eq!(policy.minimum(ApiKey::ProduceKey), Some(3));
+    assert_eq!(policy.minimum(ApiKey::MetadataKey), None);
+    assert!(!policy.allows(ApiKey::ProduceKey, 2));
+    assert!(policy.allows(ApiKey::ProduceKey, 3));
+    assert!(policy.allows(ApiKey::MetadataKey, 0));
+
+    let mut response = api_versions(&[
+        (ApiKey::ProduceKey, 0, 9),
+        (ApiKey::FetchKey, 5, 13),
+        (ApiKey::MetadataKey, 0, 12),
+        (ApiKey::SaslHandshakeKey, 0, 1),
+    ]);
+    policy.trim(&mut response);
+    assert_eq!(
+        response,
+        api_versions(&[
+            (ApiKey::ProduceKey, 3, 9),
+            (ApiKey::FetchKey, 5, 13),
+            (ApiKey::MetadataKey, 0, 12),
+        ])
+    );
+
+    for invalid in ["Produce", "Producer=3", "Produce=-1", "Produce=three"] {
+        assert!(VersionPolicy::parse(&[invalid]).is_err(), "{invalid}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_old_requests() -> Result<()> {
+    // a broker that never answers, old requests must not reach it
+    let broker = FakeBroker::silent().await?;
+    spawn_server().await;
+
+    let policy = VersionPolicy::parse(&["Produce=3"])?;
+    let proxy = KafkaProxy::new("localhost", None).with_version_policy(policy);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    let old = produce("orders", vec![(0, None)]);
+    client
+        .send(request(ApiKey::ProduceKey, 2, 7, &old)?)
+        .await?;
+    let response: ProduceResponse = receive(&mut client, 7, 2).await?;
+    assert_eq!(
+        produce_error(&response, "orders"),
+        ResponseError::UnsupportedVersion.code()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn answers_rejected_requests_in_order() -> Result<()> {
+    let broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    let policy = VersionPolicy::parse(&["Produce=3"])?;
+    let proxy = KafkaProxy::new("localhost", None).with_version_policy(policy);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    // rejected and forwarded requests pipelined, without waiting for their responses
+    let orders = produce("orders", vec![(0, None)]);
+    for correlation_id in 1..=20 {
+        let version = if correlation_id % 2 == 0 {
+            2
+        } else {
+            PRODUCE_VERSION
+        };
+        client
+            .feed(request(
+                ApiKey::ProduceKey,
+                version,
+                correlation_id,
+                &orders,
+            )?)
+            .await?;
+    }
+    client.flush().await?;
+
+    for correlation_id in 1..=20 {
+        let (version, error) = if correlation_id % 2 == 0 {
+            (2, ResponseError::UnsupportedVersion.code())
+        } else {
+            (PRODUCE_VERSION, 0)
+        };
+        let response: ProduceResponse = receive(&mut client, correlation_id, version).await?;
+        assert_eq!(produce_error(&response, "orders"), error);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn hides_undecodable_versions() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    // authorizing requests decodes them, so only the versions kafka-protocol knows are let through
+    let proxy =
+        KafkaProxy::new("localhost", None).with_acl(Authorizer::from_json(r#"{ "rules": [] }"#)?);
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    client
+        .send(request(
+            ApiKey::ApiVersionsKey,
+            3,
+            1,
+            &ApiVersionsRequest::default(),
+        )?)
+        .await?;
+    let response: ApiVersionsResponse = receive(&mut client, 1, 3).await?;
+    assert_eq!(
+        response,
+        api_versions(&[
+            (ApiKey::ProduceKey, 0, 9),
+            (ApiKey::FetchKey, 0, 13),
+            (ApiKey::ApiVersionsKey, 0, 3),
+        ])
+    );
+    broker.next_request(ApiKey::ApiVersionsKey).await?;
+
+    // like brokers, newer ApiVersions requests are answered with the versions to retry with
+    client
+        .send(request(
+            ApiKey::ApiVersionsKey,
+            4,
+            2,
+            &ApiVersionsRequest::default(),
+        )?)
+        .await?;
+    let response: ApiVersionsResponse = receive(&mut client, 2, 0).await?;
+    let mut expected = api_versions(&[(ApiKey::ApiVersionsKey, 0, 3)]);
+    expected.error_code = ResponseError::UnsupportedVersion.code();
+    assert_eq!(response, expected);
+
+    // a fetch of a version whose layout is unknown cannot be answered, so the connection is closed
+    client
+        .send(request(ApiKey::FetchKey, 15, 3, &FetchRequest::default())?)
+        .await?;
+    assert!(time::timeout(Duration::from_secs(5), client.next())
+        .await?
+        .is_none());
+    assert!(broker.is_idle());
+    Ok(())
+}
+
+#[tokio::test]
+async fn observers_let_every_version_through() -> Result<()> {
+    let mut broker = FakeBroker::spawn().await?;
+    spawn_server().await;
+
+    // the slow log and accounting skip what they cannot decode, clients see the broker as is
+    let slow_log = Arc::new(SlowLog::new(Duration::ZERO, Duration::from_secs(60)));
+    let proxy = KafkaProxy::new("localhost", None)
+        .with_slow_log(slow_log)
+        .with_accounting(Arc::new(TopicAccounting::new()));
+    let remote = Arc::new(proxy).expose(&broker.addr).await?;
+    let mut client = connect(&remote).await?;
+
+    client
+        .send(request(
+            ApiKey::ApiVersionsKey,
+            3,
+            1,
+            &ApiVersionsRequest::default(),
+        )?)
+        .await?;
+    let response: ApiVersionsResponse = receive(&mut client, 1, 3).await?;
+    assert_eq!(response, api_versions(&BROKER_VERSIONS));
+    broker.next_request(ApiKey::ApiVersionsKey).await?;
+
+    client
+        .send(request(ApiKey::FetchKey, 15, 2, &FetchRequest::default())?)
+        .await?;
+    let (header, _) = broker.next_request(ApiKey::FetchKey).await?;
+    assert_eq!(header.request_api_version, 15);
+    Ok(())
+}